@@ -0,0 +1,46 @@
+// Scans about 1MB of Lua source and reports the throughput.
+//
+//     cargo bench --bench scanner
+
+extern crate purua;
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use purua::scanner::Scanner;
+
+const CHUNK: &str = r#"-- naïve fibonacci, with some UTF-8: λ → μ
+local function fib(n)
+  if n < 2 then
+    return n
+  end
+  return fib(n - 1) + fib(n - 2)
+end
+local greeting = "héllo, 世界\n"
+local data = { 0x1F, 3.25e-2, [[long
+string]], 'quoted \"text\"' }
+print(greeting, fib(20), #data)
+"#;
+
+fn main() {
+    let source = CHUNK.repeat((1 << 20) / CHUNK.len() + 1);
+    let runs = 10;
+
+    let mut best = f64::MAX;
+    let mut tokens = 0;
+    for _ in 0..runs {
+        let mut scanner = Scanner::new(black_box(&source));
+        let started = Instant::now();
+        scanner.scan().expect("benchmark source scans");
+        best = best.min(started.elapsed().as_secs_f64());
+        tokens = scanner.tokens.len();
+    }
+
+    println!(
+        "scanned {} bytes ({} tokens) in {:.2} ms, {:.1} MB/s",
+        source.len(),
+        tokens,
+        best * 1000.0,
+        source.len() as f64 / best / 1e6
+    );
+}
@@ -56,8 +56,12 @@ fn walk_expr(stack: &mut Vec<f64>, expr: &Expr) {
                 _ => panic!("Unknown operator: {:?}", op),
             }
         }
-        Expr::Number(v) => {
-            println!("Number: {:?}", *v);
+        Expr::Integer(v) => {
+            println!("Integer: {:?}", *v);
+            stack.push(*v as f64);
+        }
+        Expr::Float(v) => {
+            println!("Float: {:?}", *v);
             stack.push(*v);
         }
         _ => {
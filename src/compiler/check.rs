@@ -0,0 +1,402 @@
+//
+// Static checks of a parsed chunk that depend on its scopes, shared by the
+// compiler and the tree-walking interpreter: labels and the `goto`s jumping
+// to them (as `createlabel` and `solvegotos` in lparser.c), the attributes
+// of locals, and assignments to `<const>` locals.
+//
+use crate::errors::CompileError;
+use crate::parser::ast::*;
+use crate::scanner::{Interner, Symbol};
+use crate::Token;
+
+type CheckResult = Result<(), CompileError>;
+
+/// Checks a chunk, reporting errors like the compiler, prefixed with
+/// `chunkname`. `names` is the interner of the scanner the chunk came from.
+pub fn check<'src>(
+    block: &Block<'src>,
+    names: &mut Interner<'src>,
+    chunkname: &str,
+) -> CheckResult {
+    let mut checker = Checker {
+        chunkname,
+        names,
+        funcs: Vec::new(),
+    };
+    checker.function(&[], false, block)
+}
+
+struct Checker<'a, 'src> {
+    chunkname: &'a str,
+    names: &'a mut Interner<'src>,
+    /// The function being checked and all functions enclosing it.
+    funcs: Vec<FuncScope>,
+}
+
+#[derive(Default)]
+struct FuncScope {
+    /// The active locals, and whether each is `<const>` or `<close>`.
+    locals: Vec<(Symbol, bool)>,
+    blocks: Vec<BlockScope>,
+    /// Labels of the open blocks, which are the ones a `goto` can see.
+    labels: Vec<Label>,
+    /// Forward `goto`s whose label has not been seen yet.
+    gotos: Vec<Label>,
+}
+
+struct BlockScope {
+    /// Number of active locals outside the block.
+    nactvar: usize,
+    /// Index of the first label of the block in `FuncScope::labels`.
+    first_label: usize,
+    /// Index of the first pending `goto` of the block in `FuncScope::gotos`.
+    first_goto: usize,
+}
+
+/// A label, or a pending `goto`.
+struct Label {
+    name: Symbol,
+    line: usize,
+    /// Number of active locals at the label or the `goto`.
+    nactvar: usize,
+}
+
+impl<'a, 'src> Checker<'a, 'src> {
+    fn fs(&mut self) -> &mut FuncScope {
+        self.funcs.last_mut().expect("no function is being checked")
+    }
+
+    fn error(&self, line: usize, message: impl Into<String>) -> CompileError {
+        CompileError {
+            chunkname: self.chunkname.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+
+    fn function(
+        &mut self,
+        params: &[Token<'src>],
+        method: bool,
+        body: &Block<'src>,
+    ) -> CheckResult {
+        self.funcs.push(FuncScope::default());
+        if method {
+            let name = self.names.intern("self");
+            self.fs().locals.push((name, false));
+        }
+        for param in params {
+            self.declare(param, false);
+        }
+        self.enter_block();
+        self.statlist(&body.0, true)?;
+        self.leave_block();
+        let fs = self.funcs.pop().expect("no function is being checked");
+        match fs.gotos.first() {
+            Some(goto) => Err(self.error(
+                goto.line,
+                format!(
+                    "no visible label '{}' for <goto> at line {}",
+                    self.names.resolve(goto.name),
+                    goto.line
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn declare(&mut self, name: &Token<'src>, readonly: bool) {
+        let name = self.names.symbol(name);
+        self.fs().locals.push((name, readonly));
+    }
+
+    fn enter_block(&mut self) {
+        let fs = self.fs();
+        let block = BlockScope {
+            nactvar: fs.locals.len(),
+            first_label: fs.labels.len(),
+            first_goto: fs.gotos.len(),
+        };
+        fs.blocks.push(block);
+    }
+
+    fn leave_block(&mut self) {
+        let fs = self.fs();
+        let block = fs.blocks.pop().expect("no block is open");
+        fs.locals.truncate(block.nactvar);
+        fs.labels.truncate(block.first_label);
+        // Pending gotos now jump from the enclosing block.
+        for goto in &mut fs.gotos[block.first_goto..] {
+            goto.nactvar = block.nactvar;
+        }
+    }
+
+    fn block(&mut self, block: &Block<'src>) -> CheckResult {
+        self.enter_block();
+        self.statlist(&block.0, true)?;
+        self.leave_block();
+        Ok(())
+    }
+
+    /// Checks the statements of a block. `ends_block` is false for the body
+    /// of a `repeat`, whose locals are still visible in the `until`
+    /// condition.
+    fn statlist(&mut self, chunk: &Chunk<'src>, ends_block: bool) -> CheckResult {
+        let Chunk(stats, last_stat) = chunk;
+        for (i, stat) in stats.iter().enumerate() {
+            match stat {
+                Stat::Label(name) => {
+                    let last = ends_block
+                        && last_stat.is_none()
+                        && stats[i + 1..].iter().all(|s| matches!(s, Stat::Label(_)));
+                    self.label(name, last)?;
+                }
+                stat => self.stat(stat)?,
+            }
+        }
+        match last_stat {
+            Some(LastStat::Return(Some(exprlist))) => self.exprlist(exprlist),
+            _ => Ok(()),
+        }
+    }
+
+    /// Declares a label and resolves the pending gotos of the current block
+    /// jumping to it. A label at the end of its block is outside the scope
+    /// of the block's locals, so gotos may jump over their declarations.
+    fn label(&mut self, token: &Token<'src>, last: bool) -> CheckResult {
+        let line = token.line as usize;
+        let name = self.names.symbol(token);
+        let fs = self.funcs.last_mut().expect("no function is being checked");
+        if let Some(label) = fs.labels.iter().find(|label| label.name == name) {
+            let message = format!(
+                "label '{}' already defined on line {}",
+                token.lexeme, label.line
+            );
+            return Err(self.error(line, message));
+        }
+        let block = fs.blocks.last().expect("no block is open");
+        let nactvar = if last { block.nactvar } else { fs.locals.len() };
+        let first_goto = block.first_goto;
+        let jumps_in = fs.gotos[first_goto..]
+            .iter()
+            .find(|goto| goto.name == name && goto.nactvar < nactvar);
+        if let Some(goto) = jumps_in {
+            let message = format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'",
+                token.lexeme,
+                goto.line,
+                self.names.resolve(fs.locals[goto.nactvar].0)
+            );
+            return Err(self.error(line, message));
+        }
+        let mut i = first_goto;
+        while i < fs.gotos.len() {
+            if fs.gotos[i].name == name {
+                fs.gotos.remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        fs.labels.push(Label {
+            name,
+            line,
+            nactvar,
+        });
+        Ok(())
+    }
+
+    fn goto(&mut self, token: &Token<'src>) {
+        let name = self.names.symbol(token);
+        let fs = self.fs();
+        if !fs.labels.iter().any(|label| label.name == name) {
+            let nactvar = fs.locals.len();
+            fs.gotos.push(Label {
+                name,
+                line: token.line as usize,
+                nactvar,
+            });
+        }
+    }
+
+    /// Checks that `name` is not a `<const>` or `<close>` local of this or
+    /// an enclosing function.
+    fn assignable(&mut self, token: &Token<'src>) -> CheckResult {
+        let name = self.names.symbol(token);
+        for fs in self.funcs.iter().rev() {
+            let local = fs.locals.iter().rev().find(|&&(n, _)| n == name);
+            if let Some(&(_, readonly)) = local {
+                if readonly {
+                    let message = format!("attempt to assign to const variable '{}'", token.lexeme);
+                    return Err(self.error(token.line as usize, message));
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&mut self, stat: &Stat<'src>) -> CheckResult {
+        match stat {
+            Stat::Assign(VarList(vars), exprlist) => {
+                for var in vars {
+                    match var {
+                        Var::VarName(name) => self.assignable(name)?,
+                        var => self.var(var)?,
+                    }
+                }
+                self.exprlist(exprlist)?;
+            }
+            Stat::FunctionCall(call) => self.call(call)?,
+            Stat::Do(block) => self.block(block)?,
+            Stat::While(cond, block) => {
+                self.expr(cond)?;
+                self.block(block)?;
+            }
+            Stat::Repeat(cond, block) => {
+                self.enter_block();
+                self.statlist(&block.0, false)?;
+                self.expr(cond)?;
+                self.leave_block();
+            }
+            Stat::If(cond, block, elseifs, else_block) => {
+                self.expr(cond)?;
+                self.block(block)?;
+                for (cond, block) in elseifs {
+                    self.expr(cond)?;
+                    self.block(block)?;
+                }
+                if let Some(block) = else_block {
+                    self.block(block)?;
+                }
+            }
+            Stat::For(name, init, limit, step, block) => {
+                self.expr(init)?;
+                self.expr(limit)?;
+                if let Some(step) = step {
+                    self.expr(step)?;
+                }
+                self.enter_block();
+                self.declare(name, false);
+                self.statlist(&block.0, true)?;
+                self.leave_block();
+            }
+            Stat::ForIn(NameList(names), exprlist, block) => {
+                self.exprlist(exprlist)?;
+                self.enter_block();
+                for name in names {
+                    self.declare(name, false);
+                }
+                self.statlist(&block.0, true)?;
+                self.leave_block();
+            }
+            Stat::Function(FuncName(names, method), FuncBody(ParamList(params, _), body)) => {
+                if let [name] = &names[..] {
+                    if method.is_none() {
+                        self.assignable(name)?;
+                    }
+                }
+                self.function(&params.0, method.is_some(), body)?;
+            }
+            Stat::LocalFunction(name, FuncBody(ParamList(params, _), body)) => {
+                self.declare(name, false);
+                self.function(&params.0, false, body)?;
+            }
+            Stat::LocalDeclVar(AttNameList(names), exprlist) => {
+                if let Some(exprlist) = exprlist {
+                    self.exprlist(exprlist)?;
+                }
+                let mut close = false;
+                for AttName(name, attrib) in names {
+                    let readonly = match attrib {
+                        None => false,
+                        Some(attrib) if attrib.lexeme == "const" => true,
+                        Some(attrib) if attrib.lexeme == "close" && close => {
+                            let message = "multiple to-be-closed variables in local list";
+                            return Err(self.error(attrib.line as usize, message));
+                        }
+                        Some(attrib) if attrib.lexeme == "close" => {
+                            close = true;
+                            true
+                        }
+                        Some(attrib) => {
+                            let message = format!("unknown attribute '{}'", attrib.lexeme);
+                            return Err(self.error(attrib.line as usize, message));
+                        }
+                    };
+                    self.declare(name, readonly);
+                }
+            }
+            Stat::Label(name) => self.label(name, false)?,
+            Stat::Goto(name) => self.goto(name),
+            Stat::Break => {}
+        }
+        Ok(())
+    }
+
+    fn exprlist(&mut self, ExprList(exprs): &ExprList<'src>) -> CheckResult {
+        for expr in exprs {
+            self.expr(expr)?;
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr<'src>) -> CheckResult {
+        match expr {
+            Expr::Function(Function(FuncBody(ParamList(params, _), body))) => {
+                self.function(&params.0, false, body)
+            }
+            Expr::PrefixExp(prefix) => self.prefix(prefix),
+            Expr::TableConstructor(table) => self.table(table),
+            Expr::ExprBinop(lhs, _, rhs) => {
+                self.expr(lhs)?;
+                self.expr(rhs)
+            }
+            Expr::Unop(_, operand) => self.expr(operand),
+            _ => Ok(()),
+        }
+    }
+
+    fn prefix(&mut self, prefix: &PrefixExp<'src>) -> CheckResult {
+        match prefix {
+            PrefixExp::PrefixVar(var) => self.var(var),
+            PrefixExp::PrefixCall(call) => self.call(call),
+            PrefixExp::PrefixParen(expr) => self.expr(expr),
+        }
+    }
+
+    fn var(&mut self, var: &Var<'src>) -> CheckResult {
+        match var {
+            Var::VarName(_) => Ok(()),
+            Var::VarIdx(prefix, key) => {
+                self.prefix(prefix)?;
+                self.expr(key)
+            }
+            Var::VarMember(prefix, _) => self.prefix(prefix),
+        }
+    }
+
+    fn call(&mut self, FunctionCall(prefix, _, args): &FunctionCall<'src>) -> CheckResult {
+        self.prefix(prefix)?;
+        match args {
+            Args::ArgsList(exprlist) => self.exprlist(exprlist),
+            Args::ArgsTable(table) => self.table(table),
+            Args::ArgsNone | Args::ArgsString(_) => Ok(()),
+        }
+    }
+
+    fn table(
+        &mut self,
+        TableConstructor(FieldList(fields)): &TableConstructor<'src>,
+    ) -> CheckResult {
+        for field in fields {
+            match field {
+                Field::AssignIdx(key, value) => {
+                    self.expr(key)?;
+                    self.expr(value)?;
+                }
+                Field::AssignName(_, value) | Field::UniExp(value) => self.expr(value)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//
+// The instruction set of the virtual machine, modelled on the register
+// machine of the reference implementation (see lopcodes.h in Lua 5.1).
+//
+use std::fmt;
+
+use crate::value::ArithOp;
+
+/// A register of the current call frame.
+pub type Reg = u8;
+
+/// Largest number of registers a single function may use.
+pub const MAX_REGS: usize = 250;
+
+/// Number of list items stored by one `SETLIST`.
+pub const FIELDS_PER_FLUSH: usize = 50;
+
+/// An operand that is either a register or a constant ("RK" in lopcodes.h).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rk {
+    Reg(Reg),
+    Const(u32),
+}
+
+/// A single instruction. Operand names follow lopcodes.h: `a` is usually the
+/// target register, `bx` indexes the constants or nested prototypes, and
+/// `sbx` is a jump offset relative to the following instruction. Counts
+/// stored in `b` and `c` are "n + 1", where 0 means "up to the top of the
+/// stack".
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    /// R(a) := R(b)
+    Move { a: Reg, b: Reg },
+    /// R(a) := K(bx)
+    LoadK { a: Reg, bx: u32 },
+    /// R(a) := b; if c then pc++
+    LoadBool { a: Reg, b: bool, c: bool },
+    /// R(a), ..., R(a+b) := nil
+    LoadNil { a: Reg, b: u8 },
+    /// R(a) := UpValue[b]
+    GetUpval { a: Reg, b: u8 },
+    /// R(a) := Gbl[K(bx)]
+    GetGlobal { a: Reg, bx: u32 },
+    /// R(a) := R(b)[RK(c)]
+    GetTable { a: Reg, b: Reg, c: Rk },
+    /// Gbl[K(bx)] := R(a)
+    SetGlobal { a: Reg, bx: u32 },
+    /// UpValue[b] := R(a)
+    SetUpval { a: Reg, b: u8 },
+    /// R(a)[RK(b)] := RK(c)
+    SetTable { a: Reg, b: Rk, c: Rk },
+    /// R(a) := {} with room for `b` list items and `c` other fields
+    NewTable { a: Reg, b: u32, c: u32 },
+    /// R(a+1) := R(b); R(a) := R(b)[RK(c)]
+    Method { a: Reg, b: Reg, c: Rk },
+    /// R(a) := RK(b) op RK(c)
+    Arith { op: ArithOp, a: Reg, b: Rk, c: Rk },
+    /// R(a) := -R(b)
+    Unm { a: Reg, b: Reg },
+    /// R(a) := ~R(b)
+    BNot { a: Reg, b: Reg },
+    /// R(a) := not R(b)
+    Not { a: Reg, b: Reg },
+    /// R(a) := #R(b)
+    Len { a: Reg, b: Reg },
+    /// R(a) := R(b) .. ... .. R(c)
+    Concat { a: Reg, b: Reg, c: Reg },
+    /// pc += sbx
+    Jmp { sbx: i32 },
+    /// if (RK(b) == RK(c)) ~= a then pc++
+    Eq { a: bool, b: Rk, c: Rk },
+    /// if (RK(b) < RK(c)) ~= a then pc++
+    Lt { a: bool, b: Rk, c: Rk },
+    /// if (RK(b) <= RK(c)) ~= a then pc++
+    Le { a: bool, b: Rk, c: Rk },
+    /// if R(a) is truthy ~= c then pc++
+    Test { a: Reg, c: bool },
+    /// R(a), ..., R(a+c-2) := R(a)(R(a+1), ..., R(a+b-1))
+    Call { a: Reg, b: u8, c: u8 },
+    /// return R(a)(R(a+1), ..., R(a+b-1))
+    TailCall { a: Reg, b: u8 },
+    /// return R(a), ..., R(a+b-2)
+    Return { a: Reg, b: u8 },
+    /// Prepares the loop over R(a) = initial value, R(a+1) = limit and
+    /// R(a+2) = step: R(a+3) := R(a), or pc += sbx when the loop is empty.
+    ForPrep { a: Reg, sbx: i32 },
+    /// Advances R(a); if the loop goes on, R(a+3) := R(a) and pc += sbx.
+    ForLoop { a: Reg, sbx: i32 },
+    /// R(a+3), ..., R(a+2+c) := R(a)(R(a+1), R(a+2))
+    TForCall { a: Reg, c: u8 },
+    /// if R(a+1) ~= nil then { R(a) := R(a+1); pc += sbx }
+    TForLoop { a: Reg, sbx: i32 },
+    /// R(a)[c+i] := R(a+i) for 1 <= i <= b
+    SetList { a: Reg, b: u8, c: u32 },
+    /// Closes every upvalue pointing at R(a) or above and calls the
+    /// `__close` metamethods of the to-be-closed variables there.
+    Close { a: Reg },
+    /// Marks R(a) as a to-be-closed variable.
+    Tbc { a: Reg },
+    /// R(a) := closure(KPROTO[bx])
+    Closure { a: Reg, bx: u32 },
+    /// R(a), ..., R(a+b-2) := vararg
+    VarArg { a: Reg, b: u8 },
+}
+
+impl fmt::Display for Rk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rk::Reg(r) => write!(f, "{}", r),
+            Rk::Const(k) => write!(f, "K{}", k),
+        }
+    }
+}
+
+fn arith_name(op: ArithOp) -> &'static str {
+    match op {
+        ArithOp::Add => "ADD",
+        ArithOp::Sub => "SUB",
+        ArithOp::Mul => "MUL",
+        ArithOp::Div => "DIV",
+        ArithOp::Mod => "MOD",
+        ArithOp::Pow => "POW",
+        ArithOp::IDiv => "IDIV",
+        ArithOp::BAnd => "BAND",
+        ArithOp::BOr => "BOR",
+        ArithOp::BXor => "BXOR",
+        ArithOp::Shl => "SHL",
+        ArithOp::Shr => "SHR",
+        ArithOp::Unm => "UNM",
+        ArithOp::BNot => "BNOT",
+    }
+}
+
+/// Formats instructions like `luac -l`, e.g. `GETTABLE  0 1 K2`.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+
+        let b = |b: bool| b as u8;
+        let (name, operands) = match *self {
+            Move { a, b } => ("MOVE", format!("{} {}", a, b)),
+            LoadK { a, bx } => ("LOADK", format!("{} K{}", a, bx)),
+            LoadBool { a, b: v, c } => ("LOADBOOL", format!("{} {} {}", a, b(v), b(c))),
+            LoadNil { a, b } => ("LOADNIL", format!("{} {}", a, b)),
+            GetUpval { a, b } => ("GETUPVAL", format!("{} {}", a, b)),
+            GetGlobal { a, bx } => ("GETGLOBAL", format!("{} K{}", a, bx)),
+            GetTable { a, b, c } => ("GETTABLE", format!("{} {} {}", a, b, c)),
+            SetGlobal { a, bx } => ("SETGLOBAL", format!("{} K{}", a, bx)),
+            SetUpval { a, b } => ("SETUPVAL", format!("{} {}", a, b)),
+            SetTable { a, b, c } => ("SETTABLE", format!("{} {} {}", a, b, c)),
+            NewTable { a, b, c } => ("NEWTABLE", format!("{} {} {}", a, b, c)),
+            Method { a, b, c } => ("SELF", format!("{} {} {}", a, b, c)),
+            Arith { op, a, b, c } => (arith_name(op), format!("{} {} {}", a, b, c)),
+            Unm { a, b } => ("UNM", format!("{} {}", a, b)),
+            BNot { a, b } => ("BNOT", format!("{} {}", a, b)),
+            Not { a, b } => ("NOT", format!("{} {}", a, b)),
+            Len { a, b } => ("LEN", format!("{} {}", a, b)),
+            Concat { a, b, c } => ("CONCAT", format!("{} {} {}", a, b, c)),
+            Jmp { sbx } => ("JMP", format!("{}", sbx)),
+            Eq { a, b: l, c } => ("EQ", format!("{} {} {}", b(a), l, c)),
+            Lt { a, b: l, c } => ("LT", format!("{} {} {}", b(a), l, c)),
+            Le { a, b: l, c } => ("LE", format!("{} {} {}", b(a), l, c)),
+            Test { a, c } => ("TEST", format!("{} {}", a, b(c))),
+            Call { a, b, c } => ("CALL", format!("{} {} {}", a, b, c)),
+            TailCall { a, b } => ("TAILCALL", format!("{} {}", a, b)),
+            Return { a, b } => ("RETURN", format!("{} {}", a, b)),
+            ForPrep { a, sbx } => ("FORPREP", format!("{} {}", a, sbx)),
+            ForLoop { a, sbx } => ("FORLOOP", format!("{} {}", a, sbx)),
+            TForCall { a, c } => ("TFORCALL", format!("{} {}", a, c)),
+            TForLoop { a, sbx } => ("TFORLOOP", format!("{} {}", a, sbx)),
+            SetList { a, b, c } => ("SETLIST", format!("{} {} {}", a, b, c)),
+            Close { a } => ("CLOSE", format!("{}", a)),
+            Tbc { a } => ("TBC", format!("{}", a)),
+            Closure { a, bx } => ("CLOSURE", format!("{} {}", a, bx)),
+            VarArg { a, b } => ("VARARG", format!("{} {}", a, b)),
+        };
+        write!(f, "{:<9} {}", name, operands)
+    }
+}
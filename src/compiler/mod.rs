@@ -0,0 +1,1479 @@
+//
+// Compiles `parser::ast` into register-based bytecode, producing one
+// `Proto` per function. The code generation follows lparser.c/lcode.c of
+// the reference implementation: locals live in the lowest registers of a
+// frame, temporaries are allocated above them like a stack, and conditions
+// are compiled into lists of jumps to be patched later.
+//
+pub mod check;
+pub mod code;
+pub mod proto;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::errors::CompileError;
+use crate::parser::ast;
+use crate::parser::ast::*;
+use crate::token_type::TokenType;
+use crate::scanner::{Interner, Symbol};
+use crate::value::{ArithOp, LuaString, Value};
+use crate::Token;
+
+use code::{Instruction, Reg, Rk, FIELDS_PER_FLUSH, MAX_REGS};
+use proto::{LocalVar, Proto, UpvalDesc};
+
+type CompileResult<T> = Result<T, CompileError>;
+
+const MAX_LOCALS: usize = 200;
+const MAX_UPVALUES: usize = 255;
+
+/// Compiles a parsed chunk into the prototype of its main function, which
+/// receives the chunk's arguments as `...`. `names` is the interner of the
+/// scanner the chunk came from, which gave its names their symbols.
+pub fn compile<'src>(
+    block: &Block<'src>,
+    names: &mut Interner<'src>,
+    chunkname: &str,
+) -> CompileResult<Rc<Proto>> {
+    check::check(block, names, chunkname)?;
+    let mut compiler = Compiler {
+        chunkname,
+        names,
+        funcs: Vec::new(),
+        line: 1,
+    };
+    compiler.open_function(0);
+    compiler.fs().proto.is_vararg = true;
+    compiler.chunk(&block.0)?;
+    Ok(Rc::new(compiler.close_function()?))
+}
+
+struct Compiler<'a, 'src> {
+    chunkname: &'a str,
+    names: &'a mut Interner<'src>,
+    /// The function being compiled and all functions enclosing it.
+    funcs: Vec<FuncState>,
+    /// Line of the last token seen, attached to emitted instructions.
+    line: usize,
+}
+
+#[derive(Default)]
+struct FuncState {
+    proto: Proto,
+    /// Indices into `proto.locals` of the active locals. The local at
+    /// position `i` lives in register `i`.
+    actvar: Vec<usize>,
+    /// The symbol of each of `proto.locals`, which names are looked up by.
+    local_names: Vec<Symbol>,
+    /// The symbol of each of `proto.upvalues`.
+    upvalue_names: Vec<Symbol>,
+    blocks: Vec<BlockScope>,
+    free_reg: usize,
+    constants: HashMap<ConstKey, u32>,
+    /// Labels of the open blocks, which are the ones a `goto` can see.
+    labels: Vec<LabelDesc>,
+    /// Forward `goto`s whose label has not been seen yet.
+    gotos: Vec<LabelDesc>,
+}
+
+struct BlockScope {
+    /// Number of active locals outside the block.
+    nactvar: usize,
+    is_loop: bool,
+    /// Some local of this block is captured by a closure.
+    has_upval: bool,
+    /// Some local of a nested block was captured, so `break` must close
+    /// upvalues as well.
+    inner_upval: bool,
+    /// The block is inside the scope of a to-be-closed variable, which
+    /// rules out tail calls.
+    inside_tbc: bool,
+    breaks: Vec<usize>,
+    /// Index of the first label of the block in `FuncState::labels`.
+    first_label: usize,
+    /// Index of the first pending `goto` of the block in
+    /// `FuncState::gotos`.
+    first_goto: usize,
+}
+
+/// A label, or a pending `goto` (as `Labeldesc` in lparser.h).
+struct LabelDesc {
+    name: Symbol,
+    /// Position of the label, or of the jump of the `goto`.
+    pc: usize,
+    /// Number of active locals at the label or the `goto`.
+    nactvar: usize,
+    /// The `goto` leaves a block with captured locals, so its label has to
+    /// close upvalues.
+    close: bool,
+}
+
+/// Identity of a constant. Integers and floats are kept apart, unlike in
+/// tables, so that `1` and `1.0` stay distinct constants.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(u64),
+    String(LuaString),
+}
+
+/// Where a variable lives.
+#[derive(Clone, Copy)]
+enum VarLoc {
+    Local(Reg),
+    Upval(u8),
+    Global(u32),
+}
+
+/// Where an assignment stores its value.
+enum Target {
+    Var(VarLoc),
+    Index(Reg, Rk),
+}
+
+impl FuncState {
+    fn find_local(&self, name: Symbol) -> Option<Reg> {
+        self.actvar
+            .iter()
+            .rposition(|&i| self.local_names[i] == name)
+            .map(|reg| reg as Reg)
+    }
+
+    /// Marks the block declaring the local in `reg` as needing to close
+    /// its upvalues.
+    fn mark_captured(&mut self, reg: Reg) {
+        if let Some(block) = self
+            .blocks
+            .iter_mut()
+            .rev()
+            .find(|block| block.nactvar <= reg as usize)
+        {
+            block.has_upval = true;
+        }
+    }
+}
+
+/// The value of a literal expression, folding negated number literals.
+fn constant_of(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Nil => Some(Value::Nil),
+        Expr::False => Some(Value::Boolean(false)),
+        Expr::True => Some(Value::Boolean(true)),
+        Expr::Integer(i) => Some(Value::Integer(*i)),
+        Expr::Float(n) => Some(Value::Float(*n)),
+        Expr::String(s) => Some(Value::from(LuaString::from(s.as_slice()))),
+        Expr::Unop(Unop(op), operand) if op.token_type == TokenType::Minus => {
+            match operand.as_ref() {
+                Expr::Integer(i) => Some(Value::Integer(i.wrapping_neg())),
+                Expr::Float(n) => Some(Value::Float(-n)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether compiling `expr` straight into a register may overwrite it
+/// before every operand has been read, as `x = y and x` would.
+fn writes_early(expr: &Expr) -> bool {
+    match expr {
+        Expr::ExprBinop(_, Binop(op), _) => {
+            matches!(op.token_type, TokenType::And | TokenType::Or)
+        }
+        Expr::PrefixExp(PrefixExp::PrefixParen(expr)) => writes_early(expr),
+        _ => false,
+    }
+}
+
+fn arith_op(op: &Token) -> Option<ArithOp> {
+    use TokenType::*;
+
+    Some(match op.token_type {
+        Plus => ArithOp::Add,
+        Minus => ArithOp::Sub,
+        Aster => ArithOp::Mul,
+        Slash => ArithOp::Div,
+        Perc => ArithOp::Mod,
+        Hat => ArithOp::Pow,
+        IDiv => ArithOp::IDiv,
+        Amp => ArithOp::BAnd,
+        Bar => ArithOp::BOr,
+        Tilda => ArithOp::BXor,
+        ShL => ArithOp::Shl,
+        ShR => ArithOp::Shr,
+        _ => return None,
+    })
+}
+
+impl<'a, 'src> Compiler<'a, 'src> {
+    fn fs(&mut self) -> &mut FuncState {
+        self.funcs
+            .last_mut()
+            .expect("no function is being compiled")
+    }
+
+    fn error(&self, message: impl Into<String>) -> CompileError {
+        CompileError {
+            chunkname: self.chunkname.to_string(),
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn at(&mut self, token: &Token<'src>) {
+        if token.line > 0 {
+            self.line = token.line as usize;
+        }
+    }
+
+    fn pc(&mut self) -> usize {
+        self.fs().proto.code.len()
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        let line = self.line;
+        let proto = &mut self.fs().proto;
+        proto.code.push(instruction);
+        proto.lines.push(line);
+        proto.code.len() - 1
+    }
+
+    fn jump(&mut self) -> usize {
+        self.emit(Instruction::Jmp { sbx: 0 })
+    }
+
+    /// Points the jump at `pc` to `target`.
+    fn patch(&mut self, pc: usize, target: usize) {
+        let offset = target as i32 - (pc as i32 + 1);
+        match &mut self.fs().proto.code[pc] {
+            Instruction::Jmp { sbx }
+            | Instruction::ForPrep { sbx, .. }
+            | Instruction::ForLoop { sbx, .. }
+            | Instruction::TForLoop { sbx, .. } => *sbx = offset,
+            instruction => unreachable!("{} is not a jump", instruction),
+        }
+    }
+
+    fn patch_here(&mut self, jumps: Vec<usize>) {
+        let here = self.pc();
+        for pc in jumps {
+            self.patch(pc, here);
+        }
+    }
+
+    fn free_reg(&mut self) -> Reg {
+        self.fs().free_reg as Reg
+    }
+
+    /// Makes `reg` the first free register, growing the frame as needed.
+    fn set_free(&mut self, reg: usize) -> CompileResult<()> {
+        if reg > MAX_REGS {
+            return Err(self.error("function or expression needs too many registers"));
+        }
+        let fs = self.fs();
+        fs.free_reg = reg;
+        fs.proto.max_stack = fs.proto.max_stack.max(reg as u8);
+        Ok(())
+    }
+
+    fn reserve(&mut self, n: usize) -> CompileResult<Reg> {
+        let reg = self.fs().free_reg;
+        self.set_free(reg + n)?;
+        Ok(reg as Reg)
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let key = match &value {
+            Value::Nil => ConstKey::Nil,
+            Value::Boolean(b) => ConstKey::Boolean(*b),
+            Value::Integer(i) => ConstKey::Integer(*i),
+            Value::Float(n) => ConstKey::Float(n.to_bits()),
+            Value::String(s) => ConstKey::String(s.clone()),
+            v => unreachable!("{} values are not constants", v.type_name()),
+        };
+        let fs = self.fs();
+        if let Some(&k) = fs.constants.get(&key) {
+            return k;
+        }
+        let k = fs.proto.constants.len() as u32;
+        fs.proto.constants.push(value);
+        fs.constants.insert(key, k);
+        k
+    }
+
+    fn string_constant(&mut self, s: &str) -> u32 {
+        self.constant(Value::from(s))
+    }
+
+    fn load_constant(&mut self, value: Value, dst: Reg) {
+        let instruction = match value {
+            Value::Nil => Instruction::LoadNil { a: dst, b: 0 },
+            Value::Boolean(b) => Instruction::LoadBool {
+                a: dst,
+                b,
+                c: false,
+            },
+            value => Instruction::LoadK {
+                a: dst,
+                bx: self.constant(value),
+            },
+        };
+        self.emit(instruction);
+    }
+
+    fn open_function(&mut self, line: usize) {
+        let mut fs = FuncState::default();
+        fs.proto.source = self.chunkname.to_string();
+        fs.proto.line_defined = line;
+        self.funcs.push(fs);
+        self.enter_block(false);
+    }
+
+    fn close_function(&mut self) -> CompileResult<Proto> {
+        self.emit(Instruction::Return { a: 0, b: 1 });
+        self.leave_block();
+        let mut fs = self.funcs.pop().expect("no function is being compiled");
+        debug_assert!(fs.gotos.is_empty(), "`check` rejects gotos without a label");
+        fs.proto.max_stack = fs.proto.max_stack.max(2);
+        Ok(fs.proto)
+    }
+
+    /// Declares a local living in the next register after the active ones.
+    fn add_local(&mut self, name: Symbol) -> CompileResult<()> {
+        if self.fs().actvar.len() >= MAX_LOCALS {
+            return Err(self.error("too many local variables"));
+        }
+        let pc = self.pc();
+        let text = self.names.resolve(name);
+        let fs = self.fs();
+        fs.actvar.push(fs.proto.locals.len());
+        fs.local_names.push(name);
+        fs.proto.locals.push(LocalVar {
+            name: text.to_string(),
+            start_pc: pc,
+            end_pc: pc,
+        });
+        Ok(())
+    }
+
+    fn add_local_name(&mut self, name: &Token<'src>) -> CompileResult<()> {
+        let name = self.names.symbol(name);
+        self.add_local(name)
+    }
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let fs = self.fs();
+        let nactvar = fs.actvar.len();
+        let inside_tbc = fs.blocks.last().is_some_and(|block| block.inside_tbc);
+        let first_label = fs.labels.len();
+        let first_goto = fs.gotos.len();
+        fs.blocks.push(BlockScope {
+            nactvar,
+            is_loop,
+            has_upval: false,
+            inner_upval: false,
+            inside_tbc,
+            breaks: Vec::new(),
+            first_label,
+            first_goto,
+        });
+    }
+
+    fn leave_block(&mut self) {
+        let pc = self.pc();
+        let fs = self.fs();
+        let block = fs.blocks.pop().expect("no block is open");
+        for i in fs.actvar.drain(block.nactvar..) {
+            fs.proto.locals[i].end_pc = pc;
+        }
+        fs.free_reg = block.nactvar;
+        fs.labels.truncate(block.first_label);
+        // Pending gotos now jump from the enclosing block.
+        for goto in &mut fs.gotos[block.first_goto..] {
+            if goto.nactvar > block.nactvar {
+                goto.close |= block.has_upval;
+            }
+            goto.nactvar = block.nactvar;
+        }
+        let level = block.nactvar as Reg;
+        // The outermost block of a function ends in a `RETURN`, which
+        // closes the upvalues anyway.
+        let outermost = match fs.blocks.last_mut() {
+            Some(parent) => {
+                parent.inner_upval |= block.has_upval || block.inner_upval;
+                false
+            }
+            None => true,
+        };
+        if block.has_upval && !outermost {
+            self.emit(Instruction::Close { a: level });
+        }
+        if block.is_loop && !block.breaks.is_empty() {
+            let exit = self.pc();
+            if block.inner_upval {
+                self.emit(Instruction::Close { a: level });
+            }
+            for pc in block.breaks {
+                self.patch(pc, exit);
+            }
+        }
+    }
+
+    fn resolve(&mut self, name: &Token<'src>) -> CompileResult<VarLoc> {
+        let symbol = self.names.symbol(name);
+        let level = self.funcs.len() - 1;
+        match self.find_var(level, symbol)? {
+            Some(loc) => Ok(loc),
+            None => Ok(VarLoc::Global(self.string_constant(name.lexeme))),
+        }
+    }
+
+    /// Looks `name` up in the function at `level`, adding upvalues to it
+    /// (and to the functions in between) when the name is a local of an
+    /// enclosing function. `None` means the name is a global.
+    fn find_var(&mut self, level: usize, name: Symbol) -> CompileResult<Option<VarLoc>> {
+        let fs = &self.funcs[level];
+        if let Some(reg) = fs.find_local(name) {
+            return Ok(Some(VarLoc::Local(reg)));
+        }
+        if let Some(index) = fs.upvalue_names.iter().position(|&up| up == name) {
+            return Ok(Some(VarLoc::Upval(index as u8)));
+        }
+        if level == 0 {
+            return Ok(None);
+        }
+        let (in_stack, index) = match self.find_var(level - 1, name)? {
+            Some(VarLoc::Local(reg)) => {
+                self.funcs[level - 1].mark_captured(reg);
+                (true, reg)
+            }
+            Some(VarLoc::Upval(index)) => (false, index),
+            _ => return Ok(None),
+        };
+        if self.funcs[level].proto.upvalues.len() >= MAX_UPVALUES {
+            return Err(self.error("too many upvalues"));
+        }
+        let text = self.names.resolve(name);
+        let fs = &mut self.funcs[level];
+        fs.upvalue_names.push(name);
+        fs.proto.upvalues.push(UpvalDesc {
+            name: text.to_string(),
+            in_stack,
+            index,
+        });
+        Ok(Some(VarLoc::Upval(fs.proto.upvalues.len() as u8 - 1)))
+    }
+
+    fn check_vararg(&mut self) -> CompileResult<()> {
+        if self.fs().proto.is_vararg {
+            Ok(())
+        } else {
+            Err(self.error("cannot use '...' outside a vararg function"))
+        }
+    }
+
+    fn block(&mut self, block: &Block<'src>) -> CompileResult<()> {
+        self.enter_block(false);
+        self.chunk(&block.0)?;
+        self.leave_block();
+        Ok(())
+    }
+
+    fn chunk(&mut self, chunk: &Chunk<'src>) -> CompileResult<()> {
+        self.statlist(chunk, true)
+    }
+
+    /// Compiles the statements of a block. `ends_block` is false for the
+    /// body of a `repeat`, whose locals are still visible in the `until`
+    /// condition.
+    fn statlist(&mut self, chunk: &Chunk<'src>, ends_block: bool) -> CompileResult<()> {
+        let Chunk(stats, last_stat) = chunk;
+        for (i, stat) in stats.iter().enumerate() {
+            match stat {
+                Stat::Label(name) => {
+                    let last = ends_block
+                        && last_stat.is_none()
+                        && stats[i + 1..].iter().all(|s| matches!(s, Stat::Label(_)));
+                    self.label(name, last);
+                }
+                stat => self.stat(stat)?,
+            }
+            let fs = self.fs();
+            fs.free_reg = fs.actvar.len();
+        }
+        match last_stat {
+            None => Ok(()),
+            Some(LastStat::Return(exprlist)) => {
+                let exprs = exprlist.as_ref().map_or(&[][..], |ExprList(exprs)| exprs);
+                self.return_stat(exprs)
+            }
+        }
+    }
+
+    fn break_stat(&mut self) -> CompileResult<()> {
+        if !self.fs().blocks.iter().any(|block| block.is_loop) {
+            return Err(self.error("break outside a loop"));
+        }
+        let jump = self.jump();
+        let fs = self.fs();
+        if let Some(block) = fs.blocks.iter_mut().rev().find(|block| block.is_loop) {
+            block.breaks.push(jump);
+        }
+        Ok(())
+    }
+
+    /// Declares a label and resolves the pending gotos of the current block
+    /// jumping to it. A label at the end of its block is outside the scope
+    /// of the block's locals, so gotos may jump over their declarations;
+    /// `check` has rejected the other gotos jumping into a local's scope.
+    fn label(&mut self, name: &Token<'src>, last: bool) {
+        self.at(name);
+        let name = self.names.symbol(name);
+        let fs = self.fs();
+        let block = fs.blocks.last().expect("no block is open");
+        let nactvar = if last { block.nactvar } else { fs.actvar.len() };
+        let first_goto = block.first_goto;
+        let pc = self.pc();
+        let mut close = false;
+        let mut i = first_goto;
+        while i < self.fs().gotos.len() {
+            if self.fs().gotos[i].name != name {
+                i += 1;
+                continue;
+            }
+            let goto = self.fs().gotos.remove(i);
+            close |= goto.close;
+            self.patch(goto.pc, pc);
+        }
+        if close {
+            self.emit(Instruction::Close { a: nactvar as Reg });
+        }
+        self.fs().labels.push(LabelDesc {
+            name,
+            pc,
+            nactvar,
+            close: false,
+        });
+    }
+
+    /// Compiles a `goto`: a backward jump to a visible label closes the
+    /// locals declared since, a forward one waits for its label.
+    fn goto_stat(&mut self, name: &Token<'src>) {
+        self.at(name);
+        let name = self.names.symbol(name);
+        let fs = self.fs();
+        let nactvar = fs.actvar.len();
+        let label = fs
+            .labels
+            .iter()
+            .find(|label| label.name == name)
+            .map(|label| (label.pc, label.nactvar));
+        match label {
+            Some((target, level)) => {
+                if nactvar > level {
+                    self.emit(Instruction::Close { a: level as Reg });
+                }
+                let jump = self.jump();
+                self.patch(jump, target);
+            }
+            None => {
+                let pc = self.jump();
+                self.fs().gotos.push(LabelDesc {
+                    name,
+                    pc,
+                    nactvar,
+                    close: false,
+                });
+            }
+        }
+    }
+
+    fn return_stat(&mut self, exprs: &[Expr<'src>]) -> CompileResult<()> {
+        match exprs {
+            [Expr::PrefixExp(PrefixExp::PrefixCall(call))] => {
+                let base = self.call(call, None)?;
+                let fs = self.fs();
+                // To-be-closed variables are closed after the call returns.
+                let inside_tbc = fs.blocks.last().is_some_and(|block| block.inside_tbc);
+                let proto = &mut fs.proto;
+                if let Some(Instruction::Call { a, b, .. }) = proto.code.last().copied() {
+                    if !inside_tbc {
+                        *proto.code.last_mut().unwrap() = Instruction::TailCall { a, b };
+                    }
+                }
+                self.emit(Instruction::Return { a: base, b: 0 });
+            }
+            [expr] if !matches!(expr, Expr::Dots) => {
+                let reg = self.expr_any(expr)?;
+                self.emit(Instruction::Return { a: reg, b: 2 });
+            }
+            exprs => {
+                let base = self.free_reg();
+                let n = self.explist_open(exprs)?;
+                self.emit(Instruction::Return {
+                    a: base,
+                    b: n.map_or(0, |n| n as u8 + 1),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&mut self, stat: &Stat<'src>) -> CompileResult<()> {
+        match stat {
+            Stat::Assign(VarList(vars), ExprList(exprs)) => match (&vars[..], &exprs[..]) {
+                ([var], [expr]) => self.assign_single(var, expr)?,
+                _ => self.assign_multi(vars, exprs)?,
+            },
+            Stat::FunctionCall(call) => {
+                self.call(call, Some(0))?;
+            }
+            Stat::Do(block) => self.block(block)?,
+            Stat::While(cond, block) => {
+                let start = self.pc();
+                let exits = self.cond(cond, false)?;
+                self.enter_block(true);
+                self.block(block)?;
+                let back = self.jump();
+                self.patch(back, start);
+                self.leave_block();
+                self.patch_here(exits);
+            }
+            Stat::Repeat(cond, block) => {
+                let start = self.pc();
+                self.enter_block(true);
+                // The condition is compiled inside the body's scope.
+                self.enter_block(false);
+                self.statlist(&block.0, false)?;
+                let backs = self.cond(cond, false)?;
+                let scope = self.fs().blocks.last().expect("no block is open");
+                if scope.has_upval {
+                    // Each iteration has to close the body's upvalues
+                    // before looping back.
+                    let level = scope.nactvar as Reg;
+                    let exit = self.jump();
+                    self.patch_here(backs);
+                    self.emit(Instruction::Close { a: level });
+                    let back = self.jump();
+                    self.patch(back, start);
+                    self.patch_here(vec![exit]);
+                } else {
+                    for pc in backs {
+                        self.patch(pc, start);
+                    }
+                }
+                self.leave_block();
+                self.leave_block();
+            }
+            Stat::If(cond, block, elseifs, else_block) => {
+                let arms =
+                    std::iter::once((cond, block)).chain(elseifs.iter().map(|(c, b)| (c, b)));
+                let mut ends = Vec::new();
+                for (i, (cond, block)) in arms.enumerate() {
+                    let next = self.cond(cond, false)?;
+                    self.block(block)?;
+                    if i < elseifs.len() || else_block.is_some() {
+                        ends.push(self.jump());
+                    }
+                    self.patch_here(next);
+                }
+                if let Some(block) = else_block {
+                    self.block(block)?;
+                }
+                self.patch_here(ends);
+            }
+            Stat::For(name, init, limit, step, block) => {
+                self.at(name);
+                let line = self.line;
+                self.enter_block(true);
+                let base = self.free_reg();
+                self.expr_next(init)?;
+                self.expr_next(limit)?;
+                match step {
+                    Some(step) => {
+                        self.expr_next(step)?;
+                    }
+                    None => {
+                        let reg = self.reserve(1)?;
+                        self.load_constant(Value::Integer(1), reg);
+                    }
+                }
+                for hidden in ["(for index)", "(for limit)", "(for step)"] {
+                    let hidden = self.names.intern(hidden);
+                    self.add_local(hidden)?;
+                }
+                let prep = self.emit(Instruction::ForPrep { a: base, sbx: 0 });
+                let body = self.pc();
+                self.enter_block(false);
+                self.reserve(1)?;
+                self.add_local_name(name)?;
+                self.chunk(&block.0)?;
+                self.leave_block();
+                self.line = line;
+                let back = self.emit(Instruction::ForLoop { a: base, sbx: 0 });
+                self.patch(back, body);
+                let exit = self.pc();
+                self.patch(prep, exit);
+                self.leave_block();
+            }
+            Stat::ForIn(NameList(names), ExprList(exprs), block) => {
+                // `TFORCALL` stores the results from the first name on.
+                let first = names
+                    .first()
+                    .ok_or_else(|| self.error("'for' loop without variables"))?;
+                self.at(first);
+                let line = self.line;
+                self.enter_block(true);
+                let base = self.free_reg();
+                self.explist_adjust(exprs, 3)?;
+                for hidden in ["(for generator)", "(for state)", "(for control)"] {
+                    let hidden = self.names.intern(hidden);
+                    self.add_local(hidden)?;
+                }
+                let prep = self.jump();
+                let body = self.pc();
+                self.enter_block(false);
+                self.reserve(names.len())?;
+                for name in names {
+                    self.add_local_name(name)?;
+                }
+                self.chunk(&block.0)?;
+                self.leave_block();
+                self.line = line;
+                self.patch_here(vec![prep]);
+                self.emit(Instruction::TForCall {
+                    a: base,
+                    c: names.len() as u8,
+                });
+                let back = self.emit(Instruction::TForLoop {
+                    a: base + 2,
+                    sbx: 0,
+                });
+                self.patch(back, body);
+                self.leave_block();
+            }
+            Stat::Function(FuncName(names, method), body) => {
+                let (first, rest) = names
+                    .split_first()
+                    .ok_or_else(|| self.error("function name is empty"))?;
+                self.at(first);
+                let mut path: Vec<&Token> = rest.iter().collect();
+                path.extend(method.iter());
+                match path.split_last() {
+                    None => {
+                        let loc = self.resolve(first)?;
+                        let func = self.reserve(1)?;
+                        self.function(body, false, func)?;
+                        self.store(Target::Var(loc), func);
+                    }
+                    Some((key, tables)) => {
+                        let mut table = self.name_any(first)?;
+                        for name in tables {
+                            let reg = self.reserve(1)?;
+                            let c = Rk::Const(self.string_constant(name.lexeme));
+                            self.emit(Instruction::GetTable {
+                                a: reg,
+                                b: table,
+                                c,
+                            });
+                            table = reg;
+                        }
+                        let key = Rk::Const(self.string_constant(key.lexeme));
+                        let func = self.reserve(1)?;
+                        self.function(body, method.is_some(), func)?;
+                        self.store(Target::Index(table, key), func);
+                    }
+                }
+            }
+            Stat::LocalFunction(name, body) => {
+                // The local is visible inside the body, so the function can
+                // call itself.
+                self.at(name);
+                let func = self.reserve(1)?;
+                self.add_local_name(name)?;
+                self.function(body, false, func)?;
+            }
+            Stat::LocalDeclVar(AttNameList(names), exprlist) => {
+                if let Some(AttName(name, _)) = names.first() {
+                    self.at(name);
+                }
+                let exprs = exprlist.as_ref().map_or(&[][..], |ExprList(exprs)| exprs);
+                self.explist_adjust(exprs, names.len())?;
+                let mut tbc = None;
+                for AttName(name, attrib) in names {
+                    // `check` has rejected other attributes and a second
+                    // `<close>`.
+                    if attrib.as_ref().is_some_and(|a| a.lexeme == "close") {
+                        tbc = Some(self.fs().actvar.len() as Reg);
+                    }
+                    self.add_local_name(name)?;
+                }
+                if let Some(reg) = tbc {
+                    // Leaving the block must close the variable, like an
+                    // upvalue.
+                    let block = self.fs().blocks.last_mut().expect("no block is open");
+                    block.has_upval = true;
+                    block.inside_tbc = true;
+                    self.emit(Instruction::Tbc { a: reg });
+                }
+            }
+            Stat::Goto(name) => self.goto_stat(name),
+            Stat::Label(name) => self.label(name, false),
+            Stat::Break => self.break_stat()?,
+        }
+        Ok(())
+    }
+
+    fn assign_single(&mut self, var: &Var<'src>, expr: &Expr<'src>) -> CompileResult<()> {
+        match var {
+            Var::VarName(name) => {
+                self.at(name);
+                match self.resolve(name)? {
+                    VarLoc::Local(reg) if writes_early(expr) => {
+                        let value = self.expr_next(expr)?;
+                        self.emit(Instruction::Move { a: reg, b: value });
+                    }
+                    VarLoc::Local(reg) => self.expr_to(expr, reg)?,
+                    loc => {
+                        let value = self.expr_any(expr)?;
+                        self.store(Target::Var(loc), value);
+                    }
+                }
+            }
+            Var::VarIdx(prefix, key) => {
+                let table = self.prefix_any(prefix)?;
+                let key = self.expr_rk(key)?;
+                let value = self.expr_rk(expr)?;
+                self.emit(Instruction::SetTable {
+                    a: table,
+                    b: key,
+                    c: value,
+                });
+            }
+            Var::VarMember(prefix, name) => {
+                let table = self.prefix_any(prefix)?;
+                self.at(name);
+                let key = Rk::Const(self.string_constant(name.lexeme));
+                let value = self.expr_rk(expr)?;
+                self.emit(Instruction::SetTable {
+                    a: table,
+                    b: key,
+                    c: value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles `a, b.x = ...`: tables and keys are copied into fresh
+    /// registers first, so assigning to a local cannot change which table
+    /// or key a later target refers to.
+    fn assign_multi(&mut self, vars: &[Var<'src>], exprs: &[Expr<'src>]) -> CompileResult<()> {
+        let mut targets = Vec::with_capacity(vars.len());
+        for var in vars {
+            targets.push(match var {
+                Var::VarName(name) => {
+                    self.at(name);
+                    Target::Var(self.resolve(name)?)
+                }
+                Var::VarIdx(prefix, key) => {
+                    let table = self.prefix_next(prefix)?;
+                    let key = match constant_of(key) {
+                        Some(value) => Rk::Const(self.constant(value)),
+                        None => Rk::Reg(self.expr_next(key)?),
+                    };
+                    Target::Index(table, key)
+                }
+                Var::VarMember(prefix, name) => {
+                    let table = self.prefix_next(prefix)?;
+                    self.at(name);
+                    Target::Index(table, Rk::Const(self.string_constant(name.lexeme)))
+                }
+            });
+        }
+        let base = self.free_reg();
+        self.explist_adjust(exprs, vars.len())?;
+        for (i, target) in targets.into_iter().enumerate().rev() {
+            self.store(target, base + i as Reg);
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, target: Target, value: Reg) {
+        self.emit(match target {
+            Target::Var(VarLoc::Local(reg)) => Instruction::Move { a: reg, b: value },
+            Target::Var(VarLoc::Upval(b)) => Instruction::SetUpval { a: value, b },
+            Target::Var(VarLoc::Global(bx)) => Instruction::SetGlobal { a: value, bx },
+            Target::Index(table, key) => Instruction::SetTable {
+                a: table,
+                b: key,
+                c: Rk::Reg(value),
+            },
+        });
+    }
+
+    /// Compiles a function body into a nested prototype and a `CLOSURE`
+    /// storing it into `dst`.
+    fn function(&mut self, body: &FuncBody<'src>, method: bool, dst: Reg) -> CompileResult<()> {
+        let FuncBody(ParamList(NameList(params), vararg), block) = body;
+        let line = self.line;
+        self.open_function(line);
+        if method {
+            let name = self.names.intern("self");
+            self.add_local(name)?;
+        }
+        for param in params {
+            self.at(param);
+            self.add_local_name(param)?;
+        }
+        let fs = self.fs();
+        let nparams = fs.actvar.len();
+        fs.proto.num_params = nparams as u8;
+        fs.proto.is_vararg = *vararg;
+        self.set_free(nparams)?;
+        self.chunk(&block.0)?;
+        let proto = self.close_function()?;
+
+        self.line = line;
+        let protos = &mut self.fs().proto.protos;
+        protos.push(Rc::new(proto));
+        let bx = protos.len() as u32 - 1;
+        self.emit(Instruction::Closure { a: dst, bx });
+        Ok(())
+    }
+
+    /// Compiles a call with the function in the first free register, which
+    /// is returned. The call keeps `nresults` values there, or every value
+    /// up to the top of the stack when `nresults` is `None`.
+    fn call(&mut self, call: &FunctionCall<'src>, nresults: Option<usize>) -> CompileResult<Reg> {
+        let FunctionCall(prefix, method, args) = call;
+        let base = match method {
+            Some(name) => {
+                let base = self.free_reg();
+                let object = self.prefix_any(prefix)?;
+                self.at(name);
+                let key = Rk::Const(self.string_constant(name.lexeme));
+                self.set_free(base as usize + 2)?;
+                self.emit(Instruction::Method {
+                    a: base,
+                    b: object,
+                    c: key,
+                });
+                base
+            }
+            None => self.prefix_next(prefix)?,
+        };
+        let line = self.line;
+        let nargs = match args {
+            Args::ArgsNone => Some(0),
+            Args::ArgsList(ExprList(exprs)) => self.explist_open(exprs)?,
+            Args::ArgsTable(table) => {
+                self.table_next(table)?;
+                Some(1)
+            }
+            Args::ArgsString(s) => {
+                let reg = self.reserve(1)?;
+                self.load_constant(Value::from(LuaString::from(s.as_slice())), reg);
+                Some(1)
+            }
+        };
+        let nargs = nargs.map(|n| n + method.is_some() as usize);
+        self.line = line;
+        self.emit(Instruction::Call {
+            a: base,
+            b: nargs.map_or(0, |n| n as u8 + 1),
+            c: nresults.map_or(0, |n| n as u8 + 1),
+        });
+        self.set_free(base as usize + nresults.unwrap_or(0))?;
+        Ok(base)
+    }
+
+    fn vararg(&mut self, nresults: Option<usize>) -> CompileResult<Reg> {
+        self.check_vararg()?;
+        let base = self.free_reg();
+        self.emit(Instruction::VarArg {
+            a: base,
+            b: nresults.map_or(0, |n| n as u8 + 1),
+        });
+        self.set_free(base as usize + nresults.unwrap_or(0))?;
+        Ok(base)
+    }
+
+    /// Compiles an expression list into consecutive new registers. Returns
+    /// the number of values, or `None` when the last expression is a call
+    /// or `...` whose values all stay on the stack.
+    fn explist_open(&mut self, exprs: &[Expr<'src>]) -> CompileResult<Option<usize>> {
+        let (last, init) = match exprs.split_last() {
+            Some(split) => split,
+            None => return Ok(Some(0)),
+        };
+        for expr in init {
+            self.expr_next(expr)?;
+        }
+        match last {
+            Expr::PrefixExp(PrefixExp::PrefixCall(call)) => {
+                self.call(call, None)?;
+                Ok(None)
+            }
+            Expr::Dots => {
+                self.vararg(None)?;
+                Ok(None)
+            }
+            expr => {
+                self.expr_next(expr)?;
+                Ok(Some(exprs.len()))
+            }
+        }
+    }
+
+    /// Compiles an expression list into exactly `want` new registers,
+    /// expanding a trailing call or `...`, padding with nil and dropping
+    /// extra values.
+    fn explist_adjust(&mut self, exprs: &[Expr<'src>], want: usize) -> CompileResult<()> {
+        let base = self.fs().free_reg;
+        for (i, expr) in exprs.iter().enumerate() {
+            let open = i + 1 == exprs.len() && i < want;
+            match expr {
+                Expr::PrefixExp(PrefixExp::PrefixCall(call)) if open => {
+                    self.call(call, Some(want - i))?;
+                    return Ok(());
+                }
+                Expr::Dots if open => {
+                    self.vararg(Some(want - i))?;
+                    return Ok(());
+                }
+                expr => {
+                    self.expr_next(expr)?;
+                }
+            }
+        }
+        if exprs.len() < want {
+            let missing = want - exprs.len();
+            let a = self.reserve(missing)?;
+            self.emit(Instruction::LoadNil {
+                a,
+                b: missing as u8 - 1,
+            });
+        }
+        self.set_free(base + want)
+    }
+
+    /// Compiles `expr` into a new register at the top of the stack.
+    fn expr_next(&mut self, expr: &Expr<'src>) -> CompileResult<Reg> {
+        match expr {
+            Expr::PrefixExp(prefix) => self.prefix_next(prefix),
+            Expr::TableConstructor(table) => self.table_next(table),
+            expr => {
+                let reg = self.reserve(1)?;
+                self.expr_to(expr, reg)?;
+                Ok(reg)
+            }
+        }
+    }
+
+    /// Returns a register holding the value of `expr`, using locals in
+    /// place.
+    fn expr_any(&mut self, expr: &Expr<'src>) -> CompileResult<Reg> {
+        match expr {
+            Expr::PrefixExp(prefix) => self.prefix_any(prefix),
+            expr => self.expr_next(expr),
+        }
+    }
+
+    /// Returns `expr` as an instruction operand, which is a constant for
+    /// literals.
+    fn expr_rk(&mut self, expr: &Expr<'src>) -> CompileResult<Rk> {
+        match constant_of(expr) {
+            Some(value) => Ok(Rk::Const(self.constant(value))),
+            None => self.expr_any(expr).map(Rk::Reg),
+        }
+    }
+
+    /// Whether the first operand `expr` of an operation storing into `dst`
+    /// is better computed straight into `dst`: it needs computing and `dst`
+    /// is a temporary, so that `a + b + c` reuses one register rather than
+    /// taking one per operator.
+    fn computes_into(&mut self, expr: &Expr<'src>, dst: Reg) -> bool {
+        matches!(expr, Expr::ExprBinop(..) | Expr::Unop(..))
+            && dst as usize >= self.fs().actvar.len()
+    }
+
+    /// Compiles `expr` into `dst`, which must be a local or an allocated
+    /// temporary.
+    fn expr_to(&mut self, expr: &Expr<'src>, dst: Reg) -> CompileResult<()> {
+        let mark = self.fs().free_reg;
+        if let Some(value) = constant_of(expr) {
+            self.load_constant(value, dst);
+            return Ok(());
+        }
+        match expr {
+            Expr::Dots => {
+                self.check_vararg()?;
+                self.emit(Instruction::VarArg { a: dst, b: 2 });
+            }
+            Expr::Function(ast::Function(body)) => self.function(body, false, dst)?,
+            Expr::PrefixExp(prefix) => self.prefix_to(prefix, dst)?,
+            Expr::TableConstructor(table) => {
+                let reg = self.table_next(table)?;
+                self.emit(Instruction::Move { a: dst, b: reg });
+            }
+            Expr::ExprBinop(lhs, Binop(op), rhs) => self.binop_to(lhs, op, rhs, dst)?,
+            Expr::Unop(Unop(op), operand) => {
+                let b = if self.computes_into(operand, dst) {
+                    self.expr_to(operand, dst)?;
+                    dst
+                } else {
+                    self.expr_any(operand)?
+                };
+                self.at(op);
+                let instruction = match op.token_type {
+                    TokenType::Minus => Instruction::Unm { a: dst, b },
+                    TokenType::Not => Instruction::Not { a: dst, b },
+                    TokenType::Opus => Instruction::Len { a: dst, b },
+                    TokenType::Tilda => Instruction::BNot { a: dst, b },
+                    _ => return Err(self.error(format!("unknown unary operator '{}'", op.lexeme))),
+                };
+                self.emit(instruction);
+            }
+            _ => unreachable!("literals are compiled as constants"),
+        }
+        self.fs().free_reg = mark;
+        Ok(())
+    }
+
+    fn binop_to(&mut self, lhs: &Expr<'src>, op: &Token<'src>, rhs: &Expr<'src>, dst: Reg) -> CompileResult<()> {
+        use TokenType::*;
+
+        match op.token_type {
+            And | Or => {
+                // Keep the left operand when it decides the result.
+                self.expr_to(lhs, dst)?;
+                self.at(op);
+                self.emit(Instruction::Test {
+                    a: dst,
+                    c: op.token_type == Or,
+                });
+                let skip = self.jump();
+                self.expr_to(rhs, dst)?;
+                self.patch_here(vec![skip]);
+            }
+            Concat => {
+                // `a .. b .. c` is a single CONCAT over consecutive registers.
+                let base = self.free_reg();
+                self.expr_next(lhs)?;
+                let mut rhs = rhs;
+                while let Expr::ExprBinop(l, Binop(next), r) = rhs {
+                    if next.token_type != Concat {
+                        break;
+                    }
+                    self.expr_next(l)?;
+                    rhs = r;
+                }
+                self.expr_next(rhs)?;
+                let last = self.free_reg() - 1;
+                self.at(op);
+                self.emit(Instruction::Concat {
+                    a: dst,
+                    b: base,
+                    c: last,
+                });
+            }
+            Eql | Ne | Less | Le | Greater | Ge => {
+                let when_false = self.compare(lhs, op, rhs, false)?;
+                self.emit(Instruction::LoadBool {
+                    a: dst,
+                    b: true,
+                    c: true,
+                });
+                self.patch_here(vec![when_false]);
+                self.emit(Instruction::LoadBool {
+                    a: dst,
+                    b: false,
+                    c: false,
+                });
+            }
+            _ => {
+                // A left-associative chain like `a + b - c` computes each
+                // step into `dst`, so its left spine is walked in a loop
+                // rather than recursively.
+                let mut steps = vec![(op, rhs)];
+                let mut lhs = lhs;
+                while let Expr::ExprBinop(l, Binop(op), r) = lhs {
+                    if arith_op(op).is_none() || !self.computes_into(lhs, dst) {
+                        break;
+                    }
+                    steps.push((op, r));
+                    lhs = l;
+                }
+                let mut b = if self.computes_into(lhs, dst) {
+                    self.expr_to(lhs, dst)?;
+                    Rk::Reg(dst)
+                } else {
+                    self.expr_rk(lhs)?
+                };
+                for (op, rhs) in steps.into_iter().rev() {
+                    let arith = arith_op(op).ok_or_else(|| {
+                        self.error(format!("unknown binary operator '{}'", op.lexeme))
+                    })?;
+                    let mark = self.fs().free_reg;
+                    let c = self.expr_rk(rhs)?;
+                    self.fs().free_reg = mark;
+                    self.at(op);
+                    self.emit(Instruction::Arith {
+                        op: arith,
+                        a: dst,
+                        b,
+                        c,
+                    });
+                    b = Rk::Reg(dst);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles a comparison followed by a jump, which is taken when the
+    /// comparison yields `jump_if`. Returns the jump.
+    fn compare(
+        &mut self,
+        lhs: &Expr<'src>,
+        op: &Token<'src>,
+        rhs: &Expr<'src>,
+        jump_if: bool,
+    ) -> CompileResult<usize> {
+        let mark = self.fs().free_reg;
+        let b = self.expr_rk(lhs)?;
+        let c = self.expr_rk(rhs)?;
+        self.fs().free_reg = mark;
+        self.at(op);
+        let instruction = match op.token_type {
+            TokenType::Eql => Instruction::Eq { a: jump_if, b, c },
+            TokenType::Ne => Instruction::Eq { a: !jump_if, b, c },
+            TokenType::Less => Instruction::Lt { a: jump_if, b, c },
+            TokenType::Le => Instruction::Le { a: jump_if, b, c },
+            TokenType::Greater => Instruction::Lt {
+                a: jump_if,
+                b: c,
+                c: b,
+            },
+            TokenType::Ge => Instruction::Le {
+                a: jump_if,
+                b: c,
+                c: b,
+            },
+            _ => unreachable!("'{}' is not a comparison", op.lexeme),
+        };
+        self.emit(instruction);
+        Ok(self.jump())
+    }
+
+    /// Compiles `expr` as a condition. Returns the jumps taken when its
+    /// truthiness is `jump_if`; otherwise execution falls through.
+    fn cond(&mut self, expr: &Expr<'src>, jump_if: bool) -> CompileResult<Vec<usize>> {
+        use TokenType::*;
+
+        if let Some(value) = constant_of(expr) {
+            return Ok(if value.is_truthy() == jump_if {
+                vec![self.jump()]
+            } else {
+                Vec::new()
+            });
+        }
+        match expr {
+            Expr::Unop(Unop(op), operand) if op.token_type == Not => self.cond(operand, !jump_if),
+            Expr::PrefixExp(PrefixExp::PrefixParen(expr)) => self.cond(expr, jump_if),
+            Expr::ExprBinop(lhs, Binop(op), rhs) if matches!(op.token_type, And | Or) => {
+                // `a and b` is false as soon as `a` is; `a or b` is true as
+                // soon as `a` is.
+                let short = op.token_type == Or;
+                if jump_if == short {
+                    let mut jumps = self.cond(lhs, jump_if)?;
+                    jumps.extend(self.cond(rhs, jump_if)?);
+                    Ok(jumps)
+                } else {
+                    let decided = self.cond(lhs, short)?;
+                    let jumps = self.cond(rhs, jump_if)?;
+                    self.patch_here(decided);
+                    Ok(jumps)
+                }
+            }
+            Expr::ExprBinop(lhs, Binop(op), rhs)
+                if matches!(op.token_type, Eql | Ne | Less | Le | Greater | Ge) =>
+            {
+                Ok(vec![self.compare(lhs, op, rhs, jump_if)?])
+            }
+            expr => {
+                let mark = self.fs().free_reg;
+                let reg = self.expr_any(expr)?;
+                self.fs().free_reg = mark;
+                self.emit(Instruction::Test { a: reg, c: jump_if });
+                Ok(vec![self.jump()])
+            }
+        }
+    }
+
+    /// Returns a register holding the variable `name`, using locals in place.
+    fn name_any(&mut self, name: &Token<'src>) -> CompileResult<Reg> {
+        self.at(name);
+        match self.resolve(name)? {
+            VarLoc::Local(reg) => Ok(reg),
+            VarLoc::Upval(b) => {
+                let a = self.reserve(1)?;
+                self.emit(Instruction::GetUpval { a, b });
+                Ok(a)
+            }
+            VarLoc::Global(bx) => {
+                let a = self.reserve(1)?;
+                self.emit(Instruction::GetGlobal { a, bx });
+                Ok(a)
+            }
+        }
+    }
+
+    fn prefix_next(&mut self, prefix: &PrefixExp<'src>) -> CompileResult<Reg> {
+        match prefix {
+            PrefixExp::PrefixCall(call) => self.call(call, Some(1)),
+            prefix => {
+                let reg = self.reserve(1)?;
+                self.prefix_to(prefix, reg)?;
+                Ok(reg)
+            }
+        }
+    }
+
+    fn prefix_any(&mut self, prefix: &PrefixExp<'src>) -> CompileResult<Reg> {
+        match prefix {
+            PrefixExp::PrefixVar(var) => match var.as_ref() {
+                Var::VarName(name) => self.name_any(name),
+                _ => self.prefix_next(prefix),
+            },
+            PrefixExp::PrefixParen(expr) => self.expr_any(expr),
+            PrefixExp::PrefixCall(_) => self.prefix_next(prefix),
+        }
+    }
+
+    fn prefix_to(&mut self, prefix: &PrefixExp<'src>, dst: Reg) -> CompileResult<()> {
+        let mark = self.fs().free_reg;
+        match prefix {
+            PrefixExp::PrefixVar(var) => match var.as_ref() {
+                Var::VarName(name) => {
+                    self.at(name);
+                    let instruction = match self.resolve(name)? {
+                        VarLoc::Local(reg) if reg == dst => None,
+                        VarLoc::Local(b) => Some(Instruction::Move { a: dst, b }),
+                        VarLoc::Upval(b) => Some(Instruction::GetUpval { a: dst, b }),
+                        VarLoc::Global(bx) => Some(Instruction::GetGlobal { a: dst, bx }),
+                    };
+                    if let Some(instruction) = instruction {
+                        self.emit(instruction);
+                    }
+                }
+                Var::VarIdx(prefix, key) => {
+                    let b = self.prefix_any(prefix)?;
+                    let c = self.expr_rk(key)?;
+                    self.emit(Instruction::GetTable { a: dst, b, c });
+                }
+                Var::VarMember(prefix, name) => {
+                    let b = self.prefix_any(prefix)?;
+                    self.at(name);
+                    let c = Rk::Const(self.string_constant(name.lexeme));
+                    self.emit(Instruction::GetTable { a: dst, b, c });
+                }
+            },
+            PrefixExp::PrefixCall(call) => {
+                let reg = self.call(call, Some(1))?;
+                self.emit(Instruction::Move { a: dst, b: reg });
+            }
+            PrefixExp::PrefixParen(expr) => self.expr_to(expr, dst)?,
+        }
+        self.fs().free_reg = mark;
+        Ok(())
+    }
+
+    /// Compiles a table constructor into a new register at the top of the
+    /// stack. List items are collected in the registers above it and
+    /// stored with `SETLIST` in batches.
+    fn table_next(&mut self, table: &TableConstructor<'src>) -> CompileResult<Reg> {
+        let TableConstructor(FieldList(fields)) = table;
+        let reg = self.reserve(1)?;
+        let narray = fields
+            .iter()
+            .filter(|field| matches!(field, Field::UniExp(_)))
+            .count();
+        self.emit(Instruction::NewTable {
+            a: reg,
+            b: narray as u32,
+            c: (fields.len() - narray) as u32,
+        });
+
+        let mut pending = 0;
+        let mut stored = 0;
+        for (i, field) in fields.iter().enumerate() {
+            let mark = self.fs().free_reg;
+            match field {
+                Field::UniExp(expr) if i + 1 == fields.len() => {
+                    let open = match expr.as_ref() {
+                        Expr::PrefixExp(PrefixExp::PrefixCall(call)) => {
+                            self.call(call, None)?;
+                            true
+                        }
+                        Expr::Dots => {
+                            self.vararg(None)?;
+                            true
+                        }
+                        expr => {
+                            self.expr_next(expr)?;
+                            false
+                        }
+                    };
+                    pending += 1;
+                    self.emit(Instruction::SetList {
+                        a: reg,
+                        b: if open { 0 } else { pending as u8 },
+                        c: stored,
+                    });
+                    pending = 0;
+                }
+                Field::UniExp(expr) => {
+                    self.expr_next(expr)?;
+                    pending += 1;
+                    if pending == FIELDS_PER_FLUSH {
+                        self.emit(Instruction::SetList {
+                            a: reg,
+                            b: pending as u8,
+                            c: stored,
+                        });
+                        stored += pending as u32;
+                        pending = 0;
+                        self.fs().free_reg = reg as usize + 1;
+                    }
+                }
+                Field::AssignName(name, value) => {
+                    self.at(name);
+                    let b = Rk::Const(self.string_constant(name.lexeme));
+                    let c = self.expr_rk(value)?;
+                    self.emit(Instruction::SetTable { a: reg, b, c });
+                    self.fs().free_reg = mark;
+                }
+                Field::AssignIdx(key, value) => {
+                    let b = self.expr_rk(key)?;
+                    let c = self.expr_rk(value)?;
+                    self.emit(Instruction::SetTable { a: reg, b, c });
+                    self.fs().free_reg = mark;
+                }
+            }
+        }
+        if pending > 0 {
+            self.emit(Instruction::SetList {
+                a: reg,
+                b: pending as u8,
+                c: stored,
+            });
+        }
+        self.fs().free_reg = reg as usize + 1;
+        Ok(reg)
+    }
+}
@@ -0,0 +1,164 @@
+//
+// Function prototypes: the compiled, immutable part of a Lua function that
+// every closure created from it shares.
+//
+use std::fmt;
+use std::rc::Rc;
+
+use super::code::{Instruction, Reg, Rk};
+use crate::value::Value;
+
+#[derive(Debug, Default)]
+pub struct Proto {
+    /// Name of the chunk the function was defined in, e.g. `fib.lua`.
+    pub source: String,
+    pub line_defined: usize,
+    pub num_params: u8,
+    pub is_vararg: bool,
+    /// Number of registers a call frame of this function needs.
+    pub max_stack: u8,
+    pub code: Vec<Instruction>,
+    /// Source line of each instruction in `code`.
+    pub lines: Vec<usize>,
+    pub constants: Vec<Value>,
+    pub protos: Vec<Rc<Proto>>,
+    pub upvalues: Vec<UpvalDesc>,
+    pub locals: Vec<LocalVar>,
+}
+
+/// Where a closure finds an upvalue when it is created: a register of the
+/// enclosing function (`in_stack`) or one of the enclosing function's own
+/// upvalues.
+#[derive(Debug, Clone)]
+pub struct UpvalDesc {
+    pub name: String,
+    pub in_stack: bool,
+    pub index: u8,
+}
+
+/// Debug information about a local variable, which is live while the
+/// program counter is in `start_pc..end_pc`.
+#[derive(Debug, Clone)]
+pub struct LocalVar {
+    pub name: String,
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+impl Proto {
+    /// Returns the name of the local variable held in register `reg` at
+    /// `pc`, like `luaF_getlocalname`.
+    pub fn local_name(&self, reg: Reg, pc: usize) -> Option<&str> {
+        self.locals
+            .iter()
+            .take_while(|local| local.start_pc <= pc)
+            .filter(|local| pc < local.end_pc)
+            .nth(reg as usize)
+            .map(|local| local.name.as_str())
+    }
+
+    /// Names the variable the value in register `reg` came from at `pc`,
+    /// e.g. `("global", "print")`, like `getobjname` in ldebug.c.
+    pub fn object_name(&self, pc: usize, reg: Reg) -> Option<(&'static str, String)> {
+        if let Some(name) = self.local_name(reg, pc) {
+            return Some(("local", name.to_string()));
+        }
+        let setter = self.find_setter(pc, reg)?;
+        let constant = |k: u32| match &self.constants[k as usize] {
+            Value::String(s) => Some(s.to_string()),
+            _ => None,
+        };
+        match self.code[setter] {
+            Instruction::Move { a, b } if b < a => self.object_name(setter, b),
+            Instruction::GetGlobal { bx, .. } => Some(("global", constant(bx)?)),
+            Instruction::GetTable { c: Rk::Const(k), .. } => Some(("field", constant(k)?)),
+            Instruction::GetUpval { b, .. } => {
+                Some(("upvalue", self.upvalues.get(b as usize)?.name.clone()))
+            }
+            Instruction::LoadK { bx, .. } => Some(("constant", constant(bx)?)),
+            Instruction::Method { a, c: Rk::Const(k), .. } if a == reg => {
+                Some(("method", constant(k)?))
+            }
+            _ => None,
+        }
+    }
+
+    /// Finds the last instruction before `last_pc` that sets `reg`, unless
+    /// a jump could skip it.
+    fn find_setter(&self, last_pc: usize, reg: Reg) -> Option<usize> {
+        use Instruction::*;
+
+        let mut setter = None;
+        let mut jump_target = 0;
+        for (pc, instruction) in self.code[..last_pc].iter().enumerate() {
+            let sets = match *instruction {
+                LoadNil { a, b } => a <= reg && reg <= a + b,
+                TForCall { a, .. } => reg >= a + 2,
+                Call { a, .. } | TailCall { a, .. } | VarArg { a, .. } => reg >= a,
+                Method { a, .. } => reg == a || reg == a + 1,
+                Jmp { sbx } => {
+                    let target = (pc as i64 + 1 + sbx as i64) as usize;
+                    if pc < target && target <= last_pc {
+                        jump_target = jump_target.max(target);
+                    }
+                    false
+                }
+                Move { a, .. }
+                | LoadK { a, .. }
+                | LoadBool { a, .. }
+                | GetUpval { a, .. }
+                | GetGlobal { a, .. }
+                | GetTable { a, .. }
+                | NewTable { a, .. }
+                | Arith { a, .. }
+                | Unm { a, .. }
+                | BNot { a, .. }
+                | Not { a, .. }
+                | Len { a, .. }
+                | Concat { a, .. }
+                | Closure { a, .. } => reg == a,
+                _ => false,
+            };
+            if sets {
+                setter = if pc < jump_target { None } else { Some(pc) };
+            }
+        }
+        setter
+    }
+
+    pub fn line_at(&self, pc: usize) -> usize {
+        self.lines.get(pc).copied().unwrap_or(self.line_defined)
+    }
+}
+
+/// Lists the function and all nested functions, like `luac -l`.
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "function <{}:{}> ({} instructions)",
+            self.source,
+            self.line_defined,
+            self.code.len()
+        )?;
+        writeln!(
+            f,
+            "{}{} params, {} slots, {} upvalues, {} locals, {} constants, {} functions",
+            self.num_params,
+            if self.is_vararg { "+" } else { "" },
+            self.max_stack,
+            self.upvalues.len(),
+            self.locals.len(),
+            self.constants.len(),
+            self.protos.len()
+        )?;
+        for (pc, instruction) in self.code.iter().enumerate() {
+            writeln!(f, "\t{}\t[{}]\t{}", pc + 1, self.line_at(pc), instruction)?;
+        }
+        for proto in &self.protos {
+            writeln!(f)?;
+            write!(f, "{}", proto)?;
+        }
+        Ok(())
+    }
+}
@@ -1,19 +1,220 @@
 use std::error::Error;
 use std::fmt;
 
-#[derive(Debug)]
-pub struct ScanError {}
+use crate::scanner::Span;
+use crate::token_type::TokenType;
+use crate::value::Value;
+
+/// What kind of malformed input the scanner found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScanErrorKind {
+    /// A character that cannot start a token.
+    UnexpectedSymbol,
+    /// A quoted string without its closing quote on the same line.
+    UnfinishedString,
+    /// A malformed escape sequence in a string, and what is wrong with it,
+    /// e.g. "hexadecimal digit expected".
+    InvalidEscape(&'static str),
+    MalformedNumber,
+    UnfinishedLongString,
+    UnfinishedLongComment,
+    /// A token converted to a value it does not denote, e.g. a name to a
+    /// number.
+    InvalidConversion,
+    /// A string converted to a Rust `String` which is not valid UTF-8.
+    InvalidUtf8,
+}
+
+/// A lexical error, located by the span of the offending text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanError {
+    pub kind: ScanErrorKind,
+    pub span: Span,
+    /// The offending text, or `<eof>` when the source ended too early.
+    pub text: String,
+}
 
 impl ScanError {
-    pub fn raise() -> Self {
-        Self {}
+    pub fn new(kind: ScanErrorKind, span: Span, text: impl Into<String>) -> Self {
+        Self {
+            kind,
+            span,
+            text: text.into(),
+        }
+    }
+
+    /// The message without the position, e.g.
+    /// `unfinished string near '"abc'`.
+    pub fn message(&self) -> String {
+        let what = match self.kind {
+            ScanErrorKind::UnexpectedSymbol => "unexpected symbol".to_string(),
+            ScanErrorKind::UnfinishedString => "unfinished string".to_string(),
+            ScanErrorKind::InvalidEscape(reason) => reason.to_string(),
+            ScanErrorKind::MalformedNumber => "malformed number".to_string(),
+            ScanErrorKind::UnfinishedLongString => {
+                format!("unfinished long string (starting at line {})", self.span.line)
+            }
+            ScanErrorKind::UnfinishedLongComment => {
+                format!("unfinished long comment (starting at line {})", self.span.line)
+            }
+            ScanErrorKind::InvalidConversion => "invalid token conversion".to_string(),
+            ScanErrorKind::InvalidUtf8 => "invalid UTF-8 in string".to_string(),
+        };
+        format!("{} near '{}'", what, self.text)
     }
 }
 
+/// Formats as `line: message`, so that prefixing the chunkname and a colon
+/// gives Lua's `chunk:line: message`.
 impl fmt::Display for ScanError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ScanError.",)
+        write!(f, "{}: {}", self.span.end_line, self.message())
     }
 }
 
 impl Error for ScanError {}
+
+/// A syntax error, located at the first token the parser could not accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The type of the offending token, `Eof` at the end of the source.
+    pub token_type: TokenType,
+    /// The text of the offending token.
+    pub text: String,
+    /// The line the offending token ends on.
+    pub line: usize,
+    pub span: Span,
+    /// The types of the tokens the parser would have accepted instead.
+    pub expected: Vec<TokenType>,
+    /// What is wrong when no expected token says it, e.g. `syntax error`
+    /// for an assignment to a function call.
+    pub message: Option<String>,
+}
+
+impl ParseError {
+    /// The message without the position, e.g. `'then' expected near 'do'`,
+    /// or `unexpected symbol near 'do'` when more than one token would do.
+    pub fn message(&self) -> String {
+        let near = match self.token_type {
+            TokenType::Eof => self.token_type.as_str().to_string(),
+            _ => format!("'{}'", self.text),
+        };
+        if let Some(message) = &self.message {
+            return format!("{} near {}", message, near);
+        }
+        match self.expected[..] {
+            [expected] if expected.as_str().starts_with('<') => {
+                format!("{} expected near {}", expected.as_str(), near)
+            }
+            [expected] => format!("'{}' expected near {}", expected.as_str(), near),
+            _ => format!("unexpected symbol near {}", near),
+        }
+    }
+}
+
+/// Formats as `line: message`, like `ScanError`.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.line, self.message())
+    }
+}
+
+impl Error for ParseError {}
+
+/// An error in an operation on values, such as indexing a table with nil.
+/// The VM reports it as a `LuaError::Runtime` prefixed with the position
+/// of the running code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+}
+
+impl RuntimeError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for RuntimeError {}
+
+/// An error raised while loading or running Lua code, classified like the
+/// status codes of `lua_pcall`.
+#[derive(Debug, Clone)]
+pub enum LuaError {
+    /// An error raised by running code, e.g. with `error`. The error value
+    /// can be any Lua value.
+    Runtime(Value),
+    /// A chunk that failed to scan, parse or compile.
+    Syntax(String),
+    /// The VM exceeded its memory limit.
+    Memory,
+    /// The message handler of `xpcall` failed with this error value.
+    ErrorHandler(Value),
+}
+
+impl LuaError {
+    pub fn runtime(message: impl Into<String>) -> Self {
+        LuaError::Runtime(Value::from(message.into()))
+    }
+
+    /// The value a protected call returns for this error.
+    pub fn value(&self) -> Value {
+        match self {
+            LuaError::Runtime(v) => v.clone(),
+            LuaError::Syntax(message) => Value::from(message.as_str()),
+            LuaError::Memory => Value::from("not enough memory"),
+            LuaError::ErrorHandler(_) => Value::from("error in error handling"),
+        }
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaError::Runtime(v) | LuaError::ErrorHandler(v) => {
+                if let LuaError::ErrorHandler(_) = self {
+                    write!(f, "error in error handling: ")?;
+                }
+                match v {
+                    Value::String(_) | Value::Integer(_) | Value::Float(_) => write!(f, "{}", v),
+                    v => write!(f, "(error object is a {} value)", v.type_name()),
+                }
+            }
+            LuaError::Syntax(message) => write!(f, "{}", message),
+            LuaError::Memory => write!(f, "not enough memory"),
+        }
+    }
+}
+
+impl Error for LuaError {}
+
+impl From<CompileError> for LuaError {
+    fn from(e: CompileError) -> Self {
+        LuaError::Syntax(e.to_string())
+    }
+}
+
+/// An error found while compiling a parsed chunk, such as a `break` outside
+/// a loop or a function needing too many registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub chunkname: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.chunkname, self.line, self.message)
+    }
+}
+
+impl Error for CompileError {}
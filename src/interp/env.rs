@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::value::Value;
+
+/// A local variable. Closures capture the cell itself, so every closure and
+/// the declaring scope share one variable, and the variable lives on after
+/// its scope exits for as long as a closure refers to it.
+pub type Cell = Rc<RefCell<Value>>;
+
+/// A lexical scope. Each `local` statement opens a new child scope so that
+/// closures created earlier keep seeing the shadowed binding.
+#[derive(Debug, Default)]
+pub struct Scope {
+    vars: RefCell<HashMap<String, Cell>>,
+    varargs: Option<Vec<Value>>,
+    parent: Option<Rc<Scope>>,
+}
+
+impl Scope {
+    pub fn root() -> Rc<Self> {
+        Rc::new(Self::default())
+    }
+
+    /// The outermost scope of a closure: just the variables it captured.
+    pub fn upvalues(upvalues: Vec<(String, Cell)>) -> Rc<Self> {
+        Rc::new(Self {
+            vars: RefCell::new(upvalues.into_iter().collect()),
+            varargs: None,
+            parent: None,
+        })
+    }
+
+    pub fn child(parent: &Rc<Scope>) -> Rc<Self> {
+        Rc::new(Self {
+            vars: RefCell::new(HashMap::new()),
+            varargs: None,
+            parent: Some(parent.clone()),
+        })
+    }
+
+    /// Scope of a function activation, holding the extra arguments for `...`.
+    pub fn function(parent: &Rc<Scope>, varargs: Vec<Value>) -> Rc<Self> {
+        Rc::new(Self {
+            vars: RefCell::new(HashMap::new()),
+            varargs: Some(varargs),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    pub fn declare(&self, name: &str, value: Value) {
+        self.vars
+            .borrow_mut()
+            .insert(name.to_string(), Rc::new(RefCell::new(value)));
+    }
+
+    /// Finds the cell of the local variable `name`, if it is one.
+    pub fn cell(&self, name: &str) -> Option<Cell> {
+        if let Some(cell) = self.vars.borrow().get(name) {
+            return Some(cell.clone());
+        }
+        self.parent.as_ref().and_then(|parent| parent.cell(name))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Value> {
+        self.cell(name).map(|cell| cell.borrow().clone())
+    }
+
+    /// Updates an existing local binding. Returns `false` when `name` is not
+    /// a local in this scope chain, i.e. it refers to a global.
+    pub fn assign(&self, name: &str, value: Value) -> bool {
+        match self.cell(name) {
+            Some(cell) => {
+                *cell.borrow_mut() = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn varargs(&self) -> Option<&[Value]> {
+        match &self.varargs {
+            Some(args) => Some(args),
+            None => self.parent.as_ref().and_then(|parent| parent.varargs()),
+        }
+    }
+}
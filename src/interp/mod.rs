@@ -0,0 +1,847 @@
+//
+// A tree-walking interpreter over `parser::ast`. It runs on a `Vm`, whose
+// globals, builtins and metatables it uses, and its closures are builtins
+// of that VM, so that the VM and its builtins can call them. Like
+// metamethods, they run on the native stack and cannot yield.
+//
+pub mod env;
+pub mod resolve;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Write;
+use std::mem;
+use std::rc::Rc;
+
+use crate::compiler::check::check;
+use crate::errors::LuaError;
+use crate::parser::ast;
+use crate::parser::ast::*;
+use crate::parser::parser::parse;
+use crate::parser::stream::TokenStream;
+use crate::scanner::Scanner;
+use crate::table::Table;
+use crate::token_type::TokenType;
+use crate::value::{self, ArithOp, LuaString, Value};
+use crate::vm::{meta, Vm};
+use crate::Token;
+
+use env::Scope;
+
+type EvalResult<T> = Result<T, LuaError>;
+
+/// A closure of the interpreter. The VM sees it as a builtin whose only
+/// upvalue is this closure, wrapped in a userdata.
+struct TreeClosure {
+    params: Vec<String>,
+    vararg: bool,
+    body: Rc<Block<'static>>,
+    upvalues: Rc<Scope>,
+    /// The chunk whose text the tokens of `body` borrow. Declared after
+    /// `body`, so that it is dropped last.
+    source: Rc<str>,
+}
+
+/// How control leaves a block.
+enum Flow {
+    Normal,
+    Break,
+    Return(Vec<Value>),
+    /// A `goto` whose label is not in the block that ran it.
+    Goto(String),
+}
+
+pub struct Interpreter {
+    vm: Vm,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_output(std::io::stdout())
+    }
+
+    /// Creates an interpreter whose `print` writes to `out`.
+    pub fn with_output(out: impl Write + 'static) -> Self {
+        Self {
+            vm: Vm::with_output(out),
+        }
+    }
+
+    pub fn globals(&self) -> Rc<RefCell<Table>> {
+        self.vm.globals()
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.vm.get_global(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.vm.set_global(name, value);
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        self.vm.output()
+    }
+
+    /// Scans, parses and runs `source` as the main chunk. The errors the
+    /// compiler would find statically, such as a `goto` without a visible
+    /// label, are reported before anything runs.
+    pub fn exec_source(&mut self, source: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+        let source: Rc<str> = Rc::from(source);
+        let mut scanner = Scanner::new(&source);
+        scanner
+            .scan()
+            .map_err(|e| LuaError::Syntax(format!("?:{}", e)))?;
+        let block = parse(TokenStream::new(scanner.tokens))
+            .map_err(|e| LuaError::Syntax(format!("?:{}", e)))?;
+        check(&block, &mut scanner.names, "?").map_err(LuaError::from)?;
+        // SAFETY: only the lifetime changes. The tokens of the block borrow
+        // `source`, and every closure keeping a function body of the block
+        // also keeps `source`, which it drops after the body.
+        let block = unsafe { mem::transmute::<Block<'_>, Block<'static>>(block) };
+        let mut eval = Eval {
+            vm: &mut self.vm,
+            source,
+        };
+        let scope = Scope::function(&Scope::root(), Vec::new());
+        let results = function_results(eval.exec_block(&block, scope)?)?;
+        self.vm.output().flush()?;
+        Ok(results)
+    }
+
+    /// Calls `func` with `args` and returns all of its results.
+    pub fn call(&mut self, func: &Value, args: Vec<Value>) -> EvalResult<Vec<Value>> {
+        self.vm.call(func, args)
+    }
+}
+
+/// Calls a `TreeClosure`, which comes first in `args`.
+fn call_closure(vm: &mut Vm, mut args: Vec<Value>) -> EvalResult<Vec<Value>> {
+    let Value::UserData(data) = args.remove(0) else {
+        unreachable!("a tree closure keeps itself as its upvalue");
+    };
+    let closure = data
+        .borrow::<TreeClosure>()
+        .expect("a tree closure keeps itself as its upvalue");
+    let extra = if closure.vararg && args.len() > closure.params.len() {
+        args.split_off(closure.params.len())
+    } else {
+        Vec::new()
+    };
+    let scope = Scope::function(&closure.upvalues, extra);
+    let mut args = args.into_iter();
+    for param in &closure.params {
+        scope.declare(param, args.next().unwrap_or(Value::Nil));
+    }
+    let mut eval = Eval {
+        vm,
+        source: closure.source.clone(),
+    };
+    function_results(eval.exec_block(&closure.body, scope)?)
+}
+
+/// Runs the code of one chunk.
+struct Eval<'v> {
+    vm: &'v mut Vm,
+    /// The text of the chunk, kept by the closures it creates.
+    source: Rc<str>,
+}
+
+impl Eval<'_> {
+    fn exec_block(&mut self, block: &Block<'static>, scope: Rc<Scope>) -> EvalResult<Flow> {
+        self.exec_chunk(&block.0, scope).map(|(flow, _)| flow)
+    }
+
+    /// Runs a chunk and also hands back the innermost scope, which `repeat`
+    /// needs to evaluate its `until` condition.
+    fn exec_chunk(
+        &mut self,
+        chunk: &Chunk<'static>,
+        scope: Rc<Scope>,
+    ) -> EvalResult<(Flow, Rc<Scope>)> {
+        let Chunk(stats, last_stat) = chunk;
+        let mut scope = scope;
+        // The scopes labels were reached in, to resume a backward `goto`.
+        let mut labels: HashMap<&str, Rc<Scope>> = HashMap::new();
+        let mut pc = 0;
+        while let Some(stat) = stats.get(pc) {
+            pc += 1;
+            if let Stat::Label(name) = stat {
+                labels.insert(name.lexeme, scope.clone());
+            }
+            match self.exec_stat(stat, &mut scope)? {
+                Flow::Normal => {}
+                Flow::Goto(label) => {
+                    let target = stats
+                        .iter()
+                        .position(|stat| matches!(stat, Stat::Label(name) if name.lexeme == label));
+                    let Some(target) = target else {
+                        return Ok((Flow::Goto(label), scope));
+                    };
+                    // A forward jump never enters the scope of a local (the
+                    // compiler rejects it), so it keeps the current scope.
+                    if target < pc {
+                        if let Some(label_scope) = labels.get(label.as_str()) {
+                            scope = label_scope.clone();
+                        }
+                    }
+                    pc = target;
+                }
+                flow => return Ok((flow, scope)),
+            }
+        }
+        let flow = match last_stat {
+            None => Flow::Normal,
+            Some(LastStat::Return(None)) => Flow::Return(Vec::new()),
+            Some(LastStat::Return(Some(exprlist))) => {
+                Flow::Return(self.eval_exprlist(exprlist, &scope)?)
+            }
+        };
+        Ok((flow, scope))
+    }
+
+    fn exec_stat(&mut self, stat: &Stat<'static>, scope: &mut Rc<Scope>) -> EvalResult<Flow> {
+        match stat {
+            Stat::Assign(VarList(vars), exprlist) => {
+                let mut targets = Vec::with_capacity(vars.len());
+                for var in vars {
+                    targets.push(self.eval_target(var, scope)?);
+                }
+                let mut values = self.eval_exprlist(exprlist, scope)?.into_iter();
+                for target in targets {
+                    self.assign(target, values.next().unwrap_or(Value::Nil), scope)?;
+                }
+            }
+            Stat::FunctionCall(call) => {
+                self.eval_call(call, scope)?;
+            }
+            Stat::Do(block) => return self.exec_block(block, Scope::child(scope)),
+            Stat::While(cond, block) => {
+                while self.eval(cond, scope)?.is_truthy() {
+                    match self.exec_block(block, Scope::child(scope))? {
+                        Flow::Break => break,
+                        flow @ (Flow::Return(_) | Flow::Goto(_)) => return Ok(flow),
+                        Flow::Normal => {}
+                    }
+                }
+            }
+            Stat::Repeat(cond, block) => loop {
+                let (flow, inner) = self.exec_chunk(&block.0, Scope::child(scope))?;
+                match flow {
+                    Flow::Break => break,
+                    flow @ (Flow::Return(_) | Flow::Goto(_)) => return Ok(flow),
+                    Flow::Normal => {}
+                }
+                if self.eval(cond, &inner)?.is_truthy() {
+                    break;
+                }
+            },
+            Stat::If(cond, block, elseifs, else_block) => {
+                if self.eval(cond, scope)?.is_truthy() {
+                    return self.exec_block(block, Scope::child(scope));
+                }
+                for (cond, block) in elseifs {
+                    if self.eval(cond, scope)?.is_truthy() {
+                        return self.exec_block(block, Scope::child(scope));
+                    }
+                }
+                if let Some(block) = else_block {
+                    return self.exec_block(block, Scope::child(scope));
+                }
+            }
+            Stat::For(name, init, limit, step, block) => {
+                let init = self.eval_for_number(init, scope, "initial")?;
+                let limit = self.eval_for_number(limit, scope, "limit")?;
+                let step = match step {
+                    Some(step) => self.eval_for_number(step, scope, "step")?,
+                    None => Value::Integer(1),
+                };
+                for i in ForLoop::new(init, limit, step)? {
+                    let inner = Scope::child(scope);
+                    inner.declare(name.lexeme, i);
+                    match self.exec_block(block, inner)? {
+                        Flow::Break => break,
+                        flow @ (Flow::Return(_) | Flow::Goto(_)) => return Ok(flow),
+                        Flow::Normal => {}
+                    }
+                }
+            }
+            Stat::ForIn(NameList(names), exprlist, block) => {
+                let mut values = self.eval_exprlist(exprlist, scope)?.into_iter();
+                let func = values.next().unwrap_or(Value::Nil);
+                let state = values.next().unwrap_or(Value::Nil);
+                let mut control = values.next().unwrap_or(Value::Nil);
+                loop {
+                    let results = self.vm.call(&func, vec![state.clone(), control.clone()])?;
+                    let mut results = results.into_iter();
+                    let first = results.next().unwrap_or(Value::Nil);
+                    if let Value::Nil = first {
+                        break;
+                    }
+                    control = first.clone();
+
+                    let inner = Scope::child(scope);
+                    let mut names = names.iter();
+                    if let Some(name) = names.next() {
+                        inner.declare(name.lexeme, first);
+                    }
+                    for name in names {
+                        inner.declare(name.lexeme, results.next().unwrap_or(Value::Nil));
+                    }
+                    match self.exec_block(block, inner)? {
+                        Flow::Break => break,
+                        flow @ (Flow::Return(_) | Flow::Goto(_)) => return Ok(flow),
+                        Flow::Normal => {}
+                    }
+                }
+            }
+            Stat::Function(FuncName(names, method), body) => {
+                let func = self.make_function(body, method.is_some(), scope);
+                let (first, rest) = names
+                    .split_first()
+                    .ok_or_else(|| LuaError::runtime("function name is empty"))?;
+                let mut path: Vec<&Token> = rest.iter().collect();
+                path.extend(method.iter());
+                match path.split_last() {
+                    None => self.assign(Target::Name(first.lexeme.to_string()), func, scope)?,
+                    Some((key, tables)) => {
+                        let mut table = self.lookup(first.lexeme, scope);
+                        for name in tables {
+                            table = self.vm.index(&table, &Value::from(name.lexeme))?;
+                        }
+                        let key = Value::from(key.lexeme);
+                        self.assign(Target::Index(table, key), func, scope)?;
+                    }
+                }
+            }
+            Stat::LocalFunction(name, body) => {
+                // The name is in scope inside the body, so the function can recurse.
+                *scope = Scope::child(scope);
+                scope.declare(name.lexeme, Value::Nil);
+                let func = self.make_function(body, false, scope);
+                scope.assign(name.lexeme, func);
+            }
+            Stat::LocalDeclVar(AttNameList(names), exprlist) => {
+                let values = match exprlist {
+                    Some(exprlist) => self.eval_exprlist(exprlist, scope)?,
+                    None => Vec::new(),
+                };
+                *scope = Scope::child(scope);
+                let mut values = values.into_iter();
+                for AttName(name, attrib) in names {
+                    let value = values.next().unwrap_or(Value::Nil);
+                    // `__close` metamethods are not run, so only nil and
+                    // false are closable.
+                    let close = attrib.as_ref().is_some_and(|a| a.lexeme == "close");
+                    if close && value.is_truthy() {
+                        return Err(LuaError::runtime(format!(
+                            "variable '{}' got a non-closable value",
+                            name.lexeme
+                        )));
+                    }
+                    scope.declare(name.lexeme, value);
+                }
+            }
+            Stat::Label(_) => {}
+            Stat::Goto(label) => return Ok(Flow::Goto(label.lexeme.to_string())),
+            Stat::Break => return Ok(Flow::Break),
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn eval_for_number(
+        &mut self,
+        expr: &Expr<'static>,
+        scope: &Rc<Scope>,
+        what: &str,
+    ) -> EvalResult<Value> {
+        match self.eval(expr, scope)? {
+            v @ (Value::Integer(_) | Value::Float(_)) => Ok(v),
+            _ => Err(LuaError::runtime(format!(
+                "'for' {} value must be a number",
+                what
+            ))),
+        }
+    }
+
+    /// Creates a closure, capturing the cells of the enclosing locals that
+    /// the body uses. The closure shares the body with the syntax tree.
+    fn make_function(
+        &mut self,
+        body: &FuncBody<'static>,
+        method: bool,
+        scope: &Rc<Scope>,
+    ) -> Value {
+        let FuncBody(ParamList(NameList(names), vararg), block) = body;
+        let mut params: Vec<String> = Vec::with_capacity(names.len() + 1);
+        if method {
+            params.push("self".to_string());
+        }
+        params.extend(names.iter().map(|name| name.lexeme.to_string()));
+        let upvalues = resolve::free_names(body, method)
+            .into_iter()
+            .filter_map(|name| scope.cell(&name).map(|cell| (name, cell)))
+            .collect();
+        let closure = TreeClosure {
+            params,
+            vararg: *vararg,
+            body: block.clone(),
+            upvalues: Scope::upvalues(upvalues),
+            source: self.source.clone(),
+        };
+        Value::Function(Rc::new(value::Function::Builtin {
+            name: "?",
+            func: call_closure,
+            upvalues: vec![Value::new_userdata(closure)],
+        }))
+    }
+
+    fn lookup(&self, name: &str, scope: &Rc<Scope>) -> Value {
+        match scope.lookup(name) {
+            Some(value) => value,
+            None => self.vm.get_global(name),
+        }
+    }
+
+    fn eval_target(&mut self, var: &Var<'static>, scope: &Rc<Scope>) -> EvalResult<Target> {
+        Ok(match var {
+            Var::VarName(name) => Target::Name(name.lexeme.to_string()),
+            Var::VarIdx(prefix, key) => {
+                let table = self.eval_prefix(prefix, scope)?;
+                let key = self.eval(key, scope)?;
+                Target::Index(table, key)
+            }
+            Var::VarMember(prefix, name) => {
+                let table = self.eval_prefix(prefix, scope)?;
+                Target::Index(table, Value::from(name.lexeme))
+            }
+        })
+    }
+
+    fn assign(&mut self, target: Target, value: Value, scope: &Rc<Scope>) -> EvalResult<()> {
+        match target {
+            Target::Name(name) => {
+                if !scope.assign(&name, value.clone()) {
+                    self.vm.set_global(&name, value);
+                }
+                Ok(())
+            }
+            Target::Index(table, key) => self.vm.set_index(&table, key, value),
+        }
+    }
+
+    /// Evaluates an expression list, expanding every value of the last
+    /// expression when it is a call or `...`.
+    fn eval_exprlist(
+        &mut self,
+        exprlist: &ExprList<'static>,
+        scope: &Rc<Scope>,
+    ) -> EvalResult<Vec<Value>> {
+        let ExprList(exprs) = exprlist;
+        let mut values = Vec::with_capacity(exprs.len());
+        if let Some((last, init)) = exprs.split_last() {
+            for expr in init {
+                values.push(self.eval(expr, scope)?);
+            }
+            values.extend(self.eval_multi(last, scope)?);
+        }
+        Ok(values)
+    }
+
+    fn eval_multi(&mut self, expr: &Expr<'static>, scope: &Rc<Scope>) -> EvalResult<Vec<Value>> {
+        match expr {
+            Expr::PrefixExp(PrefixExp::PrefixCall(call)) => self.eval_call(call, scope),
+            Expr::Dots => Ok(scope
+                .varargs()
+                .map(|args| args.to_vec())
+                .unwrap_or_default()),
+            expr => Ok(vec![self.eval(expr, scope)?]),
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr<'static>, scope: &Rc<Scope>) -> EvalResult<Value> {
+        match expr {
+            Expr::Nil => Ok(Value::Nil),
+            Expr::False => Ok(Value::Boolean(false)),
+            Expr::True => Ok(Value::Boolean(true)),
+            Expr::Integer(i) => Ok(Value::Integer(*i)),
+            Expr::Float(n) => Ok(Value::Float(*n)),
+            Expr::String(s) => Ok(Value::from(LuaString::from(s.as_slice()))),
+            Expr::Dots => Ok(scope
+                .varargs()
+                .and_then(|args| args.first().cloned())
+                .unwrap_or(Value::Nil)),
+            Expr::Function(ast::Function(body)) => Ok(self.make_function(body, false, scope)),
+            Expr::PrefixExp(prefix) => self.eval_prefix(prefix, scope),
+            Expr::TableConstructor(table) => self.eval_table(table, scope),
+            Expr::ExprBinop(lhs, Binop(op), rhs) => self.eval_binop(lhs, op, rhs, scope),
+            Expr::Unop(Unop(op), operand) => {
+                let value = self.eval(operand, scope)?;
+                match op.token_type {
+                    TokenType::Not => Ok(Value::Boolean(!value.is_truthy())),
+                    TokenType::Minus | TokenType::Tilda => {
+                        let op = match op.token_type {
+                            TokenType::Minus => ArithOp::Unm,
+                            _ => ArithOp::BNot,
+                        };
+                        self.vm
+                            .arith_meta(op, &value, &value)?
+                            .ok_or_else(|| arith_error(&value, &value, operand, operand, scope))
+                    }
+                    TokenType::Opus => self.vm.length(&value)?.ok_or_else(|| {
+                        LuaError::runtime(format!(
+                            "attempt to get length of a {} value{}",
+                            value.type_name(),
+                            describe(operand, scope)
+                        ))
+                    }),
+                    _ => Err(LuaError::runtime(format!(
+                        "unknown unary operator '{}'",
+                        op.lexeme
+                    ))),
+                }
+            }
+        }
+    }
+
+    fn eval_prefix(&mut self, prefix: &PrefixExp<'static>, scope: &Rc<Scope>) -> EvalResult<Value> {
+        match prefix {
+            PrefixExp::PrefixVar(var) => match var.as_ref() {
+                Var::VarName(name) => Ok(self.lookup(name.lexeme, scope)),
+                Var::VarIdx(prefix, key) => {
+                    let table = self.eval_prefix(prefix, scope)?;
+                    let key = self.eval(key, scope)?;
+                    self.vm.index(&table, &key)
+                }
+                Var::VarMember(prefix, name) => {
+                    let table = self.eval_prefix(prefix, scope)?;
+                    self.vm.index(&table, &Value::from(name.lexeme))
+                }
+            },
+            PrefixExp::PrefixCall(call) => Ok(self
+                .eval_call(call, scope)?
+                .into_iter()
+                .next()
+                .unwrap_or(Value::Nil)),
+            PrefixExp::PrefixParen(expr) => self.eval(expr, scope),
+        }
+    }
+
+    fn eval_call(
+        &mut self,
+        call: &FunctionCall<'static>,
+        scope: &Rc<Scope>,
+    ) -> EvalResult<Vec<Value>> {
+        let FunctionCall(prefix, method, args) = call;
+        let callee = self.eval_prefix(prefix, scope)?;
+        let (func, mut arg_values) = match method {
+            Some(name) => {
+                let func = self.vm.index(&callee, &Value::from(name.lexeme))?;
+                (func, vec![callee])
+            }
+            None => (callee, Vec::new()),
+        };
+        match args {
+            Args::ArgsNone => {}
+            Args::ArgsList(exprlist) => arg_values.extend(self.eval_exprlist(exprlist, scope)?),
+            Args::ArgsTable(table) => arg_values.push(self.eval_table(table, scope)?),
+            Args::ArgsString(s) => arg_values.push(Value::from(LuaString::from(s.as_slice()))),
+        }
+
+        if !meta::is_callable(&func) {
+            let name = match method {
+                Some(name) => format!(" (method '{}')", name.lexeme),
+                None => describe_prefix(prefix, scope),
+            };
+            return Err(LuaError::runtime(format!(
+                "attempt to call a {} value{}",
+                func.type_name(),
+                name
+            )));
+        }
+        self.vm.call(&func, arg_values)
+    }
+
+    fn eval_table(
+        &mut self,
+        table: &TableConstructor<'static>,
+        scope: &Rc<Scope>,
+    ) -> EvalResult<Value> {
+        let TableConstructor(FieldList(fields)) = table;
+        let positional = fields
+            .iter()
+            .filter(|field| matches!(field, Field::UniExp(_)))
+            .count();
+        let mut t = Table::with_capacity(positional, fields.len() - positional);
+        // Positional items are stored last, so `{ [1] = "x", "y" }` keeps
+        // "y" as the reference implementation does.
+        let mut items = Vec::with_capacity(positional);
+        for (i, field) in fields.iter().enumerate() {
+            match field {
+                Field::AssignIdx(key, value) => {
+                    let key = self.eval(key, scope)?;
+                    let value = self.eval(value, scope)?;
+                    t.set(key, value)
+                        .map_err(|e| LuaError::runtime(e.message))?;
+                }
+                Field::AssignName(name, value) => {
+                    let value = self.eval(value, scope)?;
+                    t.set_str(name.lexeme, value);
+                }
+                Field::UniExp(expr) if i + 1 == fields.len() => {
+                    items.extend(self.eval_multi(expr, scope)?);
+                }
+                Field::UniExp(expr) => items.push(self.eval(expr, scope)?),
+            }
+        }
+        for (i, value) in items.into_iter().enumerate() {
+            t.set_int(i as i64 + 1, value);
+        }
+        Ok(Value::Table(Rc::new(RefCell::new(t))))
+    }
+
+    fn eval_binop(
+        &mut self,
+        lhs: &Expr<'static>,
+        op: &Token<'static>,
+        rhs: &Expr<'static>,
+        scope: &Rc<Scope>,
+    ) -> EvalResult<Value> {
+        use TokenType::*;
+
+        // Logical operators short-circuit.
+        match op.token_type {
+            And => {
+                let l = self.eval(lhs, scope)?;
+                return if l.is_truthy() {
+                    self.eval(rhs, scope)
+                } else {
+                    Ok(l)
+                };
+            }
+            Or => {
+                let l = self.eval(lhs, scope)?;
+                return if l.is_truthy() {
+                    Ok(l)
+                } else {
+                    self.eval(rhs, scope)
+                };
+            }
+            _ => {}
+        }
+
+        let l = self.eval(lhs, scope)?;
+        let r = self.eval(rhs, scope)?;
+        match op.token_type {
+            Plus | Minus | Aster | Slash | Perc | Hat | IDiv | Amp | Bar | Tilda | ShL | ShR => {
+                let op = match op.token_type {
+                    Plus => ArithOp::Add,
+                    Minus => ArithOp::Sub,
+                    Aster => ArithOp::Mul,
+                    Slash => ArithOp::Div,
+                    Perc => ArithOp::Mod,
+                    Hat => ArithOp::Pow,
+                    IDiv => ArithOp::IDiv,
+                    Amp => ArithOp::BAnd,
+                    Bar => ArithOp::BOr,
+                    Tilda => ArithOp::BXor,
+                    ShL => ArithOp::Shl,
+                    _ => ArithOp::Shr,
+                };
+                self.vm
+                    .arith_meta(op, &l, &r)?
+                    .ok_or_else(|| arith_error(&l, &r, lhs, rhs, scope))
+            }
+            Concat => self.vm.concat(&l, &r)?.ok_or_else(|| {
+                let (bad, expr) = match l.to_lua_string() {
+                    Some(_) => (&r, rhs),
+                    None => (&l, lhs),
+                };
+                LuaError::runtime(format!(
+                    "attempt to concatenate a {} value{}",
+                    bad.type_name(),
+                    describe(expr, scope)
+                ))
+            }),
+            Eql => Ok(Value::Boolean(self.vm.equals(&l, &r)?)),
+            Ne => Ok(Value::Boolean(!self.vm.equals(&l, &r)?)),
+            Less => Ok(Value::Boolean(self.vm.less(&l, &r, false)?)),
+            Le => Ok(Value::Boolean(self.vm.less(&l, &r, true)?)),
+            Greater => Ok(Value::Boolean(self.vm.less(&r, &l, false)?)),
+            Ge => Ok(Value::Boolean(self.vm.less(&r, &l, true)?)),
+            _ => Err(LuaError::runtime(format!(
+                "unknown binary operator '{}'",
+                op.lexeme
+            ))),
+        }
+    }
+}
+
+/// The values a function returns when its body leaves with `flow`.
+fn function_results(flow: Flow) -> EvalResult<Vec<Value>> {
+    match flow {
+        Flow::Return(values) => Ok(values),
+        Flow::Normal => Ok(Vec::new()),
+        Flow::Break => Err(LuaError::runtime("break outside a loop")),
+        Flow::Goto(label) => Err(LuaError::runtime(format!(
+            "no visible label '{}' for goto",
+            label
+        ))),
+    }
+}
+
+/// Where an assignment stores its value.
+enum Target {
+    Name(String),
+    Index(Value, Value),
+}
+
+fn arith_error(l: &Value, r: &Value, lhs: &Expr, rhs: &Expr, scope: &Scope) -> LuaError {
+    let (bad, expr) = if l.to_numeric().is_none() {
+        (l, lhs)
+    } else {
+        (r, rhs)
+    };
+    LuaError::runtime(format!(
+        "attempt to perform arithmetic on a {} value{}",
+        bad.type_name(),
+        describe(expr, scope)
+    ))
+}
+
+/// Names the variable an erroneous value came from, like Lua's
+/// ` (global 'x')` suffix.
+fn describe(expr: &Expr, scope: &Scope) -> String {
+    match expr {
+        Expr::PrefixExp(prefix) => describe_prefix(prefix, scope),
+        _ => String::new(),
+    }
+}
+
+fn describe_prefix(prefix: &PrefixExp, scope: &Scope) -> String {
+    match prefix {
+        PrefixExp::PrefixVar(var) => match var.as_ref() {
+            Var::VarName(name) => {
+                let kind = match scope.lookup(name.lexeme) {
+                    Some(_) => "local",
+                    None => "global",
+                };
+                format!(" ({} '{}')", kind, name.lexeme)
+            }
+            Var::VarMember(_, name) => format!(" (field '{}')", name.lexeme),
+            Var::VarIdx(..) => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+/// The values taken by the control variable of a numeric `for`, following
+/// Lua 5.4: an integer loop when both `init` and `step` are integers
+/// (never overflowing), a float loop otherwise.
+enum ForLoop {
+    Integer {
+        next: i64,
+        step: i64,
+        remaining: Option<u64>,
+    },
+    Float {
+        next: f64,
+        limit: f64,
+        step: f64,
+    },
+}
+
+impl ForLoop {
+    fn new(init: Value, limit: Value, step: Value) -> EvalResult<Self> {
+        if let (Value::Integer(init), Value::Integer(step)) = (&init, &step) {
+            let (init, step) = (*init, *step);
+            if step == 0 {
+                return Err(LuaError::runtime("'for' step is zero"));
+            }
+            let remaining = match for_limit(&limit, step) {
+                Some(limit) if (step > 0 && init <= limit) || (step < 0 && init >= limit) => {
+                    // Number of further iterations, computed without overflow.
+                    let count = if step > 0 {
+                        (limit as u64).wrapping_sub(init as u64) / step as u64
+                    } else {
+                        (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+                    };
+                    Some(count)
+                }
+                _ => None,
+            };
+            return Ok(ForLoop::Integer {
+                next: init,
+                step,
+                remaining,
+            });
+        }
+
+        let (init, limit, step) = match (init.to_number(), limit.to_number(), step.to_number()) {
+            (Some(init), Some(limit), Some(step)) => (init, limit, step),
+            _ => unreachable!("'for' operands are checked to be numbers"),
+        };
+        if step == 0.0 {
+            return Err(LuaError::runtime("'for' step is zero"));
+        }
+        Ok(ForLoop::Float {
+            next: init,
+            limit,
+            step,
+        })
+    }
+}
+
+/// Converts the limit of an integer loop, clipping floats; `None` when the
+/// loop must not run at all.
+fn for_limit(limit: &Value, step: i64) -> Option<i64> {
+    match limit {
+        Value::Integer(limit) => Some(*limit),
+        Value::Float(f) if f.is_nan() => None,
+        Value::Float(f) => {
+            let f = if step > 0 { f.floor() } else { f.ceil() };
+            match value::float_to_integer(f) {
+                Some(limit) => Some(limit),
+                None if f > 0.0 => (step > 0).then_some(i64::MAX),
+                None => (step < 0).then_some(i64::MIN),
+            }
+        }
+        _ => None,
+    }
+}
+
+impl Iterator for ForLoop {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        match self {
+            ForLoop::Integer {
+                next,
+                step,
+                remaining,
+            } => {
+                let count = (*remaining)?;
+                let current = *next;
+                *remaining = count.checked_sub(1);
+                *next = next.wrapping_add(*step);
+                Some(Value::Integer(current))
+            }
+            ForLoop::Float { next, limit, step } => {
+                let current = *next;
+                if (*step > 0.0 && current <= *limit) || (*step < 0.0 && current >= *limit) {
+                    *next += *step;
+                    Some(Value::Float(current))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//
+// Finds the variables a function body uses from enclosing scopes, i.e. the
+// upvalues its closures have to capture.
+//
+use std::collections::HashSet;
+
+use crate::parser::ast::*;
+
+/// Returns the names `body` refers to without declaring them, in order of
+/// first use. Each is either an upvalue or a global, depending on the scope
+/// the closure is created in.
+pub fn free_names(body: &FuncBody, method: bool) -> Vec<String> {
+    let mut resolver = Resolver::default();
+    resolver.funcbody(body, method);
+    resolver.free
+}
+
+#[derive(Default)]
+struct Resolver {
+    scopes: Vec<HashSet<String>>,
+    seen: HashSet<String>,
+    free: Vec<String>,
+}
+
+impl Resolver {
+    fn declare(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string());
+        }
+    }
+
+    fn reference(&mut self, name: &str) {
+        if self.scopes.iter().any(|scope| scope.contains(name)) {
+            return;
+        }
+        if self.seen.insert(name.to_string()) {
+            self.free.push(name.to_string());
+        }
+    }
+
+    fn funcbody(&mut self, body: &FuncBody, method: bool) {
+        let FuncBody(ParamList(NameList(params), _), block) = body;
+        self.scopes.push(HashSet::new());
+        if method {
+            self.declare("self");
+        }
+        for param in params {
+            self.declare(param.lexeme);
+        }
+        self.chunk(&block.0);
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.scopes.push(HashSet::new());
+        self.chunk(&block.0);
+        self.scopes.pop();
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) {
+        let Chunk(stats, last_stat) = chunk;
+        for stat in stats {
+            self.stat(stat);
+        }
+        if let Some(LastStat::Return(Some(exprlist))) = last_stat {
+            self.exprlist(exprlist);
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match stat {
+            Stat::Assign(VarList(vars), exprlist) => {
+                for var in vars {
+                    self.var(var);
+                }
+                self.exprlist(exprlist);
+            }
+            Stat::FunctionCall(call) => self.call(call),
+            Stat::Do(block) => self.block(block),
+            Stat::While(cond, block) => {
+                self.expr(cond);
+                self.block(block);
+            }
+            Stat::Repeat(cond, block) => {
+                // The condition sees the locals of the loop body.
+                self.scopes.push(HashSet::new());
+                self.chunk(&block.0);
+                self.expr(cond);
+                self.scopes.pop();
+            }
+            Stat::If(cond, block, elseifs, else_block) => {
+                self.expr(cond);
+                self.block(block);
+                for (cond, block) in elseifs {
+                    self.expr(cond);
+                    self.block(block);
+                }
+                if let Some(block) = else_block {
+                    self.block(block);
+                }
+            }
+            Stat::For(name, init, limit, step, block) => {
+                self.expr(init);
+                self.expr(limit);
+                if let Some(step) = step {
+                    self.expr(step);
+                }
+                self.scopes.push(HashSet::new());
+                self.declare(name.lexeme);
+                self.block(block);
+                self.scopes.pop();
+            }
+            Stat::ForIn(NameList(names), exprlist, block) => {
+                self.exprlist(exprlist);
+                self.scopes.push(HashSet::new());
+                for name in names {
+                    self.declare(name.lexeme);
+                }
+                self.block(block);
+                self.scopes.pop();
+            }
+            Stat::Function(FuncName(names, method), body) => {
+                if let Some(name) = names.first() {
+                    self.reference(name.lexeme);
+                }
+                self.funcbody(body, method.is_some());
+            }
+            Stat::LocalFunction(name, body) => {
+                self.declare(name.lexeme);
+                self.funcbody(body, false);
+            }
+            Stat::LocalDeclVar(AttNameList(names), exprlist) => {
+                if let Some(exprlist) = exprlist {
+                    self.exprlist(exprlist);
+                }
+                for AttName(name, _) in names {
+                    self.declare(name.lexeme);
+                }
+            }
+            Stat::Label(_) | Stat::Goto(_) | Stat::Break => {}
+        }
+    }
+
+    fn exprlist(&mut self, ExprList(exprs): &ExprList) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Function(Function(body)) => self.funcbody(body, false),
+            Expr::PrefixExp(prefix) => self.prefix(prefix),
+            Expr::TableConstructor(table) => self.table(table),
+            Expr::ExprBinop(lhs, _, rhs) => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expr::Unop(_, operand) => self.expr(operand),
+            _ => {}
+        }
+    }
+
+    fn prefix(&mut self, prefix: &PrefixExp) {
+        match prefix {
+            PrefixExp::PrefixVar(var) => self.var(var),
+            PrefixExp::PrefixCall(call) => self.call(call),
+            PrefixExp::PrefixParen(expr) => self.expr(expr),
+        }
+    }
+
+    fn var(&mut self, var: &Var) {
+        match var {
+            Var::VarName(name) => self.reference(name.lexeme),
+            Var::VarIdx(prefix, key) => {
+                self.prefix(prefix);
+                self.expr(key);
+            }
+            Var::VarMember(prefix, _) => self.prefix(prefix),
+        }
+    }
+
+    fn call(&mut self, FunctionCall(prefix, _, args): &FunctionCall) {
+        self.prefix(prefix);
+        match args {
+            Args::ArgsList(exprlist) => self.exprlist(exprlist),
+            Args::ArgsTable(table) => self.table(table),
+            Args::ArgsNone | Args::ArgsString(_) => {}
+        }
+    }
+
+    fn table(&mut self, TableConstructor(FieldList(fields)): &TableConstructor) {
+        for field in fields {
+            match field {
+                Field::AssignIdx(key, value) => {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                Field::AssignName(_, value) | Field::UniExp(value) => self.expr(value),
+            }
+        }
+    }
+}
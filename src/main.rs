@@ -1,15 +1,35 @@
 extern crate purua;
 
+use std::process::exit;
+
 fn main() {
-    let code = "-- comment ignored:
-if 1 + 1 < 2 do
-  print(\"hello, world\")
-  return 3
-end
-        ";
-    dbg!(code);
-    let mut s = purua::scanner::Scanner::new(code);
-    s.scan().unwrap();
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: purua <script.lua>");
+            exit(2);
+        }
+    };
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("purua: cannot open {}: {}", path, e);
+            exit(1);
+        }
+    };
 
-    dbg!(s.tokens);
+    let mut vm = purua::vm::Vm::new();
+    let main = match vm.load(&source, &path) {
+        Ok(main) => main,
+        Err(e) => {
+            eprintln!("purua: {}", e);
+            exit(1);
+        }
+    };
+    let result = vm.call(&main, Vec::new());
+    let _ = vm.output().flush();
+    if let Err(e) = result {
+        eprintln!("purua: {}", e);
+        exit(1);
+    }
 }
@@ -1,11 +1,15 @@
 //
-// The Complete Syntax of Lua 5.1:
+// The Complete Syntax of Lua 5.1, with `goto` and labels from Lua 5.2 and
+// attributes from Lua 5.4:
 //   From... https://www.lua.org/manual/5.1/manual.html#8
+//   and... https://www.lua.org/manual/5.4/manual.html#9
 //
 // chunk ::= {stat [`;´]} [laststat [`;´]]
 // block ::= chunk
 // stat ::=  varlist `=´ explist |
 // 	 functioncall |
+// 	 label |
+// 	 goto Name |
 // 	 do block end |
 // 	 while exp do block end |
 // 	 repeat block until exp |
@@ -14,12 +18,15 @@
 // 	 for namelist in explist do block end |
 // 	 function funcname funcbody |
 // 	 local function Name funcbody |
-// 	 local namelist [`=´ explist]
+// 	 local attnamelist [`=´ explist]
 // laststat ::= return [explist] | break
+// label ::= `::´ Name `::´
 // funcname ::= Name {`.´ Name} [`:´ Name]
 // varlist ::= var {`,´ var}
 // var ::=  Name | prefixexp `[´ exp `]´ | prefixexp `.´ Name
 // namelist ::= Name {`,´ Name}
+// attnamelist ::= Name attrib {`,´ Name attrib}
+// attrib ::= [`<´ Name `>´]
 // explist ::= {exp `,´} exp
 // exp ::=  nil | false | true | Number | String | `...´ | function |
 // 	 prefixexp | tableconstructor | exp binop exp | unop exp
@@ -38,114 +45,144 @@
 // 	 and | or
 // unop ::= `-´ | not | `#´
 //
+use std::rc::Rc;
+
 use crate::Token;
 
 #[derive(Debug, Clone)]
-pub struct Chunk(pub Vec<Stat>, pub Option<LastStat>);
+pub struct Chunk<'src>(pub Vec<Stat<'src>>, pub Option<LastStat<'src>>);
+
+#[derive(Debug, Clone)]
+pub struct Block<'src>(pub Chunk<'src>);
+
+#[derive(Debug, Clone)]
+pub enum Stat<'src> {
+    Assign(VarList<'src>, ExprList<'src>),
+    FunctionCall(FunctionCall<'src>),
+    Do(Block<'src>),
+    While(Box<Expr<'src>>, Block<'src>),
+    Repeat(Box<Expr<'src>>, Block<'src>),
+    If(
+        Box<Expr<'src>>,
+        Block<'src>,
+        Vec<(Box<Expr<'src>>, Block<'src>)>,
+        Option<Block<'src>>,
+    ),
+    For(
+        Token<'src>,
+        Box<Expr<'src>>,
+        Box<Expr<'src>>,
+        Option<Box<Expr<'src>>>,
+        Block<'src>,
+    ),
+    ForIn(NameList<'src>, ExprList<'src>, Block<'src>),
+    Function(FuncName<'src>, FuncBody<'src>),
+    LocalFunction(Token<'src>, FuncBody<'src>),
+    LocalDeclVar(AttNameList<'src>, Option<ExprList<'src>>),
+    Label(Token<'src>),
+    Goto(Token<'src>),
+    Break,
+}
 
 #[derive(Debug, Clone)]
-pub struct Block(pub Chunk);
+pub enum LastStat<'src> {
+    Return(Option<ExprList<'src>>),
+}
 
 #[derive(Debug, Clone)]
-pub enum Stat {
-    Assign(VarList, ExprList),
-    FunctionCall(FunctionCall),
-    Do(Block),
-    While(Box<Expr>, Block),
-    Repeat(Box<Expr>, Block),
-    If(Box<Expr>, Block, Vec<(Box<Expr>, Block)>, Option<Block>),
-    For(Token, Box<Expr>, Box<Expr>, Option<Box<Expr>>, Block),
-    ForIn(NameList, ExprList, Block),
-    Function(FuncName, FuncBody),
-    LocalFunction(Token, FuncBody),
-    LocalDeclVar(NameList, Option<ExprList>),
-}
+pub struct FuncName<'src>(pub Vec<Token<'src>>, pub Option<Token<'src>>);
 
 #[derive(Debug, Clone)]
-pub enum LastStat {
-    Return(Option<ExprList>),
-    Break,
-}
+pub struct VarList<'src>(pub Vec<Var<'src>>);
 
 #[derive(Debug, Clone)]
-pub struct FuncName(pub Vec<Token>, pub Option<Token>);
+pub enum Var<'src> {
+    VarName(Token<'src>),
+    VarIdx(PrefixExp<'src>, Box<Expr<'src>>),
+    VarMember(PrefixExp<'src>, Token<'src>),
+}
 
 #[derive(Debug, Clone)]
-pub struct VarList(pub Vec<Var>);
+pub struct NameList<'src>(pub Vec<Token<'src>>);
 
+/// Names declared by a `local` statement, each with an optional attribute
+/// such as `<const>` or `<close>` (from Lua 5.4).
 #[derive(Debug, Clone)]
-pub enum Var {
-    VarName(Token),
-    VarIdx(PrefixExp, Box<Expr>),
-    VarMember(PrefixExp, Token),
-}
+pub struct AttNameList<'src>(pub Vec<AttName<'src>>);
 
 #[derive(Debug, Clone)]
-pub struct NameList(pub Vec<Token>);
+pub struct AttName<'src>(pub Token<'src>, pub Option<Token<'src>>);
 
 #[derive(Debug, Clone)]
-pub struct ExprList(pub Vec<Expr>);
+pub struct ExprList<'src>(pub Vec<Expr<'src>>);
 
 #[derive(Debug, Clone)]
-pub enum Expr {
+pub enum Expr<'src> {
     Nil,
     False,
     True,
-    Number(f64),
-    String(String),
+    Integer(i64),
+    Float(f64),
+    String(Vec<u8>),
     Dots,
-    Function(Function),
-    PrefixExp(PrefixExp),
-    TableConstructor(TableConstructor),
-    ExprBinop(Box<Expr>, Binop, Box<Expr>),
-    Unop(Unop, Box<Expr>),
+    Function(Function<'src>),
+    PrefixExp(PrefixExp<'src>),
+    TableConstructor(TableConstructor<'src>),
+    ExprBinop(Box<Expr<'src>>, Binop<'src>, Box<Expr<'src>>),
+    Unop(Unop<'src>, Box<Expr<'src>>),
 }
 
 #[derive(Debug, Clone)]
-pub enum PrefixExp {
-    PrefixVar(Box<Var>),
-    PrefixCall(FunctionCall),
-    PrefixParen(Box<Expr>),
+pub enum PrefixExp<'src> {
+    PrefixVar(Box<Var<'src>>),
+    PrefixCall(FunctionCall<'src>),
+    PrefixParen(Box<Expr<'src>>),
 }
 
 #[derive(Debug, Clone)]
-pub struct FunctionCall(pub Box<PrefixExp>, pub Option<Token>, pub Args);
+pub struct FunctionCall<'src>(
+    pub Box<PrefixExp<'src>>,
+    pub Option<Token<'src>>,
+    pub Args<'src>,
+);
 
 #[derive(Debug, Clone)]
-pub enum Args {
+pub enum Args<'src> {
     ArgsNone,
-    ArgsList(ExprList),
-    ArgsTable(TableConstructor),
-    ArgsString(String),
+    ArgsList(ExprList<'src>),
+    ArgsTable(TableConstructor<'src>),
+    ArgsString(Vec<u8>),
 }
 
 #[derive(Debug, Clone)]
-pub struct Function(pub FuncBody);
+pub struct Function<'src>(pub FuncBody<'src>);
 
+/// A function's parameters and body. The body is shared, so that the
+/// closures of the tree-walking interpreter can keep it without a copy.
 #[derive(Debug, Clone)]
-pub struct FuncBody(pub ParamList, pub Block);
+pub struct FuncBody<'src>(pub ParamList<'src>, pub Rc<Block<'src>>);
 
 #[derive(Debug, Clone)]
-pub struct ParamList(pub NameList, pub bool);
+pub struct ParamList<'src>(pub NameList<'src>, pub bool);
 
 #[derive(Debug, Clone)]
-pub struct TableConstructor(pub FieldList);
+pub struct TableConstructor<'src>(pub FieldList<'src>);
 
 #[derive(Debug, Clone)]
-pub struct FieldList(pub Vec<Field>);
+pub struct FieldList<'src>(pub Vec<Field<'src>>);
 
 #[derive(Debug, Clone)]
-pub enum Field {
-    AssignIdx(Box<Expr>, Box<Expr>),
-    AssignName(Token, Box<Expr>),
-    UniExp(Box<Expr>),
+pub enum Field<'src> {
+    AssignIdx(Box<Expr<'src>>, Box<Expr<'src>>),
+    AssignName(Token<'src>, Box<Expr<'src>>),
+    UniExp(Box<Expr<'src>>),
 }
 
 #[derive(Debug, Clone)]
 pub struct Fieldsep;
 
 #[derive(Debug, Clone)]
-pub struct Binop(pub Token);
+pub struct Binop<'src>(pub Token<'src>);
 
 #[derive(Debug, Clone)]
-pub struct Unop(pub Token);
+pub struct Unop<'src>(pub Token<'src>);
@@ -1,3 +1,4 @@
 pub mod ast;
+#[allow(clippy::module_inception)]
 pub mod parser;
 pub mod stream;
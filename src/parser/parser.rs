@@ -1,887 +1,1134 @@
+use std::rc::Rc;
+
+use combine::error::{Commit, StreamError, Tracked};
+use combine::stream::StreamErrorFor;
 use combine::{
-    attempt, chainl1, many, optional, parser, sep_by, sep_by1, token, ParseError, Parser, Stream,
-    StreamOnce,
+    any, attempt, look_ahead, many, optional, parser, satisfy, satisfy_map, sep_by1,
+    ParseError, Parser, StdParseResult, Stream, StreamOnce,
 };
 
 use super::ast;
 use super::ast::*;
-use super::stream::TokenStream;
+use super::stream::{Nesting, TokenStream};
+use crate::errors;
 use crate::token_type::TokenType;
 use crate::Token;
 
-pub fn parse(stream: TokenStream) -> Result<Block, String> {
-    let mut parser = (block(), token(TokenType::Eof.into()));
-    let result = parser.parse(stream);
-
-    match &result {
-        Ok(((block, _), _)) => {
-            Ok(block.clone())
+pub fn parse(mut stream: TokenStream<'_>) -> Result<Block<'_>, errors::ParseError> {
+    let result = (parser(block), token(TokenType::Eof))
+        .parse(&mut stream)
+        .map(|((block, _), _)| block);
+    match result {
+        Ok(block) => Ok(block),
+        Err(err) => {
+            // A stream from the scanner ends with `Eof`, which no parser
+            // gets past, but one built by hand may end early.
+            let eof = Token::from(TokenType::Eof);
+            let token = stream
+                .input
+                .get(err.position)
+                .or(stream.input.last())
+                .unwrap_or(&eof);
+            Err(errors::ParseError {
+                token_type: token.token_type,
+                text: token.lexeme.to_string(),
+                line: token.line as usize,
+                span: token.span,
+                expected: err.expected,
+                message: err.message.map(String::from),
+            })
         }
-        Err(err) => Err(format!("Parse error: {:?}", err)),
     }
 }
 
-parser! {
-    fn block[Input]()(Input) -> Block
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        chunk().map(|chunk| {
-            Block(chunk)
-        })
+// The rules that nest, blocks, statements and expressions, are functions
+// calling each other directly, as in Lua's `lparser.c`, and only the rules
+// that do not nest are built from combinators. A combinator takes several
+// stack frames for each of its parts in debug builds, and a chain of them
+// at each level of nesting adds up to tens of kilobytes.
+
+/// The error a rule fails with.
+type Failure<Input> = Commit<Tracked<<Input as StreamOnce>::Error>>;
+
+/// How deeply statements and expressions may nest, as `LUAI_MAXCCALLS`
+/// in Lua.
+const MAX_LEVELS: usize = 200;
+
+/// Parses with `rule` one level of nesting deeper, failing once the source
+/// nests more than `MAX_LEVELS` deep, as `enterlevel` in `lparser.c` does.
+fn nested<'src, Input, T>(
+    input: &mut Input,
+    rule: impl FnOnce(&mut Input) -> StdParseResult<T, Input>,
+) -> StdParseResult<T, Input>
+where
+    Input: Stream<Token = Token<'src>> + Nesting,
+    <Input as StreamOnce>::Error: ParseError<
+        <Input as StreamOnce>::Token,
+        <Input as StreamOnce>::Range,
+        <Input as StreamOnce>::Position,
+    >,
+{
+    if *input.level() >= MAX_LEVELS {
+        let position = input.position();
+        return Err(error_at::<Input>(position, "chunk has too many C levels"));
     }
+    *input.level() += 1;
+    let result = rule(input);
+    *input.level() -= 1;
+    result
 }
 
-fn chunk<Input>() -> impl Parser<Input, Output = Chunk>
+/// A committed error with the given message at `position`.
+fn error_at<Input>(position: Input::Position, message: &'static str) -> Failure<Input>
 where
-    Input: Stream<Token = Token>,
+    Input: StreamOnce,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    many(stat().skip(optional(token(TokenType::SemiColon.into()))))
-        .and(optional(laststat()))
-        .map(|(stat, last_stat)| Chunk(stat, last_stat))
+    let message = StreamErrorFor::<Input>::message_static_message(message);
+    Commit::Commit(Input::Error::from_error(position, message).into())
 }
 
-fn stat<Input>() -> impl Parser<Input, Output = Stat>
+/// An error at the next token, where a token of one of the given types
+/// would have been accepted.
+fn unexpected<'src, Input>(input: &mut Input, expected: &[TokenType]) -> Failure<Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let assign = stat_assign();
-    let function_call = stat_function_call();
-    let do_block = stat_do_block();
-    let while_block = stat_while_block();
-    let repeat_block = stat_repeat_block();
-    let if_block = stat_if_block();
-    let for_block = stat_for_block();
-    let for_in_block = stat_for_in_block();
-    let function_decl = stat_function_decl();
-    let local_function_decl = stat_local_function_decl();
-    let local_var_decl = stat_local_var_decl();
+    let mut err = Input::Error::empty(input.position());
+    for &token_type in expected {
+        let token = Token::from(token_type);
+        err.add(StreamErrorFor::<Input>::expected_token(token));
+    }
+    Commit::Peek(err.into())
+}
 
-    attempt(assign)
-        .or(attempt(function_call))
-        .or(do_block)
-        .or(while_block)
-        .or(repeat_block)
-        .or(if_block)
-        .or(attempt(for_block))
-        .or(for_in_block)
-        .or(function_decl)
-        .or(attempt(local_function_decl))
-        .or(local_var_decl)
+/// The result of a part of a rule after its first token. Having consumed
+/// a token, the rule cannot be anything else, so its failure is committed.
+fn committed<T, E>(result: Result<(T, Commit<()>), Commit<E>>) -> Result<T, Commit<E>> {
+    result.map(|(value, _)| value).map_err(Commit::into_commit)
 }
 
-fn stat_assign<Input>() -> impl Parser<Input, Output = Stat>
+/// The type of the next token, if there is one, leaving it in the stream.
+fn next_token_type<'src, Input>(input: &mut Input) -> Option<TokenType>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let varlist = varlist();
-    let exprlist = exprlist1();
-    (varlist, token(TokenType::Assign.into()), exprlist)
-        .map(|(varlist, _, exprlist)| Stat::Assign(varlist, exprlist))
+    let next = look_ahead(any()).parse_stream(input).into_result();
+    next.ok().map(|(t, _): (Token, _)| t.token_type)
 }
 
-fn stat_function_call<Input>() -> impl Parser<Input, Output = Stat>
+/// Parses one token of the given type. Unlike `combine::token`, the parser
+/// only holds the `TokenType`, not a whole `Token`: parsers are built on the
+/// stack and this keeps the deeply nested ones small.
+fn token<'src, Input>(token_type: TokenType) -> impl Parser<Input, Output = Token<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let function_call = functioncall();
-    function_call.map(|function_call| Stat::FunctionCall(function_call))
+    satisfy(move |t: Token| t.token_type == token_type).expected(token_type)
 }
 
-fn stat_do_block<Input>() -> impl Parser<Input, Output = Stat>
+/// Parses one token of the given type holding a value, failing as an
+/// unexpected symbol if `convert` cannot read the value from its text.
+fn literal<'src, Input, T>(
+    token_type: TokenType,
+    convert: fn(Token<'src>) -> Option<T>,
+) -> impl Parser<Input, Output = T> + use<'src, Input, T>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let block = block();
-    (
-        token(TokenType::Do.into()),
-        block,
-        token(TokenType::End.into()),
-    )
-        .map(|(_, block, _)| Stat::Do(block))
+    satisfy_map(move |t: Token<'src>| {
+        if t.token_type == token_type {
+            convert(t)
+        } else {
+            None
+        }
+    })
+    .expected(token_type)
 }
 
-fn stat_while_block<Input>() -> impl Parser<Input, Output = Stat>
+/// Parses one token of the given type, as `checknext` in `lparser.c`.
+fn expect<'src, Input>(
+    input: &mut Input,
+    token_type: TokenType,
+) -> StdParseResult<Token<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let expr = expr_binop_bottom();
-    let block = block();
-    (
-        token(TokenType::While.into()),
-        expr,
-        token(TokenType::Do.into()),
-        block,
-        token(TokenType::End.into()),
-    )
-        .map(|(_, expr, _, block, _)| Stat::While(Box::new(expr), block))
+    token(token_type).parse_stream(input).into_result()
 }
 
-fn stat_repeat_block<Input>() -> impl Parser<Input, Output = Stat>
+/// Whether a name and `=` come next, which start a numeric `for` or a
+/// named field rather than a list of names or an expression.
+fn name_assign_next<'src, Input>(input: &mut Input) -> bool
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let block = block();
-    let expr = expr_binop_bottom();
-    (
-        token(TokenType::Repeat.into()),
-        block,
-        token(TokenType::Until.into()),
-        expr,
-    )
-        .map(|(_, block, _, expr)| Stat::Repeat(Box::new(expr), block))
+    look_ahead((token(TokenType::Name), token(TokenType::Assign)))
+        .parse_stream(input)
+        .into_result()
+        .is_ok()
 }
 
-fn stat_if_block<Input>() -> impl Parser<Input, Output = Stat>
+/// Parses one token of the given type if it is next, as `testnext` in
+/// `lparser.c`.
+fn test_next<'src, Input>(input: &mut Input, token_type: TokenType) -> Option<Token<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let expr_elseif = expr_binop_bottom();
-    let expr = expr_binop_bottom();
-    let block_elseif = block();
-    let block_else = block();
-    let block = block();
-    let elseif_block = many(
-        (
-            token(TokenType::Elseif.into()),
-            expr_elseif,
-            token(TokenType::Then.into()),
-            block_elseif,
-        )
-            .map(|(_, expr, _, block)| (Box::new(expr), block)),
-    );
-    let else_block = optional((token(TokenType::Else.into()), block_else).map(|(_, block)| block));
-    (
-        token(TokenType::If.into()),
-        expr,
-        token(TokenType::Then.into()),
-        block,
-        elseif_block,
-        else_block,
-        token(TokenType::End.into()),
-    )
-        .map(|(_, expr, _, block, elseif_block, else_block, _)| {
-            Stat::If(Box::new(expr), block, elseif_block, else_block)
-        })
+    expect(input, token_type).ok().map(|(token, _)| token)
 }
 
-fn stat_for_block<Input>() -> impl Parser<Input, Output = Stat>
+/// Parses the statements of a block up to the token that ends it, as
+/// `statlist` in `lparser.c`. Without a last statement the block must stop
+/// there, so that a stray token is an unexpected symbol rather than a
+/// missing `end`.
+fn block<'src, Input>(input: &mut Input) -> StdParseResult<Block<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let name = token(TokenType::Name.into());
-    let expr_init = expr_binop_bottom();
-    let expr_cond = expr_binop_bottom();
-    let expr_incr = expr_binop_bottom();
-    let block = block();
-    (
-        token(TokenType::For.into()),
-        name,
-        token(TokenType::Assign.into()),
-        expr_init,
-        token(TokenType::Comma.into()),
-        expr_cond,
-        optional(token(TokenType::Comma.into()).with(expr_incr)),
-        token(TokenType::Do.into()),
-        block,
-        token(TokenType::End.into()),
-    )
-        .map(|(_, name, _, expr1, _, expr2, expr3, _, block, _)| {
-            Stat::For(
-                name,
-                Box::new(expr1),
-                Box::new(expr2),
-                expr3.map(Box::new),
-                block,
+    let mut stats = Vec::new();
+    let mut commit = Commit::Peek(());
+    let last_stat = loop {
+        match next_token_type(input) {
+            Some(TokenType::Return) => {
+                break Some(committed(laststat(input))?);
+            }
+            Some(TokenType::SemiColon) => {
+                // An empty statement.
+                test_next(input, TokenType::SemiColon);
+                commit = Commit::Commit(());
+                continue;
+            }
+            Some(
+                TokenType::End
+                | TokenType::Else
+                | TokenType::Elseif
+                | TokenType::Until
+                | TokenType::Eof,
             )
-        })
+            | None => break None,
+            _ => {}
+        }
+        let (stat, _) = match commit {
+            Commit::Commit(()) => stat(input).map_err(Commit::into_commit)?,
+            Commit::Peek(()) => stat(input)?,
+        };
+        stats.push(stat);
+        commit = Commit::Commit(());
+    };
+    if last_stat.is_some() {
+        commit = Commit::Commit(());
+    }
+    Ok((Block(Chunk(stats, last_stat)), commit))
 }
 
-fn stat_for_in_block<Input>() -> impl Parser<Input, Output = Stat>
+/// Parses a statement with the rule its first token calls for, as
+/// `statement` in `lparser.c` does.
+fn stat<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let name_list = namelist();
-    let expr_list = exprlist();
-    let block = block();
-    (
-        token(TokenType::For.into()),
-        name_list,
-        token(TokenType::In.into()),
-        expr_list,
-        token(TokenType::Do.into()),
-        block,
-        token(TokenType::End.into()),
-    )
-        .map(|(_, name_list, _, expr_list, _, block, _)| Stat::ForIn(name_list, expr_list, block))
+    nested(input, |input| match next_token_type(input) {
+        Some(TokenType::Do) => stat_do_block(input),
+        Some(TokenType::While) => stat_while_block(input),
+        Some(TokenType::Repeat) => stat_repeat_block(input),
+        Some(TokenType::If) => stat_if_block(input),
+        Some(TokenType::For) => stat_for_block(input),
+        Some(TokenType::Function) => stat_function_decl(input),
+        Some(TokenType::Local) => stat_local_decl(input),
+        Some(TokenType::DbColon) => stat_label().parse_stream(input).into_result(),
+        Some(TokenType::Goto) => stat_goto().parse_stream(input).into_result(),
+        Some(TokenType::Break) => {
+            let (_, commit) = expect(input, TokenType::Break)?;
+            Ok((Stat::Break, commit))
+        }
+        _ => stat_expr(input),
+    })
 }
 
-fn stat_function_decl<Input>() -> impl Parser<Input, Output = Stat>
+/// A function call or an assignment, told apart once their first prefix
+/// expression is parsed, as `exprstat` in `lparser.c` does.
+fn stat_expr<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let func_name = funcname();
-    let func_body = funcbody();
-    (token(TokenType::Function.into()), func_name, func_body)
-        .map(|(_, func_name, func_body)| Stat::Function(func_name, func_body))
+    let (prefix, commit) = prefixexp(input)?;
+    let position = input.position();
+    let first = match prefix {
+        PrefixExp::PrefixCall(call) => match next_token_type(input) {
+            Some(TokenType::Comma | TokenType::Assign) => None,
+            _ => return Ok((Stat::FunctionCall(call), commit)),
+        },
+        PrefixExp::PrefixVar(var) => Some(var),
+        PrefixExp::PrefixParen(_) => None,
+    };
+    let mut vars = Vec::new();
+    while test_next(input, TokenType::Comma).is_some() {
+        vars.push(committed(var(input))?);
+    }
+    let exprlist = match test_next(input, TokenType::Assign) {
+        Some(_) => Some(committed(exprlist1(input))?),
+        None => None,
+    };
+    match (first, exprlist) {
+        (Some(first), Some(exprlist)) => {
+            let varlist = std::iter::once(*first).chain(vars).collect();
+            Ok((Stat::Assign(VarList(varlist), exprlist), Commit::Commit(())))
+        }
+        _ => Err(error_at::<Input>(position, "syntax error")),
+    }
 }
 
-fn stat_local_function_decl<Input>() -> impl Parser<Input, Output = Stat>
+fn stat_do_block<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let name = token(TokenType::Name.into());
-    let func_body = funcbody();
-    (
-        token(TokenType::Local.into()),
-        token(TokenType::Function.into()),
-        name,
-        func_body,
-    )
-        .map(|(_, _, name, func_body)| Stat::LocalFunction(name, func_body))
+    expect(input, TokenType::Do)?;
+    let block = committed(block(input))?;
+    committed(expect(input, TokenType::End))?;
+    Ok((Stat::Do(block), Commit::Commit(())))
 }
 
-fn stat_local_var_decl<Input>() -> impl Parser<Input, Output = Stat>
+fn stat_while_block<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let name_list = namelist();
-    let expr_list = optional(token(TokenType::Assign.into()).with(exprlist1()));
-    (token(TokenType::Local.into()), name_list, expr_list)
-        .map(|(_, name_list, expr_list)| Stat::LocalDeclVar(name_list, expr_list))
+    expect(input, TokenType::While)?;
+    let expr = committed(expr(input))?;
+    committed(expect(input, TokenType::Do))?;
+    let block = committed(block(input))?;
+    committed(expect(input, TokenType::End))?;
+    Ok((Stat::While(Box::new(expr), block), Commit::Commit(())))
 }
 
-fn laststat<Input>() -> impl Parser<Input, Output = LastStat>
+fn stat_repeat_block<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let return_stat = token(TokenType::Return.into())
-        .and(optional(exprlist()))
-        .map(|(_, exprlist)| LastStat::Return(exprlist));
-    let break_stat = token(TokenType::Break.into()).map(|_| LastStat::Break);
-    return_stat.or(break_stat)
+    expect(input, TokenType::Repeat)?;
+    let block = committed(block(input))?;
+    committed(expect(input, TokenType::Until))?;
+    let expr = committed(expr(input))?;
+    Ok((Stat::Repeat(Box::new(expr), block), Commit::Commit(())))
 }
 
-fn funcname<Input>() -> impl Parser<Input, Output = FuncName>
+fn stat_if_block<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let name = token(TokenType::Name.into());
-    let dot = token(TokenType::Period.into());
-    let colon_name = token(TokenType::Colon.into()).with(token(TokenType::Name.into()));
-    sep_by1(name, dot)
-        .and(optional(colon_name))
-        .map(|(names, colon)| FuncName(names, colon))
+    expect(input, TokenType::If)?;
+    let cond = committed(expr(input))?;
+    committed(expect(input, TokenType::Then))?;
+    let then_block = committed(block(input))?;
+    let mut elseif_blocks = Vec::new();
+    while test_next(input, TokenType::Elseif).is_some() {
+        let cond = committed(expr(input))?;
+        committed(expect(input, TokenType::Then))?;
+        elseif_blocks.push((Box::new(cond), committed(block(input))?));
+    }
+    let else_block = match test_next(input, TokenType::Else) {
+        Some(_) => Some(committed(block(input))?),
+        None => None,
+    };
+    committed(expect(input, TokenType::End))?;
+    let stat = Stat::If(Box::new(cond), then_block, elseif_blocks, else_block);
+    Ok((stat, Commit::Commit(())))
 }
 
-fn varlist<Input>() -> impl Parser<Input, Output = VarList>
+/// A numeric or a generic `for`, which only `for Name =` tells apart.
+fn stat_for_block<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    sep_by1(var(), token(TokenType::Comma.into())).map(|vars| VarList(vars))
+    expect(input, TokenType::For)?;
+    let stat = if name_assign_next(input) {
+        let name = committed(expect(input, TokenType::Name))?;
+        committed(expect(input, TokenType::Assign))?;
+        let init = committed(expr(input))?;
+        committed(expect(input, TokenType::Comma))?;
+        let limit = committed(expr(input))?;
+        let step = match test_next(input, TokenType::Comma) {
+            Some(_) => Some(Box::new(committed(expr(input))?)),
+            None => None,
+        };
+        committed(expect(input, TokenType::Do))?;
+        let block = committed(block(input))?;
+        Stat::For(name, Box::new(init), Box::new(limit), step, block)
+    } else {
+        let name_list = committed(namelist().parse_stream(input).into_result())?;
+        committed(expect(input, TokenType::In))?;
+        let expr_list = committed(exprlist(input))?;
+        committed(expect(input, TokenType::Do))?;
+        let block = committed(block(input))?;
+        Stat::ForIn(name_list, expr_list, block)
+    };
+    committed(expect(input, TokenType::End))?;
+    Ok((stat, Commit::Commit(())))
 }
 
-parser! {
-    fn var[Input]()(Input) -> Var
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        let name = token(TokenType::Name.into()).map(|name| Var::VarName(name));
-        let prefixexp_idx = prefixexp_name_mocked(); // TODO: true prefixname?
-        let prefixexp_mem = prefixexp_name_mocked(); // TODO: true prefixname?
-        let index = token(TokenType::BracketL.into())
-            .with(expr_binop_bottom())
-            .skip(token(TokenType::BracketR.into()));
-        let dot_name = token(TokenType::Period.into())
-            .with(token(TokenType::Name.into()));
+fn stat_function_decl<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
+where
+    Input: Stream<Token = Token<'src>> + Nesting,
+    <Input as StreamOnce>::Error: ParseError<
+        <Input as StreamOnce>::Token,
+        <Input as StreamOnce>::Range,
+        <Input as StreamOnce>::Position,
+    >,
+{
+    expect(input, TokenType::Function)?;
+    let func_name = committed(funcname().parse_stream(input).into_result())?;
+    let func_body = committed(funcbody(input))?;
+    Ok((Stat::Function(func_name, func_body), Commit::Commit(())))
+}
 
-        attempt(prefixexp_idx.and(index).map(|(prefix, index)| Var::VarIdx(prefix, Box::new(index))))
-            .or(attempt(prefixexp_mem.and(dot_name).map(|(prefix, name)| Var::VarMember(prefix, name))))
-            .or(name)
+/// A `local function` or a declaration of local variables.
+fn stat_local_decl<'src, Input>(input: &mut Input) -> StdParseResult<Stat<'src>, Input>
+where
+    Input: Stream<Token = Token<'src>> + Nesting,
+    <Input as StreamOnce>::Error: ParseError<
+        <Input as StreamOnce>::Token,
+        <Input as StreamOnce>::Range,
+        <Input as StreamOnce>::Position,
+    >,
+{
+    expect(input, TokenType::Local)?;
+    if test_next(input, TokenType::Function).is_some() {
+        let name = committed(expect(input, TokenType::Name))?;
+        let func_body = committed(funcbody(input))?;
+        return Ok((Stat::LocalFunction(name, func_body), Commit::Commit(())));
     }
+    let name_list = committed(attnamelist().parse_stream(input).into_result())?;
+    let expr_list = match test_next(input, TokenType::Assign) {
+        Some(_) => Some(committed(exprlist1(input))?),
+        None => None,
+    };
+    Ok((Stat::LocalDeclVar(name_list, expr_list), Commit::Commit(())))
 }
 
-fn namelist<Input>() -> impl Parser<Input, Output = NameList>
+fn stat_label<'src, Input>() -> impl Parser<Input, Output = Stat<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    sep_by(
-        token(TokenType::Name.into()),
-        token(TokenType::Comma.into()),
+    (
+        token(TokenType::DbColon),
+        token(TokenType::Name),
+        token(TokenType::DbColon),
     )
-    .map(|names| NameList(names))
+        .map(|(_, name, _)| Stat::Label(name))
 }
 
-fn exprlist1<Input>() -> impl Parser<Input, Output = ExprList>
+fn stat_goto<'src, Input>() -> impl Parser<Input, Output = Stat<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    sep_by1(expr_binop_bottom(), token(TokenType::Comma.into())).map(|exprs| ExprList(exprs))
+    token(TokenType::Goto)
+        .with(token(TokenType::Name))
+        .map(Stat::Goto)
 }
 
-fn exprlist<Input>() -> impl Parser<Input, Output = ExprList>
+fn attnamelist<'src, Input>() -> impl Parser<Input, Output = AttNameList<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    sep_by(expr_binop_bottom(), token(TokenType::Comma.into())).map(|exprs| ExprList(exprs))
-}
-
-parser! {
-    fn expr[Input]()(Input) -> Expr
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        attempt(expr_upper())
-            .or(expr_lower())
-    }
+    let attrib = optional(
+        token(TokenType::Less)
+            .with(token(TokenType::Name))
+            .skip(token(TokenType::Greater)),
+    );
+    sep_by1(
+        (token(TokenType::Name), attrib).map(|(name, attrib)| AttName(name, attrib)),
+        token(TokenType::Comma),
+    )
+    .map(AttNameList)
 }
 
-fn expr_upper<Input>() -> impl Parser<Input, Output = Expr>
+fn laststat<'src, Input>(input: &mut Input) -> StdParseResult<LastStat<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let nil = token(TokenType::Nil.into()).map(|_| Expr::Nil);
-    let false_expr = token(TokenType::False.into()).map(|_| Expr::False);
-    let true_expr = token(TokenType::True.into()).map(|_| Expr::True);
-    let number = token(TokenType::Int.into())
-        .map(|num: Token| Expr::Number(num.try_into().unwrap()))
-        .or(token(TokenType::Float.into()).map(|num: Token| Expr::Number(num.try_into().unwrap())));
-    let string =
-        token(TokenType::StringLit.into()).map(|s: Token| Expr::String(s.try_into().unwrap()));
-    let dots = token(TokenType::Dots.into()).map(|_| Expr::Dots);
-    nil.or(false_expr)
-        .or(true_expr)
-        .or(number)
-        .or(string)
-        .or(dots)
+    expect(input, TokenType::Return)?;
+    let exprlist = committed(exprlist(input))?;
+    test_next(input, TokenType::SemiColon);
+    Ok((LastStat::Return(Some(exprlist)), Commit::Commit(())))
 }
 
-fn expr_lower<Input>() -> impl Parser<Input, Output = Expr>
+fn funcname<'src, Input>() -> impl Parser<Input, Output = FuncName<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let function = function().map(|fun| Expr::Function(fun));
-    function
-        .or(prefixexp().map(|prefix| Expr::PrefixExp(prefix)))
-        .or(tableconstructor().map(|table| Expr::TableConstructor(table)))
-        .or(unop()
-            .and(expr_binop_bottom())
-            .map(|(unop, expr)| Expr::Unop(unop, Box::new(expr))))
-}
-
-parser! {
-    fn prefixexp[Input]()(Input) -> PrefixExp
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        let var = var();
-        let function_call = functioncall();
-        let paren = token(TokenType::ParenL.into()).with(expr_binop_bottom()).skip(token(TokenType::ParenR.into()));
-        attempt(function_call.map(|call| PrefixExp::PrefixCall(call)))
-            .or(var.map(|var| PrefixExp::PrefixVar(Box::new(var))))
-            .or(paren.map(|expr| PrefixExp::PrefixParen(Box::new(expr))))
-    }
+    let name = token(TokenType::Name);
+    let dot = token(TokenType::Period);
+    let colon_name = token(TokenType::Colon).with(token(TokenType::Name));
+    sep_by1(name, dot)
+        .and(optional(colon_name))
+        .map(|(names, colon)| FuncName(names, colon))
 }
 
-parser! {
-    fn prefixexp_lowered[Input]()(Input) -> PrefixExp
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        let var = var();
-        let paren = token(TokenType::ParenL.into()).with(expr_binop_bottom()).skip(token(TokenType::ParenR.into()));
-        var.map(|var| PrefixExp::PrefixVar(Box::new(var)))
-            .or(paren.map(|expr| PrefixExp::PrefixParen(Box::new(expr))))
+/// A prefix expression that can be assigned to.
+fn var<'src, Input>(input: &mut Input) -> StdParseResult<Var<'src>, Input>
+where
+    Input: Stream<Token = Token<'src>> + Nesting,
+    <Input as StreamOnce>::Error: ParseError<
+        <Input as StreamOnce>::Token,
+        <Input as StreamOnce>::Range,
+        <Input as StreamOnce>::Position,
+    >,
+{
+    let position = input.position();
+    match prefixexp(input)? {
+        (PrefixExp::PrefixVar(var), commit) => Ok((*var, commit)),
+        _ => Err(error_at::<Input>(position, "syntax error")),
     }
 }
 
-parser! {
-    fn prefixexp_name_mocked[Input]()(Input) -> PrefixExp
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        let var_name_mocked = token(TokenType::Name.into()).map(|name| Var::VarName(name));
-        let paren = token(TokenType::ParenL.into()).with(expr_binop_bottom()).skip(token(TokenType::ParenR.into()));
-        var_name_mocked.map(|var| PrefixExp::PrefixVar(Box::new(var)))
-            .or(paren.map(|expr| PrefixExp::PrefixParen(Box::new(expr))))
-    }
+fn namelist<'src, Input>() -> impl Parser<Input, Output = NameList<'src>>
+where
+    Input: Stream<Token = Token<'src>>,
+    <Input as StreamOnce>::Error: ParseError<
+        <Input as StreamOnce>::Token,
+        <Input as StreamOnce>::Range,
+        <Input as StreamOnce>::Position,
+    >,
+{
+    sep_by1(
+        token(TokenType::Name),
+        token(TokenType::Comma),
+    )
+    .map(NameList)
 }
 
-parser! {
-    fn functioncall[Input]()(Input) -> FunctionCall
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        let prefixexp = prefixexp_lowered();
-        let args = args();
-        (
-            prefixexp,
-            optional(token(TokenType::Colon.into()).with(token(TokenType::Name.into()))),
-            args,
-        )
-            .map(|(prefix, name, args)| FunctionCall(Box::new(prefix), name, args))
+fn exprlist1<'src, Input>(input: &mut Input) -> StdParseResult<ExprList<'src>, Input>
+where
+    Input: Stream<Token = Token<'src>> + Nesting,
+    <Input as StreamOnce>::Error: ParseError<
+        <Input as StreamOnce>::Token,
+        <Input as StreamOnce>::Range,
+        <Input as StreamOnce>::Position,
+    >,
+{
+    let (first, commit) = expr(input)?;
+    let mut exprs = vec![first];
+    while test_next(input, TokenType::Comma).is_some() {
+        exprs.push(committed(expr(input))?);
     }
+    Ok((ExprList(exprs), commit))
 }
 
-fn args<Input>() -> impl Parser<Input, Output = Args>
+/// Parses expressions separated by commas, possibly none.
+fn exprlist<'src, Input>(input: &mut Input) -> StdParseResult<ExprList<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let expr_list = exprlist();
-    let table_constructor = tableconstructor().map(|table| Args::ArgsTable(table));
-    let string =
-        token(TokenType::StringLit.into()).map(|s: Token| Args::ArgsString(s.try_into().unwrap()));
-    token(TokenType::ParenL.into())
-        .with(expr_list)
-        .skip(token(TokenType::ParenR.into()))
-        .map(|expr_list| Args::ArgsList(expr_list))
-        .or(table_constructor)
-        .or(string)
-}
-
-parser! {
-    fn function[Input]()(Input) -> ast::Function
-    where [
-        Input: Stream<Token = Token>,
-    ] {
-        let func_body = funcbody();
-        token(TokenType::Function.into()).with(func_body).map(|func_body| ast::Function(func_body))
+    match exprlist1(input) {
+        Err(Commit::Peek(_)) => Ok((ExprList(Vec::new()), Commit::Peek(()))),
+        result => result,
     }
 }
 
-fn funcbody<Input>() -> impl Parser<Input, Output = FuncBody>
+/// Parses an operand with the rule its first token calls for, as
+/// `simpleexp` in `lparser.c` does.
+fn simpleexp<'src, Input>(input: &mut Input) -> StdParseResult<Expr<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let param_list = paramlist();
-    let block = block();
-    (
-        token(TokenType::ParenL.into()),
-        param_list,
-        token(TokenType::ParenR.into()),
-        block,
-        token(TokenType::End.into()),
-    )
-        .map(|(_, param_list, _, block, _)| FuncBody(param_list, block))
+    match next_token_type(input) {
+        Some(TokenType::Function) => function(input),
+        Some(TokenType::BraceL) => {
+            tableconstructor(input).map(|(table, commit)| (Expr::TableConstructor(table), commit))
+        }
+        Some(TokenType::Name | TokenType::ParenL) => {
+            prefixexp(input).map(|(prefix, commit)| (Expr::PrefixExp(prefix), commit))
+        }
+        _ => expr_upper().parse_stream(input).into_result(),
+    }
 }
 
-fn paramlist<Input>() -> impl Parser<Input, Output = ParamList>
+/// An anonymous function.
+fn function<'src, Input>(input: &mut Input) -> StdParseResult<Expr<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let name_list = namelist();
-    let dots = token(TokenType::Dots.into());
-    let vararg = optional(dots).map(|_| true);
-    (name_list, vararg).map(|(name_list, vararg)| ParamList(name_list, vararg))
+    expect(input, TokenType::Function)?;
+    let func_body = committed(funcbody(input))?;
+    Ok((Expr::Function(ast::Function(func_body)), Commit::Commit(())))
 }
 
-fn tableconstructor<Input>() -> impl Parser<Input, Output = TableConstructor>
+fn expr_upper<'src, Input>() -> impl Parser<Input, Output = Expr<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let field_list = fieldlist();
-    (
-        token(TokenType::BraceL.into()),
-        field_list,
-        token(TokenType::BraceR.into()),
-    )
-        .map(|(_, field_list, _)| TableConstructor(field_list))
+    let nil = token(TokenType::Nil).map(|_| Expr::Nil);
+    let false_expr = token(TokenType::False).map(|_| Expr::False);
+    let true_expr = token(TokenType::True).map(|_| Expr::True);
+    let number = literal(TokenType::Int, |num| i64::try_from(num).ok())
+        .map(Expr::Integer)
+        .or(literal(TokenType::Float, |num| f64::try_from(num).ok()).map(Expr::Float));
+    let string = literal(TokenType::StringLit, |s| Vec::try_from(s).ok()).map(Expr::String);
+    let dots = token(TokenType::Dots).map(|_| Expr::Dots);
+    nil.or(false_expr)
+        .or(true_expr)
+        .or(number)
+        .or(string)
+        .or(dots)
 }
 
-fn fieldlist<Input>() -> impl Parser<Input, Output = FieldList>
+/// A name or a parenthesized expression followed by any number of
+/// suffixes, each applied to everything before it: `a.b[c]:m(x)` calls
+/// the method `m` of `a.b[c]`.
+fn prefixexp<'src, Input>(input: &mut Input) -> StdParseResult<PrefixExp<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let field = field();
-    sep_by1(field, fieldsep())
-        .skip(optional(fieldsep()))
-        .map(|fields| FieldList(fields))
+    let (prefix, commit) = primaryexp(input)?;
+    Ok((suffixes(input, prefix)?, commit))
 }
 
-fn field<Input>() -> impl Parser<Input, Output = Field>
+/// Applies the suffixes that follow `prefix` to it.
+fn suffixes<'src, Input>(
+    input: &mut Input,
+    mut prefix: PrefixExp<'src>,
+) -> Result<PrefixExp<'src>, Failure<Input>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let index = token(TokenType::BracketL.into())
-        .with(expr_binop_bottom())
-        .skip(token(TokenType::BracketR.into()));
-    let assign = || token(TokenType::Assign.into());
-    let name = token(TokenType::Name.into());
-    let field_assign = (index, assign(), expr_binop_bottom())
-        .map(|(index, _, expr)| Field::AssignIdx(Box::new(index), Box::new(expr)));
-    let field_name = (name, assign(), expr_binop_bottom())
-        .map(|(name, _, expr)| Field::AssignName(name, Box::new(expr)));
-    let field_expr = expr_binop_bottom().map(|expr| Field::UniExp(Box::new(expr)));
-    field_assign.or(field_name).or(field_expr)
+    while let Some(suffix) = suffix(input)? {
+        prefix = suffix.apply(prefix);
+    }
+    Ok(prefix)
 }
 
-fn fieldsep<Input>() -> impl Parser<Input, Output = Fieldsep>
+/// A name or a parenthesized expression, as `primaryexp` in `lparser.c`.
+fn primaryexp<'src, Input>(input: &mut Input) -> StdParseResult<PrefixExp<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Comma.into())
-        .or(token(TokenType::SemiColon.into()))
-        .map(|_| Fieldsep)
+    match next_token_type(input) {
+        Some(TokenType::Name) => {
+            let (name, commit) = expect(input, TokenType::Name)?;
+            Ok((PrefixExp::PrefixVar(Box::new(Var::VarName(name))), commit))
+        }
+        Some(TokenType::ParenL) => paren(input),
+        _ => Err(unexpected(input, &[TokenType::Name, TokenType::ParenL])),
+    }
 }
 
-// TODO: this is the "root" expr, rename it.
-fn expr_binop_bottom<Input>() -> impl Parser<Input, Output = Expr>
+/// A parenthesized expression.
+fn paren<'src, Input>(input: &mut Input) -> StdParseResult<PrefixExp<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    expr_binop_or()
+    expect(input, TokenType::ParenL)?;
+    let expr = committed(expr(input))?;
+    committed(expect(input, TokenType::ParenR))?;
+    Ok((PrefixExp::PrefixParen(Box::new(expr)), Commit::Commit(())))
+}
+
+/// What may follow a prefix expression: a field, an index, or the
+/// arguments of a call, with the method name of a `:` call.
+enum Suffix<'src> {
+    Member(Token<'src>),
+    Index(Expr<'src>),
+    Call(Option<Token<'src>>, Args<'src>),
+}
+
+impl<'src> Suffix<'src> {
+    fn apply(self, prefix: PrefixExp<'src>) -> PrefixExp<'src> {
+        match self {
+            Suffix::Member(name) => PrefixExp::PrefixVar(Box::new(Var::VarMember(prefix, name))),
+            Suffix::Index(key) => {
+                PrefixExp::PrefixVar(Box::new(Var::VarIdx(prefix, Box::new(key))))
+            }
+            Suffix::Call(method, args) => {
+                PrefixExp::PrefixCall(FunctionCall(Box::new(prefix), method, args))
+            }
+        }
+    }
 }
 
-fn expr_binop_hat<Input>() -> impl Parser<Input, Output = Expr>
+/// Parses a suffix if one is next. It always follows a prefix expression,
+/// so failing to parse one is committed.
+fn suffix<'src, Input>(input: &mut Input) -> Result<Option<Suffix<'src>>, Failure<Input>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop =
-        attempt(binop_hat().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr(), binop)
+    let suffix = match next_token_type(input) {
+        Some(TokenType::Period) => {
+            committed(expect(input, TokenType::Period))?;
+            Suffix::Member(committed(expect(input, TokenType::Name))?)
+        }
+        Some(TokenType::BracketL) => {
+            committed(expect(input, TokenType::BracketL))?;
+            let key = committed(expr(input))?;
+            committed(expect(input, TokenType::BracketR))?;
+            Suffix::Index(key)
+        }
+        Some(TokenType::Colon) => {
+            committed(expect(input, TokenType::Colon))?;
+            let name = committed(expect(input, TokenType::Name))?;
+            Suffix::Call(Some(name), committed(args(input))?)
+        }
+        Some(TokenType::ParenL | TokenType::BraceL | TokenType::StringLit) => {
+            Suffix::Call(None, committed(args(input))?)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(suffix))
 }
 
-fn binop_hat<Input>() -> impl Parser<Input, Output = Binop>
+fn args<'src, Input>(input: &mut Input) -> StdParseResult<Args<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Hat.into()).map(|op| Binop(op))
+    match next_token_type(input) {
+        Some(TokenType::ParenL) => {
+            expect(input, TokenType::ParenL)?;
+            let expr_list = committed(exprlist(input))?;
+            committed(expect(input, TokenType::ParenR))?;
+            Ok((Args::ArgsList(expr_list), Commit::Commit(())))
+        }
+        Some(TokenType::BraceL) => {
+            let (table, commit) = tableconstructor(input)?;
+            Ok((Args::ArgsTable(table), commit))
+        }
+        Some(TokenType::StringLit) => {
+            let (s, commit) = literal(TokenType::StringLit, |s| Vec::try_from(s).ok())
+                .parse_stream(input)
+                .into_result()
+                .map_err(|_| unexpected(input, &[]))?;
+            Ok((Args::ArgsString(s), commit))
+        }
+        _ => {
+            let position = input.position();
+            Err(error_at::<Input>(position, "function arguments expected"))
+        }
+    }
 }
 
-// FIXME: insert true precedence of unop...
-fn expr_binop_muldiv<Input>() -> impl Parser<Input, Output = Expr>
+fn funcbody<'src, Input>(input: &mut Input) -> StdParseResult<FuncBody<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop = attempt(binop_muldiv().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr_binop_hat(), binop)
+    expect(input, TokenType::ParenL)?;
+    let param_list = committed(paramlist().parse_stream(input).into_result())?;
+    committed(expect(input, TokenType::ParenR))?;
+    let block = committed(block(input))?;
+    committed(expect(input, TokenType::End))?;
+    Ok((FuncBody(param_list, Rc::new(block)), Commit::Commit(())))
 }
 
-fn binop_muldiv<Input>() -> impl Parser<Input, Output = Binop>
+fn paramlist<'src, Input>() -> impl Parser<Input, Output = ParamList<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Aster.into())
-        .or(token(TokenType::Slash.into()))
-        .or(token(TokenType::Perc.into()))
-        .map(|op| Binop(op))
+    let name = || token(TokenType::Name);
+    let comma = || token(TokenType::Comma);
+    let dots = || token(TokenType::Dots);
+    // `a, b`, `a, b, ...` or just `...`
+    let names = (
+        name(),
+        many(attempt(comma().with(name()))),
+        optional(comma().with(dots())),
+    )
+        .map(|(first, rest, dots): (Token, Vec<Token>, Option<Token>)| {
+            let mut names = vec![first];
+            names.extend(rest);
+            ParamList(NameList(names), dots.is_some())
+        });
+    let only_dots = dots().map(|_| ParamList(NameList(Vec::new()), true));
+    optional(names.or(only_dots))
+        .map(|params| params.unwrap_or_else(|| ParamList(NameList(Vec::new()), false)))
 }
 
-fn expr_binop_addsub<Input>() -> impl Parser<Input, Output = Expr>
+/// A table constructor, whose fields may be separated by `,` or `;` and
+/// followed by one more separator.
+fn tableconstructor<'src, Input>(input: &mut Input) -> StdParseResult<TableConstructor<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop =
-        attempt(binop_addsub().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr_binop_muldiv(), binop)
+    expect(input, TokenType::BraceL)?;
+    let mut fields = Vec::new();
+    while next_token_type(input) != Some(TokenType::BraceR) {
+        fields.push(committed(field(input))?);
+        if fieldsep().parse_stream(input).into_result().is_err() {
+            break;
+        }
+    }
+    committed(expect(input, TokenType::BraceR))?;
+    Ok((TableConstructor(FieldList(fields)), Commit::Commit(())))
 }
 
-fn binop_addsub<Input>() -> impl Parser<Input, Output = Binop>
+fn field<'src, Input>(input: &mut Input) -> StdParseResult<Field<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Plus.into())
-        .or(token(TokenType::Minus.into()))
-        .map(|op| Binop(op))
+    if next_token_type(input) == Some(TokenType::BracketL) {
+        field_index(input)
+    } else if name_assign_next(input) {
+        field_name(input)
+    } else {
+        expr(input).map(|(expr, commit)| (Field::UniExp(Box::new(expr)), commit))
+    }
 }
 
-fn expr_binop_concat<Input>() -> impl Parser<Input, Output = Expr>
+/// A field with a key in brackets, `[key] = value`.
+fn field_index<'src, Input>(input: &mut Input) -> StdParseResult<Field<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop =
-        attempt(binop_concat().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr_binop_addsub(), binop)
+    expect(input, TokenType::BracketL)?;
+    let index = committed(expr(input))?;
+    committed(expect(input, TokenType::BracketR))?;
+    committed(expect(input, TokenType::Assign))?;
+    let expr = committed(expr(input))?;
+    let field = Field::AssignIdx(Box::new(index), Box::new(expr));
+    Ok((field, Commit::Commit(())))
 }
 
-fn binop_concat<Input>() -> impl Parser<Input, Output = Binop>
+/// A field with a name for its key, `name = value`.
+fn field_name<'src, Input>(input: &mut Input) -> StdParseResult<Field<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Concat.into()).map(|op| Binop(op))
+    let (name, _) = expect(input, TokenType::Name)?;
+    committed(expect(input, TokenType::Assign))?;
+    let expr = committed(expr(input))?;
+    Ok((Field::AssignName(name, Box::new(expr)), Commit::Commit(())))
 }
 
-fn expr_binop_compare<Input>() -> impl Parser<Input, Output = Expr>
+fn fieldsep<'src, Input>() -> impl Parser<Input, Output = Fieldsep> + use<'src, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop =
-        attempt(binop_compare().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr_binop_concat(), binop)
+    token(TokenType::Comma)
+        .or(token(TokenType::SemiColon))
+        .map(|_| Fieldsep)
+}
+
+/// The priority of unary operators: higher than that of every binary
+/// operator but `^`, so `-x^2` is `-(x^2)` and `not a == b` is `(not a) == b`.
+const UNARY_PRIORITY: u8 = 12;
+
+/// The left and right priorities of a binary operator, as in Lua's
+/// `lparser.c`. An operator takes its left operand when its left priority
+/// is above the current limit, and parses its right operand with its right
+/// priority as the limit; right associative `..` and `^` have the lower
+/// right priority.
+fn binary_priority(token_type: TokenType) -> Option<(u8, u8)> {
+    use TokenType::*;
+    Some(match token_type {
+        Or => (1, 1),
+        And => (2, 2),
+        Less | Greater | Le | Ge | Ne | Eql => (3, 3),
+        Bar => (4, 4),
+        Tilda => (5, 5),
+        Amp => (6, 6),
+        ShL | ShR => (7, 7),
+        Concat => (9, 8),
+        Plus | Minus => (10, 10),
+        Aster | Slash | IDiv | Perc => (11, 11),
+        Hat => (14, 13),
+        _ => return None,
+    })
 }
 
-fn binop_compare<Input>() -> impl Parser<Input, Output = Binop>
+fn is_unop(token_type: TokenType) -> bool {
+    use TokenType::*;
+    matches!(token_type, Not | Minus | Opus | Tilda)
+}
+
+fn expr<'src, Input>(input: &mut Input) -> StdParseResult<Expr<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Eql.into())
-        .or(token(TokenType::Ne.into()))
-        .or(token(TokenType::Less.into()))
-        .or(token(TokenType::Le.into()))
-        .or(token(TokenType::Greater.into()))
-        .or(token(TokenType::Ge.into()))
-        .map(|op| Binop(op))
+    subexpr(input, 0)
 }
 
-fn expr_binop_and<Input>() -> impl Parser<Input, Output = Expr>
+/// Parses an expression whose binary operators all have a left priority
+/// above `limit`, climbing the precedence levels in a loop rather than
+/// through a parser per level. The operands of unary and right associative
+/// operators nest, so each call is a level of nesting.
+fn subexpr<'src, Input>(input: &mut Input, limit: u8) -> StdParseResult<Expr<'src>, Input>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop =
-        attempt(binop_and().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr_binop_compare(), binop)
+    nested(input, |input| {
+        let (mut lhs, mut commit) = match unop(input) {
+            Some(op) => (unary(input, op)?, Commit::Commit(())),
+            None => simpleexp(input)?,
+        };
+        while let Some((op, right)) = binop(input, limit) {
+            lhs = binary(input, lhs, op, right)?;
+            commit = Commit::Commit(());
+        }
+        Ok((lhs, commit))
+    })
 }
 
-fn binop_and<Input>() -> impl Parser<Input, Output = Binop>
+/// Parses the operand of the unary operator `op`.
+fn unary<'src, Input>(input: &mut Input, op: Token<'src>) -> Result<Expr<'src>, Failure<Input>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::And.into()).map(|op| Binop(op))
+    let operand = committed(subexpr(input, UNARY_PRIORITY))?;
+    Ok(Expr::Unop(Unop(op), Box::new(operand)))
 }
 
-fn expr_binop_or<Input>() -> impl Parser<Input, Output = Expr>
+/// Parses the right operand of the binary operator `op`, whose right
+/// priority is `right`.
+fn binary<'src, Input>(
+    input: &mut Input,
+    lhs: Expr<'src>,
+    op: Token<'src>,
+    right: u8,
+) -> Result<Expr<'src>, Failure<Input>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>> + Nesting,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let binop =
-        attempt(binop_or().map(|binop| |l, r| Expr::ExprBinop(Box::new(l), binop, Box::new(r))));
-    chainl1(expr_binop_and(), binop)
+    let rhs = committed(subexpr(input, right))?;
+    Ok(Expr::ExprBinop(Box::new(lhs), Binop(op), Box::new(rhs)))
 }
 
-fn binop_or<Input>() -> impl Parser<Input, Output = Binop>
+/// Parses a unary operator if one is next.
+fn unop<'src, Input>(input: &mut Input) -> Option<Token<'src>>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    token(TokenType::Or.into()).map(|op| Binop(op))
+    let op = satisfy(|t: Token| is_unop(t.token_type))
+        .parse_stream(input)
+        .into_result();
+    op.ok().map(|(op, _)| op)
 }
 
-fn unop<Input>() -> impl Parser<Input, Output = Unop>
+/// Parses a binary operator if one with a left priority above `limit` is
+/// next, returning it with its right priority.
+fn binop<'src, Input>(input: &mut Input, limit: u8) -> Option<(Token<'src>, u8)>
 where
-    Input: Stream<Token = Token>,
+    Input: Stream<Token = Token<'src>>,
     <Input as StreamOnce>::Error: ParseError<
         <Input as StreamOnce>::Token,
         <Input as StreamOnce>::Range,
         <Input as StreamOnce>::Position,
     >,
 {
-    let op = token(TokenType::Minus.into())
-        .or(token(TokenType::Not.into()))
-        .or(token(TokenType::Opus.into()));
-
-    op.map(|op| Unop(op))
+    let op = satisfy_map(|t: Token| {
+        let (left, right) = binary_priority(t.token_type)?;
+        (left > limit).then_some((t, right))
+    })
+    .parse_stream(input)
+    .into_result();
+    op.ok().map(|(op, _)| op)
 }
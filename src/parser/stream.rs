@@ -1,153 +1,252 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::token_type::TokenType;
 use crate::Token;
 
-use combine::{error::StreamError, stream::ResetStream, ParseError, Positioned, StreamOnce};
+use combine::error::{ErrorInfo, Info, StreamError, Tracked};
+use combine::{stream::ResetStream, ParseError, Positioned, StreamOnce};
 
-macro_rules! debug_error_msg {
-    ($($msg:tt)*) => {
-        if std::env::var("DEBUG").is_ok() {
-            eprintln!($($msg)*);
-        }
-    };
-}
+const END_OF_INPUT: &str = "end of input";
 
 #[derive(Debug, Clone)]
-pub struct TokenStream {
-    pub input: Vec<Token>,
+pub struct TokenStream<'src> {
+    pub input: Vec<Token<'src>>,
     pub position: usize,
+    level: usize,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// A stream that counts how deeply the parsers running on it are nested,
+/// as `nCcalls` in Lua does, so that deeply nested source is a syntax
+/// error rather than a stack overflow.
+pub trait Nesting {
+    /// The number of nested statements and expressions being parsed.
+    fn level(&mut self) -> &mut usize;
+}
+
+impl Nesting for TokenStream<'_> {
+    fn level(&mut self) -> &mut usize {
+        &mut self.level
+    }
+}
+
+impl<T: Nesting + ?Sized> Nesting for &mut T {
+    fn level(&mut self) -> &mut usize {
+        (**self).level()
+    }
+}
+
+/// The error the parsers build while parsing a `TokenStream`. It only
+/// records where parsing failed and what was expected there; `parse` turns
+/// it into an `errors::ParseError` describing the offending token.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct TokenStreamError {
+    /// The index of the offending token in the stream.
     pub position: usize,
+    /// The types of the tokens that would have been accepted there.
+    pub expected: Vec<TokenType>,
+    /// A message from a combinator, e.g. `end of input`.
+    pub message: Option<Box<str>>,
 }
 
-#[allow(unused_variables)]
-impl StreamError<Token, Vec<Token>> for TokenStreamError {
-    fn unexpected_token(token: Token) -> Self {
-        debug_error_msg!("unexpected token: {:?}", token);
+impl TokenStreamError {
+    pub fn empty(position: usize) -> Self {
+        TokenStreamError {
+            position,
+            ..Self::default()
+        }
+    }
+
+    fn with_message(msg: impl fmt::Display) -> Self {
         TokenStreamError {
-            position: token.line,
+            message: Some(msg.to_string().into()),
+            ..Self::default()
+        }
+    }
+
+    fn expect(&mut self, token_type: TokenType) {
+        if !self.expected.contains(&token_type) {
+            self.expected.push(token_type);
         }
     }
+}
+
+/// Lets a parser name the token it expects with `.expected(token_type)`.
+impl<'src, R> ErrorInfo<'_, Token<'src>, R> for TokenType {
+    type Format = &'static str;
+
+    fn into_info(&self) -> Info<Token<'src>, R, Self::Format> {
+        Info::Token(Token::from(*self))
+    }
+}
+
+impl<'src> StreamError<Token<'src>, Vec<Token<'src>>> for TokenStreamError {
+    // The offending token is the one at the error's position, so the
+    // unexpected tokens themselves need not be kept.
+    fn unexpected_token(_token: Token<'src>) -> Self {
+        Self::default()
+    }
 
-    fn unexpected_range(token: Vec<Token>) -> Self {
-        todo!()
+    fn unexpected_range(_token: Vec<Token<'src>>) -> Self {
+        Self::default()
     }
 
     fn unexpected_format<T>(msg: T) -> Self
     where
-        T: std::fmt::Display,
+        T: fmt::Display,
     {
-        todo!()
+        Self::with_message(msg)
     }
 
-    fn expected_token(token: Token) -> Self {
-        debug_error_msg!("expected token: {:?}", token);
+    fn expected_token(token: Token<'src>) -> Self {
         TokenStreamError {
-            position: token.line,
+            expected: vec![token.token_type],
+            ..Self::default()
         }
     }
 
-    fn expected_range(token: Vec<Token>) -> Self {
-        todo!()
+    fn expected_range(token: Vec<Token<'src>>) -> Self {
+        let mut err = Self::default();
+        token.iter().for_each(|t| err.expect(t.token_type));
+        err
     }
 
     fn expected_format<T>(msg: T) -> Self
     where
-        T: std::fmt::Display,
+        T: fmt::Display,
     {
-        todo!()
+        Self::with_message(format_args!("{} expected", msg))
     }
 
-    fn message_token(token: Token) -> Self {
-        todo!()
+    fn message_token(token: Token<'src>) -> Self {
+        Self::with_message(token.lexeme)
     }
 
-    fn message_range(token: Vec<Token>) -> Self {
-        todo!()
+    fn message_range(token: Vec<Token<'src>>) -> Self {
+        let lexemes: Vec<&str> = token.iter().map(|t| t.lexeme).collect();
+        Self::with_message(lexemes.join(" "))
     }
 
     fn message_format<T>(msg: T) -> Self
     where
-        T: std::fmt::Display,
+        T: fmt::Display,
     {
-        todo!()
+        Self::with_message(msg)
+    }
+
+    fn end_of_input() -> Self {
+        Self::with_message(END_OF_INPUT)
     }
 
     fn is_unexpected_end_of_input(&self) -> bool {
-        todo!()
+        self.message.as_deref() == Some(END_OF_INPUT)
     }
 
     fn into_other<T>(self) -> T
     where
-        T: StreamError<Token, Vec<Token>>,
+        T: StreamError<Token<'src>, Vec<Token<'src>>>,
     {
-        todo!()
+        match (self.message, self.expected.first()) {
+            (Some(message), _) => T::message_format(message),
+            (None, Some(&token_type)) => T::expected_token(Token::from(token_type)),
+            (None, None) => T::unexpected_static_message("token"),
+        }
     }
 }
 
-#[allow(unused_variables)]
-impl ParseError<Token, Vec<Token>, usize> for TokenStreamError {
+impl<'src> ParseError<Token<'src>, Vec<Token<'src>>, usize> for TokenStreamError {
     type StreamError = Self;
-    fn from_error(position: usize, _message: TokenStreamError) -> Self {
-        TokenStreamError { position }
-    }
 
     fn empty(position: usize) -> Self {
-        debug_error_msg!("reached empty");
-        TokenStreamError { position }
+        TokenStreamError::empty(position)
+    }
+
+    fn from_error(position: usize, message: TokenStreamError) -> Self {
+        TokenStreamError {
+            position,
+            ..message
+        }
+    }
+
+    fn position(&self) -> usize {
+        self.position
     }
 
     fn set_position(&mut self, position: usize) {
         self.position = position;
     }
 
+    /// Keeps the error furthest into the stream, or both at the same token.
+    fn merge(self, other: Self) -> Self {
+        match self.position.cmp(&other.position) {
+            Ordering::Less => other,
+            Ordering::Greater => self,
+            Ordering::Equal => {
+                let mut merged = self;
+                merged.add(other);
+                merged
+            }
+        }
+    }
+
     fn add(&mut self, err: Self::StreamError) {
-        debug_error_msg!("added error: {:?}", err);
+        err.expected.into_iter().for_each(|t| self.expect(t));
+        if self.message.is_none() {
+            self.message = err.message;
+        }
     }
 
-    fn set_expected<F>(self_: &mut combine::error::Tracked<Self>, info: Self::StreamError, f: F)
+    fn set_expected<F>(self_: &mut Tracked<Self>, info: Self::StreamError, f: F)
     where
-        F: FnOnce(&mut combine::error::Tracked<Self>),
+        F: FnOnce(&mut Tracked<Self>),
     {
-        todo!()
+        // Replace what `f` expects with `info`, keeping earlier errors.
+        let start = self_.error.expected.len();
+        f(self_);
+        self_.error.expected.truncate(start);
+        self_.error.add(info);
+    }
+
+    fn clear_expected(&mut self) {
+        self.expected.clear();
     }
 
     fn is_unexpected_end_of_input(&self) -> bool {
-        todo!()
+        StreamError::is_unexpected_end_of_input(self)
     }
 
     fn into_other<T>(self) -> T
     where
-        T: ParseError<Token, Vec<Token>, usize>,
+        T: ParseError<Token<'src>, Vec<Token<'src>>, usize>,
     {
-        todo!()
-    }
-}
-
-impl TokenStreamError {
-    pub fn empty(position: usize) -> Self {
-        TokenStreamError { position }
+        let mut err = T::empty(self.position);
+        if let Some(message) = self.message {
+            err.add(T::StreamError::message_format(message));
+        }
+        for token_type in self.expected {
+            err.add(T::StreamError::expected_token(Token::from(token_type)));
+        }
+        err
     }
 }
 
-impl Extend<Token> for TokenStream {
-    fn extend<T: IntoIterator<Item = Token>>(&mut self, iter: T) {
+impl<'src> Extend<Token<'src>> for TokenStream<'src> {
+    fn extend<T: IntoIterator<Item = Token<'src>>>(&mut self, iter: T) {
         for token in iter {
             self.input.push(token);
         }
     }
 }
 
-impl StreamOnce for TokenStream {
-    type Token = Token;
+impl<'src> StreamOnce for TokenStream<'src> {
+    type Token = Token<'src>;
     type Position = usize;
-    type Range = Vec<Token>;
+    type Range = Vec<Token<'src>>;
     type Error = TokenStreamError;
 
     fn uncons(&mut self) -> Result<Self::Token, TokenStreamError> {
         if self.position >= self.input.len() {
-            Err(TokenStreamError::empty(self.position))
+            Err(TokenStreamError::end_of_input())
         } else {
             let token = self.input[self.position].clone();
             self.position += 1;
@@ -160,7 +259,7 @@ impl StreamOnce for TokenStream {
     }
 }
 
-impl ResetStream for TokenStream {
+impl ResetStream for TokenStream<'_> {
     type Checkpoint = usize;
 
     fn reset(&mut self, checkpoint: Self::Checkpoint) -> Result<(), TokenStreamError> {
@@ -173,14 +272,18 @@ impl ResetStream for TokenStream {
     }
 }
 
-impl Positioned for TokenStream {
+impl Positioned for TokenStream<'_> {
     fn position(&self) -> Self::Position {
         self.position
     }
 }
 
-impl TokenStream {
-    pub const fn new(input: Vec<Token>) -> Self {
-        TokenStream { input, position: 0 }
+impl<'src> TokenStream<'src> {
+    pub const fn new(input: Vec<Token<'src>>) -> Self {
+        TokenStream {
+            input,
+            position: 0,
+            level: 0,
+        }
     }
 }
@@ -1,76 +1,245 @@
-use crate::errors::ScanError;
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::errors::{ScanError, ScanErrorKind};
+use crate::value::{parse_numeral, Value};
 pub use crate::token_type::TokenType;
 
+/// A token borrowing its text from the source, so that neither scanning
+/// nor cloning a token while parsing allocates.
 #[derive(Debug, Clone)]
-pub struct Token {
+pub struct Token<'src> {
     pub token_type: TokenType,
-    pub lexeme: String,
-    pub line: usize,
+    pub lexeme: &'src str,
+    /// The line the token ends on, which is what Lua reports in messages.
+    /// A `u32` like the `Span` fields, keeping tokens at 64 bytes now that
+    /// they carry trivia.
+    pub line: u32,
+    pub span: Span,
+    /// The interned name of a `Name` token; see `Scanner::names`.
+    pub symbol: Option<Symbol>,
+    /// The surrounding whitespace and comments, kept only by a
+    /// `Scanner::lossless` scanner.
+    pub trivia: Option<Rc<TokenTrivia<'src>>>,
+}
+
+/// The trivia around a token. Trailing trivia runs up to and including the
+/// end of the token's line; everything else before a token is its leading
+/// trivia. Concatenating `Token::full_text` of all tokens, `Eof` included,
+/// reproduces the source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenTrivia<'src> {
+    pub leading: Vec<Trivia<'src>>,
+    pub trailing: Vec<Trivia<'src>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// A run of spaces, tabs and carriage returns.
+    Whitespace,
+    Newline,
+    Comment,
+    /// A UTF-8 byte order mark at the start of the source.
+    Bom,
+    /// A first line starting with `#`, such as `#!/usr/bin/env purua`.
+    Shebang,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trivia<'src> {
+    pub kind: TriviaKind,
+    pub text: &'src str,
+    pub span: Span,
+}
+
+/// The range of source a token was scanned from. `start` and `end` are
+/// byte offsets, `end` exclusive; lines and columns count from 1, columns
+/// in chars, and `end_column` is the column just past the last char. The
+/// fields are `u32` to keep tokens small, limiting sources to 4GiB.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: u32,
+    pub end: u32,
+    pub line: u32,
+    pub column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
+}
+
+impl Span {
+    /// The byte range to slice the source with.
+    pub fn range(&self) -> Range<usize> {
+        self.start as usize..self.end as usize
+    }
+}
+
+/// An identifier interned by an `Interner`. Two `Name` tokens from the same
+/// scanner have the same symbol exactly when they spell the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(NonZeroU32);
+
+/// Maps each distinct identifier of a source to a `Symbol`.
+#[derive(Debug, Default)]
+pub struct Interner<'src> {
+    symbols: HashMap<&'src str, Symbol>,
+    names: Vec<&'src str>,
 }
 
-impl PartialEq for Token {
+impl<'src> Interner<'src> {
+    pub fn intern(&mut self, name: &'src str) -> Symbol {
+        if let Some(&symbol) = self.symbols.get(name) {
+            return symbol;
+        }
+        let id = NonZeroU32::new(self.names.len() as u32 + 1).expect("too many names");
+        let symbol = Symbol(id);
+        self.names.push(name);
+        self.symbols.insert(name, symbol);
+        symbol
+    }
+
+    /// The symbol of a `Name` token. Tokens that were not scanned, such as
+    /// those of a syntax tree built by hand, have their lexeme interned.
+    pub fn symbol(&mut self, name: &Token<'src>) -> Symbol {
+        match name.symbol {
+            Some(symbol) => symbol,
+            None => self.intern(name.lexeme),
+        }
+    }
+
+    /// The symbol of `name`, if it has been interned.
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.symbols.get(name).copied()
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &'src str {
+        self.names[symbol.0.get() as usize - 1]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+impl PartialEq for Token<'_> {
     fn eq(&self, other: &Self) -> bool {
         self.token_type == other.token_type
     }
 }
 
-impl From<TokenType> for Token {
+impl From<TokenType> for Token<'_> {
     fn from(token_type: TokenType) -> Self {
         Self {
             token_type,
-            lexeme: String::new(),
+            lexeme: "",
             line: 0,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         }
     }
 }
 
-impl Token {
-    pub fn new(token_type: TokenType, lexeme: impl Into<String>, line: usize) -> Self {
+impl<'src> Token<'src> {
+    pub fn new(token_type: TokenType, lexeme: &'src str, span: Span) -> Self {
         Self {
             token_type,
-            lexeme: lexeme.into(),
-            line,
+            lexeme,
+            line: span.end_line,
+            span,
+            symbol: None,
+            trivia: None,
         }
     }
+
+    /// The token's text with its trivia, if it has any.
+    pub fn full_text(&self) -> String {
+        let mut text = String::new();
+        let texts = |trivia: &[Trivia], text: &mut String| {
+            trivia.iter().for_each(|t| text.push_str(t.text));
+        };
+        if let Some(trivia) = &self.trivia {
+            texts(&trivia.leading, &mut text);
+        }
+        text.push_str(self.lexeme);
+        if let Some(trivia) = &self.trivia {
+            texts(&trivia.trailing, &mut text);
+        }
+        text
+    }
+
+    fn error(&self, kind: ScanErrorKind) -> ScanError {
+        ScanError::new(kind, self.span, self.lexeme)
+    }
 }
 
-impl TryFrom<Token> for String {
+/// Decodes a string literal into the bytes of the string. Lua strings
+/// need not be valid UTF-8: `"\xff"` is a one-byte string.
+impl TryFrom<Token<'_>> for Vec<u8> {
     type Error = ScanError;
 
     fn try_from(value: Token) -> Result<Self, Self::Error> {
+        let invalid = || value.error(ScanErrorKind::InvalidConversion);
         match value.token_type {
             TokenType::StringLit => {
                 let raw_str = value.lexeme;
+                if raw_str.starts_with('[') {
+                    return long_string_content(raw_str)
+                        .map(String::into_bytes)
+                        .ok_or_else(invalid);
+                }
                 let content = if raw_str.starts_with('"') {
                     raw_str
                         .strip_prefix('"')
                         .and_then(|s| s.strip_suffix('"'))
-                        .ok_or_else(|| ScanError::raise())?
+                        .ok_or_else(invalid)?
                 } else {
                     raw_str
                         .strip_prefix('\'')
                         .and_then(|s| s.strip_suffix('\''))
-                        .ok_or_else(|| ScanError::raise())?
+                        .ok_or_else(invalid)?
                 };
-                let parsed = content.replace("\\n", "\n")
-                                    .replace("\\t", "\t")
-                                    .replace("\\r", "\r")
-                                    .replace("\\\\", "\\");
-                Ok(parsed.to_string())
-            },
-            _ => Err(ScanError::raise()),
+                unescape(content).map_err(|e| value.error(ScanErrorKind::InvalidEscape(e.reason)))
+            }
+            _ => Err(invalid()),
         }
     }
 }
 
-impl TryFrom<Token> for f64 {
+impl TryFrom<Token<'_>> for String {
     type Error = ScanError;
 
     fn try_from(value: Token) -> Result<Self, Self::Error> {
-        match value.token_type {
-            TokenType::Int => value.lexeme.parse::<f64>().map_err(|_| ScanError::raise()),
-            TokenType::Float => value.lexeme.parse::<f64>().map_err(|_| ScanError::raise()),
-            _ => Err(ScanError::raise()),
+        let error = value.error(ScanErrorKind::InvalidUtf8);
+        let bytes = Vec::<u8>::try_from(value)?;
+        String::from_utf8(bytes).map_err(|_| error)
+    }
+}
+
+impl TryFrom<Token<'_>> for i64 {
+    type Error = ScanError;
+
+    fn try_from(value: Token) -> Result<Self, Self::Error> {
+        match (value.token_type, parse_numeral(value.lexeme)) {
+            (TokenType::Int, Some(Value::Integer(i))) => Ok(i),
+            _ => Err(value.error(ScanErrorKind::InvalidConversion)),
+        }
+    }
+}
+
+impl TryFrom<Token<'_>> for f64 {
+    type Error = ScanError;
+
+    fn try_from(value: Token) -> Result<Self, Self::Error> {
+        match (value.token_type, parse_numeral(value.lexeme)) {
+            (TokenType::Int | TokenType::Float, Some(Value::Integer(i))) => Ok(i as f64),
+            (TokenType::Int | TokenType::Float, Some(Value::Float(f))) => Ok(f),
+            _ => Err(value.error(ScanErrorKind::InvalidConversion)),
         }
     }
 }
@@ -78,13 +247,25 @@ impl TryFrom<Token> for f64 {
 #[derive(Debug)]
 pub struct Scanner<'source> {
     pub source: &'source str,
-    pub tokens: Vec<Token>,
-    pub comments: Vec<Token>,
+    pub tokens: Vec<Token<'source>>,
+    pub comments: Vec<Token<'source>>,
+    /// The identifiers of the `Name` tokens.
+    pub names: Interner<'source>,
+    /// The errors found by `scan_recovering`.
+    pub errors: Vec<ScanError>,
+    lossless: bool,
+    // Trivia seen since the last token ended its line, to lead the next one.
+    pending_trivia: Vec<Trivia<'source>>,
+    // Whether trivia still belongs to the last token, on the same line.
+    trailing: bool,
+    // Byte offsets into `source`, always on a char boundary.
     start: usize,
     current: usize,
     line: usize,
-    // current_index: usize,
-    // needs context?
+    column: usize,
+    // The line and column of `start`.
+    start_line: usize,
+    start_column: usize,
 }
 
 impl<'source> Scanner<'source> {
@@ -95,20 +276,87 @@ impl<'source> Scanner<'source> {
             source,
             tokens,
             comments,
+            errors: Vec::new(),
+            names: Interner::default(),
+            lossless: false,
+            pending_trivia: Vec::new(),
+            trailing: false,
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+        }
+    }
+
+    /// A scanner that attaches whitespace and comments to the tokens as
+    /// trivia, so that the tokens reproduce the source exactly.
+    pub fn lossless(source: &'source str) -> Self {
+        Self {
+            lossless: true,
+            ..Self::new(source)
         }
     }
 
     pub fn scan(&mut self) -> Result<usize, ScanError> {
+        self.skip_prelude();
         while !self.is_at_end() {
-            self.start = self.current;
+            self.begin_token();
             self.scan_token()?;
         }
+        Ok(self.finish())
+    }
+
+    /// Scans the whole source even if it is malformed, as an editor needs.
+    /// Each malformed token becomes an `Error` token spanning the text
+    /// skipped over, and its error is recorded in `errors`; scanning then
+    /// resumes right after it. Returns the number of tokens like `scan`.
+    pub fn scan_recovering(&mut self) -> usize {
+        self.skip_prelude();
+        while !self.is_at_end() {
+            self.begin_token();
+            if let Err(e) = self.scan_token() {
+                self.push_token(TokenType::Error);
+                self.errors.push(e);
+            }
+        }
+        self.finish()
+    }
+
+    /// Skips a leading byte order mark and then a first line starting with
+    /// `#`, as `luaL_loadfile` does, so that scripts can be executable.
+    /// The newline ending the `#` line is left to be scanned as usual.
+    fn skip_prelude(&mut self) {
+        if self.current != 0 {
+            return;
+        }
+        if self.source.starts_with('\u{feff}') {
+            // The mark takes up no column.
+            self.begin_token();
+            self.current += '\u{feff}'.len_utf8();
+            self.push_trivia(TriviaKind::Bom);
+        }
+        let rest = &self.source[self.current..];
+        if rest.starts_with('#') {
+            let len = rest.find('\n').unwrap_or(rest.len());
+            self.begin_token();
+            self.current += len;
+            self.column += rest[..len].chars().count();
+            self.push_trivia(TriviaKind::Shebang);
+        }
+    }
+
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+    }
 
-        self.tokens.push(Token::new(TokenType::Eof, "", self.line));
-        Ok(self.tokens.len())
+    fn finish(&mut self) -> usize {
+        self.begin_token();
+        self.push_token(TokenType::Eof);
+        self.tokens.len()
     }
 
     fn scan_token(&mut self) -> Result<(), ScanError> {
@@ -128,8 +376,12 @@ impl<'source> Scanner<'source> {
                 self.push_token(BraceR);
             }
             '[' => {
-                //TODO: multiline string literal....!!!1
-                self.push_token(BracketL);
+                if let Some(level) = self.long_bracket_level()? {
+                    self.long_bracket(level, ScanErrorKind::UnfinishedLongString)?;
+                    self.push_token(StringLit);
+                } else {
+                    self.push_token(BracketL);
+                }
             }
             ']' => {
                 self.push_token(BracketR);
@@ -140,19 +392,31 @@ impl<'source> Scanner<'source> {
             '.' => {
                 if self.test('.')? {
                     if self.test('.')? {
-                        self.push_token(Concat);
-                    } else {
                         self.push_token(Dots);
+                    } else {
+                        self.push_token(Concat);
                     }
+                } else if is_digit(self.peek()?) {
+                    self.number()?;
                 } else {
                     self.push_token(Period);
                 }
             }
             '-' => {
-                // comment: --
+                // comment: -- or a long comment: --[[ ]]
                 if self.test('-')? {
-                    while self.peek()? != '\n' && !self.is_at_end() {
-                        self.advance()?;
+                    let level = if self.test('[')? {
+                        self.long_bracket_level()?
+                    } else {
+                        None
+                    };
+                    match level {
+                        Some(level) => self.long_bracket(level, ScanErrorKind::UnfinishedLongComment)?,
+                        None => {
+                            while self.peek()? != '\n' && !self.is_at_end() {
+                                self.advance()?;
+                            }
+                        }
                     }
                     self.push_comment();
                 } else {
@@ -218,11 +482,12 @@ impl<'source> Scanner<'source> {
                 self.push_token(Opus);
             }
 
+            '\n' => self.push_trivia(TriviaKind::Newline),
             ' ' | '\r' | '\t' => {
-                // Ignore whitespace.
-            }
-            '\n' => {
-                self.line += 1;
+                while matches!(self.peek()?, ' ' | '\r' | '\t') {
+                    self.advance()?;
+                }
+                self.push_trivia(TriviaKind::Whitespace);
             }
             '\'' => {
                 self.string('\'')?;
@@ -237,8 +502,7 @@ impl<'source> Scanner<'source> {
                 } else if is_alpha(c) {
                     self.name()?;
                 } else {
-                    eprintln!("unexpected character: {}", c);
-                    return Err(ScanError::raise());
+                    return Err(self.error(ScanErrorKind::UnexpectedSymbol));
                 }
             }
         }
@@ -246,45 +510,112 @@ impl<'source> Scanner<'source> {
     }
 
     fn string(&mut self, quote: char) -> Result<(), ScanError> {
-        while self.peek()? != quote && !self.is_at_end() {
-            if self.peek()? == '\n' {
-                eprintln!("cannot contain linebreak");
-                return Err(ScanError::raise());
+        loop {
+            if self.is_at_end() || matches!(self.peek()?, '\n' | '\r') {
+                return Err(self.error(ScanErrorKind::UnfinishedString));
+            }
+            match self.advance()? {
+                c if c == quote => break,
+                // An escaped character never ends the string; escaped line
+                // breaks and the whitespace skipped by `\z` may span lines.
+                '\\' if !self.is_at_end() => match self.advance()? {
+                    'z' => {
+                        while !self.is_at_end() && self.peek()?.is_ascii_whitespace() {
+                            self.advance()?;
+                        }
+                    }
+                    // `\r\n` and `\n\r` are a single line break.
+                    c @ ('\n' | '\r') => {
+                        let pair = if c == '\n' { '\r' } else { '\n' };
+                        self.test(pair)?;
+                    }
+                    _ => {}
+                },
+                _ => {}
             }
-            self.advance()?;
         }
 
-        if self.is_at_end() {
-            eprintln!("Unterminated string");
-            return Err(ScanError::raise());
+        let content_start = self.start + 1;
+        if let Err(e) = unescape(&self.source[content_start..self.current - 1]) {
+            let range = content_start + e.range.start..content_start + e.range.end;
+            return Err(ScanError::new(
+                ScanErrorKind::InvalidEscape(e.reason),
+                self.span_of(range.clone()),
+                &self.source[range],
+            ));
         }
-
-        // The closing quote.
-        self.advance()?;
         self.push_token(TokenType::StringLit);
 
         Ok(())
     }
 
-    fn number(&mut self) -> Result<(), ScanError> {
-        let mut is_float = false;
-        while is_digit(self.peek()?) {
-            self.advance()?;
+    /// Having read a `[`, reads the rest of an opening long bracket `[[`,
+    /// `[=[`, `[==[`, ... and returns its level, the number of `=`. Returns
+    /// `None` and reads nothing if no long bracket follows.
+    fn long_bracket_level(&mut self) -> Result<Option<usize>, ScanError> {
+        let rest = &self.source.as_bytes()[self.current..];
+        let level = rest.iter().take_while(|&&b| b == b'=').count();
+        if rest.get(level) != Some(&b'[') {
+            return Ok(None);
         }
-        if self.peek()? == '.' && is_digit(self.peek_next()?) {
-            // Consume the "."
-            self.advance()?;
+        self.current += level + 1;
+        self.column += level + 1;
+        Ok(Some(level))
+    }
 
-            while is_digit(self.peek()?) {
-                self.advance()?;
+    /// Reads the contents of a long string or comment up to and including
+    /// the closing long bracket of the same level. `unfinished` is the
+    /// error if the source ends first.
+    fn long_bracket(&mut self, level: usize, unfinished: ScanErrorKind) -> Result<(), ScanError> {
+        loop {
+            if self.is_at_end() {
+                return Err(ScanError::new(unfinished, self.span(), "<eof>"));
+            }
+            if self.advance()? == ']' {
+                let mut n = 0;
+                while n < level && self.peek()? == '=' {
+                    self.advance()?;
+                    n += 1;
+                }
+                if n == level && self.test(']')? {
+                    return Ok(());
+                }
             }
-            is_float = true
         }
+    }
 
-        if is_float {
-            self.push_token(TokenType::Float);
+    /// Scans a numeral the way llex.c does: greedily take digits, dots and
+    /// signed exponents, then let `parse_numeral` reject malformed ones such
+    /// as `1..2` or `3e`. The first digit (or dot) is already consumed.
+    fn number(&mut self) -> Result<(), ScanError> {
+        let first = self.source[self.start..].chars().next();
+        let exponent = if first == Some('0') && (self.test('x')? || self.test('X')?) {
+            ['p', 'P']
         } else {
-            self.push_token(TokenType::Int);
+            ['e', 'E']
+        };
+        loop {
+            let c = self.peek()?;
+            if exponent.contains(&c) {
+                self.advance()?;
+                if !self.test('+')? {
+                    self.test('-')?;
+                }
+            } else if c.is_ascii_hexdigit() || c == '.' {
+                self.advance()?;
+            } else {
+                break;
+            }
+        }
+        // A numeral running straight into a name, as in `3x`, is malformed.
+        while is_alphanumeric(self.peek()?) {
+            self.advance()?;
+        }
+
+        match parse_numeral(&self.source[self.start..self.current]) {
+            Some(Value::Integer(_)) => self.push_token(TokenType::Int),
+            Some(_) => self.push_token(TokenType::Float),
+            None => return Err(self.error(ScanErrorKind::MalformedNumber)),
         }
         Ok(())
     }
@@ -327,12 +658,24 @@ impl<'source> Scanner<'source> {
         };
 
         self.push_token(tok);
+        if tok == Name {
+            let symbol = self.names.intern(text);
+            if let Some(token) = self.tokens.last_mut() {
+                token.symbol = Some(symbol);
+            }
+        }
         Ok(())
     }
 
     fn advance(&mut self) -> Result<char, ScanError> {
-        let c = self.getchar(self.current as usize)?;
-        self.current += 1;
+        let c = self.getchar(self.current)?;
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         Ok(c)
     }
 
@@ -345,15 +688,16 @@ impl<'source> Scanner<'source> {
             return Ok(false);
         }
 
-        self.current += 1;
+        self.advance()?;
         Ok(true)
     }
 
-    fn getchar(&mut self, nth: usize) -> Result<char, ScanError> {
+    /// Decodes the char starting at byte offset `at`.
+    fn getchar(&mut self, at: usize) -> Result<char, ScanError> {
         self.source
-            .chars()
-            .nth(nth)
-            .ok_or_else(|| ScanError::raise())
+            .get(at..)
+            .and_then(|rest| rest.chars().next())
+            .ok_or_else(|| ScanError::new(ScanErrorKind::UnexpectedSymbol, self.span(), "<eof>"))
     }
 
     fn peek(&mut self) -> Result<char, ScanError> {
@@ -364,36 +708,253 @@ impl<'source> Scanner<'source> {
         }
     }
 
-    fn peek_next(&mut self) -> Result<char, ScanError> {
-        if self.current + 1 >= self.source.len() {
-            Ok('\0')
-        } else {
-            self.getchar(self.current + 1)
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+
+    fn span(&self) -> Span {
+        Span {
+            start: self.start as u32,
+            end: self.current as u32,
+            line: self.start_line as u32,
+            column: self.start_column as u32,
+            end_line: self.line as u32,
+            end_column: self.column as u32,
         }
     }
 
-    fn is_at_end(&self) -> bool {
-        self.current >= self.source.len()
+    /// The span of `range`, a byte range within the token being scanned.
+    fn span_of(&self, range: Range<usize>) -> Span {
+        let (mut line, mut column) = (self.start_line, self.start_column);
+        let mut position = |from: usize, to: usize| {
+            for c in self.source[from..to].chars() {
+                if c == '\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+            }
+            (line as u32, column as u32)
+        };
+        let (start_line, start_column) = position(self.start, range.start);
+        let (end_line, end_column) = position(range.start, range.end);
+        Span {
+            start: range.start as u32,
+            end: range.end as u32,
+            line: start_line,
+            column: start_column,
+            end_line,
+            end_column,
+        }
+    }
+
+    fn error(&self, kind: ScanErrorKind) -> ScanError {
+        ScanError::new(kind, self.span(), &self.source[self.start..self.current])
     }
 
     fn push_token(&mut self, token_type: TokenType) {
         let lexeme = &self.source[self.start..self.current];
-        self.tokens.push(Token::new(token_type, lexeme, self.line));
+        let mut token = Token::new(token_type, lexeme, self.span());
+        if self.lossless {
+            token.trivia = Some(Rc::new(TokenTrivia {
+                leading: std::mem::take(&mut self.pending_trivia),
+                trailing: Vec::new(),
+            }));
+            self.trailing = true;
+        }
+        self.tokens.push(token);
     }
 
     fn push_comment(&mut self) {
         let lexeme = &self.source[self.start..self.current];
         self.comments
-            .push(Token::new(TokenType::Comment, lexeme, self.line));
+            .push(Token::new(TokenType::Comment, lexeme, self.span()));
+        self.push_trivia(TriviaKind::Comment);
+    }
+
+    fn push_trivia(&mut self, kind: TriviaKind) {
+        if !self.lossless {
+            return;
+        }
+        let trivia = Trivia {
+            kind,
+            text: &self.source[self.start..self.current],
+            span: self.span(),
+        };
+        // Only the scanner holds the trivia so far, so `get_mut` succeeds.
+        let last = self
+            .tokens
+            .last_mut()
+            .and_then(|t| t.trivia.as_mut())
+            .and_then(Rc::get_mut);
+        match last {
+            Some(last) if self.trailing => {
+                last.trailing.push(trivia);
+                self.trailing = kind != TriviaKind::Newline;
+            }
+            _ => self.pending_trivia.push(trivia),
+        }
+    }
+}
+
+/// Strips the long brackets from a long string literal, along with a
+/// newline right after the opening bracket.
+fn long_string_content(raw_str: &str) -> Option<String> {
+    let level = raw_str[1..].find('[')?;
+    let content = raw_str.get(level + 2..raw_str.len() - level - 2)?;
+    let content = content
+        .strip_prefix("\r\n")
+        .or_else(|| content.strip_prefix('\n'))
+        .unwrap_or(content);
+    Some(content.to_string())
+}
+
+/// A malformed escape sequence found by `unescape`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscapeError {
+    /// What is wrong with it, e.g. "decimal escape too large".
+    pub reason: &'static str,
+    /// Its byte range in the string contents.
+    pub range: Range<usize>,
+}
+
+/// Decodes the escape sequences in the contents of a quoted string
+/// literal, like `read_string` in llex.c, stopping at the first malformed
+/// escape sequence.
+pub fn unescape(content: &str) -> Result<Vec<u8>, EscapeError> {
+    let bytes = content.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let escape_start = i;
+        i += 1;
+        let invalid = |end: usize, reason: &'static str| {
+            let mut end = end.min(bytes.len());
+            while !content.is_char_boundary(end) {
+                end += 1;
+            }
+            EscapeError {
+                reason,
+                range: escape_start..end,
+            }
+        };
+        let c = match bytes.get(i) {
+            Some(&c) => c,
+            None => return Err(invalid(i, "unfinished string")),
+        };
+        i += 1;
+        match c {
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'\\' | b'"' | b'\'' => out.push(c),
+            b'\n' | b'\r' => {
+                // A line break, which may be \r\n or \n\r.
+                if matches!(bytes.get(i), Some(&next) if (next == b'\n' || next == b'\r') && next != c)
+                {
+                    i += 1;
+                }
+                out.push(b'\n');
+            }
+            b'x' => {
+                let mut value = 0;
+                for _ in 0..2 {
+                    match bytes.get(i).and_then(|&d| (d as char).to_digit(16)) {
+                        Some(d) => value = value * 16 + d,
+                        None => return Err(invalid(i + 1, "hexadecimal digit expected")),
+                    }
+                    i += 1;
+                }
+                out.push(value as u8);
+            }
+            b'z' => {
+                while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+            }
+            b'u' => {
+                if bytes.get(i) != Some(&b'{') {
+                    return Err(invalid(i + 1, "missing '{' in \\u{xxxx}"));
+                }
+                i += 1;
+                let mut value: u32 = 0;
+                let mut digits = 0;
+                while let Some(d) = bytes.get(i).and_then(|&d| (d as char).to_digit(16)) {
+                    value = match value.checked_mul(16).map(|v| v + d) {
+                        Some(v) if v <= 0x7fff_ffff => v,
+                        _ => return Err(invalid(i + 1, "UTF-8 value too large")),
+                    };
+                    digits += 1;
+                    i += 1;
+                }
+                if digits == 0 {
+                    return Err(invalid(i + 1, "hexadecimal digit expected"));
+                }
+                if bytes.get(i) != Some(&b'}') {
+                    return Err(invalid(i + 1, "missing '}' in \\u{xxxx}"));
+                }
+                i += 1;
+                utf8_escape(value, &mut out);
+            }
+            b'0'..=b'9' => {
+                let mut value = (c - b'0') as u32;
+                for _ in 0..2 {
+                    match bytes.get(i) {
+                        Some(&d) if d.is_ascii_digit() => {
+                            value = value * 10 + (d - b'0') as u32;
+                            i += 1;
+                        }
+                        _ => break,
+                    }
+                }
+                if value > 255 {
+                    return Err(invalid(i, "decimal escape too large"));
+                }
+                out.push(value as u8);
+            }
+            _ => return Err(invalid(i, "invalid escape sequence")),
+        }
+    }
+    Ok(out)
+}
+
+/// Appends the UTF-8 encoding of `x`, which may be up to 2^31 - 1 and use
+/// the original 6-byte form, like `luaO_utf8esc`.
+fn utf8_escape(mut x: u32, out: &mut Vec<u8>) {
+    if x < 0x80 {
+        out.push(x as u8);
+        return;
+    }
+    let mut buf = Vec::new();
+    let mut mfb = 0x3f;
+    loop {
+        buf.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
     }
+    buf.push(((!mfb << 1) | x) as u8);
+    out.extend(buf.iter().rev());
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == '_'
 }
 
 fn is_alphanumeric(c: char) -> bool {
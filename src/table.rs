@@ -0,0 +1,414 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::errors::RuntimeError;
+use crate::value::{float_to_integer, LuaString, Value};
+
+/// A Lua table, split like the reference implementation into an array part
+/// holding the keys `1..=n` and a hash part for every other key.
+///
+/// The hash part keeps its entries in insertion order. Removing a key leaves
+/// a dead entry behind until the next rehash, so `next` keeps working while
+/// fields are cleared during a traversal.
+#[derive(Default)]
+pub struct Table {
+    array: Vec<Value>,
+    nodes: Vec<(Value, Value)>,
+    index: HashMap<Key, usize>,
+    /// Number of entries the hash part may hold before the next rehash.
+    node_capacity: usize,
+    metatable: Option<Rc<RefCell<Table>>>,
+}
+
+/// The hashable identity of a non-nil, non-NaN key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Boolean(bool),
+    Integer(i64),
+    Float(u64),
+    String(LuaString),
+    Reference(usize),
+}
+
+impl Key {
+    fn new(value: &Value) -> Option<Key> {
+        Some(match value {
+            Value::Nil => return None,
+            Value::Boolean(b) => Key::Boolean(*b),
+            Value::Integer(i) => Key::Integer(*i),
+            Value::Float(n) if n.is_nan() => return None,
+            Value::Float(n) => match float_to_integer(*n) {
+                Some(i) => Key::Integer(i),
+                None => Key::Float(n.to_bits()),
+            },
+            Value::String(s) => Key::String(s.clone()),
+            Value::Table(t) => Key::Reference(Rc::as_ptr(t) as *const () as usize),
+            Value::Function(f) => Key::Reference(Rc::as_ptr(f) as *const () as usize),
+            Value::UserData(u) => Key::Reference(Rc::as_ptr(u) as *const () as usize),
+            Value::Thread(t) => Key::Reference(Rc::as_ptr(t) as *const () as usize),
+        })
+    }
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a table with `narray` preallocated array slots and room for
+    /// `nhash` other keys, like `lua_createtable`.
+    pub fn with_capacity(narray: usize, nhash: usize) -> Self {
+        Self {
+            array: vec![Value::Nil; narray],
+            nodes: Vec::with_capacity(nhash),
+            index: HashMap::with_capacity(nhash),
+            node_capacity: nhash,
+            metatable: None,
+        }
+    }
+
+    pub fn metatable(&self) -> Option<Rc<RefCell<Table>>> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<Rc<RefCell<Table>>>) {
+        self.metatable = metatable;
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        match key {
+            Value::Integer(i) => self.get_int(*i),
+            Value::Float(n) => match float_to_integer(*n) {
+                Some(i) => self.get_int(i),
+                None => self.get_node(key),
+            },
+            _ => self.get_node(key),
+        }
+    }
+
+    pub fn get_int(&self, key: i64) -> Value {
+        match self.array_slot(key) {
+            Some(slot) => self.array[slot].clone(),
+            None => self.get_node(&Value::Integer(key)),
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> Value {
+        self.get_node(&Value::from(key))
+    }
+
+    fn get_node(&self, key: &Value) -> Value {
+        Key::new(key)
+            .and_then(|key| self.index.get(&key))
+            .map(|&pos| self.nodes[pos].1.clone())
+            .unwrap_or(Value::Nil)
+    }
+
+    /// Assigns `table[key] = value`. Assigning `nil` removes the key.
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), RuntimeError> {
+        let key = match key {
+            Value::Nil => return Err(RuntimeError::new("table index is nil")),
+            Value::Float(n) if n.is_nan() => return Err(RuntimeError::new("table index is NaN")),
+            Value::Float(n) => match float_to_integer(n) {
+                Some(i) => Value::Integer(i),
+                None => Value::Float(n),
+            },
+            key => key,
+        };
+        if let Value::Integer(i) = key {
+            self.set_int(i, value);
+            return Ok(());
+        }
+        self.set_node(key, value);
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, key: i64, value: Value) {
+        if let Some(slot) = self.array_slot(key) {
+            self.array[slot] = value;
+            return;
+        }
+        if key as usize == self.array.len() + 1 && key > 0 && !value.is_nil() {
+            // Appending right after the array part is the common case of
+            // building a sequence; keep it out of the hash part.
+            self.remove_node(&Key::Integer(key));
+            self.array.push(value);
+            self.migrate_to_array();
+            return;
+        }
+        self.set_node(Value::Integer(key), value);
+    }
+
+    pub fn set_str(&mut self, key: &str, value: Value) {
+        self.set_node(Value::from(key), value);
+    }
+
+    fn set_node(&mut self, key: Value, value: Value) {
+        let k = Key::new(&key).expect("nil and NaN keys are rejected by callers");
+        if let Some(&pos) = self.index.get(&k) {
+            // Dead entries are revived in place, keeping traversal order.
+            self.nodes[pos].1 = value;
+            return;
+        }
+        if value.is_nil() {
+            return;
+        }
+        if self.nodes.len() >= self.node_capacity {
+            self.rehash(&key);
+            if let Value::Integer(i) = key {
+                if self.array_slot(i).is_some() {
+                    self.set_int(i, value);
+                    return;
+                }
+            }
+        }
+        self.index.insert(k, self.nodes.len());
+        self.nodes.push((key, value));
+    }
+
+    fn remove_node(&mut self, key: &Key) {
+        if let Some(&pos) = self.index.get(key) {
+            self.nodes[pos].1 = Value::Nil;
+        }
+    }
+
+    /// Moves the keys that now directly follow the array part out of the
+    /// hash part.
+    fn migrate_to_array(&mut self) {
+        loop {
+            let key = Key::Integer(self.array.len() as i64 + 1);
+            match self.index.get(&key) {
+                Some(&pos) if !self.nodes[pos].1.is_nil() => {
+                    let value = std::mem::take(&mut self.nodes[pos].1);
+                    self.array.push(value);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn array_slot(&self, key: i64) -> Option<usize> {
+        if key >= 1 && (key as u64) <= self.array.len() as u64 {
+            Some(key as usize - 1)
+        } else {
+            None
+        }
+    }
+
+    /// Resizes both parts when the hash part is full: the array part
+    /// becomes the largest power of two `n` such that more than half of the
+    /// slots `1..=n` would be in use, and dead entries are dropped.
+    fn rehash(&mut self, extra_key: &Value) {
+        // nums[b] counts the integer keys k with 2^(b-1) < k <= 2^b.
+        let mut nums = [0usize; 65];
+        let mut total_int = 0;
+        let mut total_nodes = 1;
+        let mut count_int = |k: i64| {
+            if k >= 1 {
+                nums[ceil_log2(k as u64)] += 1;
+                total_int += 1;
+            }
+        };
+        for (i, v) in self.array.iter().enumerate() {
+            if !v.is_nil() {
+                count_int(i as i64 + 1);
+            }
+        }
+        for (k, v) in &self.nodes {
+            if v.is_nil() {
+                continue;
+            }
+            total_nodes += 1;
+            if let Value::Integer(i) = k {
+                count_int(*i);
+            }
+        }
+        if let Value::Integer(i) = extra_key {
+            count_int(*i);
+        }
+
+        let mut array_size = 0;
+        let mut in_array = 0;
+        let mut accumulated = 0;
+        for (b, &n) in nums.iter().enumerate() {
+            let twotoi = 1usize << b.min(63);
+            if total_int <= twotoi / 2 {
+                break;
+            }
+            accumulated += n;
+            if accumulated > twotoi / 2 {
+                array_size = twotoi;
+                in_array = accumulated;
+            }
+        }
+
+        let mut array = std::mem::take(&mut self.array);
+        let nodes = std::mem::take(&mut self.nodes);
+        self.index.clear();
+        let mut overflow = Vec::new();
+        if array.len() > array_size {
+            for (i, v) in array.drain(array_size..).enumerate() {
+                if !v.is_nil() {
+                    overflow.push((Value::Integer((array_size + i) as i64 + 1), v));
+                }
+            }
+        }
+        array.resize(array_size, Value::Nil);
+
+        let node_count = total_nodes + overflow.len();
+        self.node_capacity = node_count.saturating_sub(in_array).next_power_of_two();
+        for (k, v) in nodes.into_iter().chain(overflow) {
+            if v.is_nil() {
+                continue;
+            }
+            match k {
+                Value::Integer(i) if i >= 1 && (i as u64) <= array_size as u64 => {
+                    array[i as usize - 1] = v;
+                }
+                k => {
+                    self.index.insert(Key::new(&k).unwrap(), self.nodes.len());
+                    self.nodes.push((k, v));
+                }
+            }
+        }
+        self.array = array;
+    }
+
+    /// Returns a border of the table, as the length operator `#` does: an
+    /// index `n` where `t[n]` is not nil and `t[n + 1]` is nil (or 0 when
+    /// `t[1]` is nil).
+    pub fn len(&self) -> usize {
+        let n = self.array.len();
+        if n > 0 && self.array[n - 1].is_nil() {
+            // Binary search for a border inside the array part.
+            let (mut lo, mut hi) = (0, n);
+            while hi - lo > 1 {
+                let mid = (lo + hi) / 2;
+                if self.array[mid - 1].is_nil() {
+                    hi = mid;
+                } else {
+                    lo = mid;
+                }
+            }
+            return lo;
+        }
+        if self.nodes.is_empty() || self.get_node(&Value::Integer(n as i64 + 1)).is_nil() {
+            return n;
+        }
+
+        // Unbounded search through the hash part.
+        let mut lo = n + 1;
+        let mut hi = lo * 2;
+        while !self.get_int(hi as i64).is_nil() {
+            lo = hi;
+            if hi > i64::MAX as usize / 2 {
+                // Pathological table: fall back to a linear search.
+                let mut i = 1;
+                while !self.get_int(i).is_nil() {
+                    i += 1;
+                }
+                return i as usize - 1;
+            }
+            hi *= 2;
+        }
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if self.get_int(mid as i64).is_nil() {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        lo
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.array.iter().all(Value::is_nil) && self.nodes.iter().all(|(_, v)| v.is_nil())
+    }
+
+    /// Returns the entry following `key` in traversal order, as `next` does:
+    /// the array part in index order, then the hash part in insertion order.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, RuntimeError> {
+        let start = match key {
+            Value::Nil => 0,
+            key => {
+                let slot = match key {
+                    Value::Integer(i) => self.array_slot(*i),
+                    Value::Float(n) => float_to_integer(*n).and_then(|i| self.array_slot(i)),
+                    _ => None,
+                };
+                match slot {
+                    Some(slot) => slot + 1,
+                    None => match Key::new(key).and_then(|k| self.index.get(&k)) {
+                        Some(&pos) => self.array.len() + pos + 1,
+                        None => return Err(RuntimeError::new("invalid key to 'next'")),
+                    },
+                }
+            }
+        };
+
+        for slot in start..self.array.len() {
+            if !self.array[slot].is_nil() {
+                let k = Value::Integer(slot as i64 + 1);
+                return Ok(Some((k, self.array[slot].clone())));
+            }
+        }
+        let start = start.saturating_sub(self.array.len());
+        Ok(self.nodes[start.min(self.nodes.len())..]
+            .iter()
+            .find(|(_, v)| !v.is_nil())
+            .cloned())
+    }
+
+    /// Approximate number of bytes the table and the strings it holds
+    /// occupy, for `collectgarbage("count")`.
+    pub fn allocated_size(&self) -> usize {
+        let strings: usize = self
+            .iter()
+            .flat_map(|(k, v)| [k, v.clone()])
+            .map(|v| match v {
+                Value::String(s) => s.len(),
+                _ => 0,
+            })
+            .sum();
+        std::mem::size_of::<Self>()
+            + self.array.capacity() * std::mem::size_of::<Value>()
+            + self.nodes.capacity() * std::mem::size_of::<(Value, Value)>()
+            + self.index.capacity() * std::mem::size_of::<(Key, usize)>()
+            + strings
+    }
+
+    /// Iterates over all live entries in traversal order.
+    pub fn iter(&self) -> impl Iterator<Item = (Value, &Value)> + '_ {
+        let array = self
+            .array
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !v.is_nil())
+            .map(|(i, v)| (Value::Integer(i as i64 + 1), v));
+        let nodes = self
+            .nodes
+            .iter()
+            .filter(|(_, v)| !v.is_nil())
+            .map(|(k, v)| (k.clone(), v));
+        array.chain(nodes)
+    }
+}
+
+/// Tables often reach themselves through their metatable or fields, so
+/// the metatable is only shown by address.
+impl fmt::Debug for Table {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Table")
+            .field("array", &self.array)
+            .field("nodes", &self.nodes)
+            .field("metatable", &self.metatable.as_ref().map(Rc::as_ptr))
+            .finish()
+    }
+}
+
+fn ceil_log2(x: u64) -> usize {
+    (64 - (x - 1).leading_zeros()) as usize
+}
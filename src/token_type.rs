@@ -66,4 +66,78 @@ pub enum TokenType {
     StringLit,
 
     Comment,
+    /// Malformed input skipped by `Scanner::scan_recovering`.
+    Error,
+}
+
+impl TokenType {
+    /// How Lua spells the token in messages: the symbol or reserved word
+    /// itself, or a placeholder such as `<name>` for tokens with a value.
+    pub fn as_str(self) -> &'static str {
+        use TokenType::*;
+        match self {
+            Opus => "#",
+            Perc => "%",
+            Amp => "&",
+            ParenL => "(",
+            ParenR => ")",
+            Aster => "*",
+            Plus => "+",
+            Comma => ",",
+            Minus => "-",
+            Period => ".",
+            Slash => "/",
+            Colon => ":",
+            SemiColon => ";",
+            Less => "<",
+            Assign => "=",
+            Greater => ">",
+            BracketL => "[",
+            BracketR => "]",
+            Hat => "^",
+            BraceL => "{",
+            Bar => "|",
+            BraceR => "}",
+            Tilda => "~",
+            And => "and",
+            Break => "break",
+            Do => "do",
+            Else => "else",
+            Elseif => "elseif",
+            End => "end",
+            False => "false",
+            For => "for",
+            Function => "function",
+            Goto => "goto",
+            If => "if",
+            In => "in",
+            Local => "local",
+            Nil => "nil",
+            Not => "not",
+            Or => "or",
+            Repeat => "repeat",
+            Return => "return",
+            Then => "then",
+            True => "true",
+            Until => "until",
+            While => "while",
+            IDiv => "//",
+            Concat => "..",
+            Dots => "...",
+            Eql => "==",
+            Ge => ">=",
+            Le => "<=",
+            Ne => "~=",
+            ShL => "<<",
+            ShR => ">>",
+            DbColon => "::",
+            Eof => "<eof>",
+            Float => "<number>",
+            Int => "<integer>",
+            Name => "<name>",
+            StringLit => "<string>",
+            Comment => "<comment>",
+            Error => "<error>",
+        }
+    }
 }
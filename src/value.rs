@@ -0,0 +1,742 @@
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use crate::compiler::proto::Proto;
+use crate::errors::{LuaError, RuntimeError};
+use crate::table::Table;
+use crate::vm::coroutine::ThreadState;
+use crate::vm::Vm;
+
+pub type BuiltinFn = fn(&mut Vm, Vec<Value>) -> Result<Vec<Value>, LuaError>;
+
+/// A Lua value. Numbers keep the Lua 5.3 integer/float distinction.
+#[derive(Clone, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(LuaString),
+    Table(Rc<RefCell<Table>>),
+    Function(Rc<Function>),
+    UserData(Rc<UserData>),
+    Thread(Rc<Thread>),
+}
+
+/// An immutable Lua string. Lua strings are byte strings and need not be
+/// valid UTF-8.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LuaString(Rc<[u8]>);
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> Self {
+        LuaString(s.as_bytes().into())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(s: String) -> Self {
+        LuaString(s.into_bytes().into())
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        LuaString(bytes.into())
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> Self {
+        LuaString(bytes.into())
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.to_str_lossy())
+    }
+}
+
+pub enum Function {
+    Lua(LuaClosure),
+    Builtin {
+        name: &'static str,
+        func: BuiltinFn,
+        /// Values passed to `func` before the call arguments, like the
+        /// upvalues of a C closure.
+        upvalues: Vec<Value>,
+    },
+    /// `pcall`, or `xpcall` when `handler` is set. The VM marks the frame of
+    /// the function they call instead of calling it from Rust, so that the
+    /// function can yield.
+    Protected {
+        name: &'static str,
+        handler: bool,
+    },
+}
+
+/// A compiled function together with the variables it captured.
+pub struct LuaClosure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// A variable captured by a closure. It is open, referring to a slot of the
+/// stack of the thread that declared it, while the variable is in scope,
+/// and holds the value itself once closed.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open { thread: Weak<Thread>, slot: usize },
+    Closed(Value),
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Function::Lua(closure) => f
+                .debug_struct("Lua")
+                .field("source", &closure.proto.source)
+                .field("line_defined", &closure.proto.line_defined)
+                .finish(),
+            Function::Builtin { name, .. } | Function::Protected { name, .. } => {
+                f.debug_struct("Builtin").field("name", name).finish()
+            }
+        }
+    }
+}
+
+/// Arbitrary host data exposed to Lua.
+pub struct UserData {
+    data: RefCell<Box<dyn Any>>,
+}
+
+impl UserData {
+    pub fn new<T: Any>(data: T) -> Self {
+        Self {
+            data: RefCell::new(Box::new(data)),
+        }
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.borrow().is::<T>()
+    }
+
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |data| data.downcast_ref::<T>()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |data| data.downcast_mut::<T>()).ok()
+    }
+}
+
+/// A Lua thread (coroutine) with its own stack of values and call frames.
+/// The state of the running thread lives in the VM; `state` holds it while
+/// the thread is suspended or has resumed another one.
+pub struct Thread {
+    pub(crate) status: Cell<ThreadStatus>,
+    pub(crate) state: RefCell<ThreadState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadStatus {
+    Suspended,
+    Running,
+    /// Active but not running: it resumed another coroutine.
+    Normal,
+    Dead,
+}
+
+impl Thread {
+    pub fn status(&self) -> ThreadStatus {
+        self.status.get()
+    }
+}
+
+impl ThreadStatus {
+    /// The name `coroutine.status` returns.
+    pub fn name(self) -> &'static str {
+        match self {
+            ThreadStatus::Suspended => "suspended",
+            ThreadStatus::Running => "running",
+            ThreadStatus::Normal => "normal",
+            ThreadStatus::Dead => "dead",
+        }
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Float(n) => write!(f, "{:?}", n),
+            v => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Formats values as `tostring` does, without consulting metamethods.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(n) => write!(f, "{}", format_float(*n)),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Table(t) => write!(f, "table: {:p}", Rc::as_ptr(t)),
+            Value::Function(func) => match func.as_ref() {
+                Function::Builtin { .. } | Function::Protected { .. } => {
+                    write!(f, "function: builtin: {:p}", Rc::as_ptr(func))
+                }
+                Function::Lua(_) => {
+                    write!(f, "function: {:p}", Rc::as_ptr(func))
+                }
+            },
+            Value::UserData(u) => write!(f, "userdata: {:p}", Rc::as_ptr(u)),
+            Value::Thread(t) => write!(f, "thread: {:p}", Rc::as_ptr(t)),
+        }
+    }
+}
+
+/// Raw equality: no metamethods, integers and floats compare by
+/// mathematical value, reference types by identity.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Integer(i), Value::Float(f)) | (Value::Float(f), Value::Integer(i)) => {
+                float_to_integer(*f) == Some(*i)
+            }
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Table(a), Value::Table(b)) => Rc::ptr_eq(a, b),
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::UserData(a), Value::UserData(b)) => Rc::ptr_eq(a, b),
+            (Value::Thread(a), Value::Thread(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Boolean(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Integer(i)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Float(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<LuaString> for Value {
+    fn from(s: LuaString) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<Table> for Value {
+    fn from(t: Table) -> Self {
+        Value::Table(Rc::new(RefCell::new(t)))
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::String(_) => "string",
+            Value::Table(_) => "table",
+            Value::Function(_) => "function",
+            Value::UserData(_) => "userdata",
+            Value::Thread(_) => "thread",
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    /// Everything except `nil` and `false` is true.
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Boolean(false))
+    }
+
+    pub fn raw_equal(&self, other: &Value) -> bool {
+        self == other
+    }
+
+    pub fn new_table() -> Self {
+        Table::default().into()
+    }
+
+    pub fn new_userdata<T: Any>(data: T) -> Self {
+        Value::UserData(Rc::new(UserData::new(data)))
+    }
+
+    /// Converts numbers and numeric strings to a number, keeping the
+    /// integer/float subtype.
+    pub fn to_numeric(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Float(_) => Some(self.clone()),
+            Value::String(s) => s.to_str().and_then(str_to_number),
+            _ => None,
+        }
+    }
+
+    pub fn to_number(&self) -> Option<f64> {
+        match self.to_numeric()? {
+            Value::Integer(i) => Some(i as f64),
+            Value::Float(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Converts to an integer if the value has an exact integer
+    /// representation.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_numeric()? {
+            Value::Integer(i) => Some(i),
+            Value::Float(n) => float_to_integer(n),
+            _ => None,
+        }
+    }
+
+    /// Returns the string form used by `..`: strings as-is, numbers
+    /// formatted, everything else `None`.
+    pub fn to_lua_string(&self) -> Option<LuaString> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Integer(_) | Value::Float(_) => Some(self.to_string().into()),
+            _ => None,
+        }
+    }
+}
+
+/// Formats a float the way Lua does: `%.14g`, with `.0` appended when the
+/// result would otherwise read as an integer.
+pub fn format_float(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0.0" } else { "0.0" }.to_string();
+    }
+
+    let sci = format!("{:.13e}", n);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if (-4..14).contains(&exp) {
+        let decimals = (13 - exp) as usize;
+        let fixed = format!("{:.*}", decimals, n);
+        let fixed = trim_fraction(&fixed);
+        if fixed.contains('.') {
+            fixed.to_string()
+        } else {
+            format!("{}.0", fixed)
+        }
+    } else {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exp.abs())
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Converts a float with an exact integer value to `i64`.
+pub fn float_to_integer(n: f64) -> Option<i64> {
+    // -2^63 is exactly representable, 2^63 is the first float out of range.
+    if n.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&n) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Parses a numeric string as `tonumber` does: surrounding whitespace is
+/// allowed, decimal and hexadecimal integers stay integers.
+pub fn str_to_number(s: &str) -> Option<Value> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    match parse_numeral(digits)? {
+        Value::Integer(i) if negative => Some(Value::Integer(i.wrapping_neg())),
+        Value::Float(f) if negative => Some(Value::Float(-f)),
+        n => Some(n),
+    }
+}
+
+/// Parses an integer written in `base` (2 to 36) as `tonumber(s, base)`
+/// does: surrounding whitespace and a leading `-` are allowed, letters
+/// stand for the digits above 9, and the result wraps around on overflow.
+pub fn str_to_integer_base(s: &str, base: u32) -> Option<i64> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        let digit = c.to_digit(base)?;
+        n = n.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Some(if negative { n.wrapping_neg() } else { n })
+}
+
+/// Parses an unsigned Lua numeral: a decimal or hexadecimal integer, or a
+/// float with an optional fraction and exponent (`p` for hexadecimal).
+/// Hexadecimal integers wrap around on overflow and decimal integers too
+/// large for an `i64` become floats.
+pub fn parse_numeral(s: &str) -> Option<Value> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return parse_hex_numeral(hex);
+    }
+
+    let (mantissa, exponent) = match s.find(['e', 'E']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int_part, frac_part) = match mantissa.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (mantissa, None),
+    };
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if int_part.len() + frac_part.map_or(0, str::len) == 0
+        || !all_digits(int_part)
+        || !frac_part.is_none_or(all_digits)
+    {
+        return None;
+    }
+    if let Some(exponent) = exponent {
+        let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        if digits.is_empty() || !all_digits(digits) {
+            return None;
+        }
+    }
+
+    if frac_part.is_none() && exponent.is_none() {
+        if let Ok(i) = s.parse::<i64>() {
+            return Some(Value::Integer(i));
+        }
+    }
+    s.parse::<f64>().ok().map(Value::Float)
+}
+
+fn parse_hex_numeral(s: &str) -> Option<Value> {
+    let (mantissa, exponent) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int_part, frac_part) = match mantissa.split_once('.') {
+        Some((i, f)) => (i, Some(f)),
+        None => (mantissa, None),
+    };
+    let all_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    if int_part.len() + frac_part.map_or(0, str::len) == 0
+        || !all_hex(int_part)
+        || !frac_part.is_none_or(all_hex)
+    {
+        return None;
+    }
+    let hex_digit = |b: u8| (b as char).to_digit(16).unwrap();
+
+    if frac_part.is_none() && exponent.is_none() {
+        // Hexadecimal integers wrap around on overflow.
+        let n = int_part.bytes().fold(0i64, |acc, b| {
+            acc.wrapping_mul(16).wrapping_add(hex_digit(b) as i64)
+        });
+        return Some(Value::Integer(n));
+    }
+
+    let mut exp: i64 = match exponent {
+        Some(e) => {
+            let digits = e.strip_prefix(['+', '-']).unwrap_or(e);
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            // Saturate rather than fail: such exponents overflow anyway.
+            let n = digits.parse::<i64>().unwrap_or(i64::MAX / 2).min(100_000);
+            if e.starts_with('-') {
+                -n
+            } else {
+                n
+            }
+        }
+        None => 0,
+    };
+    let mut m = 0.0f64;
+    for b in int_part.bytes() {
+        m = m * 16.0 + hex_digit(b) as f64;
+    }
+    for b in frac_part.unwrap_or("").bytes() {
+        m = m * 16.0 + hex_digit(b) as f64;
+        exp -= 4;
+    }
+    Some(Value::Float(ldexp(m, exp)))
+}
+
+/// Computes `m * 2^exp` without overflowing intermediate powers of two.
+fn ldexp(mut m: f64, mut exp: i64) -> f64 {
+    if m == 0.0 {
+        return m;
+    }
+    while exp > 1000 {
+        m *= 2f64.powi(1000);
+        exp -= 1000;
+    }
+    while exp < -1000 {
+        m *= 2f64.powi(-1000);
+        exp += 1000;
+    }
+    m * 2f64.powi(exp as i32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Unm,
+    BNot,
+}
+
+impl ArithOp {
+    /// Name of the metamethod implementing the operator, e.g. `__add`.
+    pub fn event(self) -> &'static str {
+        use ArithOp::*;
+        match self {
+            Add => "__add",
+            Sub => "__sub",
+            Mul => "__mul",
+            Div => "__div",
+            Mod => "__mod",
+            Pow => "__pow",
+            IDiv => "__idiv",
+            BAnd => "__band",
+            BOr => "__bor",
+            BXor => "__bxor",
+            Shl => "__shl",
+            Shr => "__shr",
+            Unm => "__unm",
+            BNot => "__bnot",
+        }
+    }
+
+    pub fn is_bitwise(self) -> bool {
+        use ArithOp::*;
+        matches!(self, BAnd | BOr | BXor | Shl | Shr | BNot)
+    }
+}
+
+/// Performs an arithmetic or bitwise operation on two numbers (or numeric
+/// strings). Returns `Ok(None)` when an operand is not a number; unary
+/// operators ignore `b`.
+pub fn arith(op: ArithOp, a: &Value, b: &Value) -> Result<Option<Value>, RuntimeError> {
+    use ArithOp::*;
+
+    let (a, b) = match (a.to_numeric(), b.to_numeric()) {
+        (Some(a), Some(b)) => (a, b),
+        (Some(a), None) if matches!(op, Unm | BNot) => (a, Value::Integer(0)),
+        _ => return Ok(None),
+    };
+
+    if op.is_bitwise() {
+        let (x, y) = match (a.to_integer(), b.to_integer()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return Err(RuntimeError::new("number has no integer representation")),
+        };
+        let n = match op {
+            BAnd => x & y,
+            BOr => x | y,
+            BXor => x ^ y,
+            Shl => shift_left(x, y),
+            Shr => shift_left(x, y.wrapping_neg()),
+            _ => !x,
+        };
+        return Ok(Some(Value::Integer(n)));
+    }
+
+    if let (Value::Integer(x), Value::Integer(y)) = (&a, &b) {
+        let (x, y) = (*x, *y);
+        let n = match op {
+            Add => x.wrapping_add(y),
+            Sub => x.wrapping_sub(y),
+            Mul => x.wrapping_mul(y),
+            Unm => x.wrapping_neg(),
+            IDiv => {
+                if y == 0 {
+                    return Err(RuntimeError::new("attempt to perform 'n//0'"));
+                }
+                let q = x.wrapping_div(y);
+                if (x % y != 0) && ((x < 0) != (y < 0)) {
+                    q - 1
+                } else {
+                    q
+                }
+            }
+            Mod => {
+                if y == 0 {
+                    return Err(RuntimeError::new("attempt to perform 'n%0'"));
+                }
+                let r = x.wrapping_rem(y);
+                if r != 0 && ((r < 0) != (y < 0)) {
+                    r + y
+                } else {
+                    r
+                }
+            }
+            // `/` and `^` always produce floats.
+            _ => return Ok(Some(Value::Float(float_arith(op, x as f64, y as f64)))),
+        };
+        return Ok(Some(Value::Integer(n)));
+    }
+
+    let x = a.to_number().unwrap();
+    let y = b.to_number().unwrap();
+    Ok(Some(Value::Float(float_arith(op, x, y))))
+}
+
+fn float_arith(op: ArithOp, x: f64, y: f64) -> f64 {
+    use ArithOp::*;
+    match op {
+        Add => x + y,
+        Sub => x - y,
+        Mul => x * y,
+        Div => x / y,
+        Pow => x.powf(y),
+        IDiv => (x / y).floor(),
+        Mod => {
+            let r = x % y;
+            if r != 0.0 && ((r < 0.0) != (y < 0.0)) {
+                r + y
+            } else {
+                r
+            }
+        }
+        Unm => -x,
+        _ => unreachable!("bitwise operators work on integers"),
+    }
+}
+
+fn shift_left(x: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((x as u64) << n) as i64
+    } else {
+        ((x as u64) >> -n) as i64
+    }
+}
+
+/// Orders two numbers or two strings; `None` means the values are not
+/// comparable without metamethods.
+pub fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => Some(x.cmp(y)),
+        (Value::Float(x), Value::Float(y)) => x.partial_cmp(y),
+        (Value::Integer(i), Value::Float(f)) => cmp_int_float(*i, *f),
+        (Value::Float(f), Value::Integer(i)) => cmp_int_float(*i, *f).map(Ordering::reverse),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        _ => None,
+    }
+}
+
+/// Compares an integer with a float exactly, without rounding the integer.
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        return None;
+    }
+    if f >= 9223372036854775808.0 {
+        return Some(Ordering::Less);
+    }
+    if f < -9223372036854775808.0 {
+        return Some(Ordering::Greater);
+    }
+    let floor = f.floor();
+    match i.cmp(&(floor as i64)) {
+        Ordering::Equal if f > floor => Some(Ordering::Less),
+        ord => Some(ord),
+    }
+}
@@ -0,0 +1,343 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::{meta, Vm};
+use crate::errors::LuaError;
+use crate::table::Table;
+use crate::value::{self, BuiltinFn, Function, Value};
+
+type BuiltinResult = Result<Vec<Value>, LuaError>;
+
+pub fn register(vm: &mut Vm) {
+    let builtins: &[(&'static str, BuiltinFn)] = &[
+        ("print", print),
+        ("type", type_),
+        ("tostring", tostring),
+        ("tonumber", tonumber),
+        ("next", next),
+        ("pairs", pairs),
+        ("ipairs", ipairs),
+        ("select", select),
+        ("collectgarbage", collectgarbage),
+        ("error", error),
+        ("setmetatable", setmetatable),
+        ("getmetatable", getmetatable),
+        ("rawget", rawget),
+        ("rawset", rawset),
+        ("rawequal", rawequal),
+        ("rawlen", rawlen),
+    ];
+    for &(name, func) in builtins {
+        vm.set_global(
+            name,
+            Value::Function(Rc::new(Function::Builtin {
+                name,
+                func,
+                upvalues: Vec::new(),
+            })),
+        );
+    }
+    for (name, handler) in [("pcall", false), ("xpcall", true)] {
+        let f = Function::Protected { name, handler };
+        vm.set_global(name, Value::Function(Rc::new(f)));
+    }
+}
+
+fn table_arg(
+    vm: &Vm,
+    args: &[Value],
+    n: usize,
+    name: &str,
+) -> Result<Rc<RefCell<Table>>, LuaError> {
+    match arg(vm, args, n, name)? {
+        Value::Table(t) => Ok(t),
+        v => Err(vm.error(format!(
+            "bad argument #{} to '{}' (table expected, got {})",
+            n + 1,
+            name,
+            v.type_name()
+        ))),
+    }
+}
+
+fn arg(vm: &Vm, args: &[Value], n: usize, name: &str) -> Result<Value, LuaError> {
+    args.get(n).cloned().ok_or_else(|| {
+        vm.error(format!(
+            "bad argument #{} to '{}' (value expected)",
+            n + 1,
+            name
+        ))
+    })
+}
+
+/// Writes its arguments separated by tabs, followed by a newline.
+fn print(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let mut line = args
+        .iter()
+        .map(|v| vm.tostring(v).map(|s| s.to_string()))
+        .collect::<Result<Vec<_>, _>>()?
+        .join("\t");
+    line.push('\n');
+    if let Err(e) = vm.output().write_all(line.as_bytes()) {
+        return Err(vm.error(e.to_string()));
+    }
+    Ok(Vec::new())
+}
+
+fn type_(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let v = arg(vm, &args, 0, "type")?;
+    Ok(vec![Value::from(v.type_name())])
+}
+
+fn tostring(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let v = arg(vm, &args, 0, "tostring")?;
+    Ok(vec![vm.tostring(&v)?])
+}
+
+fn tonumber(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let v = arg(vm, &args, 0, "tonumber")?;
+    let base = match args.get(1) {
+        None | Some(Value::Nil) => return Ok(vec![v.to_numeric().unwrap_or(Value::Nil)]),
+        Some(base) => base.to_integer().ok_or_else(|| {
+            vm.error(format!(
+                "bad argument #2 to 'tonumber' (number expected, got {})",
+                base.type_name()
+            ))
+        })?,
+    };
+    let s = match &v {
+        Value::String(s) => s.to_str_lossy(),
+        v => {
+            return Err(vm.error(format!(
+                "bad argument #1 to 'tonumber' (string expected, got {})",
+                v.type_name()
+            )))
+        }
+    };
+    if !(2..=36).contains(&base) {
+        return Err(vm.error("bad argument #2 to 'tonumber' (base out of range)"));
+    }
+    let n = value::str_to_integer_base(&s, base as u32);
+    Ok(vec![n.map_or(Value::Nil, Value::Integer)])
+}
+
+fn next(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let table = table_arg(vm, &args, 0, "next")?;
+    let key = args.get(1).cloned().unwrap_or(Value::Nil);
+    let entry = table.borrow().next(&key).map_err(|e| vm.error(e.message))?;
+    match entry {
+        Some((k, v)) => Ok(vec![k, v]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+fn pairs(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let table = arg(vm, &args, 0, "pairs")?;
+    let handler = meta::metamethod(&table, "__pairs");
+    if !handler.is_nil() {
+        let mut results = vm.call(&handler, vec![table])?;
+        results.resize(3, Value::Nil);
+        return Ok(results);
+    }
+    if !matches!(table, Value::Table(_)) {
+        return Err(vm.error(format!(
+            "bad argument #1 to 'pairs' (table expected, got {})",
+            table.type_name()
+        )));
+    }
+    Ok(vec![vm.get_global("next"), table, Value::Nil])
+}
+
+fn ipairs_iter(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let (table, i) = match (args.first(), args.get(1)) {
+        (Some(Value::Table(t)), Some(Value::Integer(i))) => (t.clone(), i.wrapping_add(1)),
+        _ => return Err(vm.error("bad argument to 'ipairs' iterator")),
+    };
+    let value = table.borrow().get_int(i);
+    match value {
+        Value::Nil => Ok(vec![Value::Nil]),
+        v => Ok(vec![Value::Integer(i), v]),
+    }
+}
+
+fn ipairs(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let table = arg(vm, &args, 0, "ipairs")?;
+    let iter = Function::Builtin {
+        name: "ipairs_iter",
+        func: ipairs_iter,
+        upvalues: Vec::new(),
+    };
+    Ok(vec![
+        Value::Function(Rc::new(iter)),
+        table,
+        Value::Integer(0),
+    ])
+}
+
+/// `select(n, ...)` returns the arguments after the `n`th one, counting
+/// from the end when `n` is negative; `select('#', ...)` counts them.
+fn select(vm: &mut Vm, mut args: Vec<Value>) -> BuiltinResult {
+    let n = arg(vm, &args, 0, "select")?;
+    let count = args.len() as i64 - 1;
+    if matches!(&n, Value::String(s) if s.as_bytes() == b"#") {
+        return Ok(vec![Value::Integer(count)]);
+    }
+    let n = match n.to_integer() {
+        Some(n) if n < 0 && -n <= count => count + n,
+        Some(n) if n > 0 => (n - 1).min(count),
+        _ => return Err(vm.error("bad argument #1 to 'select' (index out of range)")),
+    };
+    Ok(args.split_off(n as usize + 1))
+}
+
+fn collectgarbage(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let option = match args.first() {
+        None | Some(Value::Nil) => "collect".to_string(),
+        Some(Value::String(s)) => s.to_string(),
+        Some(v) => {
+            return Err(vm.error(format!(
+                "bad argument #1 to 'collectgarbage' (string expected, got {})",
+                v.type_name()
+            )))
+        }
+    };
+    match option.as_str() {
+        "collect" => {
+            vm.collect_garbage();
+            Ok(vec![Value::Integer(0)])
+        }
+        "count" => Ok(vec![Value::Float(vm.heap.count() as f64 / 1024.0)]),
+        // Collections are not incremental, so a step either finishes a
+        // whole cycle or does nothing.
+        "step" => {
+            let kbytes = match args.get(1) {
+                None | Some(Value::Nil) => 0,
+                Some(v) => v.to_integer().ok_or_else(|| {
+                    vm.error(format!(
+                        "bad argument #2 to 'collectgarbage' (number expected, got {})",
+                        v.type_name()
+                    ))
+                })?,
+            };
+            let due = vm.heap.step(kbytes.max(0) as usize);
+            if due {
+                vm.collect_garbage();
+            }
+            Ok(vec![Value::Boolean(due)])
+        }
+        // There is only one kind of collector; the modes are accepted so
+        // that scripts choosing one still run.
+        "incremental" => Ok(vec![Value::from(vm.heap.set_mode("incremental"))]),
+        "generational" => Ok(vec![Value::from(vm.heap.set_mode("generational"))]),
+        "stop" => {
+            vm.heap.stop();
+            Ok(vec![Value::Integer(0)])
+        }
+        "restart" => {
+            vm.heap.restart();
+            Ok(vec![Value::Integer(0)])
+        }
+        "isrunning" => Ok(vec![Value::Boolean(vm.heap.is_running())]),
+        _ => Err(vm.error(format!(
+            "bad argument #1 to 'collectgarbage' (invalid option '{}')",
+            option
+        ))),
+    }
+}
+
+/// Sets or removes the metatable of a table. Tables whose metatable has a
+/// `__gc` field get their finalizer run once they become garbage.
+fn setmetatable(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let table = table_arg(vm, &args, 0, "setmetatable")?;
+    let metatable = match args.get(1) {
+        Some(Value::Nil) => None,
+        Some(Value::Table(mt)) => Some(mt.clone()),
+        v => {
+            return Err(vm.error(format!(
+                "bad argument #2 to 'setmetatable' (nil or table expected, got {})",
+                v.map_or("no value", |v| v.type_name())
+            )))
+        }
+    };
+    let protected = table
+        .borrow()
+        .metatable()
+        .is_some_and(|mt| !mt.borrow().get_str("__metatable").is_nil());
+    if protected {
+        return Err(vm.error("cannot change a protected metatable"));
+    }
+    if let Some(mt) = &metatable {
+        if !mt.borrow().get_str("__gc").is_nil() {
+            vm.heap.mark_finalizable(&table);
+        }
+    }
+    table.borrow_mut().set_metatable(metatable);
+    Ok(vec![Value::Table(table)])
+}
+
+/// Returns the metatable of a value, or its `__metatable` field if set.
+fn getmetatable(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let v = arg(vm, &args, 0, "getmetatable")?;
+    let Some(mt) = meta::metatable(&v) else {
+        return Ok(vec![Value::Nil]);
+    };
+    let protected = mt.borrow().get_str("__metatable");
+    match protected {
+        Value::Nil => Ok(vec![Value::Table(mt)]),
+        v => Ok(vec![v]),
+    }
+}
+
+fn rawget(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let table = table_arg(vm, &args, 0, "rawget")?;
+    let key = arg(vm, &args, 1, "rawget")?;
+    let value = table.borrow().get(&key);
+    Ok(vec![value])
+}
+
+fn rawset(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let table = table_arg(vm, &args, 0, "rawset")?;
+    let key = arg(vm, &args, 1, "rawset")?;
+    let value = arg(vm, &args, 2, "rawset")?;
+    let result = table.borrow_mut().set(key, value);
+    result.map_err(|e| vm.error(e.message))?;
+    Ok(vec![Value::Table(table)])
+}
+
+fn rawequal(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let a = arg(vm, &args, 0, "rawequal")?;
+    let b = arg(vm, &args, 1, "rawequal")?;
+    Ok(vec![Value::Boolean(a == b)])
+}
+
+fn rawlen(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    match args.first() {
+        Some(Value::Table(t)) => Ok(vec![Value::Integer(t.borrow().len() as i64)]),
+        Some(Value::String(s)) => Ok(vec![Value::Integer(s.len() as i64)]),
+        _ => Err(vm.error("table or string expected")),
+    }
+}
+
+/// Raises `value` as an error. A string message is prefixed with the
+/// position of the function `level` levels up the call stack: by default
+/// 1, the function that called `error`, and none for level 0.
+fn error(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let value = args.first().cloned().unwrap_or_default();
+    let level = match args.get(1) {
+        None | Some(Value::Nil) => 1,
+        Some(v) => v.to_integer().ok_or_else(|| {
+            vm.error(format!(
+                "bad argument #2 to 'error' (number expected, got {})",
+                v.type_name()
+            ))
+        })?,
+    };
+    match value {
+        Value::String(s) if level > 0 => Err(LuaError::runtime(format!(
+            "{}{}",
+            vm.location(level as usize),
+            s
+        ))),
+        v => Err(LuaError::Runtime(v)),
+    }
+}
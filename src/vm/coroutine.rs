@@ -0,0 +1,395 @@
+//
+// Coroutines. Every thread has its own value stack and call frames; the VM
+// holds those of the running thread and swaps them with the saved state of
+// another thread on `resume` and back on `yield`. Since Lua calls do not
+// recurse on the Rust stack, a yield anywhere in the Lua frames of a
+// coroutine just stops `execute`, leaving the frames in place to continue
+// from on the next resume. `pcall` marks a frame instead of calling from
+// Rust, so code it calls can yield too.
+//
+// Metamethods other than `__call`, `__close` handlers, finalizers and
+// functions called by builtins such as `table.sort` do run in a nested
+// `Vm::call`, which cannot
+// be suspended: yielding from them fails with "attempt to yield across a
+// C-call boundary", like yielding across a C function that has no
+// continuation in the reference implementation.
+//
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::Rc;
+
+use super::{CallInfo, Vm, VmResult, MAX_NATIVE_CALLS};
+use crate::errors::LuaError;
+use crate::table::Table;
+use crate::value::{BuiltinFn, Function, Thread, ThreadStatus, Upvalue, Value};
+
+type BuiltinResult = Result<Vec<Value>, LuaError>;
+
+/// The execution state of a thread that is not running.
+#[derive(Default)]
+pub struct ThreadState {
+    pub(super) stack: Vec<Value>,
+    pub(super) frames: Vec<CallInfo>,
+    pub(super) top: usize,
+    pub(super) open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    pub(super) tbc: Vec<usize>,
+    pub(super) builtins: Vec<usize>,
+    /// Where the results of the pending `coroutine.yield` call go.
+    pub(super) resume_point: Option<ResumePoint>,
+    /// The error a dead coroutine failed with, reported again by
+    /// `coroutine.close`.
+    pub(super) error: Option<LuaError>,
+}
+
+/// Where the values passed to `resume` go when a coroutine continues.
+#[derive(Clone, Copy)]
+pub(super) struct ResumePoint {
+    /// Stack slot of the called `coroutine.yield`.
+    pub(super) func_idx: usize,
+    /// Number of results wanted.
+    pub(super) nresults: Option<usize>,
+    /// Number of `pcall`s that called `coroutine.yield` directly, which
+    /// put a `true` before the values.
+    pub(super) protected: usize,
+}
+
+impl ThreadState {
+    /// Iterates over the values a suspended thread keeps alive.
+    pub(super) fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        let frames = self.frames.iter().flat_map(|frame| {
+            std::iter::once(Value::Function(frame.closure.clone()))
+                .chain(frame.varargs.iter().cloned())
+                .chain(frame.protections.iter().filter_map(|p| p.handler.clone()))
+        });
+        self.stack.iter().cloned().chain(frames)
+    }
+}
+
+/// Closes the upvalues still referring to the stack of a thread that goes
+/// away while suspended.
+impl Drop for ThreadState {
+    fn drop(&mut self) {
+        for upvalue in self.open_upvalues.drain(..) {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open { slot, .. } => slot,
+                Upvalue::Closed(_) => unreachable!("open upvalues are open"),
+            };
+            let value = self.stack.get(slot).cloned().unwrap_or_default();
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+        }
+    }
+}
+
+impl Thread {
+    /// A suspended thread that runs `body` when first resumed.
+    pub(super) fn new(body: Value) -> Self {
+        let mut state = ThreadState::default();
+        state.stack.push(body);
+        Self {
+            status: Cell::new(ThreadStatus::Suspended),
+            state: RefCell::new(state),
+        }
+    }
+
+    pub(super) fn main() -> Self {
+        Self {
+            status: Cell::new(ThreadStatus::Running),
+            state: RefCell::new(ThreadState::default()),
+        }
+    }
+}
+
+pub fn register(vm: &mut Vm) {
+    let functions: &[(&'static str, BuiltinFn)] = &[
+        ("create", create),
+        ("resume", resume),
+        ("yield", yield_),
+        ("status", status),
+        ("wrap", wrap),
+        ("isyieldable", isyieldable),
+        ("running", running),
+        ("close", close),
+    ];
+    let mut lib = Table::new();
+    for &(name, func) in functions {
+        let f = Function::Builtin {
+            name,
+            func,
+            upvalues: Vec::new(),
+        };
+        lib.set_str(name, Value::Function(Rc::new(f)));
+    }
+    vm.set_global("coroutine", Value::Table(Rc::new(RefCell::new(lib))));
+}
+
+impl Vm {
+    /// Runs `co` with `args` until it yields, returns or fails. Returns the
+    /// values it yielded or returned.
+    ///
+    /// Unlike in Lua 5.4, `co` cannot yield from inside a metamethod other
+    /// than `__call`, a `__close` handler, a finalizer or a function called
+    /// by a builtin such as `table.sort`. Those run in a nested `Vm::call`,
+    /// and yielding there fails with "attempt to yield across a C-call
+    /// boundary".
+    pub fn resume(&mut self, co: &Rc<Thread>, args: Vec<Value>) -> VmResult<Vec<Value>> {
+        match co.status.get() {
+            ThreadStatus::Suspended => {}
+            ThreadStatus::Dead => return Err(LuaError::runtime("cannot resume dead coroutine")),
+            _ => return Err(LuaError::runtime("cannot resume non-suspended coroutine")),
+        }
+        if self.native_calls >= MAX_NATIVE_CALLS {
+            return Err(LuaError::runtime("C stack overflow"));
+        }
+
+        let prev = self.switch_to(co.clone());
+        self.native_calls += 1;
+        let native_base = self.native_base.replace(self.native_calls);
+        let result = self.run_coroutine(args);
+        self.native_base = native_base;
+        self.native_calls -= 1;
+
+        let result = match result {
+            Ok(()) => {
+                co.status.set(ThreadStatus::Dead);
+                let results = self.stack[..self.top].to_vec();
+                self.stack.clear();
+                self.top = 0;
+                Ok(results)
+            }
+            Err(e) => match self.yielded.take() {
+                Some(values) => {
+                    co.status.set(ThreadStatus::Suspended);
+                    Ok(values)
+                }
+                None => {
+                    co.status.set(ThreadStatus::Dead);
+                    Err(e)
+                }
+            },
+        };
+        let status = co.status.get();
+        self.switch_back(prev, status);
+        if let Err(e) = &result {
+            co.state.borrow_mut().error = Some(e.clone());
+        }
+        result
+    }
+
+    /// Starts the body of the running coroutine, or continues it after the
+    /// call to `coroutine.yield` that suspended it, which returns `args`.
+    fn run_coroutine(&mut self, args: Vec<Value>) -> VmResult<()> {
+        let n = args.len();
+        match self.resume_point.take() {
+            None => {
+                // The body sits in the first stack slot.
+                self.stack.extend(args);
+                if !self.precall(0, n, None)? {
+                    return Ok(());
+                }
+            }
+            Some(point) => {
+                let src = self.stack.len();
+                let trues = std::iter::repeat_n(Value::Boolean(true), point.protected);
+                self.stack.extend(trues.chain(args));
+                self.move_results(src, point.protected + n, point.func_idx, point.nresults);
+                // A yield in a tail call leaves no frame to continue.
+                if self.frames.is_empty() {
+                    return Ok(());
+                }
+                self.restore_frame();
+            }
+        }
+        self.execute(0)
+    }
+
+    /// Suspends the running coroutine, making `resume` return `values`.
+    /// Returns the error that unwinds the coroutine's frames, which
+    /// `resume` recognizes by `yielded` being set.
+    pub(super) fn yield_values(&mut self, values: Vec<Value>) -> LuaError {
+        match self.native_base {
+            None => self.error("attempt to yield from outside a coroutine"),
+            Some(base) if base != self.native_calls => {
+                self.error("attempt to yield across a C-call boundary")
+            }
+            Some(_) => {
+                self.yielded = Some(values);
+                LuaError::runtime("coroutine yielded")
+            }
+        }
+    }
+
+    /// Closes the pending to-be-closed variables of a suspended or dead
+    /// coroutine and kills it. Returns the error it died with or one
+    /// raised by a `__close` metamethod.
+    pub fn close_thread(&mut self, co: &Rc<Thread>) -> VmResult<()> {
+        match co.status.get() {
+            ThreadStatus::Suspended | ThreadStatus::Dead => {}
+            ThreadStatus::Running => return Err(self.error("cannot close a running coroutine")),
+            ThreadStatus::Normal => return Err(self.error("cannot close a normal coroutine")),
+        }
+        let mut result = match co.state.borrow_mut().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+
+        let prev = self.switch_to(co.clone());
+        let native_base = self.native_base.take();
+        self.close_upvalues(0);
+        loop {
+            let error = result.as_ref().err().map(LuaError::value);
+            match self.close_tbc(0, error) {
+                Ok(()) => break,
+                Err(e) => result = Err(e),
+            }
+        }
+        self.native_base = native_base;
+        self.stack.clear();
+        self.frames.clear();
+        self.top = 0;
+        self.resume_point = None;
+        self.switch_back(prev, ThreadStatus::Dead);
+        result
+    }
+
+    /// Whether the running code may call `coroutine.yield`: it runs in a
+    /// coroutine, and not inside a metamethod or another nested call; see
+    /// `Vm::resume`.
+    pub fn is_yieldable(&self) -> bool {
+        self.native_base == Some(self.native_calls)
+    }
+
+    /// Makes `thread` the running thread, saving the state of the current
+    /// one, which is returned.
+    fn switch_to(&mut self, thread: Rc<Thread>) -> Rc<Thread> {
+        let prev = mem::replace(&mut self.thread, thread);
+        prev.status.set(ThreadStatus::Normal);
+        self.swap_state(&mut prev.state.borrow_mut());
+        let thread = self.thread.clone();
+        thread.status.set(ThreadStatus::Running);
+        self.swap_state(&mut thread.state.borrow_mut());
+        prev
+    }
+
+    /// Undoes `switch_to`, leaving the thread that ran with `status`.
+    fn switch_back(&mut self, prev: Rc<Thread>, status: ThreadStatus) {
+        let thread = mem::replace(&mut self.thread, prev);
+        thread.status.set(status);
+        self.swap_state(&mut thread.state.borrow_mut());
+        let prev = self.thread.clone();
+        prev.status.set(ThreadStatus::Running);
+        self.swap_state(&mut prev.state.borrow_mut());
+    }
+
+    fn swap_state(&mut self, state: &mut ThreadState) {
+        mem::swap(&mut self.stack, &mut state.stack);
+        mem::swap(&mut self.frames, &mut state.frames);
+        mem::swap(&mut self.top, &mut state.top);
+        mem::swap(&mut self.open_upvalues, &mut state.open_upvalues);
+        mem::swap(&mut self.tbc, &mut state.tbc);
+        mem::swap(&mut self.builtins, &mut state.builtins);
+        mem::swap(&mut self.resume_point, &mut state.resume_point);
+    }
+}
+
+fn thread_arg(vm: &Vm, args: &[Value], name: &str) -> Result<Rc<Thread>, LuaError> {
+    match args.first() {
+        Some(Value::Thread(co)) => Ok(co.clone()),
+        v => Err(vm.error(format!(
+            "bad argument #1 to '{}' (coroutine expected, got {})",
+            name,
+            v.map_or("no value", |v| v.type_name())
+        ))),
+    }
+}
+
+fn new_thread(vm: &mut Vm, args: &[Value], name: &str) -> Result<Rc<Thread>, LuaError> {
+    let body = match args.first() {
+        Some(f @ Value::Function(_)) => f.clone(),
+        v => {
+            return Err(vm.error(format!(
+                "bad argument #1 to '{}' (function expected, got {})",
+                name,
+                v.map_or("no value", |v| v.type_name())
+            )))
+        }
+    };
+    let co = Rc::new(Thread::new(body));
+    vm.heap.track_thread(&co);
+    Ok(co)
+}
+
+fn create(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let co = new_thread(vm, &args, "create")?;
+    Ok(vec![Value::Thread(co)])
+}
+
+/// Returns `true` and the values the coroutine yielded or returned, or
+/// `false` and the error message.
+fn resume(vm: &mut Vm, mut args: Vec<Value>) -> BuiltinResult {
+    let co = thread_arg(vm, &args, "resume")?;
+    args.remove(0);
+    match vm.resume(&co, args) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(e) => Ok(vec![Value::Boolean(false), e.value()]),
+    }
+}
+
+fn yield_(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    Err(vm.yield_values(args))
+}
+
+fn status(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let co = thread_arg(vm, &args, "status")?;
+    Ok(vec![Value::from(co.status().name())])
+}
+
+/// Returns a function resuming a new coroutine on every call, which raises
+/// its errors instead of returning them.
+fn wrap(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let co = new_thread(vm, &args, "wrap")?;
+    let f = Rc::new(Function::Builtin {
+        name: "wrap",
+        func: wrapped,
+        upvalues: vec![Value::Thread(co)],
+    });
+    vm.heap.track_function(&f);
+    Ok(vec![Value::Function(f)])
+}
+
+fn wrapped(vm: &mut Vm, mut args: Vec<Value>) -> BuiltinResult {
+    let co = thread_arg(vm, &args, "wrap")?;
+    args.remove(0);
+    vm.resume(&co, args).map_err(|e| {
+        // Like `auxwrap`, close the dead coroutine before propagating.
+        match co.status() {
+            ThreadStatus::Dead => vm.close_thread(&co).err().unwrap_or(e),
+            _ => e,
+        }
+    })
+}
+
+fn isyieldable(vm: &mut Vm, _: Vec<Value>) -> BuiltinResult {
+    Ok(vec![Value::Boolean(vm.is_yieldable())])
+}
+
+/// Returns the running thread and whether it is the main one.
+fn running(vm: &mut Vm, _: Vec<Value>) -> BuiltinResult {
+    let is_main = Rc::ptr_eq(&vm.thread, &vm.main_thread);
+    Ok(vec![
+        Value::Thread(vm.thread.clone()),
+        Value::Boolean(is_main),
+    ])
+}
+
+/// Returns `true`, or `false` and the error the coroutine died with.
+fn close(vm: &mut Vm, args: Vec<Value>) -> BuiltinResult {
+    let co = thread_arg(vm, &args, "close")?;
+    match vm.close_thread(&co) {
+        Ok(()) => Ok(vec![Value::Boolean(true)]),
+        Err(e) if co.status() == ThreadStatus::Dead => Ok(vec![Value::Boolean(false), e.value()]),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,475 @@
+//
+// A tracing collector for the reference cycles `Rc` cannot free on its own,
+// such as a table stored in one of its own fields or a closure captured by
+// its own upvalue.
+//
+// Every table, closure, upvalue and coroutine the VM creates is tracked
+// through a weak reference. A collection finds the roots by subtracting the
+// references tracked objects hold to each other from their strong counts:
+// whatever is left is held from outside the heap, by the VM stack, the
+// globals or the host. Objects not reachable from those roots are garbage
+// kept alive only by cycles; clearing their contents breaks the cycles and
+// lets `Rc` free them.
+//
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use crate::table::Table;
+use crate::value::{Function, Thread, ThreadStatus, Upvalue, Value};
+
+/// How much the number of tracked objects may grow, in percent of the
+/// objects that survived the last collection, before the next collection
+/// starts (Lua's "pause").
+const PAUSE: usize = 200;
+
+/// Fewest tracked objects that trigger an automatic collection.
+const MIN_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Tracked>,
+    threshold: usize,
+    /// Objects that `step` counted as created since the last collection,
+    /// on top of the tracked ones.
+    stepped: usize,
+    running: bool,
+    /// The mode `collectgarbage` last set, which does not change how
+    /// collections run.
+    mode: &'static str,
+    /// Tables with a `__gc` metamethod whose finalizer has not run yet.
+    finalizable: Vec<Rc<RefCell<Table>>>,
+}
+
+#[derive(Debug)]
+enum Tracked {
+    Table(Weak<RefCell<Table>>),
+    Function(Weak<Function>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+    Thread(Weak<Thread>),
+}
+
+/// A live object taking part in a collection.
+enum Object {
+    Table(Rc<RefCell<Table>>),
+    Function(Rc<Function>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+    Thread(Rc<Thread>),
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            threshold: MIN_THRESHOLD,
+            stepped: 0,
+            running: true,
+            mode: "incremental",
+            finalizable: Vec::new(),
+        }
+    }
+}
+
+impl Heap {
+    pub fn track_table(&mut self, table: &Rc<RefCell<Table>>) {
+        self.objects.push(Tracked::Table(Rc::downgrade(table)));
+    }
+
+    pub fn track_function(&mut self, function: &Rc<Function>) {
+        self.objects
+            .push(Tracked::Function(Rc::downgrade(function)));
+    }
+
+    pub fn track_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        self.objects.push(Tracked::Upvalue(Rc::downgrade(upvalue)));
+    }
+
+    pub fn track_thread(&mut self, thread: &Rc<Thread>) {
+        self.objects.push(Tracked::Thread(Rc::downgrade(thread)));
+    }
+
+    /// Whether enough objects were created since the last collection to
+    /// start another one.
+    pub fn should_collect(&self) -> bool {
+        self.running && self.objects.len() >= self.threshold
+    }
+
+    /// Counts `kbytes` kilobytes of objects as created, like a step of
+    /// Lua's incremental collector, and returns whether that makes a
+    /// collection due. With 0 it only checks, since a collection is the
+    /// smallest step there is. Unlike `should_collect`, it ignores `stop`.
+    pub fn step(&mut self, kbytes: usize) -> bool {
+        if kbytes > 0 {
+            let size = self.count() / self.objects.len().max(1);
+            self.stepped += kbytes.saturating_mul(1024) / size.max(1);
+        }
+        self.objects.len().saturating_add(self.stepped) >= self.threshold
+    }
+
+    /// Sets the mode `collectgarbage` reports, returning the previous one.
+    pub fn set_mode(&mut self, mode: &'static str) -> &'static str {
+        mem::replace(&mut self.mode, mode)
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Stops automatic collections; explicit ones still run.
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    pub fn restart(&mut self) {
+        self.running = true;
+    }
+
+    /// Approximate number of bytes used by the live tracked objects.
+    pub fn count(&self) -> usize {
+        self.objects
+            .iter()
+            .filter_map(Tracked::upgrade)
+            .map(|object| object.size())
+            .sum()
+    }
+
+    /// Registers a table whose metatable has a `__gc` field, like Lua's
+    /// `luaC_checkfinalizer`. Its finalizer runs once the table becomes
+    /// garbage.
+    pub fn mark_finalizable(&mut self, table: &Rc<RefCell<Table>>) {
+        if !self.finalizable.iter().any(|t| Rc::ptr_eq(t, table)) {
+            self.finalizable.push(table.clone());
+        }
+    }
+
+    /// Removes all objects waiting for finalization, to finalize them when
+    /// the VM is dropped.
+    pub fn take_finalizable(&mut self) -> Vec<Value> {
+        self.finalizable.drain(..).rev().map(Value::Table).collect()
+    }
+
+    /// Runs a full collection, freeing every object that is only kept alive
+    /// by reference cycles. Returns the objects whose finalizers must run;
+    /// they stay alive until then.
+    pub fn collect(&mut self) -> Vec<Value> {
+        let objects: Vec<Object> = self.objects.iter().filter_map(Tracked::upgrade).collect();
+        let index: HashMap<usize, usize> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.id(), i))
+            .collect();
+        let tracked = |value: &Value| value_id(value).and_then(|id| index.get(&id).copied());
+
+        // References between tracked objects, split by how they keep their
+        // targets alive.
+        let mut internal = vec![0; objects.len()];
+        let mut refs = Vec::with_capacity(objects.len());
+        for object in &objects {
+            let Some(r) = object.references(&index) else {
+                // Someone is modifying the object right now; try again later.
+                return Vec::new();
+            };
+            for &i in r.strong.iter().chain(&r.weak) {
+                internal[i] += 1;
+            }
+            for &(k, v) in &r.ephemerons {
+                internal[k] += 1;
+                internal[v] += 1;
+            }
+            refs.push(r);
+        }
+
+        // Objects referred to from outside the heap are the roots. `objects`
+        // holds one reference to each, and `finalizable` one more.
+        let finalizable: Vec<usize> = self
+            .finalizable
+            .iter()
+            .filter_map(|t| index.get(&table_id(t)).copied())
+            .collect();
+        let mut held = vec![1; objects.len()];
+        for &i in &finalizable {
+            held[i] += 1;
+        }
+        let roots = (0..objects.len())
+            .filter(|&i| objects[i].strong_count() - held[i] > internal[i])
+            .collect();
+        let mut marked = HashSet::new();
+        mark(roots, &refs, &mut marked);
+
+        // Entries of weak tables referring to garbage are removed.
+        for (i, object) in objects.iter().enumerate() {
+            if let (Object::Table(t), true) = (object, marked.contains(&i)) {
+                if refs[i].weak.is_empty() && refs[i].ephemerons.is_empty() {
+                    continue;
+                }
+                let (weak_keys, weak_values) = weak_mode(&t.borrow());
+                let dead = |v: &Value| tracked(v).is_some_and(|i| !marked.contains(&i));
+                let keys: Vec<Value> = t
+                    .borrow()
+                    .iter()
+                    .filter(|(k, v)| (weak_keys && dead(k)) || (weak_values && dead(v)))
+                    .map(|(k, _)| k)
+                    .collect();
+                let mut t = t.borrow_mut();
+                for k in keys {
+                    let _ = t.set(k, Value::Nil);
+                }
+            }
+        }
+
+        // Unreachable objects with finalizers are resurrected until their
+        // finalizers ran, together with everything they refer to.
+        let mut pending = Vec::new();
+        let mut resurrected = Vec::new();
+        self.finalizable.retain(|t| match index.get(&table_id(t)) {
+            Some(&i) if !marked.contains(&i) => {
+                pending.push(Value::Table(t.clone()));
+                resurrected.push(i);
+                false
+            }
+            _ => true,
+        });
+        mark(resurrected, &refs, &mut marked);
+
+        // Clear the unreachable objects and only then drop their contents,
+        // which may free further objects.
+        // Dead threads go first: dropping their state closes their open
+        // upvalues, which must happen before those are cleared.
+        for (i, object) in objects.iter().enumerate() {
+            if let (Object::Thread(t), false) = (object, marked.contains(&i)) {
+                t.status.set(ThreadStatus::Dead);
+                let state = mem::take(&mut *t.state.borrow_mut());
+                drop(state);
+            }
+        }
+        let mut tables = Vec::new();
+        let mut upvalues = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            if marked.contains(&i) {
+                continue;
+            }
+            match object {
+                Object::Table(t) => tables.push(mem::take(&mut *t.borrow_mut())),
+                Object::Upvalue(u) => upvalues.push(mem::replace(
+                    &mut *u.borrow_mut(),
+                    Upvalue::Closed(Value::Nil),
+                )),
+                // Closures never change once created, so any cycle through
+                // them also runs through an upvalue or a table.
+                Object::Function(_) | Object::Thread(_) => {}
+            }
+        }
+        drop(tables);
+        drop(upvalues);
+
+        self.objects = marked.iter().map(|&i| objects[i].downgrade()).collect();
+        self.threshold = (self.objects.len() * PAUSE / 100).max(MIN_THRESHOLD);
+        self.stepped = 0;
+        pending
+    }
+}
+
+/// The tracked objects an object refers to, by index.
+#[derive(Default)]
+struct Refs {
+    /// References keeping their targets alive.
+    strong: Vec<usize>,
+    /// References from weak tables.
+    weak: Vec<usize>,
+    /// Weak pointers that still keep their targets alive, like the thread
+    /// whose stack an open upvalue refers to. Unlike the others they do
+    /// not add to the strong count of their targets.
+    uncounted: Vec<usize>,
+    /// Entries of tables with weak keys: the value is alive only while the
+    /// key is.
+    ephemerons: Vec<(usize, usize)>,
+}
+
+/// Marks everything reachable from `gray`.
+fn mark(mut gray: Vec<usize>, refs: &[Refs], marked: &mut HashSet<usize>) {
+    let mut ephemeron_tables = Vec::new();
+    loop {
+        while let Some(i) = gray.pop() {
+            if marked.insert(i) {
+                gray.extend(
+                    refs[i]
+                        .strong
+                        .iter()
+                        .chain(&refs[i].uncounted)
+                        .filter(|c| !marked.contains(*c)),
+                );
+                if !refs[i].ephemerons.is_empty() {
+                    ephemeron_tables.push(i);
+                }
+            }
+        }
+        for &t in &ephemeron_tables {
+            gray.extend(
+                refs[t]
+                    .ephemerons
+                    .iter()
+                    .filter(|(k, v)| marked.contains(k) && !marked.contains(v))
+                    .map(|&(_, v)| v),
+            );
+        }
+        if gray.is_empty() {
+            break;
+        }
+    }
+}
+
+/// Whether the keys and the values of `t` are weak, from the `__mode`
+/// field of its metatable.
+fn weak_mode(t: &Table) -> (bool, bool) {
+    let mode = match t.metatable() {
+        Some(mt) => mt.borrow().get_str("__mode"),
+        None => return (false, false),
+    };
+    match mode {
+        Value::String(s) => (s.as_bytes().contains(&b'k'), s.as_bytes().contains(&b'v')),
+        _ => (false, false),
+    }
+}
+
+fn table_id(t: &Rc<RefCell<Table>>) -> usize {
+    Rc::as_ptr(t) as *const () as usize
+}
+
+/// The identity of a value that may be a tracked object.
+fn value_id(value: &Value) -> Option<usize> {
+    match value {
+        Value::Table(t) => Some(table_id(t)),
+        Value::Function(f) => Some(Rc::as_ptr(f) as *const () as usize),
+        Value::Thread(t) => Some(Rc::as_ptr(t) as *const () as usize),
+        _ => None,
+    }
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Object> {
+        match self {
+            Tracked::Table(t) => t.upgrade().map(Object::Table),
+            Tracked::Function(f) => f.upgrade().map(Object::Function),
+            Tracked::Upvalue(u) => u.upgrade().map(Object::Upvalue),
+            Tracked::Thread(t) => t.upgrade().map(Object::Thread),
+        }
+    }
+}
+
+impl Object {
+    fn id(&self) -> usize {
+        match self {
+            Object::Table(t) => Rc::as_ptr(t) as *const () as usize,
+            Object::Function(f) => Rc::as_ptr(f) as *const () as usize,
+            Object::Upvalue(u) => Rc::as_ptr(u) as *const () as usize,
+            Object::Thread(t) => Rc::as_ptr(t) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Object::Table(t) => Rc::strong_count(t),
+            Object::Function(f) => Rc::strong_count(f),
+            Object::Upvalue(u) => Rc::strong_count(u),
+            Object::Thread(t) => Rc::strong_count(t),
+        }
+    }
+
+    fn downgrade(&self) -> Tracked {
+        match self {
+            Object::Table(t) => Tracked::Table(Rc::downgrade(t)),
+            Object::Function(f) => Tracked::Function(Rc::downgrade(f)),
+            Object::Upvalue(u) => Tracked::Upvalue(Rc::downgrade(u)),
+            Object::Thread(t) => Tracked::Thread(Rc::downgrade(t)),
+        }
+    }
+
+    /// Lists the tracked objects this one refers to, given the index of
+    /// each tracked object by identity. Returns `None` when the object is
+    /// mutably borrowed and cannot be traversed.
+    fn references(&self, index: &HashMap<usize, usize>) -> Option<Refs> {
+        let tracked = |value: &Value| value_id(value).and_then(|id| index.get(&id).copied());
+        let mut refs = Refs::default();
+        match self {
+            Object::Table(t) => {
+                let t = t.try_borrow().ok()?;
+                if let Some(mt) = t.metatable() {
+                    refs.strong.extend(tracked(&Value::Table(mt)));
+                }
+                let (weak_keys, weak_values) = weak_mode(&t);
+                for (k, v) in t.iter() {
+                    match (tracked(&k), tracked(v)) {
+                        (Some(k), Some(v)) if weak_keys && !weak_values => {
+                            refs.ephemerons.push((k, v))
+                        }
+                        (k, v) => {
+                            let key_refs = if weak_keys {
+                                &mut refs.weak
+                            } else {
+                                &mut refs.strong
+                            };
+                            key_refs.extend(k);
+                            let value_refs = if weak_values {
+                                &mut refs.weak
+                            } else {
+                                &mut refs.strong
+                            };
+                            value_refs.extend(v);
+                        }
+                    }
+                }
+            }
+            Object::Function(function) => match function.as_ref() {
+                Function::Lua(closure) => {
+                    for upvalue in &closure.upvalues {
+                        let id = Rc::as_ptr(upvalue) as *const () as usize;
+                        refs.strong.extend(index.get(&id));
+                    }
+                }
+                Function::Builtin { upvalues, .. } => {
+                    refs.strong.extend(upvalues.iter().filter_map(tracked));
+                }
+                Function::Protected { .. } => {}
+            },
+            Object::Upvalue(u) => match &*u.try_borrow().ok()? {
+                Upvalue::Closed(v) => refs.strong.extend(tracked(v)),
+                Upvalue::Open { thread, .. } => {
+                    let id = thread.as_ptr() as *const () as usize;
+                    refs.uncounted.extend(index.get(&id));
+                }
+            },
+            Object::Thread(t) => {
+                let state = t.state.try_borrow().ok()?;
+                refs.strong
+                    .extend(state.values().filter_map(|v| tracked(&v)));
+                for upvalue in &state.open_upvalues {
+                    let id = Rc::as_ptr(upvalue) as *const () as usize;
+                    refs.strong.extend(index.get(&id));
+                }
+            }
+        }
+        Some(refs)
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Object::Table(t) => t.try_borrow().map_or(0, |t| t.allocated_size()),
+            Object::Function(f) => match f.as_ref() {
+                Function::Lua(closure) => {
+                    mem::size_of::<Function>()
+                        + closure.upvalues.len() * mem::size_of::<Rc<RefCell<Upvalue>>>()
+                }
+                Function::Builtin { .. } | Function::Protected { .. } => mem::size_of::<Function>(),
+            },
+            Object::Upvalue(_) => mem::size_of::<RefCell<Upvalue>>(),
+            Object::Thread(t) => {
+                let stack = t
+                    .state
+                    .try_borrow()
+                    .map_or(0, |state| state.stack.capacity());
+                mem::size_of::<Thread>() + stack * mem::size_of::<Value>()
+            }
+        }
+    }
+}
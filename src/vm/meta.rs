@@ -0,0 +1,225 @@
+//
+// Metatables and the operations that consult them ("tag methods", see
+// ltm.c and lvm.c in the reference implementation). Metamethods are called
+// through `Vm::call`, so they cannot yield; only `__call`, which the VM
+// resolves before pushing a frame, runs as an ordinary Lua call.
+//
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+use super::{Vm, VmResult};
+use crate::errors::LuaError;
+use crate::table::Table;
+use crate::value::{self, ArithOp, Value};
+
+/// Longest chain of `__index` or `__newindex` tables followed before
+/// giving up, like `MAXTAGLOOP`.
+const MAX_TAG_LOOP: usize = 2000;
+
+pub fn metatable(value: &Value) -> Option<Rc<RefCell<Table>>> {
+    match value {
+        Value::Table(t) => t.borrow().metatable(),
+        _ => None,
+    }
+}
+
+/// Returns the metamethod `event` of `value`, or nil.
+pub fn metamethod(value: &Value, event: &str) -> Value {
+    match metatable(value) {
+        Some(mt) => mt.borrow().get_str(event),
+        None => Value::Nil,
+    }
+}
+
+/// Whether `t[k]` is defined for `value`, directly or through `__index`.
+pub fn is_indexable(value: &Value) -> bool {
+    matches!(value, Value::Table(_)) || !metamethod(value, "__index").is_nil()
+}
+
+/// Whether `value` can be called, directly or through `__call`.
+pub fn is_callable(value: &Value) -> bool {
+    matches!(value, Value::Function(_)) || !metamethod(value, "__call").is_nil()
+}
+
+fn first(results: Vec<Value>) -> Value {
+    results.into_iter().next().unwrap_or_default()
+}
+
+impl Vm {
+    fn index_error(&self, value: &Value) -> LuaError {
+        self.error(format!("attempt to index a {} value", value.type_name()))
+    }
+
+    /// `t[key]`, following `__index`.
+    pub fn index(&mut self, t: &Value, key: &Value) -> VmResult<Value> {
+        let mut t = t.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let handler = match &t {
+                Value::Table(table) => {
+                    let v = table.borrow().get(key);
+                    if !v.is_nil() {
+                        return Ok(v);
+                    }
+                    match metamethod(&t, "__index") {
+                        Value::Nil => return Ok(Value::Nil),
+                        handler => handler,
+                    }
+                }
+                _ => match metamethod(&t, "__index") {
+                    Value::Nil => return Err(self.index_error(&t)),
+                    handler => handler,
+                },
+            };
+            if let Value::Function(_) = handler {
+                return Ok(first(self.call(&handler, vec![t, key.clone()])?));
+            }
+            t = handler;
+        }
+        Err(self.error("'__index' chain too long; possible loop"))
+    }
+
+    /// `t[key] = value`, following `__newindex` when `key` is absent.
+    pub fn set_index(&mut self, t: &Value, key: Value, value: Value) -> VmResult<()> {
+        let mut t = t.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let handler = match &t {
+                Value::Table(table) => {
+                    let handler = metamethod(&t, "__newindex");
+                    if handler.is_nil() || !table.borrow().get(&key).is_nil() {
+                        let result = table.borrow_mut().set(key, value);
+                        return result.map_err(|e| self.error(e.message));
+                    }
+                    handler
+                }
+                _ => match metamethod(&t, "__newindex") {
+                    Value::Nil => return Err(self.index_error(&t)),
+                    handler => handler,
+                },
+            };
+            if let Value::Function(_) = handler {
+                self.call(&handler, vec![t, key, value])?;
+                return Ok(());
+            }
+            t = handler;
+        }
+        Err(self.error("'__newindex' chain too long; possible loop"))
+    }
+
+    /// Calls the binary metamethod `event` of `a`, or else of `b`. Returns
+    /// `None` when neither operand has one.
+    pub fn call_binary_metamethod(
+        &mut self,
+        a: &Value,
+        b: &Value,
+        event: &str,
+    ) -> VmResult<Option<Value>> {
+        let handler = match metamethod(a, event) {
+            Value::Nil => metamethod(b, event),
+            handler => handler,
+        };
+        if handler.is_nil() {
+            return Ok(None);
+        }
+        Ok(Some(first(
+            self.call(&handler, vec![a.clone(), b.clone()])?,
+        )))
+    }
+
+    /// Arithmetic and bitwise operators, falling back to metamethods when
+    /// an operand is not a number. `None` means no metamethod applies.
+    pub fn arith_meta(&mut self, op: ArithOp, a: &Value, b: &Value) -> VmResult<Option<Value>> {
+        if let Some(v) = value::arith(op, a, b).map_err(|e| self.error(e.message))? {
+            return Ok(Some(v));
+        }
+        self.call_binary_metamethod(a, b, op.event())
+    }
+
+    /// `a .. b`, falling back to `__concat`. `None` means no metamethod
+    /// applies.
+    pub fn concat(&mut self, a: &Value, b: &Value) -> VmResult<Option<Value>> {
+        if let (Some(x), Some(y)) = (a.to_lua_string(), b.to_lua_string()) {
+            let mut bytes = x.as_bytes().to_vec();
+            bytes.extend_from_slice(y.as_bytes());
+            return Ok(Some(Value::String(bytes.into())));
+        }
+        self.call_binary_metamethod(a, b, "__concat")
+    }
+
+    /// `a == b`: raw equality, then `__eq` for two distinct tables.
+    pub fn equals(&mut self, a: &Value, b: &Value) -> VmResult<bool> {
+        if a == b {
+            return Ok(true);
+        }
+        if !matches!((a, b), (Value::Table(_), Value::Table(_))) {
+            return Ok(false);
+        }
+        Ok(self
+            .call_binary_metamethod(a, b, "__eq")?
+            .is_some_and(|v| v.is_truthy()))
+    }
+
+    /// `a < b` (or `a <= b` when `or_equal`), using `__lt` and `__le` for
+    /// operands other than two numbers or two strings.
+    pub fn less(&mut self, a: &Value, b: &Value, or_equal: bool) -> VmResult<bool> {
+        let ordered = matches!(
+            (a, b),
+            (
+                Value::Integer(_) | Value::Float(_),
+                Value::Integer(_) | Value::Float(_)
+            ) | (Value::String(_), Value::String(_))
+        );
+        if ordered {
+            let ord = value::compare(a, b);
+            return Ok(if or_equal {
+                ord.is_some_and(Ordering::is_le)
+            } else {
+                ord.is_some_and(Ordering::is_lt)
+            });
+        }
+        let event = if or_equal { "__le" } else { "__lt" };
+        match self.call_binary_metamethod(a, b, event)? {
+            Some(v) => Ok(v.is_truthy()),
+            None if a.type_name() == b.type_name() => {
+                Err(self.error(format!("attempt to compare two {} values", a.type_name())))
+            }
+            None => Err(self.error(format!(
+                "attempt to compare {} with {}",
+                a.type_name(),
+                b.type_name()
+            ))),
+        }
+    }
+
+    /// `#v`, using `__len` for tables that define it. `None` means the
+    /// value has no length.
+    pub fn length(&mut self, v: &Value) -> VmResult<Option<Value>> {
+        match v {
+            Value::String(s) => return Ok(Some(Value::Integer(s.len() as i64))),
+            Value::Table(t) => {
+                let handler = metamethod(v, "__len");
+                if handler.is_nil() {
+                    return Ok(Some(Value::Integer(t.borrow().len() as i64)));
+                }
+            }
+            _ => {}
+        }
+        match metamethod(v, "__len") {
+            Value::Nil => Ok(None),
+            handler => Ok(Some(first(
+                self.call(&handler, vec![v.clone(), v.clone()])?,
+            ))),
+        }
+    }
+
+    /// Converts `v` to a string like `tostring`, honouring `__tostring`.
+    pub fn tostring(&mut self, v: &Value) -> VmResult<Value> {
+        match metamethod(v, "__tostring") {
+            Value::Nil => Ok(Value::from(v.to_string())),
+            handler => match first(self.call(&handler, vec![v.clone()])?) {
+                s @ Value::String(_) => Ok(s),
+                _ => Err(self.error("'__tostring' must return a string")),
+            },
+        }
+    }
+}
@@ -0,0 +1,1177 @@
+//
+// A register-based virtual machine running the bytecode produced by
+// `compiler`. Lua functions calling each other push call frames onto a
+// shared value stack instead of recursing on the Rust stack, so deep
+// recursion and proper tail calls need no native stack.
+//
+pub mod builtin;
+pub mod coroutine;
+pub mod gc;
+pub mod meta;
+
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::Write;
+use std::rc::{Rc, Weak};
+
+use crate::compiler::code::{Instruction, Reg, Rk};
+use crate::compiler::compile;
+use crate::compiler::proto::Proto;
+use crate::errors::LuaError;
+use crate::parser::parser::parse;
+use crate::parser::stream::TokenStream;
+use crate::scanner::Scanner;
+use crate::table::Table;
+use crate::value::{self, ArithOp, Function, LuaClosure, Thread, Upvalue, Value};
+
+use coroutine::ResumePoint;
+use gc::Heap;
+
+type VmResult<T> = Result<T, LuaError>;
+
+/// Largest number of active Lua call frames.
+const MAX_FRAMES: usize = 200_000;
+
+/// Largest nesting of calls made from Rust, e.g. builtins calling back into
+/// Lua, each of which does use the native stack.
+const MAX_NATIVE_CALLS: usize = 200;
+
+pub struct Vm {
+    globals: Rc<RefCell<Table>>,
+    out: Box<dyn Write>,
+    stack: Vec<Value>,
+    frames: Vec<CallInfo>,
+    /// End of the values produced by the last instruction that left a
+    /// variable number of them (a call with `c == 0` or `VARARG` with
+    /// `b == 0`).
+    top: usize,
+    /// Upvalues still pointing into `stack`, ordered by stack slot.
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    /// Stack slots of the active to-be-closed variables, in order.
+    tbc: Vec<usize>,
+    /// The number of frames below each running builtin, innermost last.
+    /// Builtins have no frames but count as levels of the call stack.
+    builtins: Vec<usize>,
+    /// See `ThreadState::resume_point`.
+    resume_point: Option<ResumePoint>,
+    /// The running thread, whose state the fields above hold.
+    thread: Rc<Thread>,
+    main_thread: Rc<Thread>,
+    native_calls: usize,
+    /// The value of `native_calls` at which the running coroutine may
+    /// yield; `None` on the main thread.
+    native_base: Option<usize>,
+    /// Values passed to `coroutine.yield`, while it unwinds the coroutine.
+    yielded: Option<Vec<Value>>,
+    heap: Heap,
+    memory_limit: Option<usize>,
+}
+
+/// A running Lua function.
+struct CallInfo {
+    closure: Rc<Function>,
+    /// Stack slot of register 0. The function itself sits just below it.
+    base: usize,
+    pc: usize,
+    /// Arguments beyond the fixed parameters of a vararg function.
+    varargs: Vec<Value>,
+    /// Number of results the caller wants, `None` for all of them.
+    nresults: Option<usize>,
+    /// The `pcall`s that called this function, innermost first. An error
+    /// in the frame or above it ends the innermost one.
+    protections: Vec<Protection>,
+}
+
+/// A pending call of `pcall` or `xpcall`.
+struct Protection {
+    /// Stack slot of `pcall` itself, which receives its results.
+    func_idx: usize,
+    /// Number of results the caller of `pcall` wants.
+    nresults: Option<usize>,
+    /// The message handler of `xpcall`.
+    handler: Option<Value>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::with_output(std::io::stdout())
+    }
+
+    /// Creates a VM whose `print` writes to `out`.
+    pub fn with_output(out: impl Write + 'static) -> Self {
+        let main_thread = Rc::new(Thread::main());
+        let mut vm = Self {
+            globals: Rc::new(RefCell::new(Table::default())),
+            out: Box::new(out),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            top: 0,
+            open_upvalues: Vec::new(),
+            tbc: Vec::new(),
+            builtins: Vec::new(),
+            resume_point: None,
+            thread: main_thread.clone(),
+            main_thread,
+            native_calls: 0,
+            native_base: None,
+            yielded: None,
+            heap: Heap::default(),
+            memory_limit: None,
+        };
+        builtin::register(&mut vm);
+        coroutine::register(&mut vm);
+        vm
+    }
+
+    pub fn globals(&self) -> Rc<RefCell<Table>> {
+        self.globals.clone()
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.borrow().get_str(name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.borrow_mut().set_str(name, value);
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.out
+    }
+
+    /// Runs a full garbage collection cycle, freeing tables and closures
+    /// only kept alive by reference cycles.
+    pub fn collect_garbage(&mut self) {
+        let pending = self.heap.collect();
+        self.finalize(pending);
+    }
+
+    /// Approximate number of bytes held by tables and closures.
+    pub fn memory_in_use(&self) -> usize {
+        self.heap.count()
+    }
+
+    /// Limits the memory used by tables and closures to about `bytes`, or
+    /// lifts the limit. The limit is checked after every automatic
+    /// collection; code exceeding it fails with `LuaError::Memory`.
+    pub fn set_memory_limit(&mut self, bytes: Option<usize>) {
+        self.memory_limit = bytes;
+    }
+
+    /// Compiles `source` into a function without running it. `chunkname`
+    /// prefixes the messages of errors in the function, e.g. `fib.lua`.
+    pub fn load(&mut self, source: &str, chunkname: &str) -> Result<Value, LuaError> {
+        let mut scanner = Scanner::new(source);
+        scanner
+            .scan()
+            .map_err(|e| LuaError::Syntax(format!("{}:{}", chunkname, e)))?;
+        let block = parse(TokenStream::new(scanner.tokens))
+            .map_err(|e| LuaError::Syntax(format!("{}:{}", chunkname, e)))?;
+        let proto = compile(&block, &mut scanner.names, chunkname)?;
+        let main = LuaClosure {
+            proto,
+            upvalues: Vec::new(),
+        };
+        Ok(Value::Function(Rc::new(Function::Lua(main))))
+    }
+
+    /// Compiles and runs `source` as the main chunk.
+    pub fn exec_source(&mut self, source: &str) -> Result<Vec<Value>, Box<dyn Error>> {
+        let main = self.load(source, "?")?;
+        let results = self.call(&main, Vec::new())?;
+        self.out.flush()?;
+        Ok(results)
+    }
+
+    /// Builds a runtime error whose message starts with the position of
+    /// the running Lua code, like `luaL_error`.
+    pub fn error(&self, message: impl Into<String>) -> LuaError {
+        LuaError::runtime(format!("{}{}", self.location(1), message.into()))
+    }
+
+    /// Returns the position `chunk:line: ` of the function `level` levels
+    /// up the call stack, or an empty string when it is a builtin or there
+    /// is no such function. Level 1 is the caller of the running builtin,
+    /// or the running Lua function when no builtin runs.
+    pub fn location(&self, level: usize) -> String {
+        let calls = self.call_stack();
+        let level = match calls.first() {
+            Some(None) => level,
+            _ => level.saturating_sub(1),
+        };
+        match calls.get(level) {
+            Some(Some(frame)) => match frame.closure.as_ref() {
+                Function::Lua(lua) => {
+                    let line = lua.proto.lines[frame.pc.saturating_sub(1)];
+                    format!("{}:{}: ", lua.proto.source, line)
+                }
+                _ => unreachable!("only Lua closures have frames"),
+            },
+            _ => String::new(),
+        }
+    }
+
+    /// Lists the calls in progress, innermost first: the frame of each Lua
+    /// function, and `None` for each builtin, `pcall` included.
+    fn call_stack(&self) -> Vec<Option<&CallInfo>> {
+        let mut calls = Vec::new();
+        let mut builtins = self.builtins.iter().rev().peekable();
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            while builtins.next_if(|&&below| below > i).is_some() {
+                calls.push(None);
+            }
+            calls.push(Some(frame));
+            calls.extend(frame.protections.iter().map(|_| None));
+        }
+        calls.extend(builtins.map(|_| None));
+        calls
+    }
+
+    /// Calls `func` with `args` and returns all of its results.
+    pub fn call(&mut self, func: &Value, args: Vec<Value>) -> VmResult<Vec<Value>> {
+        if self.native_calls >= MAX_NATIVE_CALLS {
+            return Err(self.error("stack overflow"));
+        }
+
+        let func_idx = self.stack.len();
+        let nargs = args.len();
+        let top = self.top;
+        self.stack.push(func.clone());
+        self.stack.extend(args);
+        let depth = self.frames.len();
+
+        self.native_calls += 1;
+        let result = match self.precall(func_idx, nargs, None) {
+            Ok(true) => self.execute(depth),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        self.native_calls -= 1;
+
+        let result = match result {
+            Ok(()) => Ok(self.stack[func_idx..self.top].to_vec()),
+            Err(mut e) => {
+                self.frames.truncate(depth);
+                self.close_upvalues(func_idx);
+                // An error in a `__close` metamethod replaces the original
+                // one, and the remaining variables still get closed.
+                while let Err(close_error) = self.close_tbc(func_idx, Some(e.value())) {
+                    e = close_error;
+                }
+                Err(e)
+            }
+        };
+        self.stack.truncate(func_idx);
+        self.top = top;
+        result
+    }
+
+    /// Calls `func` like `call`, but passes the value of a runtime error to
+    /// `handler`, whose first result becomes the new error value, like
+    /// `xpcall`. An error in `handler` gives `LuaError::ErrorHandler`.
+    pub fn call_with_handler(
+        &mut self,
+        func: &Value,
+        args: Vec<Value>,
+        handler: &Value,
+    ) -> VmResult<Vec<Value>> {
+        match self.call(func, args) {
+            Err(LuaError::Runtime(v)) => match self.call(handler, vec![v]) {
+                Ok(results) => Err(LuaError::Runtime(
+                    results.into_iter().next().unwrap_or_default(),
+                )),
+                Err(e) => Err(LuaError::ErrorHandler(e.value())),
+            },
+            result => result,
+        }
+    }
+
+    /// Starts a call of the function at `func_idx` with the `nargs` values
+    /// above it. A Lua function gets a new frame and `true` is returned; a
+    /// builtin runs to completion and its results are moved to `func_idx`.
+    fn precall(
+        &mut self,
+        func_idx: usize,
+        nargs: usize,
+        nresults: Option<usize>,
+    ) -> VmResult<bool> {
+        let closure = match &self.stack[func_idx] {
+            Value::Function(f) => f.clone(),
+            v => {
+                // Call the `__call` metamethod with the value as its first
+                // argument.
+                let handler = meta::metamethod(v, "__call");
+                if !matches!(handler, Value::Function(_)) {
+                    return Err(self.error(format!("attempt to call a {} value", v.type_name())));
+                }
+                self.stack.insert(func_idx, handler);
+                return self.precall(func_idx, nargs + 1, nresults);
+            }
+        };
+        match closure.as_ref() {
+            Function::Builtin { func, upvalues, .. } => {
+                let mut args = upvalues.clone();
+                args.extend_from_slice(&self.stack[func_idx + 1..func_idx + 1 + nargs]);
+                self.builtins.push(self.frames.len());
+                let results = func(self, args);
+                self.builtins.pop();
+                let results = results.inspect_err(|_| {
+                    if self.yielded.is_some() {
+                        self.resume_point = Some(ResumePoint {
+                            func_idx,
+                            nresults,
+                            protected: 0,
+                        });
+                    }
+                })?;
+                let n = results.len();
+                self.stack.truncate(func_idx + 1 + nargs);
+                self.stack.extend(results);
+                self.move_results(func_idx + 1 + nargs, n, func_idx, nresults);
+                Ok(false)
+            }
+            Function::Protected { name, handler } => {
+                if nargs == 0 {
+                    return Err(
+                        self.error(format!("bad argument #1 to '{}' (value expected)", name))
+                    );
+                }
+                let handler = match handler {
+                    false => None,
+                    true if nargs < 2 => {
+                        return Err(self.error("bad argument #2 to 'xpcall' (value expected)"))
+                    }
+                    true => Some(self.stack.remove(func_idx + 2)),
+                };
+                let nargs = nargs - 1 - handler.is_some() as usize;
+                let p = Protection {
+                    func_idx,
+                    nresults,
+                    handler,
+                };
+                self.builtins.push(self.frames.len());
+                let result = self.precall(func_idx + 1, nargs, None);
+                self.builtins.pop();
+                self.protect(p, result)
+            }
+            Function::Lua(lua) => {
+                if self.frames.len() >= MAX_FRAMES {
+                    return Err(self.error("stack overflow"));
+                }
+                let proto = &lua.proto;
+                let base = func_idx + 1;
+                let num_params = proto.num_params as usize;
+                let varargs = if proto.is_vararg && nargs > num_params {
+                    self.stack[base + num_params..base + nargs].to_vec()
+                } else {
+                    Vec::new()
+                };
+                let frame_end = base + proto.max_stack as usize;
+                self.stack.truncate(base + nargs.min(num_params));
+                self.stack
+                    .resize(frame_end.max(base + num_params), Value::Nil);
+                self.frames.push(CallInfo {
+                    closure: closure.clone(),
+                    base,
+                    pc: 0,
+                    varargs,
+                    nresults,
+                    protections: Vec::new(),
+                });
+                Ok(true)
+            }
+        }
+    }
+
+    /// Moves `n` values from `src` down to `dest`, adjusted to `nresults`,
+    /// and sets `top` after them.
+    fn move_results(&mut self, src: usize, n: usize, dest: usize, nresults: Option<usize>) {
+        let wanted = nresults.unwrap_or(n);
+        if self.stack.len() < dest + wanted {
+            self.stack.resize(dest + wanted, Value::Nil);
+        }
+        for i in 0..wanted {
+            let v = if i < n {
+                std::mem::take(&mut self.stack[src + i])
+            } else {
+                Value::Nil
+            };
+            self.stack[dest + i] = v;
+        }
+        self.top = dest + wanted;
+    }
+
+    /// Completes the start of the call protected by `p`, given the result
+    /// of `precall` for the called function: a Lua function's new frame is
+    /// marked with `p`, and a builtin's results or error become those of
+    /// `pcall` right away.
+    fn protect(&mut self, p: Protection, result: VmResult<bool>) -> VmResult<bool> {
+        match result {
+            Ok(true) => {
+                let frame = self.frames.last_mut().expect("the called frame");
+                frame.protections.push(p);
+                Ok(true)
+            }
+            Ok(false) => {
+                self.finish_protected(&p);
+                Ok(false)
+            }
+            Err(e) if self.yielded.is_some() => {
+                // The values passed to `resume` become the results of `pcall`.
+                if let Some(point) = &mut self.resume_point {
+                    point.func_idx = p.func_idx;
+                    point.nresults = p.nresults;
+                    point.protected += 1;
+                }
+                Err(e)
+            }
+            Err(e) => {
+                let frames = self.frames.len();
+                self.fail_protected(&p, frames, e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Ends the call protected by `p` after the called function returned
+    /// its results just above `p.func_idx`, adding `true` before them.
+    fn finish_protected(&mut self, p: &Protection) {
+        let n = self.top - (p.func_idx + 1);
+        self.stack[p.func_idx] = Value::Boolean(true);
+        self.move_results(p.func_idx, n + 1, p.func_idx, p.nresults);
+    }
+
+    /// Ends the call protected by `p` with the error `e`: passes it to the
+    /// message handler, unwinds the frames from `frames` up, closes the
+    /// pending variables and returns `false` and the error value.
+    fn fail_protected(&mut self, p: &Protection, frames: usize, mut e: LuaError) {
+        if let (Some(handler), LuaError::Runtime(v)) = (&p.handler, &e) {
+            e = match self.call(handler, vec![v.clone()]) {
+                Ok(results) => LuaError::Runtime(results.into_iter().next().unwrap_or_default()),
+                Err(e) => LuaError::ErrorHandler(e.value()),
+            };
+        }
+        self.frames.truncate(frames);
+        self.close_upvalues(p.func_idx + 1);
+        while let Err(close_error) = self.close_tbc(p.func_idx + 1, Some(e.value())) {
+            e = close_error;
+        }
+        self.stack.truncate(p.func_idx);
+        self.stack.extend([Value::Boolean(false), e.value()]);
+        self.move_results(p.func_idx, 2, p.func_idx, p.nresults);
+    }
+
+    /// Shrinks the stack back to the registers of the current frame after a
+    /// call, keeping any results above them.
+    fn restore_frame(&mut self) {
+        let frame = self.frames.last().expect("a frame to return to");
+        let end = match frame.closure.as_ref() {
+            Function::Lua(lua) => frame.base + lua.proto.max_stack as usize,
+            _ => unreachable!("only Lua closures have frames"),
+        };
+        let len = end.max(self.top);
+        self.stack.truncate(len);
+        self.stack.resize(len, Value::Nil);
+    }
+
+    /// Runs Lua frames until the frame at `depth` returns. An error in a
+    /// frame called by `pcall` ends that call and the caller goes on.
+    fn execute(&mut self, depth: usize) -> VmResult<()> {
+        loop {
+            let e = match self.run(depth) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            // A yield unwinds to `resume`, keeping the frames to continue.
+            if self.yielded.is_some() {
+                return Err(e);
+            }
+            let protected = self.frames[depth..]
+                .iter()
+                .rposition(|frame| !frame.protections.is_empty());
+            let Some(i) = protected else {
+                return Err(e);
+            };
+            let frame = depth + i;
+            let mut protections = std::mem::take(&mut self.frames[frame].protections).into_iter();
+            let p = protections.next().expect("a protected frame");
+            self.fail_protected(&p, frame, e);
+            for p in protections {
+                self.finish_protected(&p);
+            }
+            if self.frames.len() == depth {
+                return Ok(());
+            }
+            self.restore_frame();
+        }
+    }
+
+    fn run(&mut self, depth: usize) -> VmResult<()> {
+        'frames: loop {
+            let frame = self.frames.last().expect("a frame to run");
+            let closure = frame.closure.clone();
+            let base = frame.base;
+            let lua = match closure.as_ref() {
+                Function::Lua(lua) => lua,
+                _ => unreachable!("only Lua closures have frames"),
+            };
+            let proto = lua.proto.as_ref();
+
+            loop {
+                let frame = self.frames.last_mut().expect("a frame to run");
+                let pc = frame.pc;
+                frame.pc += 1;
+
+                match proto.code[pc] {
+                    Instruction::Move { a, b } => {
+                        let v = self.reg(base, b).clone();
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::LoadK { a, bx } => {
+                        let v = proto.constants[bx as usize].clone();
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::LoadBool { a, b, c } => {
+                        self.set_reg(base, a, Value::Boolean(b));
+                        if c {
+                            self.skip();
+                        }
+                    }
+                    Instruction::LoadNil { a, b } => {
+                        for r in a..=a + b {
+                            self.set_reg(base, r, Value::Nil);
+                        }
+                    }
+                    Instruction::GetUpval { a, b } => {
+                        let v = self.get_upvalue(&lua.upvalues[b as usize]);
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::GetGlobal { a, bx } => {
+                        let globals = Value::Table(self.globals.clone());
+                        let v = self.index(&globals, &proto.constants[bx as usize])?;
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::GetTable { a, b, c } => {
+                        let t = self.reg(base, b).clone();
+                        if !meta::is_indexable(&t) {
+                            return Err(self.error(op_error(proto, pc, Rk::Reg(b), &t, "index")));
+                        }
+                        let key = self.rk(proto, base, c).clone();
+                        let v = self.index(&t, &key)?;
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::SetGlobal { a, bx } => {
+                        let globals = Value::Table(self.globals.clone());
+                        let key = proto.constants[bx as usize].clone();
+                        let v = self.reg(base, a).clone();
+                        self.set_index(&globals, key, v)?;
+                    }
+                    Instruction::SetUpval { a, b } => {
+                        let v = self.reg(base, a).clone();
+                        self.set_upvalue(&lua.upvalues[b as usize], v);
+                    }
+                    Instruction::SetTable { a, b, c } => {
+                        let t = self.reg(base, a).clone();
+                        if !matches!(t, Value::Table(_))
+                            && meta::metamethod(&t, "__newindex").is_nil()
+                        {
+                            return Err(self.error(op_error(proto, pc, Rk::Reg(a), &t, "index")));
+                        }
+                        let key = self.rk(proto, base, b).clone();
+                        let v = self.rk(proto, base, c).clone();
+                        self.set_index(&t, key, v)?;
+                    }
+                    Instruction::NewTable { a, b, c } => {
+                        let t = Rc::new(RefCell::new(Table::with_capacity(b as usize, c as usize)));
+                        self.heap.track_table(&t);
+                        self.set_reg(base, a, Value::Table(t));
+                        self.check_gc()?;
+                    }
+                    Instruction::Method { a, b, c } => {
+                        let obj = self.reg(base, b).clone();
+                        if !meta::is_indexable(&obj) {
+                            return Err(self.error(op_error(proto, pc, Rk::Reg(b), &obj, "index")));
+                        }
+                        let key = self.rk(proto, base, c).clone();
+                        let f = self.index(&obj, &key)?;
+                        self.set_reg(base, a + 1, obj);
+                        self.set_reg(base, a, f);
+                    }
+                    Instruction::Arith { op, a, b, c } => {
+                        let v = self.arith(proto, pc, base, op, b, c)?;
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::Unm { a, b } => {
+                        let v =
+                            self.arith(proto, pc, base, ArithOp::Unm, Rk::Reg(b), Rk::Reg(b))?;
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::BNot { a, b } => {
+                        let v =
+                            self.arith(proto, pc, base, ArithOp::BNot, Rk::Reg(b), Rk::Reg(b))?;
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::Not { a, b } => {
+                        let v = Value::Boolean(!self.reg(base, b).is_truthy());
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::Len { a, b } => {
+                        let operand = self.reg(base, b).clone();
+                        let v = match self.length(&operand)? {
+                            Some(v) => v,
+                            None => {
+                                return Err(self.error(op_error(
+                                    proto,
+                                    pc,
+                                    Rk::Reg(b),
+                                    &operand,
+                                    "get length of",
+                                )))
+                            }
+                        };
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::Concat { a, b, c } => {
+                        let v = self.concat_regs(proto, pc, base, b, c)?;
+                        self.set_reg(base, a, v);
+                    }
+                    Instruction::Jmp { sbx } => self.jump(sbx),
+                    Instruction::Eq { a, b, c } => {
+                        let x = self.rk(proto, base, b).clone();
+                        let y = self.rk(proto, base, c).clone();
+                        if self.equals(&x, &y)? != a {
+                            self.skip();
+                        }
+                    }
+                    Instruction::Lt { a, b, c } => {
+                        let x = self.rk(proto, base, b).clone();
+                        let y = self.rk(proto, base, c).clone();
+                        if self.less(&x, &y, false)? != a {
+                            self.skip();
+                        }
+                    }
+                    Instruction::Le { a, b, c } => {
+                        let x = self.rk(proto, base, b).clone();
+                        let y = self.rk(proto, base, c).clone();
+                        if self.less(&x, &y, true)? != a {
+                            self.skip();
+                        }
+                    }
+                    Instruction::Test { a, c } => {
+                        if self.reg(base, a).is_truthy() != c {
+                            self.skip();
+                        }
+                    }
+                    Instruction::Call { a, b, c } => {
+                        let func_idx = base + a as usize;
+                        let nargs = match b {
+                            0 => self.top - func_idx - 1,
+                            b => b as usize - 1,
+                        };
+                        let nresults = match c {
+                            0 => None,
+                            c => Some(c as usize - 1),
+                        };
+                        self.check_callable(proto, pc, base, a)?;
+                        if self.precall(func_idx, nargs, nresults)? {
+                            continue 'frames;
+                        }
+                        self.restore_frame();
+                    }
+                    Instruction::TailCall { a, b } => {
+                        let func_idx = base + a as usize;
+                        let nargs = match b {
+                            0 => self.top - func_idx - 1,
+                            b => b as usize - 1,
+                        };
+                        self.check_callable(proto, pc, base, a)?;
+                        self.close_upvalues(base);
+                        // Replace the finished frame by the callee.
+                        let frame = self.frames.pop().expect("a frame to replace");
+                        let dest = frame.base - 1;
+                        for i in 0..=nargs {
+                            self.stack[dest + i] = std::mem::take(&mut self.stack[func_idx + i]);
+                        }
+                        let mut result = self.precall(dest, nargs, frame.nresults);
+                        // The callee takes over the protected calls.
+                        for p in frame.protections {
+                            result = self.protect(p, result);
+                        }
+                        if result? {
+                            continue 'frames;
+                        }
+                        if self.frames.len() == depth {
+                            return Ok(());
+                        }
+                        self.restore_frame();
+                        continue 'frames;
+                    }
+                    Instruction::Return { a, b } => {
+                        let first = base + a as usize;
+                        let n = match b {
+                            0 => self.top - first,
+                            b => b as usize - 1,
+                        };
+                        self.close_upvalues(base);
+                        self.close_tbc(base, None)?;
+                        let frame = self.frames.pop().expect("a frame to return from");
+                        self.move_results(first, n, frame.base - 1, frame.nresults);
+                        for p in &frame.protections {
+                            self.finish_protected(p);
+                        }
+                        if self.frames.len() == depth {
+                            return Ok(());
+                        }
+                        self.restore_frame();
+                        continue 'frames;
+                    }
+                    Instruction::ForPrep { a, sbx } => {
+                        if !self.for_prep(base, a)? {
+                            self.jump(sbx);
+                        }
+                    }
+                    Instruction::ForLoop { a, sbx } => {
+                        if self.for_loop(base, a) {
+                            self.jump(sbx);
+                        }
+                    }
+                    Instruction::TForCall { a, c } => {
+                        let cb = base + a as usize + 3;
+                        if self.stack.len() < cb + 3 {
+                            self.stack.resize(cb + 3, Value::Nil);
+                        }
+                        for i in 0..3 {
+                            self.stack[cb + i] = self.stack[cb - 3 + i].clone();
+                        }
+                        if self.precall(cb, 2, Some(c as usize))? {
+                            continue 'frames;
+                        }
+                        self.restore_frame();
+                    }
+                    Instruction::TForLoop { a, sbx } => {
+                        let control = self.reg(base, a + 1).clone();
+                        if !control.is_nil() {
+                            self.set_reg(base, a, control);
+                            self.jump(sbx);
+                        }
+                    }
+                    Instruction::SetList { a, b, c } => {
+                        let first = base + a as usize + 1;
+                        let n = match b {
+                            0 => self.top - first,
+                            b => b as usize,
+                        };
+                        if let Value::Table(t) = self.reg(base, a) {
+                            let mut t = t.borrow_mut();
+                            for i in 0..n {
+                                t.set_int(c as i64 + i as i64 + 1, self.stack[first + i].clone());
+                            }
+                        }
+                    }
+                    Instruction::Close { a } => {
+                        self.close_upvalues(base + a as usize);
+                        self.close_tbc(base + a as usize, None)?;
+                    }
+                    Instruction::Tbc { a } => {
+                        let v = self.reg(base, a);
+                        if v.is_truthy() && meta::metamethod(v, "__close").is_nil() {
+                            let name = proto.local_name(a, pc).unwrap_or("?");
+                            return Err(
+                                self.error(format!("variable '{}' got a non-closable value", name))
+                            );
+                        }
+                        self.tbc.push(base + a as usize);
+                    }
+                    Instruction::Closure { a, bx } => {
+                        let proto = proto.protos[bx as usize].clone();
+                        let upvalues = proto
+                            .upvalues
+                            .iter()
+                            .map(|desc| {
+                                if desc.in_stack {
+                                    self.find_upvalue(base + desc.index as usize)
+                                } else {
+                                    lua.upvalues[desc.index as usize].clone()
+                                }
+                            })
+                            .collect();
+                        let closure = Rc::new(Function::Lua(LuaClosure { proto, upvalues }));
+                        self.heap.track_function(&closure);
+                        self.set_reg(base, a, Value::Function(closure));
+                        self.check_gc()?;
+                    }
+                    Instruction::VarArg { a, b } => {
+                        let first = base + a as usize;
+                        let frame = self.frames.last().expect("a running frame");
+                        let n = match b {
+                            0 => frame.varargs.len(),
+                            b => b as usize - 1,
+                        };
+                        if self.stack.len() < first + n {
+                            self.stack.resize(first + n, Value::Nil);
+                        }
+                        for i in 0..n {
+                            self.stack[first + i] =
+                                frame.varargs.get(i).cloned().unwrap_or_default();
+                        }
+                        if b == 0 {
+                            self.top = first + n;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collects garbage once enough objects were allocated. Called after
+    /// instructions that allocate, when every live value is reachable from
+    /// the stack, the globals or the host.
+    fn check_gc(&mut self) -> VmResult<()> {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+            if self
+                .memory_limit
+                .is_some_and(|limit| self.heap.count() > limit)
+            {
+                return Err(LuaError::Memory);
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls the `__gc` metamethods of collected objects. Errors in
+    /// finalizers are ignored, as they cannot be reported anywhere.
+    fn finalize(&mut self, objects: Vec<Value>) {
+        for object in objects {
+            let handler = meta::metamethod(&object, "__gc");
+            if let Value::Function(_) = handler {
+                let _ = self.call(&handler, vec![object]);
+            }
+        }
+    }
+
+    fn reg(&self, base: usize, r: Reg) -> &Value {
+        &self.stack[base + r as usize]
+    }
+
+    fn set_reg(&mut self, base: usize, r: Reg, value: Value) {
+        self.stack[base + r as usize] = value;
+    }
+
+    fn rk<'a>(&'a self, proto: &'a Proto, base: usize, rk: Rk) -> &'a Value {
+        match rk {
+            Rk::Reg(r) => self.reg(base, r),
+            Rk::Const(k) => &proto.constants[k as usize],
+        }
+    }
+
+    fn jump(&mut self, sbx: i32) {
+        let frame = self.frames.last_mut().expect("a running frame");
+        frame.pc = (frame.pc as i64 + sbx as i64) as usize;
+    }
+
+    fn skip(&mut self) {
+        self.jump(1);
+    }
+
+    fn check_callable(&self, proto: &Proto, pc: usize, base: usize, a: Reg) -> VmResult<()> {
+        match self.reg(base, a) {
+            v if meta::is_callable(v) => Ok(()),
+            v => Err(self.error(op_error(proto, pc, Rk::Reg(a), v, "call"))),
+        }
+    }
+
+    fn arith(
+        &mut self,
+        proto: &Proto,
+        pc: usize,
+        base: usize,
+        op: ArithOp,
+        b: Rk,
+        c: Rk,
+    ) -> VmResult<Value> {
+        let x = self.rk(proto, base, b).clone();
+        let y = self.rk(proto, base, c).clone();
+        if let Some(v) = self.arith_meta(op, &x, &y)? {
+            return Ok(v);
+        }
+        let (bad, rk) = if x.to_numeric().is_none() {
+            (&x, b)
+        } else {
+            (&y, c)
+        };
+        let action = if op.is_bitwise() {
+            "perform bitwise operation on"
+        } else {
+            "perform arithmetic on"
+        };
+        Err(self.error(op_error(proto, pc, rk, bad, action)))
+    }
+
+    /// Concatenates R(b) .. ... .. R(c). Like the reference implementation
+    /// this works from the right, so `__concat` sees the same operands.
+    fn concat_regs(
+        &mut self,
+        proto: &Proto,
+        pc: usize,
+        base: usize,
+        b: Reg,
+        c: Reg,
+    ) -> VmResult<Value> {
+        let values = &self.stack[base + b as usize..=base + c as usize];
+        if let Some(strings) = values
+            .iter()
+            .map(Value::to_lua_string)
+            .collect::<Option<Vec<_>>>()
+        {
+            let bytes: Vec<u8> = strings.iter().flat_map(|s| s.as_bytes()).copied().collect();
+            return Ok(Value::String(bytes.into()));
+        }
+        let mut acc = self.reg(base, c).clone();
+        for r in (b..c).rev() {
+            let left = self.reg(base, r).clone();
+            acc = match self.concat(&left, &acc)? {
+                Some(v) => v,
+                None if left.to_lua_string().is_none() => {
+                    return Err(self.error(op_error(proto, pc, Rk::Reg(r), &left, "concatenate")))
+                }
+                None => {
+                    return Err(self.error(op_error(
+                        proto,
+                        pc,
+                        Rk::Reg(r + 1),
+                        &acc,
+                        "concatenate",
+                    )))
+                }
+            };
+        }
+        Ok(acc)
+    }
+
+    /// Checks and converts the operands of a numeric `for` in R(a),
+    /// R(a+1) and R(a+2), following Lua 5.4: an integer loop when the
+    /// initial value and the step are integers, a float loop otherwise.
+    /// Returns whether the loop runs at all.
+    fn for_prep(&mut self, base: usize, a: Reg) -> VmResult<bool> {
+        let init = self.reg(base, a).clone();
+        let limit = self.reg(base, a + 1).clone();
+        let step = self.reg(base, a + 2).clone();
+        for (v, what) in [(&init, "initial value"), (&limit, "limit"), (&step, "step")] {
+            if !matches!(v, Value::Integer(_) | Value::Float(_)) {
+                return Err(self.error(format!("'for' {} must be a number", what)));
+            }
+        }
+
+        if let (Value::Integer(init), Value::Integer(step)) = (&init, &step) {
+            let (init, step) = (*init, *step);
+            if step == 0 {
+                return Err(self.error("'for' step is zero"));
+            }
+            let limit = match for_limit(&limit, step) {
+                Some(limit) if (step > 0 && init <= limit) || (step < 0 && init >= limit) => limit,
+                _ => return Ok(false),
+            };
+            // The limit register keeps the number of further iterations,
+            // computed without overflow.
+            let count = if step > 0 {
+                (limit as u64).wrapping_sub(init as u64) / step as u64
+            } else {
+                (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1)
+            };
+            self.set_reg(base, a + 1, Value::Integer(count as i64));
+            self.set_reg(base, a + 3, Value::Integer(init));
+            return Ok(true);
+        }
+
+        let (init, limit, step) = match (init.to_number(), limit.to_number(), step.to_number()) {
+            (Some(init), Some(limit), Some(step)) => (init, limit, step),
+            _ => unreachable!("'for' operands are checked to be numbers"),
+        };
+        if step == 0.0 {
+            return Err(self.error("'for' step is zero"));
+        }
+        if !((step > 0.0 && init <= limit) || (step < 0.0 && init >= limit)) {
+            return Ok(false);
+        }
+        self.set_reg(base, a, Value::Float(init));
+        self.set_reg(base, a + 1, Value::Float(limit));
+        self.set_reg(base, a + 2, Value::Float(step));
+        self.set_reg(base, a + 3, Value::Float(init));
+        Ok(true)
+    }
+
+    /// Advances a numeric `for` prepared by `for_prep`; returns whether the
+    /// loop goes on.
+    fn for_loop(&mut self, base: usize, a: Reg) -> bool {
+        let operands = (
+            self.reg(base, a).clone(),
+            self.reg(base, a + 1).clone(),
+            self.reg(base, a + 2).clone(),
+        );
+        let next = match operands {
+            (Value::Integer(index), Value::Integer(count), Value::Integer(step)) => {
+                if count == 0 {
+                    return false;
+                }
+                let count = Value::Integer((count as u64 - 1) as i64);
+                self.set_reg(base, a + 1, count);
+                Value::Integer(index.wrapping_add(step))
+            }
+            (Value::Float(index), Value::Float(limit), Value::Float(step)) => {
+                let index = index + step;
+                if !((step > 0.0 && index <= limit) || (step < 0.0 && index >= limit)) {
+                    return false;
+                }
+                Value::Float(index)
+            }
+            _ => unreachable!("'for' operands are prepared by FORPREP"),
+        };
+        self.set_reg(base, a, next.clone());
+        self.set_reg(base, a + 3, next);
+        true
+    }
+
+    /// Reads an upvalue, which may be open in the stack of another thread.
+    fn get_upvalue(&self, upvalue: &RefCell<Upvalue>) -> Value {
+        match &*upvalue.borrow() {
+            Upvalue::Open { thread, slot } if self.is_running(thread) => self.stack[*slot].clone(),
+            Upvalue::Open { thread, slot } => match thread.upgrade() {
+                Some(thread) => thread.state.borrow().stack[*slot].clone(),
+                None => Value::Nil,
+            },
+            Upvalue::Closed(v) => v.clone(),
+        }
+    }
+
+    fn set_upvalue(&mut self, upvalue: &RefCell<Upvalue>, value: Value) {
+        match &mut *upvalue.borrow_mut() {
+            Upvalue::Open { thread, slot } if self.is_running(thread) => self.stack[*slot] = value,
+            Upvalue::Open { thread, slot } => {
+                if let Some(thread) = thread.upgrade() {
+                    thread.state.borrow_mut().stack[*slot] = value;
+                }
+            }
+            Upvalue::Closed(v) => *v = value,
+        }
+    }
+
+    fn is_running(&self, thread: &Weak<Thread>) -> bool {
+        std::ptr::eq(thread.as_ptr(), Rc::as_ptr(&self.thread))
+    }
+
+    /// Returns the open upvalue for stack slot `slot`, creating it unless a
+    /// closure already captured the variable.
+    fn find_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let mut pos = self.open_upvalues.len();
+        while pos > 0 {
+            let open = match *self.open_upvalues[pos - 1].borrow() {
+                Upvalue::Open { slot, .. } => slot,
+                Upvalue::Closed(_) => unreachable!("open upvalues are open"),
+            };
+            if open == slot {
+                return self.open_upvalues[pos - 1].clone();
+            }
+            if open < slot {
+                break;
+            }
+            pos -= 1;
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open {
+            thread: Rc::downgrade(&self.thread),
+            slot,
+        }));
+        self.heap.track_upvalue(&upvalue);
+        self.open_upvalues.insert(pos, upvalue.clone());
+        upvalue
+    }
+
+    /// Calls the `__close` metamethods of the to-be-closed variables at
+    /// stack slot `level` or above, latest first. `error` is the error
+    /// being propagated, if any.
+    fn close_tbc(&mut self, level: usize, error: Option<Value>) -> VmResult<()> {
+        while let Some(&slot) = self.tbc.last() {
+            if slot < level {
+                break;
+            }
+            self.tbc.pop();
+            let v = self.stack.get(slot).cloned().unwrap_or_default();
+            let handler = meta::metamethod(&v, "__close");
+            if !handler.is_nil() {
+                self.call(&handler, vec![v, error.clone().unwrap_or_default()])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Closes every open upvalue pointing at stack slot `level` or above.
+    fn close_upvalues(&mut self, level: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.borrow() {
+                Upvalue::Open { slot, .. } => slot,
+                Upvalue::Closed(_) => unreachable!("open upvalues are open"),
+            };
+            if slot < level {
+                break;
+            }
+            let value = self.stack.get(slot).cloned().unwrap_or_default();
+            *upvalue.borrow_mut() = Upvalue::Closed(value);
+            self.open_upvalues.pop();
+        }
+    }
+}
+
+/// Runs the finalizers of all objects still alive, like `lua_close`.
+impl Drop for Vm {
+    fn drop(&mut self) {
+        let pending = self.heap.take_finalizable();
+        self.finalize(pending);
+    }
+}
+
+/// Builds the error for an operation that `value`, read from `rk`, does not
+/// support, naming the variable it came from like Lua's ` (global 'x')`
+/// suffix.
+fn op_error(proto: &Proto, pc: usize, rk: Rk, value: &Value, action: &str) -> String {
+    let name = match rk {
+        Rk::Reg(r) => proto.object_name(pc, r),
+        Rk::Const(k) => match &proto.constants[k as usize] {
+            Value::String(s) => Some(("constant", s.to_string())),
+            _ => None,
+        },
+    };
+    let suffix = match name {
+        Some((kind, name)) => format!(" ({} '{}')", kind, name),
+        None => String::new(),
+    };
+    format!(
+        "attempt to {} a {} value{}",
+        action,
+        value.type_name(),
+        suffix
+    )
+}
+
+/// Converts the limit of an integer loop, clipping floats; `None` when the
+/// loop must not run at all.
+fn for_limit(limit: &Value, step: i64) -> Option<i64> {
+    match limit {
+        Value::Integer(limit) => Some(*limit),
+        Value::Float(f) if f.is_nan() => None,
+        Value::Float(f) => {
+            let f = if step > 0 { f.floor() } else { f.ceil() };
+            match value::float_to_integer(f) {
+                Some(limit) => Some(limit),
+                None if f > 0.0 => (step > 0).then_some(i64::MAX),
+                None => (step < 0).then_some(i64::MIN),
+            }
+        }
+        _ => None,
+    }
+}
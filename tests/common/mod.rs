@@ -0,0 +1,81 @@
+//
+// Helpers shared by the integration tests.
+//
+use std::cell::RefCell;
+use std::error::Error;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use purua::interp::Interpreter;
+use purua::vm::Vm;
+
+/// A writer that keeps what `print` writes, so tests can compare it.
+#[derive(Clone, Default)]
+pub struct Output(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    pub fn contents(&self) -> Result<String, Box<dyn Error>> {
+        Ok(String::from_utf8(self.0.borrow().clone())?)
+    }
+}
+
+/// Runs `source` on a fresh VM and returns what it printed.
+#[allow(dead_code)]
+pub fn run(source: &str) -> Result<String, Box<dyn Error>> {
+    let out = Output::default();
+    let mut vm = Vm::with_output(out.clone());
+    vm.exec_source(source)?;
+    out.contents()
+}
+
+/// Runs `source` on a fresh tree-walking interpreter and returns what it
+/// printed.
+#[allow(dead_code)]
+pub fn interpret(source: &str) -> Result<String, Box<dyn Error>> {
+    let out = Output::default();
+    let mut interp = Interpreter::with_output(out.clone());
+    interp.exec_source(source)?;
+    out.contents()
+}
+
+/// Runs each case on both the VM and the tree-walking interpreter. A case
+/// names the test, lists chunks run one after another in the same state,
+/// and gives what they print together.
+#[allow(dead_code)]
+pub fn assert_both_print(cases: &[(&str, &[&str], &str)]) {
+    for &(name, chunks, expected) in cases {
+        let out = Output::default();
+        let mut vm = Vm::with_output(out.clone());
+        for chunk in chunks {
+            if let Err(e) = vm.exec_source(chunk) {
+                panic!("{}: the VM failed: {}", name, e);
+            }
+        }
+        assert_eq!(expected, out.contents().unwrap(), "{} on the VM", name);
+
+        let out = Output::default();
+        let mut interp = Interpreter::with_output(out.clone());
+        for chunk in chunks {
+            if let Err(e) = interp.exec_source(chunk) {
+                panic!("{}: the interpreter failed: {}", name, e);
+            }
+        }
+        assert_eq!(
+            expected,
+            out.contents().unwrap(),
+            "{} on the interpreter",
+            name
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::rc::Rc;
+
+use purua::compiler::code::Instruction::*;
+use purua::compiler::code::Rk;
+use purua::compiler::compile;
+use purua::compiler::proto::Proto;
+use purua::parser::ast::{
+    AttName, AttNameList, Block, Chunk, Expr, ExprList, LastStat, NameList, PrefixExp, Stat, Var,
+};
+use purua::parser::parser::parse;
+use purua::parser::stream::TokenStream;
+use purua::scanner::{Interner, Scanner, Token, TokenType};
+use purua::value::{ArithOp, Value};
+
+extern crate purua;
+
+fn compile_source(source: &str) -> Result<Rc<Proto>, Box<dyn Error>> {
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+    let block = parse(TokenStream::new(scanner.tokens))?;
+    Ok(compile(&block, &mut scanner.names, "test.lua")?)
+}
+
+#[test]
+fn test_compile_locals_and_calls() -> Result<(), Box<dyn Error>> {
+    let proto = compile_source("local a = 1\nprint(a + 2)\n")?;
+    assert_eq!(
+        vec![
+            LoadK { a: 0, bx: 0 },
+            GetGlobal { a: 1, bx: 1 },
+            Arith {
+                op: ArithOp::Add,
+                a: 2,
+                b: Rk::Reg(0),
+                c: Rk::Const(2)
+            },
+            Call { a: 1, b: 2, c: 1 },
+            Return { a: 0, b: 1 },
+        ],
+        proto.code
+    );
+    assert_eq!(vec![1, 2, 2, 2, 2], proto.lines);
+    assert_eq!(Some("a"), proto.local_name(0, 1));
+    assert!(proto.is_vararg);
+    Ok(())
+}
+
+#[test]
+fn test_compile_constants() -> Result<(), Box<dyn Error>> {
+    let proto = compile_source("local a, b, c, d = 1, 1.0, 1, -2")?;
+    assert_eq!(3, proto.constants.len());
+    assert!(matches!(proto.constants[0], Value::Integer(1)));
+    assert!(matches!(proto.constants[1], Value::Float(n) if n == 1.0));
+    assert!(matches!(proto.constants[2], Value::Integer(-2)));
+    assert_eq!(LoadK { a: 2, bx: 0 }, proto.code[2]);
+    Ok(())
+}
+
+#[test]
+fn test_compile_numeric_for() -> Result<(), Box<dyn Error>> {
+    let proto = compile_source("local s = 0\nfor i = 1, 10 do\n  s = s + i\nend\n")?;
+    assert_eq!(
+        vec![
+            LoadK { a: 0, bx: 0 },
+            LoadK { a: 1, bx: 1 },
+            LoadK { a: 2, bx: 2 },
+            LoadK { a: 3, bx: 1 },
+            ForPrep { a: 1, sbx: 2 },
+            Arith {
+                op: ArithOp::Add,
+                a: 0,
+                b: Rk::Reg(0),
+                c: Rk::Reg(4)
+            },
+            ForLoop { a: 1, sbx: -2 },
+            Return { a: 0, b: 1 },
+        ],
+        proto.code
+    );
+    assert_eq!(3, proto.lines[5]);
+    assert_eq!(Some("i"), proto.local_name(4, 5));
+    Ok(())
+}
+
+#[test]
+fn test_compile_conditions() -> Result<(), Box<dyn Error>> {
+    let proto = compile_source("local a, b\nif a < 3 and not b then a = 1 else a = 2 end\n")?;
+    assert_eq!(
+        vec![
+            LoadNil { a: 0, b: 1 },
+            Lt {
+                a: false,
+                b: Rk::Reg(0),
+                c: Rk::Const(0)
+            },
+            Jmp { sbx: 4 },
+            Test { a: 1, c: true },
+            Jmp { sbx: 2 },
+            LoadK { a: 0, bx: 1 },
+            Jmp { sbx: 1 },
+            LoadK { a: 0, bx: 2 },
+            Return { a: 0, b: 1 },
+        ],
+        proto.code
+    );
+    Ok(())
+}
+
+#[test]
+fn test_compile_closures() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local n = 0
+function counter()
+  return function()
+    n = n + 1
+    return n
+  end
+end
+"#;
+    let proto = compile_source(source)?;
+    let counter = &proto.protos[0];
+    let inner = &counter.protos[0];
+    assert_eq!(3, counter.line_defined);
+    assert!(counter.upvalues[0].in_stack);
+    assert_eq!(0, counter.upvalues[0].index);
+    assert!(!inner.upvalues[0].in_stack);
+    assert_eq!(0, inner.upvalues[0].index);
+    assert_eq!("n", inner.upvalues[0].name);
+    assert_eq!(SetUpval { a: 0, b: 0 }, inner.code[2]);
+    Ok(())
+}
+
+#[test]
+fn test_compile_tail_call_and_varargs() -> Result<(), Box<dyn Error>> {
+    let proto = compile_source("return f(...)")?;
+    assert_eq!(
+        vec![
+            GetGlobal { a: 0, bx: 0 },
+            VarArg { a: 1, b: 0 },
+            TailCall { a: 0, b: 0 },
+            Return { a: 0, b: 0 },
+            Return { a: 0, b: 1 },
+        ],
+        proto.code
+    );
+    Ok(())
+}
+
+#[test]
+fn test_compile_arithmetic_chain_registers() -> Result<(), Box<dyn Error>> {
+    let terms = vec!["a"; 250];
+    let proto = compile_source(&format!("local a = 1 x = {}", terms.join(" + ")))?;
+    assert_eq!(
+        Arith {
+            op: ArithOp::Add,
+            a: 1,
+            b: Rk::Reg(1),
+            c: Rk::Reg(0)
+        },
+        proto.code[proto.code.len() - 3]
+    );
+    assert_eq!(2, proto.max_stack);
+    Ok(())
+}
+
+#[test]
+fn test_compile_break_closes_upvalues() -> Result<(), Box<dyn Error>> {
+    let source = "while true do\n  local x = 1\n  g = function() return x end\n  break\nend\n";
+    let proto = compile_source(source)?;
+    assert_eq!(Jmp { sbx: 2 }, proto.code[3]);
+    assert_eq!(Close { a: 0 }, proto.code[6]);
+    Ok(())
+}
+
+#[test]
+fn test_compile_goto() -> Result<(), Box<dyn Error>> {
+    let source = "for n = 1, 3 do\n  do\n    local y = n\n    g = function() return y end\n    goto next\n  end\n  ::next::\nend\n::top::\nlocal x = 1\ngoto top\n";
+    let proto = compile_source(source)?;
+    // The forward goto leaves a block with a captured local, so its label
+    // closes upvalues; the backward one closes `x` before jumping.
+    assert_eq!(Jmp { sbx: 1 }, proto.code[7]);
+    assert_eq!(Close { a: 3 }, proto.code[9]);
+    assert_eq!(Close { a: 0 }, proto.code[12]);
+    assert_eq!(Jmp { sbx: -3 }, proto.code[13]);
+    Ok(())
+}
+
+#[test]
+fn test_compile_errors() {
+    let err = compile_source("local a = 1\n\nlocal b break").unwrap_err();
+    assert_eq!("test.lua:3: break outside a loop", err.to_string());
+
+    // The parser never produces a `for ... in` without names.
+    let for_in = Stat::ForIn(
+        NameList(Vec::new()),
+        ExprList(vec![Expr::Nil]),
+        Block(Chunk(Vec::new(), None)),
+    );
+    let block = Block(Chunk(vec![for_in], None));
+    let err = compile(&block, &mut Interner::default(), "test.lua").unwrap_err();
+    assert_eq!("test.lua:1: 'for' loop without variables", err.to_string());
+}
+
+#[test]
+fn test_compile_goto_errors() {
+    let cases = [
+        (
+            "goto l\nlocal x = 1\n::l:: print(x)",
+            "test.lua:3: <goto l> at line 1 jumps into the scope of local 'x'",
+        ),
+        (
+            "::a::\ndo ::a:: end",
+            "test.lua:2: label 'a' already defined on line 1",
+        ),
+        (
+            "do ::a:: end\ngoto a",
+            "test.lua:2: no visible label 'a' for <goto> at line 2",
+        ),
+        (
+            "local function f() goto out end\n::out::",
+            "test.lua:1: no visible label 'out' for <goto> at line 1",
+        ),
+        (
+            "repeat goto l; local x ::l:: until x",
+            "test.lua:1: <goto l> at line 1 jumps into the scope of local 'x'",
+        ),
+    ];
+    for (source, message) in cases {
+        let err = compile_source(source).unwrap_err();
+        assert_eq!(message, err.to_string());
+    }
+    assert!(compile_source("do goto l; local x ::l:: end").is_ok());
+}
+
+#[test]
+fn test_compile_resolves_names_by_symbol() -> Result<(), Box<dyn Error>> {
+    let mut scanner = Scanner::new("local x = 1\nreturn x");
+    scanner.scan()?;
+    let block = parse(TokenStream::new(scanner.tokens))?;
+    let proto = compile(&block, &mut scanner.names, "test.lua")?;
+    assert_eq!(Return { a: 0, b: 2 }, proto.code[1]);
+
+    // Tokens built by hand have their names interned by the compiler.
+    let name = |lexeme| Token {
+        lexeme,
+        ..Token::from(TokenType::Name)
+    };
+    let local = Stat::LocalDeclVar(AttNameList(vec![AttName(name("x"), None)]), None);
+    let var = Expr::PrefixExp(PrefixExp::PrefixVar(Box::new(Var::VarName(name("x")))));
+    let ret = LastStat::Return(Some(ExprList(vec![var])));
+    let block = Block(Chunk(vec![local], Some(ret)));
+    let mut names = Interner::default();
+    let proto = compile(&block, &mut names, "test.lua")?;
+    assert_eq!(Return { a: 0, b: 2 }, proto.code[1]);
+    assert!(names.get("x").is_some());
+    Ok(())
+}
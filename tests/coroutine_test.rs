@@ -0,0 +1,278 @@
+use std::error::Error;
+use std::io;
+use std::rc::Rc;
+
+use purua::value::Value;
+use purua::vm::Vm;
+
+extern crate purua;
+
+mod common;
+
+use common::run;
+
+#[test]
+fn test_resume_and_yield() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local co = coroutine.create(function(a, b)
+  print("start", a, b)
+  local c = coroutine.yield(a + b)
+  print("got", c)
+  local d, e = coroutine.yield(c * 2)
+  return d + e
+end)
+print(coroutine.status(co))
+print(coroutine.resume(co, 1, 2))
+print(coroutine.resume(co, 10))
+print(coroutine.resume(co, 3, 4))
+print(coroutine.status(co), coroutine.resume(co))
+"#;
+    assert_eq!(
+        "suspended\nstart\t1\t2\ntrue\t3\ngot\t10\ntrue\t20\ntrue\t7\ndead\tfalse\tcannot resume dead coroutine\n",
+        run(source)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_yield_across_lua_frames() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function leaves(t)
+  for i, v in ipairs(t) do
+    if type(v) == "table" then
+      leaves(v)
+    else
+      coroutine.yield(v)
+    end
+  end
+end
+local function iter(t)
+  local co = coroutine.create(function() leaves(t) end)
+  return function()
+    local ok, v = coroutine.resume(co)
+    return v
+  end
+end
+for v in iter({ 1, { 2, { 3, 4 } }, 5 }) do
+  print(v)
+end
+"#;
+    assert_eq!("1\n2\n3\n4\n5\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_yield_from_iterator() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function numbers(n)
+  local i = 0
+  return function()
+    i = i + 1
+    if i <= n then
+      coroutine.yield("next " .. i)
+      return i
+    end
+  end
+end
+local gen = coroutine.wrap(function()
+  local sum = 0
+  for i in numbers(3) do
+    sum = sum + i
+  end
+  return "sum " .. sum
+end)
+for i = 1, 4 do
+  print(gen())
+end
+"#;
+    assert_eq!("next 1\nnext 2\nnext 3\nsum 6\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_yield_inside_pcall() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local co = coroutine.wrap(function(a)
+  local ok, v = pcall(function(x)
+    local y = coroutine.yield(x + 1)
+    return y * 2
+  end, a)
+  print(ok, v)
+  print(pcall(coroutine.yield, "direct"))
+  local ok, e = pcall(function()
+    coroutine.yield("before error")
+    error("after", 0)
+  end)
+  print(ok, e)
+  print(xpcall(function()
+    coroutine.yield("in xpcall")
+    error("boom", 0)
+  end, function(m) return "handled " .. m end))
+  return "done"
+end)
+for _, v in ipairs({ 1, 20, "resumed", 0, 0 }) do
+  print(co(v))
+end
+"#;
+    assert_eq!(
+        "2\ntrue\t40\ndirect\ntrue\tresumed\nbefore error\n\
+         false\tafter\nin xpcall\nfalse\thandled boom\ndone\n",
+        run(source)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_status_transitions() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local outer
+local inner = coroutine.create(function()
+  print(coroutine.status(outer), coroutine.isyieldable())
+end)
+outer = coroutine.create(function()
+  local co, main = coroutine.running()
+  print(coroutine.status(co), main)
+  coroutine.resume(inner)
+  print(coroutine.resume(outer))
+end)
+local co, main = coroutine.running()
+print(main, coroutine.isyieldable())
+coroutine.resume(outer)
+print(coroutine.status(outer), coroutine.status(inner))
+"#;
+    assert_eq!(
+        "true\tfalse\nrunning\tfalse\nnormal\ttrue\n\
+         false\tcannot resume non-suspended coroutine\ndead\tdead\n",
+        run(source)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_errors() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local co = coroutine.create(function()
+  coroutine.yield(1)
+  local x = nil + 1
+end)
+print(coroutine.resume(co))
+local ok, msg = coroutine.resume(co)
+print(ok, coroutine.status(co))
+print(coroutine.resume(coroutine.create(print), "builtin"))
+"#;
+    assert_eq!("true\t1\nfalse\tdead\nbuiltin\ntrue\n", run(source)?);
+
+    let mut vm = Vm::with_output(io::sink());
+    let err = vm.exec_source("coroutine.yield(1)").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("attempt to yield from outside a coroutine"));
+    let source = r#"
+local f = coroutine.wrap(function() error_here() end)
+f()
+"#;
+    let err = vm.exec_source(source).unwrap_err();
+    assert!(err.to_string().contains("attempt to call a nil value"));
+    Ok(())
+}
+
+#[test]
+fn test_yield_inside_metamethod() -> Result<(), Box<dyn Error>> {
+    // Metamethods run in a nested call on the Rust stack, which a
+    // coroutine cannot be suspended in. `__call` is the exception: the
+    // called object's `__call` function runs as an ordinary Lua call.
+    let cases = [
+        ("t.x", false),
+        ("t + 1", false),
+        ("t < t", false),
+        ("t .. ''", false),
+        ("#t", false),
+        ("t == setmetatable({}, getmetatable(t))", false),
+        ("t()", true),
+    ];
+    for (expr, yieldable) in cases {
+        let source = format!(
+            r#"
+local y = function() print(coroutine.isyieldable()) return coroutine.yield(1) end
+local t = setmetatable({{}}, {{
+  __index = y, __add = y, __lt = y, __concat = y, __len = y, __eq = y, __call = y,
+}})
+local co = coroutine.create(function() return {} end)
+local ok, msg = coroutine.resume(co)
+print(ok, coroutine.status(co), msg)
+"#,
+            expr
+        );
+        let output = run(&source)?;
+        let expected = if yieldable {
+            "true\ntrue\tsuspended\t1\n"
+        } else {
+            "false\nfalse\tdead\t?:6: attempt to yield across a C-call boundary\n"
+        };
+        assert_eq!(expected, output, "{}", expr);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_shared_upvalues() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local n = 0
+local co = coroutine.wrap(function()
+  local m = 100
+  local function get() return m end
+  while true do
+    n = n + 1
+    m = m + 1
+    coroutine.yield(get)
+  end
+end)
+local get = co()
+co()
+print(n, get())
+"#;
+    assert_eq!("2\t102\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_close() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local co = coroutine.create(function()
+  local t <close> = setmetatable({}, { __close = function() print("closed") end })
+  coroutine.yield()
+end)
+coroutine.resume(co)
+print(coroutine.close(co), coroutine.status(co))
+local failed = coroutine.create(function() local x = {} + 1 end)
+coroutine.resume(failed)
+print(coroutine.close(failed))
+"#;
+    let output = run(source)?;
+    assert!(output.starts_with("closed\ntrue\tdead\nfalse\t"));
+    assert!(output.contains("attempt to perform arithmetic on a table value"));
+    Ok(())
+}
+
+#[test]
+fn test_collect_suspended_coroutines() -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::with_output(io::sink());
+    let source = r#"
+local co
+co = coroutine.create(function()
+  local self = co
+  coroutine.yield()
+end)
+coroutine.resume(co)
+return co
+"#;
+    let co = vm.exec_source(source)?.remove(0);
+    let weak = match &co {
+        Value::Thread(t) => Rc::downgrade(t),
+        v => return Err(format!("expected a thread, got {}", v).into()),
+    };
+    drop(co);
+    vm.collect_garbage();
+    assert!(weak.upgrade().is_none());
+    Ok(())
+}
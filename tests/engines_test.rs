@@ -0,0 +1,261 @@
+extern crate purua;
+
+mod common;
+
+use common::assert_both_print;
+
+// Globals some of the examples use without defining them.
+const FIB: &str = r#"
+function fib(n)
+  if n < 2 then return n end
+  return fib(n - 1) + fib(n - 2)
+end
+"#;
+const DEFUN: &str = r#"
+my = {}
+function myfunc()
+  myfunc1(1)
+  myfunc2()
+  myfunc3(1, 2, 3)
+  my.func4()
+end
+"#;
+
+#[test]
+fn test_lua_examples() {
+    assert_both_print(&[
+        (
+            "defun.lua",
+            &[DEFUN, include_str!("../lua_examples/defun.lua")],
+            concat!(
+                "Call my own func!\n\nCall my own func! 2\n\n",
+                "Call my own func! 3\n\nCall my own func! 4\n\n",
+            ),
+        ),
+        (
+            "fib.lua",
+            &[FIB, include_str!("../lua_examples/fib.lua")],
+            "55\n\n\n",
+        ),
+        (
+            "fib2.lua",
+            &[include_str!("../lua_examples/fib2.lua")],
+            "121393\n\n\n",
+        ),
+        (
+            "while.lua",
+            &["a = 0", include_str!("../lua_examples/while.lua")],
+            "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n",
+        ),
+        (
+            "forin.lua",
+            &[include_str!("../lua_examples/forin.lua")],
+            concat!(
+                "index: \n1\n \nvalue: \n1\n\n\n",
+                "index: \n2\n \nvalue: \n3\n\n\n",
+                "index: \n3\n \nvalue: \n5\n\n\n",
+                "index: \n4\n \nvalue: \n7\n\n\n",
+                "index: \n5\n \nvalue: \n9\n\n\n",
+                "message: \n1\n\n\nmessage: \n3\n\n\nmessage: \n5\n\n\n",
+                "message: \n7\n\n\nmessage: \n9\n\n\n",
+            ),
+        ),
+        (
+            "ifthenelse.lua",
+            &["n = 2", include_str!("../lua_examples/ifthenelse.lua")],
+            "hi\n",
+        ),
+        (
+            "ifthen.lua",
+            &[include_str!("../lua_examples/ifthen.lua")],
+            "No match\n\nDone\n\n",
+        ),
+    ]);
+}
+
+#[test]
+fn test_language() {
+    assert_both_print(&[
+        (
+            "lexical scope",
+            &[r#"
+local x = 1
+local function get() return x end
+local x = 2
+print(get(), x)
+do
+  local x = 3
+end
+print(x)
+"#],
+            "1\t2\n2\n",
+        ),
+        (
+            "multiple values",
+            &[r#"
+function pair() return 1, 2 end
+function count(...)
+  local t = { ... }
+  return #t
+end
+local a, b, c = pair()
+print(a, b, c, count(pair()), count(pair(), 10), count(...))
+"#],
+            "1\t2\tnil\t2\t2\t0\n",
+        ),
+        (
+            "method call",
+            &[r#"
+obj = { n = 40 }
+function obj:add(m)
+  self.n = self.n + m
+  return self.n
+end
+print(obj:add(2))
+"#],
+            "42\n",
+        ),
+        (
+            "integer and float",
+            &[r#"
+print(1 + 2, 1 + 2.0, 7 // 2, 7 / 2, 2^2, 10 % 3, 3 == 3.0, "10" + 1)
+for i = 1, 2 do print(i) end
+for i = 1.0, 2 do print(i) end
+"#],
+            "3\t3.0\t3\t3.5\t4.0\t1\ttrue\t11\n1\n2\n1.0\n2.0\n",
+        ),
+        (
+            "table constructor",
+            &[r#"
+local t = { 1, 3, 5, n = "x", [10] = 7, [2.0] = "two" }
+print(#t, t[2], t.n, t[10])
+for i, v in ipairs({ "a", "b", nil, "d" }) do print(i, v) end
+"#],
+            "3\t3\tx\t7\n1\ta\n2\tb\n",
+        ),
+        (
+            "closure counter",
+            &[r#"
+function counter()
+  local n = 0
+  return function()
+    n = n + 1
+    return n
+  end
+end
+local c1, c2 = counter(), counter()
+print(c1(), c1(), c1(), c2())
+"#],
+            "1\t2\t3\t1\n",
+        ),
+        (
+            "shared upvalues",
+            &[r#"
+local function pair()
+  local v = 0
+  local function get() return v end
+  local function set(x) v = x end
+  return get, set
+end
+local get, set = pair()
+set(10)
+local x = 1
+local function bump() x = x + 1 end
+bump()
+bump()
+print(get(), x)
+"#],
+            "10\t3\n",
+        ),
+        (
+            "fresh upvalue per iteration",
+            &[r#"
+local fns = {}
+for i = 1, 3 do
+  fns[i] = function() return i end
+end
+local j = 0
+while j < 3 do
+  j = j + 1
+  local k = j * 10
+  fns[j + 3] = function() return k end
+end
+print(fns[1](), fns[2](), fns[3](), fns[4](), fns[6]())
+"#],
+            "1\t2\t3\t10\t30\n",
+        ),
+        (
+            "recursive local function",
+            &[r#"
+local function fact(n)
+  if n <= 1 then return 1 end
+  return n * fact(n - 1)
+end
+local fib
+fib = function(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+print(fact(10), fib(15))
+"#],
+            "3628800\t610\n",
+        ),
+        (
+            "goto",
+            &[r#"
+for i = 1, 3 do
+  if i == 2 then goto continue end
+  print(i)
+  ::continue::
+end
+local i = 1
+::top::
+if i <= 3 then
+  i = i + 1
+  goto top
+end
+local fs = {}
+for n = 1, 3 do
+  do
+    local y = n * 10
+    fs[n] = function() return y end
+    goto next
+  end
+  ::next::
+end
+print(i, fs[1](), fs[2](), fs[3]())
+"#],
+            "1\n3\n4\t10\t20\t30\n",
+        ),
+        (
+            "semicolons",
+            &[r#"
+;;
+local function f(x) return x; end
+for i = 1, 10 do
+  if i > 2 then break; end;
+  print(f(i));
+end
+do ; end
+local function g() return; end
+print(g())
+"#],
+            "1\n2\n\n",
+        ),
+        (
+            "statements after break",
+            &[r#"
+while true do break; local x = 1 end
+local n = 0
+repeat
+  n = n + 1
+  if n == 3 then break print("unreachable") end
+until false
+print(n)
+"#],
+            "3\n",
+        ),
+        (
+            "bitwise operators and attributes",
+            &["local x <const>, y <close> = 6, nil\nprint(x & 3, x | 1, x ~ 5, ~x, 1 << 4, 256 >> 4)"],
+            "2\t7\t3\t-7\t16\t16\n",
+        ),
+    ]);
+}
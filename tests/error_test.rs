@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::io;
+
+use purua::errors::LuaError;
+use purua::value::Value;
+use purua::vm::Vm;
+
+extern crate purua;
+
+mod common;
+
+use common::{run, Output};
+
+fn run_chunk(source: &str, chunkname: &str) -> Result<Vec<Value>, LuaError> {
+    let mut vm = Vm::with_output(io::sink());
+    let main = vm.load(source, chunkname)?;
+    vm.call(&main, Vec::new())
+}
+
+#[test]
+fn test_error_positions() {
+    let source = r#"
+local function check(n)
+  if n < 0 then error("negative") end
+  return n
+end
+check(-1)
+"#;
+    let err = run_chunk(source, "fib.lua").unwrap_err();
+    assert_eq!("fib.lua:3: negative", err.to_string());
+
+    let source = r#"
+local function check(n)
+  error("bad input", 2)
+end
+local x = 1
+check(x)
+"#;
+    let err = run_chunk(source, "fib.lua").unwrap_err();
+    assert_eq!("fib.lua:6: bad input", err.to_string());
+
+    let err = run_chunk("local t = {}\nreturn t.x + 1", "fib.lua").unwrap_err();
+    assert_eq!(
+        "fib.lua:2: attempt to perform arithmetic on a nil value (field 'x')",
+        err.to_string()
+    );
+    let err = run_chunk("error('plain', 0)", "fib.lua").unwrap_err();
+    assert_eq!("plain", err.to_string());
+}
+
+#[test]
+fn test_pcall() -> Result<(), Box<dyn Error>> {
+    let source = r##"
+print(pcall(function(a, b) return a + b, "ok" end, 1, 2))
+print(pcall(error, "msg", 0))
+local ok, e = pcall(error, { code = 42 })
+print(ok, e.code)
+print(pcall(function() local x = nil return x.y end))
+print(select("#", pcall(error)))
+print(pcall(function() return error("tail", 0) end))
+print(pcall(pcall, error, "inner", 0))
+print(pcall(error, "msg"))
+print(pcall(error, "msg", 2))
+print(pcall(function() error("up", 2) end))
+"##;
+    assert_eq!(
+        "true\t3\tok\nfalse\tmsg\nfalse\t42\n\
+         false\t?:6: attempt to index a nil value (local 'x')\n2\n\
+         false\ttail\ntrue\tfalse\tinner\n\
+         false\tmsg\nfalse\t?:11: msg\nfalse\tup\n",
+        run(source)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_xpcall() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function handler(e) return "handled: " .. e end
+print(xpcall(function() error("oops", 0) end, handler))
+print(xpcall(function(a) return a * 2 end, handler, 21))
+print(xpcall(error, function(e) error("again") end, "x"))
+"#;
+    assert_eq!(
+        "false\thandled: oops\ntrue\t42\nfalse\terror in error handling\n",
+        run(source)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_error_variants() {
+    let err = run_chunk("return 1 +", "bad.lua").unwrap_err();
+    assert!(matches!(&err, LuaError::Syntax(m) if m.starts_with("bad.lua:")));
+    let err = run_chunk("local s = 1\nprint(\"abc)", "bad.lua").unwrap_err();
+    assert_eq!(
+        "bad.lua:2: unfinished string near '\"abc)'",
+        err.to_string()
+    );
+
+    let err = run_chunk("error({})", "t.lua").unwrap_err();
+    assert!(matches!(err, LuaError::Runtime(Value::Table(_))));
+    assert_eq!("(error object is a table value)", err.to_string());
+
+    let mut vm = Vm::with_output(io::sink());
+    let handler = vm.get_global("error");
+    let f = vm.get_global("error");
+    let err = vm
+        .call_with_handler(&f, vec![Value::from("x")], &handler)
+        .unwrap_err();
+    assert!(matches!(err, LuaError::ErrorHandler(_)));
+}
+
+#[test]
+fn test_memory_limit() -> Result<(), Box<dyn Error>> {
+    let out = Output::default();
+    let mut vm = Vm::with_output(out.clone());
+    vm.set_memory_limit(Some(1 << 20));
+    let source = r#"
+local t = {}
+print(pcall(function()
+  for i = 1, 1000000 do t[i] = { i } end
+end))
+t = nil
+collectgarbage()
+print("done")
+"#;
+    vm.exec_source(source)?;
+    assert_eq!(
+        b"false\tnot enough memory\ndone\n",
+        out.0.borrow().as_slice()
+    );
+
+    let main = vm.load("local t = {} for i = 1, 1000000 do t[i] = {} end", "m.lua")?;
+    assert!(matches!(vm.call(&main, Vec::new()), Err(LuaError::Memory)));
+    Ok(())
+}
+
+#[test]
+fn test_syntax_errors() {
+    let source = "local function f(n)\n  if n < 2 then return n end\n  if n do end\nend";
+    let err = run_chunk(source, "fib.lua").unwrap_err();
+    assert_eq!("fib.lua:3: 'then' expected near 'do'", err.to_string());
+
+    let cases = [
+        ("return return", "<eof> expected near 'return'"),
+        ("local function (a) end", "<name> expected near '('"),
+        ("local t = {1, 2", "'}' expected near <eof>"),
+        ("for i = 1 do end", "',' expected near 'do'"),
+        ("for in t do end", "<name> expected near 'in'"),
+        ("for k, in t do end", "<name> expected near 'in'"),
+        ("repeat x = 1", "'until' expected near <eof>"),
+        ("local x <const = 1", "'>' expected near '='"),
+        ("while true end", "'do' expected near 'end'"),
+        (")", "unexpected symbol near ')'"),
+        ("f() = 1", "syntax error near '='"),
+        ("f(), x = 1", "syntax error near ','"),
+        ("a.b:c = 1", "function arguments expected near '='"),
+        ("a:c", "function arguments expected near <eof>"),
+        ("(x) = 1", "syntax error near '='"),
+        ("x y", "syntax error near 'y'"),
+        ("x = 1 end", "<eof> expected near 'end'"),
+        ("return; x = 1", "<eof> expected near 'x'"),
+        ("return;;", "<eof> expected near ';'"),
+    ];
+    for (source, message) in cases {
+        let err = run_chunk(source, "t.lua").unwrap_err();
+        assert!(matches!(err, LuaError::Syntax(_)));
+        assert_eq!(format!("t.lua:1: {}", message), err.to_string());
+    }
+}
+
+#[test]
+fn test_nesting_limit() -> Result<(), Box<dyn Error>> {
+    let source = format!("{}x = 1{}", "do ".repeat(150), " end".repeat(150));
+    run_chunk(&source, "t.lua")?;
+
+    let cases = [
+        (
+            format!("{}x = 1{}", "do ".repeat(300), " end".repeat(300)),
+            "do",
+        ),
+        (
+            format!("x = {}1{}", "(".repeat(1000), ")".repeat(1000)),
+            "(",
+        ),
+        (
+            format!("x = {}1{}", "{".repeat(1000), "}".repeat(1000)),
+            "{",
+        ),
+    ];
+    for (source, near) in cases {
+        let err = run_chunk(&source, "t.lua").unwrap_err();
+        assert!(matches!(err, LuaError::Syntax(_)));
+        let message = format!("t.lua:1: chunk has too many C levels near '{}'", near);
+        assert_eq!(message, err.to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_error_fields() -> Result<(), Box<dyn Error>> {
+    use purua::parser::{parser::parse, stream::TokenStream};
+    use purua::scanner::{Scanner, TokenType};
+
+    let mut scanner = Scanner::new("x = 1\nwhile x < 2\n  x = x + 1\nend");
+    scanner.scan()?;
+    let err = parse(TokenStream::new(scanner.tokens)).unwrap_err();
+    assert_eq!(TokenType::Name, err.token_type);
+    assert_eq!("x", err.text);
+    assert_eq!(3, err.line);
+    assert_eq!((3, 3), (err.span.line, err.span.column));
+    assert_eq!(vec![TokenType::Do], err.expected);
+    assert_eq!("3: 'do' expected near 'x'", err.to_string());
+    Ok(())
+}
+
+#[test]
+fn test_invalid_literal() {
+    use purua::parser::{parser::parse, stream::TokenStream};
+    use purua::scanner::{Token, TokenType};
+
+    // The scanner only makes literals that convert, but a stream built by
+    // hand may hold any text.
+    let literal = |token_type, lexeme| Token {
+        lexeme,
+        ..Token::from(token_type)
+    };
+    let cases = [
+        (literal(TokenType::Int, "1x"), "unexpected symbol near '1x'"),
+        (
+            literal(TokenType::Float, "1.x"),
+            "unexpected symbol near '1.x'",
+        ),
+        (
+            literal(TokenType::StringLit, "'a"),
+            "unexpected symbol near ''a'",
+        ),
+    ];
+    for (token, message) in cases {
+        let tokens = vec![
+            literal(TokenType::Name, "x"),
+            Token::from(TokenType::Assign),
+            token,
+            Token::from(TokenType::Eof),
+        ];
+        let err = parse(TokenStream::new(tokens)).unwrap_err();
+        assert_eq!(format!("0: {}", message), err.to_string());
+    }
+
+    let tokens = vec![
+        literal(TokenType::Name, "f"),
+        literal(TokenType::StringLit, "'a"),
+        Token::from(TokenType::Eof),
+    ];
+    let err = parse(TokenStream::new(tokens)).unwrap_err();
+    assert_eq!("0: unexpected symbol near ''a'", err.to_string());
+}
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::rc::Rc;
+
+use purua::value::Value;
+use purua::vm::Vm;
+
+extern crate purua;
+
+#[test]
+fn test_collect_cycles() -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::with_output(std::io::sink());
+    let source = r#"
+collectgarbage("stop")
+local before = collectgarbage("count")
+for i = 1, 500 do
+  local t = {}
+  t.self = t
+  local function f() return f end
+end
+local grown = collectgarbage("count")
+collectgarbage()
+return grown > before, collectgarbage("count") < grown, collectgarbage("isrunning")
+"#;
+    let results = vm.exec_source(source)?;
+    assert!(matches!(
+        results.as_slice(),
+        [
+            Value::Boolean(true),
+            Value::Boolean(true),
+            Value::Boolean(false)
+        ]
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_step() -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::with_output(std::io::sink());
+    let source = r#"
+collectgarbage("stop")
+for i = 1, 500 do
+  local t = {}
+  t.self = t
+end
+local grown = collectgarbage("count")
+local small = collectgarbage("step")
+local unchanged = collectgarbage("count") == grown
+local big = collectgarbage("step", 1000000)
+return small, unchanged, big, collectgarbage("count") < grown
+"#;
+    let results = vm.exec_source(source)?;
+    assert!(matches!(
+        results.as_slice(),
+        [
+            Value::Boolean(false),
+            Value::Boolean(true),
+            Value::Boolean(true),
+            Value::Boolean(true)
+        ]
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_modes() -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::with_output(std::io::sink());
+    let source = r#"
+return collectgarbage("generational"), collectgarbage("incremental", 100, 200),
+  collectgarbage("incremental")
+"#;
+    let results = vm.exec_source(source)?;
+    let modes: Vec<String> = results.iter().map(Value::to_string).collect();
+    assert_eq!(vec!["incremental", "generational", "incremental"], modes);
+    Ok(())
+}
+
+#[test]
+fn test_collect_keeps_reachable_values() -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::with_output(std::io::sink());
+    let source = r#"
+local t = { name = "kept" }
+t.self = t
+kept = { t }
+local n = 0
+local function bump() n = n + 1 return n end
+counter = bump
+local held = {}
+held.self = held
+return held
+"#;
+    let held = vm.exec_source(source)?.remove(0);
+    let weak = match &held {
+        Value::Table(t) => Rc::downgrade(t),
+        v => return Err(format!("expected a table, got {}", v).into()),
+    };
+
+    vm.collect_garbage();
+    vm.exec_source("collectgarbage('collect') bump = counter bump()")?;
+    let results = vm.exec_source("local t = kept[1] local s = t.self return s.name, counter()")?;
+    assert_eq!("kept", results[0].to_string());
+    assert_eq!("2", results[1].to_string());
+    // Held by the host, so still intact.
+    assert!(matches!(&held, Value::Table(t) if !t.borrow().get_str("self").is_nil()));
+
+    drop(held);
+    vm.collect_garbage();
+    assert!(weak.upgrade().is_none());
+    Ok(())
+}
+
+#[test]
+fn test_automatic_collection() -> Result<(), Box<dyn Error>> {
+    let mut vm = Vm::with_output(std::io::sink());
+    let source = r#"
+for i = 1, 100000 do
+  local t = {}
+  t.self = t
+end
+"#;
+    vm.exec_source(source)?;
+    assert!(vm.memory_in_use() < 1 << 20);
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::error::Error;
+
+extern crate purua;
+
+mod common;
+
+use common::interpret as run;
+
+#[test]
+fn test_runtime_error() {
+    let err = run("print(fib(10))").unwrap_err();
+    assert_eq!(
+        "attempt to call a nil value (global 'fib')",
+        err.to_string()
+    );
+}
+
+#[test]
+fn test_non_closable_value() {
+    let err = run("local x <close> = {}").unwrap_err();
+    assert_eq!("variable 'x' got a non-closable value", err.to_string());
+}
+
+#[test]
+fn test_static_errors() {
+    let cases = [
+        (
+            "goto nowhere",
+            "?:1: no visible label 'nowhere' for <goto> at line 1",
+        ),
+        (
+            "local x <const> = 1 x = 2",
+            "?:1: attempt to assign to const variable 'x'",
+        ),
+        ("print(1 +", "?:1: unexpected symbol near <eof>"),
+    ];
+    for (source, message) in cases {
+        assert_eq!(message, run(source).unwrap_err().to_string());
+    }
+}
+
+#[test]
+fn test_closures_called_by_the_vm() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local v = setmetatable({}, {
+  __index = function(t, k) return k .. "!" end,
+  __add = function(a, b) return 42 end,
+})
+print(v.x, v + 1, pcall(function(a) return a * 2 end, 21))
+print(pcall(function() error("boom", 0) end))
+local co = coroutine.wrap(function() coroutine.yield(1) end)
+print(pcall(co))
+"#;
+    assert_eq!(
+        "x!\t42\ttrue\t42\nfalse\tboom\nfalse\tattempt to yield across a C-call boundary\n",
+        run(source)?
+    );
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::io;
+
+use purua::vm::Vm;
+
+extern crate purua;
+
+mod common;
+
+use common::{run, Output};
+
+#[test]
+fn test_index_and_newindex() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local defaults = { color = "red" }
+local t = setmetatable({}, { __index = defaults })
+print(t.color, t.size)
+local log = {}
+local p = setmetatable({}, {
+  __index = function(t, k) return k .. "!" end,
+  __newindex = function(t, k, v) rawset(log, k, v) end
+})
+p.x = 1
+print(p.hello, rawget(p, "x"), log.x)
+"#;
+    assert_eq!("red\tnil\nhello!\tnil\t1\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_operators() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local V = {}
+local function vec(x, y) return setmetatable({ x = x, y = y }, V) end
+V.__add = function(a, b) return vec(a.x + b.x, a.y + b.y) end
+V.__eq = function(a, b) return a.x == b.x and a.y == b.y end
+V.__lt = function(a, b) return a.x < b.x end
+V.__le = function(a, b) return a.x <= b.x end
+V.__len = function(a) return 2 end
+V.__concat = function(a, b) return "vec" end
+V.__call = function(self, n) return self.x * n end
+V.__tostring = function(v) return "(" .. v.x .. ", " .. v.y .. ")" end
+local a = vec(1, 2) + vec(3, 4)
+print(a, a == vec(4, 6), a ~= vec(4, 6), vec(1, 0) < a, a <= vec(1, 0))
+print(#a, a .. 1, a(10), tostring(a), rawequal(a, a), rawlen(a))
+"#;
+    assert_eq!(
+        "(4, 6)\ttrue\tfalse\ttrue\tfalse\n2\tvec\t40\t(4, 6)\ttrue\t0\n",
+        run(source)?
+    );
+    Ok(())
+}
+
+#[test]
+fn test_protected_metatable() {
+    let source = r#"
+local t = setmetatable({}, { __metatable = "locked" })
+print(getmetatable(t))
+setmetatable(t, {})
+"#;
+    let out = Output::default();
+    let mut vm = Vm::with_output(out.clone());
+    let err = vm.exec_source(source).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("cannot change a protected metatable"));
+    assert_eq!(b"locked\n", out.0.borrow().as_slice());
+}
+
+#[test]
+fn test_to_be_closed_variables() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function closer(name)
+  return setmetatable({}, { __close = function(o, e) print(name, e) end })
+end
+do
+  local a <close> = closer("a")
+  local b <close> = closer("b")
+  local c <const> = 1
+  print("body", c)
+end
+local function f()
+  local x <close> = closer("x")
+  return 1
+end
+print(f())
+"#;
+    assert_eq!("body\t1\nb\tnil\na\tnil\nx\tnil\n1\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_to_be_closed_on_error() {
+    let out = Output::default();
+    let mut vm = Vm::with_output(out.clone());
+    let source = r#"
+local t <close> = setmetatable({}, { __close = function(o, e) print("closed") end })
+local x = nil + 1
+"#;
+    assert!(vm.exec_source(source).is_err());
+    assert_eq!(b"closed\n", out.0.borrow().as_slice());
+}
+
+#[test]
+fn test_attribute_errors() {
+    let mut vm = Vm::with_output(io::sink());
+    let err = vm.exec_source("local x <const> = 1 x = 2").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("attempt to assign to const variable 'x'"));
+    let err = vm.exec_source("local x <close> = 1").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("variable 'x' got a non-closable value"));
+    let err = vm.exec_source("local x <other> = 1").unwrap_err();
+    assert!(err.to_string().contains("unknown attribute 'other'"));
+}
+
+#[test]
+fn test_finalizers_and_weak_tables() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local mt = { __gc = function(o) print("gc", o.name) end }
+do
+  local t = setmetatable({ name = "cycle" }, mt)
+  t.self = t
+end
+collectgarbage()
+local cache = setmetatable({}, { __mode = "v" })
+local keys = setmetatable({}, { __mode = "k" })
+local kept = {}
+do
+  local lost = {}
+  cache[1] = lost
+  cache[2] = kept
+  keys[lost] = "lost"
+  keys[kept] = "kept"
+end
+collectgarbage()
+local n = 0
+for k, v in pairs(keys) do n = n + 1 end
+print(cache[1], cache[2] == kept, n)
+"#;
+    assert_eq!("gc\tcycle\nnil\ttrue\t1\n", run(source)?);
+    Ok(())
+}
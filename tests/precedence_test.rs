@@ -0,0 +1,162 @@
+use std::error::Error;
+
+use purua::parser::ast::{Binop, Expr, LastStat, Unop};
+use purua::parser::{parser::parse, stream::TokenStream};
+use purua::scanner::{Scanner, TokenType};
+
+extern crate purua;
+
+mod common;
+
+use common::run;
+
+/// Expressions and what the reference Lua 5.4 prints for them.
+const CASES: &[(&str, &str)] = &[
+    // `^` is right associative and binds tighter than unary operators.
+    ("2^3^2", "512.0"),
+    ("-2^2", "-4.0"),
+    ("2^-2", "0.25"),
+    ("-2^-2", "-0.25"),
+    ("2^-1^2", "0.5"),
+    ("2^3*2", "16.0"),
+    ("1 + 2 * 3^2", "19.0"),
+    // Unary operators bind tighter than every other binary operator.
+    ("- - 2", "2"),
+    ("3 - -3", "6"),
+    ("-3 % 5", "2"),
+    ("#\"abc\" + 1", "4"),
+    ("#\"ab\" .. \",\"", "2,"),
+    ("#{1, 2} * 2", "4"),
+    ("not 1 == 2", "false"),
+    ("not not nil", "false"),
+    ("~0 >> 62", "3"),
+    // Arithmetic operators are left associative.
+    ("10 - 4 - 3", "3"),
+    ("2 * 3 % 4", "2"),
+    ("7 // 2 * 2", "6"),
+    ("2^2^3 / 2 / 2", "64.0"),
+    // `..` is right associative and binds looser than arithmetic.
+    ("\"a\" .. \"b\" .. \"c\"", "abc"),
+    ("1 .. 2 .. 3", "123"),
+    ("\"1\" .. 2 + 3", "15"),
+    ("1 + 2 .. \"\"", "3"),
+    ("-2^2 .. \"\"", "-4.0"),
+    ("\"10\" + 1 .. \"\"", "11"),
+    // Shifts, then `&`, `~` and `|`, sit between `..` and comparisons.
+    ("1 << 2 + 1", "8"),
+    ("1 | 2 ~ 3 & 4 << 1", "3"),
+    ("6 & 3 ~ 1 | 8", "11"),
+    ("5 & 3 == 1", "true"),
+    ("1 .. 2 == \"12\"", "true"),
+    ("1 << 1 .. \"\"", "2"),
+    // Comparisons are left associative, then `and`, then `or`.
+    ("1 < 2 == true", "true"),
+    ("false and nil or 3", "3"),
+    ("nil or false and 1", "false"),
+    ("1 == 1 and 2 or 3", "2"),
+    ("1 or error() and nil", "1"),
+];
+
+#[test]
+fn test_precedence() -> Result<(), Box<dyn Error>> {
+    for (expr, expected) in CASES {
+        let output = run(&format!("print({})", expr))?;
+        assert_eq!(format!("{}\n", expected), output, "{}", expr);
+    }
+    Ok(())
+}
+
+fn return_expr(source: &str, check: impl FnOnce(&Expr)) -> Result<(), Box<dyn Error>> {
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+    let block = parse(TokenStream::new(scanner.tokens))?;
+    match &(block.0).1 {
+        Some(LastStat::Return(Some(exprs))) => check(&exprs.0[0]),
+        last => panic!("not a return: {:?}", last),
+    }
+    Ok(())
+}
+
+fn binop<'e, 'src>(expr: &'e Expr<'src>) -> (&'e Expr<'src>, TokenType, &'e Expr<'src>) {
+    match expr {
+        Expr::ExprBinop(l, Binop(op), r) => (l, op.token_type, r),
+        _ => panic!("not a binary operation: {:?}", expr),
+    }
+}
+
+#[test]
+fn test_associativity() -> Result<(), Box<dyn Error>> {
+    return_expr("return a .. b .. c", |expr| {
+        let (l, op, r) = binop(expr);
+        assert_eq!(TokenType::Concat, op);
+        assert!(matches!(l, Expr::PrefixExp(_)));
+        assert_eq!(TokenType::Concat, binop(r).1);
+    })?;
+    return_expr("return a - b - c", |expr| {
+        let (l, op, r) = binop(expr);
+        assert_eq!(TokenType::Minus, op);
+        assert_eq!(TokenType::Minus, binop(l).1);
+        assert!(matches!(r, Expr::PrefixExp(_)));
+    })?;
+    return_expr("return -x ^ 2", |expr| match expr {
+        Expr::Unop(Unop(op), operand) => {
+            assert_eq!(TokenType::Minus, op.token_type);
+            assert_eq!(TokenType::Hat, binop(operand).1);
+        }
+        _ => panic!("not a unary operation: {:?}", expr),
+    })?;
+    return_expr("return not a == b", |expr| {
+        let (l, op, _) = binop(expr);
+        assert_eq!(TokenType::Eql, op);
+        assert!(matches!(l, Expr::Unop(..)));
+    })
+}
+
+#[test]
+fn test_long_chains() -> Result<(), Box<dyn Error>> {
+    let terms = vec!["x"; 2000];
+    return_expr(&format!("return {}", terms.join(" - ")), |expr| {
+        let mut depth = 0;
+        let mut expr = expr;
+        while let Expr::ExprBinop(l, _, _) = expr {
+            depth += 1;
+            expr = l;
+        }
+        assert_eq!(1999, depth);
+    })?;
+    let terms = vec!["x"; 150];
+    return_expr(&format!("return {}", terms.join(" .. ")), |expr| {
+        let mut depth = 0;
+        let mut expr = expr;
+        while let Expr::ExprBinop(_, _, r) = expr {
+            depth += 1;
+            expr = r;
+        }
+        assert_eq!(149, depth);
+    })?;
+    return_expr(&format!("return {}x", "not -".repeat(50)), |expr| {
+        assert!(matches!(expr, Expr::Unop(..)));
+    })?;
+
+    // Right operands and unary operands nest, as deeply as Lua allows.
+    let terms = vec!["x"; 100000];
+    let err = return_expr(&format!("return {}", terms.join(" .. ")), |_| {}).unwrap_err();
+    assert_eq!("1: chunk has too many C levels near 'x'", err.to_string());
+    let err = return_expr(&format!("return {}x", "- ".repeat(1000)), |_| {}).unwrap_err();
+    assert_eq!("1: chunk has too many C levels near '-'", err.to_string());
+    Ok(())
+}
+
+#[test]
+fn test_long_chains_run() -> Result<(), Box<dyn Error>> {
+    // Left-associative chains reuse one register, however long they are.
+    let terms = vec!["a"; 250];
+    let source = format!("local a = 1 x = {} print(x)", terms.join(" + "));
+    assert_eq!("250\n", run(&source)?);
+    let terms = vec!["a"; 2000];
+    let source = format!("local a = 3 print({})", terms.join(" * 2 - "));
+    assert_eq!("-11985\n", run(&source)?);
+    let source = format!("local a = 1 print({}a)", "not ".repeat(150));
+    assert_eq!("true\n", run(&source)?);
+    Ok(())
+}
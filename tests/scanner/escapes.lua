@@ -0,0 +1,8 @@
+local a = "say \"hi\""
+local b = 'it\'s \65\x42\u{43}'
+local c = "first \z
+           second"
+local d = "line\
+break"
+local e = "\u{20AC}\255\0"
+print(a, b, c, d)
@@ -0,0 +1,10 @@
+--[[ a block comment
+spanning lines ]]
+local sql = [[
+SELECT *
+FROM t]]
+--[==[
+]] still a comment
+]==]
+local nested = [=[a]]b]=]
+print(sql, nested) -- trailing comment
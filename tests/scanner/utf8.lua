@@ -0,0 +1,6 @@
+-- コメント: ünïcödé in a comment
+local s = "héllo, 世界"
+--[[ 多行
+コメント ]]
+local t = [==[λ → μ]==]
+print(s, t)
@@ -1,6 +1,6 @@
 use std::error::Error;
 
-use purua::scanner::{Scanner, Token, TokenType};
+use purua::scanner::{Scanner, Span, Token, TokenType, TriviaKind};
 
 extern crate purua;
 
@@ -14,78 +14,123 @@ fn test_scanned_1() -> Result<(), Box<dyn Error>> {
     let expected = vec![
         Token {
             token_type: If,
-            lexeme: "if".to_string(),
+            lexeme: "if",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Int,
-            lexeme: "1".to_string(),
+            lexeme: "1",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Plus,
-            lexeme: "+".to_string(),
+            lexeme: "+",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Int,
-            lexeme: "1".to_string(),
+            lexeme: "1",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Less,
-            lexeme: "<".to_string(),
+            lexeme: "<",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Int,
-            lexeme: "2".to_string(),
+            lexeme: "2",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Do,
-            lexeme: "do".to_string(),
+            lexeme: "do",
             line: 2,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Name,
-            lexeme: "print".to_string(),
+            lexeme: "print",
             line: 3,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: ParenL,
-            lexeme: "(".to_string(),
+            lexeme: "(",
             line: 3,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: StringLit,
-            lexeme: "\"hello, world\"".to_string(),
+            lexeme: "\"hello, world\"",
             line: 3,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: ParenR,
-            lexeme: ")".to_string(),
+            lexeme: ")",
             line: 3,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Return,
-            lexeme: "return".to_string(),
+            lexeme: "return",
             line: 4,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Int,
-            lexeme: "3".to_string(),
+            lexeme: "3",
             line: 4,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: End,
-            lexeme: "end".to_string(),
+            lexeme: "end",
             line: 5,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
         Token {
             token_type: Eof,
-            lexeme: "".to_string(),
+            lexeme: "",
             line: 6,
+            span: Span::default(),
+            symbol: None,
+            trivia: None,
         },
     ];
 
@@ -112,3 +157,410 @@ fn test_scan_failed_1() -> Result<(), Box<dyn Error>> {
     assert!(scanner.scan().is_err());
     Ok(())
 }
+
+#[test]
+fn test_long_brackets() -> Result<(), Box<dyn Error>> {
+    let source = include_str!("scanner/long_brackets.lua");
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+
+    let strings: Vec<(String, u32)> = scanner
+        .tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::StringLit)
+        .map(|t| (String::try_from(t.clone()).unwrap(), t.line))
+        .collect();
+    assert_eq!(
+        vec![("SELECT *\nFROM t".to_string(), 5), ("a]]b".to_string(), 9)],
+        strings
+    );
+    assert_eq!(3, scanner.comments.len());
+    assert_eq!(11, scanner.tokens.last().unwrap().line);
+
+    let mut scanner = Scanner::new("x = [==[ never closed ]=]");
+    assert!(scanner.scan().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_escape_sequences() -> Result<(), Box<dyn Error>> {
+    let source = include_str!("scanner/escapes.lua");
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+
+    let strings: Vec<(Vec<u8>, u32)> = scanner
+        .tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::StringLit)
+        .map(|t| (Vec::<u8>::try_from(t.clone()).unwrap(), t.line))
+        .collect();
+    assert_eq!(
+        vec![
+            (b"say \"hi\"".to_vec(), 1),
+            (b"it's ABC".to_vec(), 2),
+            (b"first second".to_vec(), 4),
+            (b"line\nbreak".to_vec(), 6),
+            (b"\xe2\x82\xac\xff\x00".to_vec(), 7),
+        ],
+        strings
+    );
+    let print = scanner.tokens.iter().find(|t| t.lexeme == "print").unwrap();
+    assert_eq!(8, print.line);
+
+    for source in ["'a\\\r\nb' x", "'a\\\n\rb' x"] {
+        let mut scanner = Scanner::new(source);
+        scanner.scan()?;
+        let string = Vec::<u8>::try_from(scanner.tokens[0].clone())?;
+        assert_eq!(b"a\nb".to_vec(), string, "{:?}", source);
+        assert_eq!(TokenType::Name, scanner.tokens[1].token_type);
+        assert_eq!(2, scanner.tokens[1].line);
+    }
+
+    for source in [
+        r#""\q""#,
+        r#""\xZZ""#,
+        r#""\256""#,
+        r#""\u{41""#,
+        r#""\u41""#,
+    ] {
+        let mut scanner = Scanner::new(source);
+        assert!(scanner.scan().is_err(), "{} should not scan", source);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_numerals() -> Result<(), Box<dyn Error>> {
+    let source = "3 345 0xff 0xBEBADA 3.0 2.25 225e-2 0.225E1 34e1 \
+                  0x0.1E 0xA23p-4 0X1.921FB54442D18P+1 .5 3. 0x7fffffffffffffff1 \
+                  9223372036854775808";
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+
+    use TokenType::*;
+    let numbers: Vec<Token> = scanner
+        .tokens
+        .into_iter()
+        .filter(|t| matches!(t.token_type, Int | Float))
+        .collect();
+    let kinds: Vec<TokenType> = numbers.iter().map(|t| t.token_type).collect();
+    assert_eq!(
+        vec![
+            Int, Int, Int, Int, Float, Float, Float, Float, Float, Float, Float, Float, Float,
+            Float, Int, Float
+        ],
+        kinds
+    );
+
+    let ints: Vec<i64> = numbers
+        .iter()
+        .filter(|t| t.token_type == Int)
+        .map(|t| i64::try_from(t.clone()).unwrap())
+        .collect();
+    assert_eq!(vec![3, 345, 255, 12499674, -15], ints);
+
+    let floats: Vec<f64> = numbers
+        .iter()
+        .filter(|t| t.token_type == Float)
+        .map(|t| f64::try_from(t.clone()).unwrap())
+        .collect();
+    assert_eq!(
+        vec![
+            3.0,
+            2.25,
+            2.25,
+            2.25,
+            340.0,
+            0.1171875,
+            162.1875,
+            std::f64::consts::PI,
+            0.5,
+            3.0,
+            9223372036854775808.0
+        ],
+        floats
+    );
+    assert!(i64::try_from(numbers[4].clone()).is_err());
+
+    for source in ["3e", "0x", "1..2", "3x", "0xep"] {
+        let mut scanner = Scanner::new(source);
+        assert!(scanner.scan().is_err(), "{} should not scan", source);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_utf8_source() -> Result<(), Box<dyn Error>> {
+    let source = include_str!("scanner/utf8.lua");
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+
+    let strings: Vec<(String, u32)> = scanner
+        .tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::StringLit)
+        .map(|t| (String::try_from(t.clone()).unwrap(), t.line))
+        .collect();
+    assert_eq!(
+        vec![("héllo, 世界".to_string(), 2), ("λ → μ".to_string(), 5)],
+        strings
+    );
+    assert_eq!(
+        "-- コメント: ünïcödé in a comment",
+        scanner.comments[0].lexeme
+    );
+    let print = scanner.tokens.iter().find(|t| t.lexeme == "print").unwrap();
+    assert_eq!(6, print.line);
+
+    let mut scanner = Scanner::new("local 変数 = 1");
+    assert!(scanner.scan().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_spans() -> Result<(), Box<dyn Error>> {
+    let source = "local s = \"é\" -- note\n  x = [[a\nb]]\n";
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+
+    let spans: Vec<(&str, Span)> = scanner.tokens.iter().map(|t| (t.lexeme, t.span)).collect();
+    let span = |start, end, line, column, end_line, end_column| Span {
+        start,
+        end,
+        line,
+        column,
+        end_line,
+        end_column,
+    };
+    assert_eq!(
+        vec![
+            ("local", span(0, 5, 1, 1, 1, 6)),
+            ("s", span(6, 7, 1, 7, 1, 8)),
+            ("=", span(8, 9, 1, 9, 1, 10)),
+            ("\"é\"", span(10, 14, 1, 11, 1, 14)),
+            ("x", span(25, 26, 2, 3, 2, 4)),
+            ("=", span(27, 28, 2, 5, 2, 6)),
+            ("[[a\nb]]", span(29, 36, 2, 7, 3, 4)),
+            ("", span(37, 37, 4, 1, 4, 1)),
+        ],
+        spans
+    );
+    assert_eq!(span(15, 22, 1, 15, 1, 22), scanner.comments[0].span);
+    // `line` is the line a token ends on.
+    assert_eq!(3, scanner.tokens[6].line);
+    for token in &scanner.tokens {
+        assert_eq!(token.lexeme, &source[token.span.range()]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_scan_errors() {
+    use purua::errors::ScanErrorKind::*;
+
+    let cases = [
+        ("x = 1 @ 2", UnexpectedSymbol, "@", (1, 7)),
+        ("s = \"abc\nprint(s)", UnfinishedString, "\"abc", (1, 5)),
+        ("s = 'abc", UnfinishedString, "'abc", (1, 5)),
+        ("s = 'a\rb'", UnfinishedString, "'a", (1, 5)),
+        (
+            "s = 'ok'\ns = 'a\\qb'",
+            InvalidEscape("invalid escape sequence"),
+            "\\q",
+            (2, 7),
+        ),
+        (
+            "s = \"é\\x4g\"",
+            InvalidEscape("hexadecimal digit expected"),
+            "\\x4g",
+            (1, 7),
+        ),
+        ("n = 3e+", MalformedNumber, "3e+", (1, 5)),
+        (
+            "s = [==[\nnever closed",
+            UnfinishedLongString,
+            "<eof>",
+            (1, 5),
+        ),
+        ("--[[ a\ncomment", UnfinishedLongComment, "<eof>", (1, 1)),
+    ];
+    for (source, kind, text, (line, column)) in cases {
+        let mut scanner = Scanner::new(source);
+        let err = scanner.scan().unwrap_err();
+        assert_eq!(kind, err.kind, "{}", source);
+        assert_eq!(text, err.text, "{}", source);
+        assert_eq!(
+            (line, column),
+            (err.span.line, err.span.column),
+            "{}",
+            source
+        );
+        if text != "<eof>" {
+            assert_eq!(text, &source[err.span.range()], "{}", source);
+        }
+    }
+
+    let mut scanner = Scanner::new("s = [[\nabc");
+    let err = scanner.scan().unwrap_err();
+    assert_eq!(
+        "2: unfinished long string (starting at line 1) near '<eof>'",
+        err.to_string()
+    );
+    let mut scanner = Scanner::new("local s = 'a\\300'");
+    let err = scanner.scan().unwrap_err();
+    assert_eq!("1: decimal escape too large near '\\300'", err.to_string());
+
+    let name = Token::new(TokenType::Name, "x", Span::default());
+    assert_eq!(InvalidConversion, i64::try_from(name).unwrap_err().kind);
+}
+
+#[test]
+fn test_scan_recovering() {
+    use purua::errors::ScanErrorKind::*;
+
+    let source = "local a = 1 @ 2\nlocal s = \"abc\nlocal t = 'x\\qy' .. 3e+\nprint(a, s, t)";
+    let mut scanner = Scanner::new(source);
+    scanner.scan_recovering();
+
+    let kinds: Vec<_> = scanner.errors.iter().map(|e| e.kind).collect();
+    assert_eq!(
+        vec![
+            UnexpectedSymbol,
+            UnfinishedString,
+            InvalidEscape("invalid escape sequence"),
+            MalformedNumber
+        ],
+        kinds
+    );
+    let lines: Vec<_> = scanner.errors.iter().map(|e| e.span.line).collect();
+    assert_eq!(vec![1, 2, 3, 3], lines);
+
+    let errors: Vec<&str> = scanner
+        .tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::Error)
+        .map(|t| t.lexeme)
+        .collect();
+    assert_eq!(vec!["@", "\"abc", "'x\\qy'", "3e+"], errors);
+
+    // Everything around the errors is still scanned.
+    let tokens: Vec<&str> = scanner.tokens.iter().map(|t| t.lexeme).collect();
+    assert_eq!(
+        vec![
+            "local", "a", "=", "1", "@", "2", "local", "s", "=", "\"abc", "local", "t", "=",
+            "'x\\qy'", "..", "3e+", "print", "(", "a", ",", "s", ",", "t", ")", ""
+        ],
+        tokens
+    );
+    assert_eq!(TokenType::Eof, scanner.tokens.last().unwrap().token_type);
+
+    let mut scanner = Scanner::new("local ok = true");
+    scanner.scan_recovering();
+    assert!(scanner.errors.is_empty());
+}
+
+#[test]
+fn test_lossless() -> Result<(), Box<dyn Error>> {
+    let sources = [
+        include_str!("scanner/test_1.lua"),
+        include_str!("scanner/test_2.lua"),
+        include_str!("scanner/long_brackets.lua"),
+        include_str!("scanner/escapes.lua"),
+        include_str!("scanner/utf8.lua"),
+        "\r\n  local x = 1\t-- one\r\n\n--[[ block ]] return x  ",
+    ];
+    for source in sources {
+        let mut scanner = Scanner::lossless(source);
+        scanner.scan()?;
+        let text: String = scanner.tokens.iter().map(Token::full_text).collect();
+        assert_eq!(source, text);
+    }
+
+    let source = "local x = 1 -- one\n\n  -- two\nreturn x";
+    let mut scanner = Scanner::lossless(source);
+    scanner.scan()?;
+    let trivia = |i: usize| {
+        let trivia = scanner.tokens[i].trivia.as_ref().unwrap();
+        let kinds = |ts: &[purua::scanner::Trivia]| ts.iter().map(|t| t.kind).collect::<Vec<_>>();
+        (kinds(&trivia.leading), kinds(&trivia.trailing))
+    };
+    use TriviaKind::*;
+    // `1` keeps the rest of its line, `return` gets the lines before it.
+    assert_eq!((vec![], vec![Whitespace, Comment, Newline]), trivia(3));
+    assert_eq!(
+        (
+            vec![Newline, Whitespace, Comment, Newline],
+            vec![Whitespace]
+        ),
+        trivia(4)
+    );
+    assert_eq!(
+        "-- two",
+        scanner.tokens[4].trivia.as_ref().unwrap().leading[2].text
+    );
+    // Comments are still collected as before.
+    assert_eq!(2, scanner.comments.len());
+
+    let mut scanner = Scanner::new(source);
+    scanner.scan()?;
+    assert!(scanner.tokens.iter().all(|t| t.trivia.is_none()));
+    Ok(())
+}
+
+#[test]
+fn test_interned_names() -> Result<(), Box<dyn Error>> {
+    let source = String::from("local x, y = x, print\nprint(x)");
+    let mut scanner = Scanner::new(&source);
+    scanner.scan()?;
+
+    let names: Vec<&Token> = scanner
+        .tokens
+        .iter()
+        .filter(|t| t.token_type == TokenType::Name)
+        .collect();
+    assert_eq!(6, names.len());
+    assert!(names.iter().all(|t| t.symbol.is_some()));
+    assert_eq!(names[0].symbol, names[2].symbol);
+    assert_eq!(names[3].symbol, names[4].symbol);
+    assert_ne!(names[0].symbol, names[1].symbol);
+    assert_eq!(3, scanner.names.len());
+    assert_eq!("print", scanner.names.resolve(names[4].symbol.unwrap()));
+    assert_eq!(names[1].symbol, scanner.names.get("y"));
+
+    // Keywords and other tokens carry no symbol; lexemes borrow the source.
+    assert!(scanner.tokens[0].symbol.is_none());
+    assert!(std::ptr::eq(names[1].lexeme, &source[9..10]));
+    Ok(())
+}
+
+#[test]
+fn test_shebang_and_bom() -> Result<(), Box<dyn Error>> {
+    let source = "\u{feff}#!/usr/bin/env purua\nreturn #t";
+    let mut scanner = Scanner::lossless(source);
+    scanner.scan()?;
+    use TokenType::*;
+    let types: Vec<TokenType> = scanner.tokens.iter().map(|t| t.token_type).collect();
+    assert_eq!(vec![Return, Opus, Name, Eof], types);
+    assert_eq!(2, scanner.tokens[0].line);
+    assert!(scanner.comments.is_empty());
+
+    let leading = &scanner.tokens[0].trivia.as_ref().unwrap().leading;
+    let kinds: Vec<TriviaKind> = leading.iter().map(|t| t.kind).collect();
+    assert_eq!(
+        vec![TriviaKind::Bom, TriviaKind::Shebang, TriviaKind::Newline],
+        kinds
+    );
+    assert_eq!("#!/usr/bin/env purua", leading[1].text);
+    let text: String = scanner.tokens.iter().map(|t| t.full_text()).collect();
+    assert_eq!(source, text);
+
+    // Only the first line may start with `#`, right after a BOM if any.
+    for source in ["\n#x", " #x", "#!/bin/purua\n#x"] {
+        let mut scanner = Scanner::new(source);
+        scanner.scan()?;
+        assert_eq!(Opus, scanner.tokens[0].token_type, "{:?}", source);
+    }
+    let mut scanner = Scanner::new("\u{feff}\u{feff}");
+    assert!(scanner.scan().is_err());
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use std::error::Error;
+
+use purua::table::Table;
+use purua::value::Value;
+
+extern crate purua;
+
+fn keys(table: &Table) -> Result<Vec<Value>, Box<dyn Error>> {
+    let mut keys = Vec::new();
+    let mut key = Value::Nil;
+    while let Some((k, _)) = table.next(&key)? {
+        keys.push(k.clone());
+        key = k;
+    }
+    Ok(keys)
+}
+
+#[test]
+fn test_sequence_and_border() {
+    let mut t = Table::new();
+    for i in 1..=5 {
+        t.set_int(i, Value::Integer(i * 2));
+    }
+    assert_eq!(5, t.len());
+    assert_eq!(Value::Integer(6), t.get(&Value::Float(3.0)));
+
+    t.set_int(5, Value::Nil);
+    assert_eq!(4, t.len());
+
+    let mut t = Table::new();
+    assert_eq!(0, t.len());
+    t.set_int(2, Value::Boolean(true));
+    assert_eq!(0, t.len());
+    t.set_int(1, Value::Boolean(true));
+    assert_eq!(2, t.len());
+}
+
+#[test]
+fn test_border_with_holes() {
+    let mut t = Table::with_capacity(8, 0);
+    for i in [1, 2, 3, 6] {
+        t.set_int(i, Value::Integer(i));
+    }
+    let n = t.len() as i64;
+    assert!(n == 3 || n == 6);
+    assert!(!t.get_int(n).is_nil() && t.get_int(n + 1).is_nil());
+}
+
+#[test]
+fn test_reverse_insertion_moves_to_array() {
+    let mut t = Table::new();
+    for i in (1..=100).rev() {
+        t.set_int(i, Value::Integer(i));
+    }
+    assert_eq!(100, t.len());
+    assert_eq!(Value::Integer(42), t.get_int(42));
+}
+
+#[test]
+fn test_invalid_keys() {
+    let mut t = Table::new();
+    let err = t.set(Value::Nil, Value::Integer(1)).unwrap_err();
+    assert_eq!("table index is nil", err.message);
+    let err = t
+        .set(Value::Float(f64::NAN), Value::Integer(1))
+        .unwrap_err();
+    assert_eq!("table index is NaN", err.message);
+    assert!(t.get(&Value::Float(f64::NAN)).is_nil());
+    assert!(t.get(&Value::Nil).is_nil());
+}
+
+#[test]
+fn test_next_order() -> Result<(), Box<dyn Error>> {
+    let mut t = Table::new();
+    t.set_str("x", Value::Integer(1));
+    t.set_int(1, Value::from("a"));
+    t.set(Value::Float(1.5), Value::from("b"))?;
+    t.set_int(2, Value::from("c"));
+    t.set(Value::Boolean(true), Value::from("d"))?;
+
+    let keys = keys(&t)?;
+    assert_eq!(
+        vec![
+            Value::Integer(1),
+            Value::Integer(2),
+            Value::from("x"),
+            Value::Float(1.5),
+            Value::Boolean(true),
+        ],
+        keys
+    );
+    assert!(t.next(&Value::from("missing")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_clear_during_traversal() -> Result<(), Box<dyn Error>> {
+    let mut t = Table::new();
+    for i in 1..=10 {
+        t.set_int(i, Value::Integer(i));
+        t.set_str(&format!("k{}", i), Value::Integer(i));
+    }
+
+    let mut key = Value::Nil;
+    let mut seen = 0;
+    while let Some((k, _)) = t.next(&key)? {
+        t.set(k.clone(), Value::Nil)?;
+        key = k;
+        seen += 1;
+    }
+    assert_eq!(20, seen);
+    assert!(t.is_empty());
+    assert!(t.next(&Value::Nil)?.is_none());
+    Ok(())
+}
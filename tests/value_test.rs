@@ -0,0 +1,121 @@
+use std::cmp::Ordering;
+
+use purua::value::{arith, compare, format_float, str_to_number, ArithOp, Value};
+
+extern crate purua;
+
+#[test]
+fn test_tostring_numbers() {
+    assert_eq!("10", Value::Integer(10).to_string());
+    assert_eq!("10.0", Value::Float(10.0).to_string());
+    assert_eq!("-0.0", Value::Float(-0.0).to_string());
+    assert_eq!(
+        "3.1415926535898",
+        Value::Float(std::f64::consts::PI).to_string()
+    );
+    assert_eq!("1e+15", format_float(1e15));
+    assert_eq!("1e-05", format_float(0.00001));
+    assert_eq!("0.0001", format_float(0.0001));
+    assert_eq!("inf", format_float(f64::INFINITY));
+    assert_eq!("-inf", format_float(f64::NEG_INFINITY));
+}
+
+#[test]
+fn test_integer_float_arith() -> Result<(), Box<dyn std::error::Error>> {
+    let int = |i| Value::Integer(i);
+    let float = |n| Value::Float(n);
+
+    assert!(matches!(
+        arith(ArithOp::Add, &int(1), &int(2))?,
+        Some(Value::Integer(3))
+    ));
+    assert!(
+        matches!(arith(ArithOp::Add, &int(1), &float(2.0))?, Some(Value::Float(f)) if f == 3.0)
+    );
+    assert!(matches!(arith(ArithOp::Div, &int(4), &int(2))?, Some(Value::Float(f)) if f == 2.0));
+    assert!(matches!(
+        arith(ArithOp::IDiv, &int(-7), &int(2))?,
+        Some(Value::Integer(-4))
+    ));
+    assert!(matches!(
+        arith(ArithOp::Mod, &int(-7), &int(2))?,
+        Some(Value::Integer(1))
+    ));
+    assert!(
+        matches!(arith(ArithOp::Mod, &float(5.5), &int(-2))?, Some(Value::Float(f)) if f == -0.5)
+    );
+    assert!(matches!(
+        arith(ArithOp::Add, &int(i64::MAX), &int(1))?,
+        Some(Value::Integer(i64::MIN))
+    ));
+    assert!(matches!(
+        arith(ArithOp::Add, &Value::from("10"), &int(1))?,
+        Some(Value::Integer(11))
+    ));
+    assert!(matches!(
+        arith(ArithOp::Shl, &int(1), &int(64))?,
+        Some(Value::Integer(0))
+    ));
+    assert!(arith(ArithOp::Add, &Value::Nil, &int(1))?.is_none());
+    let err = |op, l, r| arith(op, &int(l), &int(r)).unwrap_err().to_string();
+    assert_eq!("attempt to perform 'n//0'", err(ArithOp::IDiv, 1, 0));
+    assert_eq!("attempt to perform 'n%0'", err(ArithOp::Mod, 1, 0));
+    assert!(arith(ArithOp::BAnd, &float(1.5), &int(1)).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_raw_equality_and_order() {
+    assert_eq!(Value::Integer(1), Value::Float(1.0));
+    assert_ne!(Value::Integer(1), Value::Float(1.5));
+    assert_ne!(Value::Float(f64::NAN), Value::Float(f64::NAN));
+    assert_ne!(Value::Integer(1), Value::from("1"));
+    assert_ne!(Value::new_table(), Value::new_table());
+
+    assert_eq!(
+        Some(Ordering::Less),
+        compare(&Value::Integer(1), &Value::Float(1.5))
+    );
+    assert_eq!(
+        Some(Ordering::Less),
+        compare(
+            &Value::Integer(i64::MAX),
+            &Value::Float(9223372036854775808.0)
+        )
+    );
+    assert_eq!(None, compare(&Value::Integer(1), &Value::Float(f64::NAN)));
+    assert_eq!(
+        Some(Ordering::Less),
+        compare(&Value::from("a"), &Value::from("b"))
+    );
+}
+
+#[test]
+fn test_truthiness() {
+    assert!(!Value::Nil.is_truthy());
+    assert!(!Value::Boolean(false).is_truthy());
+    assert!(Value::Integer(0).is_truthy());
+    assert!(Value::from("").is_truthy());
+}
+
+#[test]
+fn test_str_to_number() {
+    assert!(matches!(str_to_number(" 42 "), Some(Value::Integer(42))));
+    assert!(matches!(str_to_number("0x10"), Some(Value::Integer(16))));
+    assert!(matches!(str_to_number("1e2"), Some(Value::Float(f)) if f == 100.0));
+    assert!(matches!(
+        str_to_number("9223372036854775808"),
+        Some(Value::Float(_))
+    ));
+    assert!(matches!(str_to_number("0x1p4"), Some(Value::Float(f)) if f == 16.0));
+    assert!(matches!(str_to_number("-0x.8"), Some(Value::Float(f)) if f == -0.5));
+    assert!(matches!(
+        str_to_number("0xffffffffffffffff"),
+        Some(Value::Integer(-1))
+    ));
+    assert!(str_to_number("inf").is_none());
+    assert!(str_to_number("1 2").is_none());
+    assert!(str_to_number("1e").is_none());
+    assert!(str_to_number(".").is_none());
+    assert!(str_to_number("0x").is_none());
+}
@@ -0,0 +1,174 @@
+use std::error::Error;
+
+extern crate purua;
+
+mod common;
+
+use common::run;
+
+#[test]
+fn test_runtime_error() {
+    let err = run("print(fib(10))").unwrap_err();
+    assert_eq!(
+        "?:1: attempt to call a nil value (global 'fib')",
+        err.to_string()
+    );
+}
+
+#[test]
+fn test_tonumber_base() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+print(tonumber("10", 2), tonumber("ff", 16), tonumber(" -Zz ", 36), tonumber("8", 8))
+print(tonumber("10"), tonumber("0x10"), tonumber("1e1", 10), tonumber("x"))
+"#;
+    assert_eq!("2\t255\t-1295\tnil\n10\t16\tnil\tnil\n", run(source)?);
+    let cases = [
+        (
+            "tonumber(10, 16)",
+            "bad argument #1 to 'tonumber' (string expected, got number)",
+        ),
+        (
+            "tonumber('1', 1)",
+            "bad argument #2 to 'tonumber' (base out of range)",
+        ),
+        (
+            "tonumber('1', {})",
+            "bad argument #2 to 'tonumber' (number expected, got table)",
+        ),
+    ];
+    for (source, message) in cases {
+        let message = format!("?:1: {}", message);
+        assert_eq!(message, run(source).unwrap_err().to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn test_deep_tail_recursion() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function loop(n, acc)
+  if n == 0 then return acc end
+  return loop(n - 1, acc + 1)
+end
+local function depth(n)
+  if n == 0 then return 0 end
+  return 1 + depth(n - 1)
+end
+print(loop(1000000, 0), depth(10000))
+"#;
+    assert_eq!("1000000\t10000\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_stack_overflow() {
+    let err = run("local function f() return 1 + f() end f()").unwrap_err();
+    assert_eq!("?:1: stack overflow", err.to_string());
+}
+
+#[test]
+fn test_varargs() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function rest(a, ...)
+  return select('#', ...), ...
+end
+local function first(...)
+  local x, y = ...
+  return x, y, (...)
+end
+print(rest(1, 2, nil, 4))
+print(first(5, 6, 7))
+print(select(-1, 1, 2, 3), select(2, "a", "b", "c"))
+"#;
+    assert_eq!("3\t2\tnil\t4\n5\t6\t5\n3\tb\tc\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_adjusted_results() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local function three() return 1, 2, 3 end
+local t = { three(), three() }
+local a, b, c, d = (three())
+print(#t, a, b, three(), "|", (three()))
+print(three())
+"#;
+    assert_eq!("4\t1\tnil\t1\t|\t1\n1\t2\t3\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_runtime_error_names() {
+    let cases = [
+        (
+            "local t = nil t.x = 1",
+            "attempt to index a nil value (local 't')",
+        ),
+        (
+            "local t = {} return t.a + 1",
+            "attempt to perform arithmetic on a nil value (field 'a')",
+        ),
+        (
+            "return 1 + {}",
+            "attempt to perform arithmetic on a table value",
+        ),
+        (
+            "return #x",
+            "attempt to get length of a nil value (global 'x')",
+        ),
+        ("for i = 1, 10, 0 do end", "'for' step is zero"),
+        ("return 1 % 0", "attempt to perform 'n%0'"),
+        ("return 1 // 0", "attempt to perform 'n//0'"),
+    ];
+    for (source, message) in cases {
+        let message = format!("?:1: {}", message);
+        assert_eq!(message, run(source).unwrap_err().to_string());
+    }
+}
+
+#[test]
+fn test_long_strings() -> Result<(), Box<dyn Error>> {
+    let source = "--[[ print(\"skipped\")\n]] print([==[\nline 1\nline ]] 2]==])";
+    assert_eq!("line 1\nline ]] 2\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_string_escapes() -> Result<(), Box<dyn Error>> {
+    let source = r#"print("a\"b", #"\u{20AC}", "\x41\66\z
+                         C", #"\0\255")"#;
+    assert_eq!("a\"b\t3\tABC\t2\n", run(source)?);
+    Ok(())
+}
+
+#[test]
+fn test_shebang_script() -> Result<(), Box<dyn Error>> {
+    let source = "\u{feff}#!/usr/bin/env purua\nprint(\"ok\")\nerror('x')";
+    let err = run(source).unwrap_err();
+    assert_eq!("?:3: x", err.to_string());
+    assert_eq!("ok\n", run("#!/usr/bin/env purua\r\nprint(\"ok\")")?);
+    Ok(())
+}
+
+#[test]
+fn test_suffix_chains() -> Result<(), Box<dyn Error>> {
+    let source = r#"
+local obj = { v = 1 }
+function obj:get() return self end
+function obj:set(v) self.v = v return self end
+obj:get():set(5)
+local t = { { f = function(x) return { x * 2 } end } }
+local i = 1
+print(obj.v, t[i].f(21)[1])
+local function f() return function() return "called" end end
+print(f()(), ({ 10, 20 })[2])
+local mod = { y = "field" }
+local function require(name) return mod end
+print(require "x".y)
+t.a = { b = { c = {} } }
+t.a.b.c[1], t.a.b.d = "deep", "er"
+print(t.a.b.c[1] .. t.a.b.d)
+"#;
+    assert_eq!("5\t42\ncalled\t20\nfield\ndeeper\n", run(source)?);
+    Ok(())
+}